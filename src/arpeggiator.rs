@@ -0,0 +1,414 @@
+//! Arpeggiator as a routing stage.
+//!
+//! A route with an arpeggiator plays the held notes one after another
+//! instead of forwarding them: in the modes up, down, up-down (without
+//! repeating the highest and lowest note), random or as played, over a range
+//! of octaves. Every step lasts a note value (the rate), the note sounds for
+//! the gate part of it. Steps follow the incoming MIDI clock or an internal
+//! tempo. The notes are sent by a Scheduler; with the internal tempo, a timer
+//! thread plans the steps. Other messages are forwarded as usual.
+
+use super::clock::{MAX_BPM, MIN_BPM};
+use super::scheduler::{Scheduler, SharedOutput};
+use super::traffic::SharedCounters;
+use super::MidiMessage;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const CLOCKS_PER_QUARTER: u32 = 24;
+const MAX_OCTAVES: u8 = 4;
+const MAX_INTERVAL: u64 = 500000;             // Longest clock interval in usec, longer ones are gaps
+const LOOKAHEAD: Duration = Duration::from_millis(5); // The timer schedules steps this early
+const NOTE_OFF_TAG: u32 = 1;                  // Scheduler tag of the NoteOff of the sounding note
+
+/// Order in which the held notes are played.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl ArpMode {
+    pub fn parse(name: &str) -> Option<ArpMode> {
+        match name {
+            "up" => Some(ArpMode::Up),
+            "down" => Some(ArpMode::Down),
+            "updown" => Some(ArpMode::UpDown),
+            "random" => Some(ArpMode::Random),
+            "played" => Some(ArpMode::AsPlayed),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ArpMode::Up => "up",
+            ArpMode::Down => "down",
+            ArpMode::UpDown => "updown",
+            ArpMode::Random => "random",
+            ArpMode::AsPlayed => "played",
+        }
+    }
+}
+
+/// Arpeggiator settings of a route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArpOptions {
+    pub mode: ArpMode,
+    pub octaves: u8,        // 1 - 4
+    pub gate: u8,           // Percent of a step the note sounds, 1 - 100
+    pub rate: u32,          // Note value of a step: 4 = quarter notes, 16 = sixteenths
+    pub triplets: bool,
+    pub tempo: Option<f64>, // BPM, None = follow the incoming clock
+}
+
+impl Default for ArpOptions {
+    fn default() -> Self {
+        ArpOptions{mode: ArpMode::Up, octaves: 1, gate: 50, rate: 16, triplets: false, tempo: Some(120.0)}
+    }
+}
+
+impl ArpOptions {
+    /// Parse a space separated list of settings, e.g. "mode=updown oct=2".
+    ///
+    /// Settings are mode=up|down|updown|random|played, oct=n (1 - 4),
+    /// gate=percent, rate=n (1, 2, 4, 8, 16 or 32, with a t for triplets,
+    /// e.g. 8t), bpm=tempo and clock to follow the incoming clock.
+    pub fn parse(s: &str) -> Result<ArpOptions, String> {
+        let mut options = ArpOptions::default();
+        for term in s.split_whitespace() {
+            let mut parts = term.splitn(2, '=');
+            let key = parts.next().unwrap_or("").to_lowercase();
+            let value = parts.next();
+            match (key.as_str(), value) {
+                ("mode", v) => {
+                    options.mode = v.and_then(ArpMode::parse)
+                                    .ok_or_else(|| format!("Invalid arpeggiator mode in '{}' (up, down, updown, random, played)", term))?;
+                }
+                ("oct", v) => match v.and_then(|v| v.parse::<u8>().ok()) {
+                    Some(n) if (1..=MAX_OCTAVES).contains(&n) => options.octaves = n,
+                    _ => return Err(format!("Invalid octave range in '{}' (1 - {})", term, MAX_OCTAVES)),
+                },
+                ("gate", v) => match v.and_then(|v| v.parse::<u8>().ok()) {
+                    Some(n) if (1..=100).contains(&n) => options.gate = n,
+                    _ => return Err(format!("Invalid gate in '{}' (1 - 100 percent)", term)),
+                },
+                ("rate", Some(v)) => {
+                    let (rate, triplets) = match v.strip_suffix('t') {
+                        Some(rate) => (rate, true),
+                        None => (v, false),
+                    };
+                    match rate.parse::<u32>() {
+                        Ok(n) if [1, 2, 4, 8, 16, 32].contains(&n) => {
+                            options.rate = n;
+                            options.triplets = triplets;
+                        }
+                        _ => return Err(format!("Invalid rate in '{}' (1, 2, 4, 8, 16, 32, optionally with t)", term)),
+                    }
+                }
+                ("bpm", v) => match v.and_then(|v| v.parse::<f64>().ok()) {
+                    Some(bpm) if (MIN_BPM..=MAX_BPM).contains(&bpm) => options.tempo = Some(bpm),
+                    _ => return Err(format!("Invalid tempo in '{}' ({} - {} BPM)", term, MIN_BPM, MAX_BPM)),
+                },
+                ("clock", None) => options.tempo = None,
+                _ => return Err(format!("Unknown arpeggiator setting '{}'", term)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// The settings in the form accepted by parse().
+    pub fn describe(&self) -> String {
+        let tempo = match self.tempo {
+            Some(bpm) => format!("bpm={}", bpm),
+            None => "clock".to_string(),
+        };
+        format!("mode={} oct={} gate={} rate={}{} {}", self.mode.name(), self.octaves, self.gate, self.rate,
+                if self.triplets { "t" } else { "" }, tempo)
+    }
+
+    /// Number of clocks of a step.
+    fn clocks_per_step(&self) -> u32 {
+        if self.triplets { 64 / self.rate } else { 96 / self.rate }
+    }
+
+    /// Length of a step with the given clock interval in usec.
+    fn step_length(&self, clock_interval: f64) -> Duration {
+        Duration::from_micros((clock_interval * self.clocks_per_step() as f64) as u64)
+    }
+}
+
+/// A held note.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Note {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+}
+
+/// The notes of a cycle of the pattern, random picks from the up pattern.
+fn pattern(mode: ArpMode, octaves: u8, held: &[Note]) -> Vec<Note> {
+    let mut notes = held.to_vec();
+    if mode != ArpMode::AsPlayed {
+        notes.sort_by_key(|n| n.key);
+        notes.dedup_by_key(|n| n.key);
+    }
+    let mut pattern: Vec<Note> = (0..octaves).flat_map(|octave| {
+        notes.iter().filter_map(move |n| {
+            let key = n.key + 12 * octave;
+            if key < 128 { Some(Note{key, ..*n}) } else { None }
+        })
+    }).collect();
+    match mode {
+        ArpMode::Down => pattern.reverse(),
+        ArpMode::UpDown if pattern.len() > 2 => {
+            let down: Vec<Note> = pattern[1..pattern.len() - 1].iter().rev().copied().collect();
+            pattern.extend(down);
+        }
+        _ => (),
+    }
+    pattern
+}
+
+struct State {
+    options: ArpOptions,
+    scheduler: Scheduler,
+    held: Vec<Note>,                  // In the order played
+    step: usize,
+    out_channel: u8,                  // 1 - 16, 0 = channel of the note
+    sounding: Option<(u8, u8)>,       // Channel and key of the last note
+    clocks: u32,                      // Received clocks since Start
+    last_clock: Option<Instant>,
+    clock_interval: Option<f64>,      // usec, smoothed
+    random: u64,
+    quit: bool,
+}
+
+impl State {
+    /// Play the next note at the given time.
+    fn step(&mut self, time: Instant, length: Duration) {
+        let notes = pattern(self.options.mode, self.options.octaves, &self.held);
+        if notes.is_empty() {
+            return;
+        }
+        let note = if self.options.mode == ArpMode::Random {
+            // xorshift
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 7;
+            self.random ^= self.random << 17;
+            notes[(self.random % notes.len() as u64) as usize]
+        } else {
+            notes[self.step % notes.len()]
+        };
+        self.step = self.step.wrapping_add(1);
+        let channel = if self.out_channel > 0 { self.out_channel - 1 } else { note.channel };
+
+        // End the last note first, if its NoteOff is late (the clock sped up)
+        if let Some((channel, key)) = self.sounding.take() {
+            if self.scheduler.cancel(NOTE_OFF_TAG) > 0 {
+                self.scheduler.schedule(time, &[0x80 | channel, key, 0], 0);
+            }
+        }
+        self.scheduler.schedule(time, &[0x90 | channel, note.key, note.velocity], 0);
+        let gate = length.mul_f64(self.options.gate as f64 / 100.0);
+        self.scheduler.schedule(time + gate, &[0x80 | channel, note.key, 0], NOTE_OFF_TAG);
+        self.sounding = Some((channel, note.key));
+    }
+
+    /// Add the time of a clock to the smoothed interval.
+    fn measure(&mut self, now: Instant) {
+        if let Some(last) = self.last_clock.replace(now) {
+            let interval = now.duration_since(last).as_micros() as u64;
+            self.clock_interval = match self.clock_interval {
+                _ if interval > MAX_INTERVAL => None,
+                Some(avg) => Some(avg + 0.25 * (interval as f64 - avg)),
+                None => Some(interval as f64),
+            };
+        }
+    }
+}
+
+/// Plays the notes of a route as arpeggio.
+pub struct Arpeggiator {
+    state: Arc<(Mutex<State>, Condvar)>,
+    output: SharedOutput,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Arpeggiator {
+    /// Create an arpeggiator sending to an output. Sent messages and errors
+    /// are counted in counters, if given.
+    pub fn new(options: ArpOptions, output: SharedOutput, counters: Option<SharedCounters>) -> Arpeggiator {
+        let seed = RandomState::new().build_hasher().finish() | 1;
+        let state = State{
+            options,
+            scheduler: Scheduler::new(output.clone(), counters),
+            held: vec!(),
+            step: 0,
+            out_channel: 0,
+            sounding: None,
+            clocks: 0,
+            last_clock: None,
+            clock_interval: None,
+            random: seed,
+            quit: false,
+        };
+        let state = Arc::new((Mutex::new(state), Condvar::new()));
+        let timer = options.tempo.map(|bpm| {
+            let state = state.clone();
+            let length = options.step_length(60000000.0 / (bpm * CLOCKS_PER_QUARTER as f64));
+            thread::spawn(move || Arpeggiator::run(state, length))
+        });
+        Arpeggiator{state, output, timer}
+    }
+
+    /// Handle a message received at the given time, with the output channel
+    /// of the route (1 - 16, 0 = unchanged).
+    ///
+    /// Returns true for notes, which are played by the arpeggiator, false if
+    /// the message has to be forwarded as usual.
+    pub fn process(&self, now: Instant, m: &MidiMessage, out_channel: u8) -> bool {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.out_channel = out_channel;
+        match *m {
+            MidiMessage::NoteOn{channel, key, velocity} if velocity > 0 => {
+                state.held.retain(|n| (n.channel, n.key) != (channel, key));
+                state.held.push(Note{channel, key, velocity});
+                if state.held.len() == 1 {
+                    state.step = 0;
+                    cvar.notify_one(); // Start the timer
+                }
+                true
+            }
+            MidiMessage::NoteOn{channel, key, ..} | MidiMessage::NoteOff{channel, key, ..} => {
+                state.held.retain(|n| (n.channel, n.key) != (channel, key));
+                true
+            }
+            MidiMessage::TimingClock if state.options.tempo.is_none() => {
+                state.measure(now);
+                let clocks_per_step = state.options.clocks_per_step();
+                if state.clocks.is_multiple_of(clocks_per_step) {
+                    // Without a measured interval yet, assume 120 BPM
+                    let length = state.options.step_length(state.clock_interval.unwrap_or(20833.0));
+                    state.step(now, length);
+                }
+                state.clocks = state.clocks.wrapping_add(1);
+                false
+            }
+            MidiMessage::Start => {
+                state.clocks = 0;
+                false
+            }
+            MidiMessage::SongPos{position} => {
+                state.clocks = position as u32 * 6;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget the held notes, e.g. when the route was muted.
+    pub fn release_all(&self) {
+        self.state.0.lock().unwrap().held.clear();
+    }
+
+    /// Plays a step every length while notes are held.
+    fn run(state: Arc<(Mutex<State>, Condvar)>, length: Duration) {
+        let (lock, cvar) = &*state;
+        let mut state = lock.lock().unwrap();
+        let mut next: Option<Instant> = None;
+        while !state.quit {
+            if state.held.is_empty() {
+                next = None;
+                state = cvar.wait(state).unwrap();
+                continue;
+            }
+            let now = Instant::now();
+            // The first step right away, late steps don't catch up
+            let time = match next {
+                Some(time) if time + length > now => time,
+                _ => now,
+            };
+            if time > now + LOOKAHEAD {
+                state = cvar.wait_timeout(state, time - now - LOOKAHEAD).unwrap().0;
+                next = Some(time);
+                continue;
+            }
+            state.step(time, length);
+            next = Some(time + length);
+        }
+    }
+}
+
+impl Drop for Arpeggiator {
+    fn drop(&mut self) {
+        {
+            let (lock, cvar) = &*self.state;
+            lock.lock().unwrap().quit = true;
+            cvar.notify_one();
+        }
+        if let Some(timer) = self.timer.take() {
+            timer.join().ok();
+        }
+        // Queued messages are dropped with the scheduler, the last note must not hang
+        let mut state = self.state.0.lock().unwrap();
+        if let Some((channel, key)) = state.sounding.take() {
+            if state.scheduler.cancel(NOTE_OFF_TAG) > 0 {
+                self.output.lock().unwrap().send(&[0x80 | channel, key, 0]).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(keys: &[u8]) -> Vec<Note> {
+        keys.iter().map(|&key| Note{channel: 0, key, velocity: 100}).collect()
+    }
+
+    fn keys(notes: Vec<Note>) -> Vec<u8> {
+        notes.iter().map(|n| n.key).collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = ArpOptions::parse("mode=updown oct=2 gate=80 rate=8t clock").unwrap();
+        assert_eq!(options, ArpOptions{mode: ArpMode::UpDown, octaves: 2, gate: 80, rate: 8, triplets: true, tempo: None});
+        assert_eq!(options.clocks_per_step(), 8);
+        assert_eq!(options.describe(), "mode=updown oct=2 gate=80 rate=8t clock");
+        assert_eq!(ArpOptions::parse("").unwrap().describe(), "mode=up oct=1 gate=50 rate=16 bpm=120");
+        assert_eq!(ArpOptions::parse("").unwrap().clocks_per_step(), 6);
+        assert!(ArpOptions::parse("mode=sideways").is_err());
+        assert!(ArpOptions::parse("rate=12").is_err());
+        assert!(ArpOptions::parse("oct=5").is_err());
+        assert!(ArpOptions::parse("gate=0").is_err());
+        assert!(ArpOptions::parse("bpm=1000").is_err());
+        assert!(ArpOptions::parse("latch").is_err());
+    }
+
+    #[test]
+    fn builds_patterns() {
+        let held = notes(&[64, 60, 67]);
+        assert_eq!(keys(pattern(ArpMode::Up, 1, &held)), vec!(60, 64, 67));
+        assert_eq!(keys(pattern(ArpMode::Down, 1, &held)), vec!(67, 64, 60));
+        assert_eq!(keys(pattern(ArpMode::UpDown, 1, &held)), vec!(60, 64, 67, 64));
+        assert_eq!(keys(pattern(ArpMode::AsPlayed, 1, &held)), vec!(64, 60, 67));
+        assert_eq!(keys(pattern(ArpMode::Up, 2, &held)), vec!(60, 64, 67, 72, 76, 79));
+        assert_eq!(keys(pattern(ArpMode::AsPlayed, 2, &held)), vec!(64, 60, 67, 76, 72, 79));
+        assert_eq!(keys(pattern(ArpMode::UpDown, 1, &notes(&[60, 64]))), vec!(60, 64));
+        // Notes out of range are left out
+        assert_eq!(keys(pattern(ArpMode::Up, 3, &notes(&[100]))), vec!(100, 112, 124));
+        assert_eq!(keys(pattern(ArpMode::Up, 4, &notes(&[120]))), vec!(120));
+        assert!(pattern(ArpMode::Random, 2, &[]).is_empty());
+    }
+}
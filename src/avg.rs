@@ -1,35 +1,253 @@
-pub struct Avg {
-    values: Vec<f64>,
+//! Statistics over a moving window of samples.
+//!
+//! Stats keeps the last N samples in a ringbuffer and calculates mean, min,
+//! max, variance, standard deviation, median and percentiles over them. Ema
+//! is an exponential moving average, which needs no buffer.
+
+/// Types that can be collected by Stats.
+pub trait Sample: Copy + PartialOrd {
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! impl_sample {
+    ($($t:ty),*) => {
+        $(impl Sample for $t {
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        })*
+    }
+}
+
+impl_sample!(f32, f64, u8, u16, u32, u64, usize, i8, i16, i32, i64);
+
+/// Statistics over the last N values.
+pub struct Stats<T: Sample> {
+    values: Vec<T>,
     size: usize,
     position: usize,
-    num_values: usize,
     sum: f64,
 }
 
-impl Avg {
+impl<T: Sample> Stats<T> {
     pub fn new(size: usize) -> Self {
-        Avg{values: vec!{0.0; size},
-            size,
-            position: 0,
-            num_values: 0,
-            sum: 0.0
+        Stats{values: Vec::with_capacity(size),
+              size: size.max(1),
+              position: 0,
+              sum: 0.0
         }
     }
 
     /// Add value to the ringbuffer, return average.
-    pub fn add_value(&mut self, value: f64) -> f64 {
-        if self.num_values == self.size {
-            self.sum -= self.values[self.position];
+    pub fn add_value(&mut self, value: T) -> f64 {
+        if self.values.len() < self.size {
+            self.values.push(value);
+        } else {
+            self.sum -= self.values[self.position].to_f64();
+            self.values[self.position] = value;
         }
-        self.values[self.position] = value;
-        self.sum += value;
+        self.sum += value.to_f64();
         self.position += 1;
         if self.position >= self.size {
             self.position = 0;
         }
-        if self.num_values < self.size {
-            self.num_values += 1;
+        self.sum / (self.values.len() as f64)
+    }
+
+    /// Remove all values.
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.position = 0;
+        self.sum = 0.0;
+    }
+
+    /// Number of values currently in the ringbuffer.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// True if the ringbuffer has been filled completely.
+    pub fn is_full(&self) -> bool {
+        self.values.len() == self.size
+    }
+
+    /// Average of the values currently in the ringbuffer.
+    pub fn mean(&self) -> Option<f64> {
+        if self.values.is_empty() { None } else { Some(self.sum / self.values.len() as f64) }
+    }
+
+    pub fn min(&self) -> Option<T> {
+        self.values.iter().copied().fold(None, |min, v| match min {
+            Some(m) if m <= v => Some(m),
+            _ => Some(v),
+        })
+    }
+
+    pub fn max(&self) -> Option<T> {
+        self.values.iter().copied().fold(None, |max, v| match max {
+            Some(m) if m >= v => Some(m),
+            _ => Some(v),
+        })
+    }
+
+    /// Population variance of the values.
+    pub fn variance(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let sum_sq: f64 = self.values.iter().map(|v| (v.to_f64() - mean).powi(2)).sum();
+        Some(sum_sq / self.values.len() as f64)
+    }
+
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    /// Largest distance of a value from the mean.
+    pub fn max_deviation(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        self.values.iter()
+                   .map(|v| (v.to_f64() - mean).abs())
+                   .fold(None, |max: Option<f64>, d| Some(max.map_or(d, |m| m.max(d))))
+    }
+
+    pub fn median(&self) -> Option<f64> {
+        self.percentile(50.0)
+    }
+
+    /// Percentile (0 - 100) of the values, interpolated linearly between the
+    /// closest ranks.
+    pub fn percentile(&self, percent: f64) -> Option<f64> {
+        if self.values.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f64> = self.values.iter().map(|v| v.to_f64()).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let rank = percent.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64;
+        let lower = rank.floor() as usize;
+        let upper = rank.ceil() as usize;
+        let fraction = rank - lower as f64;
+        Some(sorted[lower] + (sorted[upper] - sorted[lower]) * fraction)
+    }
+}
+
+/// Exponential moving average.
+pub struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ema {
+    /// Create an EMA with the given smoothing factor (0 - 1, higher reacts faster).
+    pub fn new(alpha: f64) -> Self {
+        Ema{alpha: alpha.clamp(0.0, 1.0), value: None}
+    }
+
+    /// Create an EMA roughly corresponding to a moving average over N samples.
+    pub fn with_period(samples: usize) -> Self {
+        Ema::new(2.0 / (samples as f64 + 1.0))
+    }
+
+    /// Add a value, return the new average.
+    pub fn add_value<T: Sample>(&mut self, value: T) -> f64 {
+        let value = value.to_f64();
+        let avg = match self.value {
+            Some(avg) => avg + self.alpha * (value - avg),
+            None => value,
+        };
+        self.value = Some(avg);
+        avg
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+
+    pub fn clear(&mut self) {
+        self.value = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn empty_stats_return_none() {
+        let s: Stats<f64> = Stats::new(4);
+        assert!(s.is_empty());
+        assert_eq!(s.mean(), None);
+        assert_eq!(s.min(), None);
+        assert_eq!(s.max(), None);
+        assert_eq!(s.std_dev(), None);
+        assert_eq!(s.median(), None);
+    }
+
+    #[test]
+    fn add_value_returns_moving_average() {
+        let mut s = Stats::new(3);
+        assert!(close(s.add_value(1.0), 1.0));
+        assert!(close(s.add_value(2.0), 1.5));
+        assert!(close(s.add_value(3.0), 2.0));
+        assert!(s.is_full());
+        // The oldest value drops out of the window
+        assert!(close(s.add_value(7.0), 4.0));
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.min(), Some(2.0));
+        assert_eq!(s.max(), Some(7.0));
+    }
+
+    #[test]
+    fn variance_and_std_dev() {
+        let mut s = Stats::new(8);
+        for v in [2u32, 4, 4, 4, 5, 5, 7, 9].iter() {
+            s.add_value(*v);
         }
-        self.sum / (self.num_values as f64)
+        assert!(close(s.mean().unwrap(), 5.0));
+        assert!(close(s.variance().unwrap(), 4.0));
+        assert!(close(s.std_dev().unwrap(), 2.0));
+        assert!(close(s.max_deviation().unwrap(), 4.0));
+    }
+
+    #[test]
+    fn median_and_percentiles() {
+        let mut s = Stats::new(10);
+        for v in [5i64, 1, 4, 2, 3].iter() {
+            s.add_value(*v);
+        }
+        assert!(close(s.median().unwrap(), 3.0));
+        assert!(close(s.percentile(0.0).unwrap(), 1.0));
+        assert!(close(s.percentile(100.0).unwrap(), 5.0));
+        assert!(close(s.percentile(25.0).unwrap(), 2.0));
+        assert!(close(s.percentile(90.0).unwrap(), 4.6));
+        s.add_value(6);
+        assert!(close(s.median().unwrap(), 3.5));
+    }
+
+    #[test]
+    fn clear_resets_window() {
+        let mut s = Stats::new(2);
+        s.add_value(10u64);
+        s.add_value(20u64);
+        s.clear();
+        assert!(s.is_empty());
+        assert!(close(s.add_value(4u64), 4.0));
+    }
+
+    #[test]
+    fn ema_follows_values() {
+        let mut e = Ema::new(0.5);
+        assert_eq!(e.value(), None);
+        assert!(close(e.add_value(10.0), 10.0));
+        assert!(close(e.add_value(20.0), 15.0));
+        assert!(close(e.add_value(20u8), 17.5));
+        let e = Ema::with_period(3);
+        assert!(close(e.alpha, 0.5));
     }
 }
@@ -0,0 +1,240 @@
+//! MIDI backends.
+//!
+//! The routing only talks to a Backend, which lists the ports, connects
+//! inputs with a callback and opens outputs for sending. MidirBackend uses
+//! the system MIDI ports through midir. mock::MockBackend keeps everything
+//! in memory, so the routing can be tested deterministically.
+
+use midir::{Ignore, MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+use std::error::Error;
+
+/// Called with the timestamp (usec) and the bytes of every received message.
+pub type InputCallback = Box<dyn FnMut(u64, &[u8]) + Send>;
+
+/// A connected input, which is closed when dropped.
+pub trait InputConnection: Send {}
+
+/// A connected output.
+pub trait OutputConnection: Send {
+    /// Send a complete message.
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+/// An output of any backend.
+pub type Output = Box<dyn OutputConnection>;
+
+/// Access to the MIDI ports of a system.
+pub trait Backend {
+    /// Names of the available input ports.
+    fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Names of the available output ports.
+    fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Call callback for every message received on an input port.
+    fn connect_input(&self, port: usize, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>>;
+
+    /// Open an output port for sending.
+    fn connect_output(&self, port: usize) -> Result<Output, Box<dyn Error>>;
+}
+
+/// The system MIDI ports.
+pub struct MidirBackend {
+    client_name: String,
+}
+
+struct MidirInput(#[allow(dead_code)] MidiInputConnection<()>); // Only kept for closing on drop
+
+impl InputConnection for MidirInput {}
+
+impl OutputConnection for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        MidiOutputConnection::send(self, message)?;
+        Ok(())
+    }
+}
+
+impl MidirBackend {
+    /// Create a backend, ports are connected under the given client name.
+    pub fn new(client_name: &str) -> Self {
+        MidirBackend{client_name: client_name.to_string()}
+    }
+}
+
+fn port_names<T: MidiIO>(midi_io: &T) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut names = vec!();
+    for port in midi_io.ports() {
+        names.push(midi_io.port_name(&port)?);
+    }
+    Ok(names)
+}
+
+fn get_port<T: MidiIO>(midi_io: &T, port: usize) -> Result<T::Port, Box<dyn Error>> {
+    let midi_ports = midi_io.ports();
+    let port = midi_ports.get(port)
+                         .ok_or("Invalid port number")?;
+    Ok(port.clone())
+}
+
+impl Backend for MidirBackend {
+    fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        port_names(&MidiInput::new(&self.client_name)?)
+    }
+
+    fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        port_names(&MidiOutput::new(&self.client_name)?)
+    }
+
+    fn connect_input(&self, port: usize, mut callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let mut midi_in = MidiInput::new(&self.client_name)?;
+        midi_in.ignore(Ignore::None);
+        let in_port = get_port(&midi_in, port)?;
+        let conn = midi_in.connect(&in_port, &self.client_name, move |timestamp, message, _| {
+            callback(timestamp, message);
+        }, ())?;
+        Ok(Box::new(MidirInput(conn)))
+    }
+
+    fn connect_output(&self, port: usize) -> Result<Output, Box<dyn Error>> {
+        let midi_out = MidiOutput::new(&self.client_name)?;
+        let out_port = get_port(&midi_out, port)?;
+        Ok(Box::new(midi_out.connect(&out_port, &self.client_name)?))
+    }
+}
+
+/// In-memory ports for testing.
+///
+/// Received messages are injected with MockBackend::receive and delivered
+/// before it returns, sent messages are collected per output port.
+pub mod mock {
+    use super::{Backend, InputCallback, InputConnection, Output, OutputConnection};
+
+    use std::error::Error;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct MockInput {
+        port: usize,
+        id: u64,
+        callbacks: Arc<Mutex<MockCallbacks>>,
+    }
+
+    impl InputConnection for MockInput {}
+
+    impl Drop for MockInput {
+        fn drop(&mut self) {
+            // Drop the callback, along with everything it owns
+            self.callbacks.lock().unwrap()[self.port].retain(|(id, _)| *id != self.id);
+        }
+    }
+
+    struct MockOutput {
+        port: usize,
+        sent: Arc<Mutex<Vec<Vec<Vec<u8>>>>>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl OutputConnection for MockOutput {
+        fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err("Mock output failure".into());
+            }
+            self.sent.lock().unwrap()[self.port].push(message.to_vec());
+            Ok(())
+        }
+    }
+
+    type MockCallbacks = Vec<Vec<(u64, Arc<Mutex<InputCallback>>)>>;
+
+    /// Backend with in-memory ports.
+    #[derive(Clone)]
+    pub struct MockBackend {
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+        callbacks: Arc<Mutex<MockCallbacks>>,
+        next_id: Arc<AtomicU64>,
+        sent: Arc<Mutex<Vec<Vec<Vec<u8>>>>>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl MockBackend {
+        /// Create a backend with the given port names.
+        pub fn new(inputs: &[&str], outputs: &[&str]) -> Self {
+            MockBackend{
+                inputs: inputs.iter().map(|s| s.to_string()).collect(),
+                outputs: outputs.iter().map(|s| s.to_string()).collect(),
+                callbacks: Arc::new(Mutex::new(inputs.iter().map(|_| vec!()).collect())),
+                next_id: Arc::new(AtomicU64::new(0)),
+                sent: Arc::new(Mutex::new(outputs.iter().map(|_| vec!()).collect())),
+                fail: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        /// Deliver a message to all connections of an input port. The callbacks
+        /// are called before this returns.
+        pub fn receive(&self, port: usize, timestamp: u64, message: &[u8]) {
+            // Don't hold the lock while calling, callbacks may connect or send
+            let callbacks: Vec<_> = self.callbacks.lock().unwrap()[port].iter()
+                                        .map(|(_, callback)| callback.clone())
+                                        .collect();
+            for callback in callbacks {
+                (callback.lock().unwrap())(timestamp, message);
+            }
+        }
+
+        /// The messages sent to an output port so far.
+        pub fn sent(&self, port: usize) -> Vec<Vec<u8>> {
+            self.sent.lock().unwrap()[port].clone()
+        }
+
+        /// Wait until the messages sent to an output port satisfy done, or
+        /// give up after five seconds. Returns the messages sent so far.
+        pub fn wait_until<F: Fn(&[Vec<u8>]) -> bool>(&self, port: usize, done: F) -> Vec<Vec<u8>> {
+            let deadline = Instant::now() + WAIT_TIMEOUT;
+            while !done(&self.sent(port)) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(2));
+            }
+            self.sent(port)
+        }
+
+        /// Wait until an output port got count messages, see wait_until.
+        pub fn wait_for_sent(&self, port: usize, count: usize) -> Vec<Vec<u8>> {
+            self.wait_until(port, |sent| sent.len() >= count)
+        }
+
+        /// Let all sends fail, to test the error handling.
+        pub fn set_failing(&self, fail: bool) {
+            self.fail.store(fail, Ordering::SeqCst);
+        }
+    }
+
+    impl Backend for MockBackend {
+        fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(self.inputs.clone())
+        }
+
+        fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(self.outputs.clone())
+        }
+
+        fn connect_input(&self, port: usize, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+            let mut callbacks = self.callbacks.lock().unwrap();
+            let port_callbacks = callbacks.get_mut(port).ok_or("Invalid port number")?;
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            port_callbacks.push((id, Arc::new(Mutex::new(callback))));
+            Ok(Box::new(MockInput{port, id, callbacks: self.callbacks.clone()}))
+        }
+
+        fn connect_output(&self, port: usize) -> Result<Output, Box<dyn Error>> {
+            if port >= self.outputs.len() {
+                return Err("Invalid port number".into());
+            }
+            Ok(Box::new(MockOutput{port, sent: self.sent.clone(), fail: self.fail.clone()}))
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Names of MIDI controllers for the monitor.
+//!
+//! The standard controller names can be extended or overridden by
+//! device-specific name maps read from a file. Each line of such a file has
+//! the form "number, name"; empty lines and lines starting with '#' are
+//! ignored.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+const STANDARD_NAMES: [(u8, &str); 59] = [
+    (0, "Bank Select"),
+    (1, "Mod Wheel"),
+    (2, "Breath"),
+    (4, "Foot Controller"),
+    (5, "Portamento Time"),
+    (6, "Data Entry"),
+    (7, "Volume"),
+    (8, "Balance"),
+    (10, "Pan"),
+    (11, "Expression"),
+    (12, "Effect Control 1"),
+    (13, "Effect Control 2"),
+    (16, "General Purpose 1"),
+    (17, "General Purpose 2"),
+    (18, "General Purpose 3"),
+    (19, "General Purpose 4"),
+    (32, "Bank Select LSB"),
+    (33, "Mod Wheel LSB"),
+    (34, "Breath LSB"),
+    (36, "Foot Controller LSB"),
+    (37, "Portamento Time LSB"),
+    (38, "Data Entry LSB"),
+    (39, "Volume LSB"),
+    (40, "Balance LSB"),
+    (42, "Pan LSB"),
+    (43, "Expression LSB"),
+    (64, "Sustain"),
+    (65, "Portamento"),
+    (66, "Sostenuto"),
+    (67, "Soft Pedal"),
+    (68, "Legato Footswitch"),
+    (69, "Hold 2"),
+    (70, "Sound Variation"),
+    (71, "Resonance"),
+    (72, "Release Time"),
+    (73, "Attack Time"),
+    (74, "Cutoff"),
+    (75, "Decay Time"),
+    (76, "Vibrato Rate"),
+    (77, "Vibrato Depth"),
+    (78, "Vibrato Delay"),
+    (79, "Sound Controller 10"),
+    (80, "General Purpose 5"),
+    (81, "General Purpose 6"),
+    (82, "General Purpose 7"),
+    (83, "General Purpose 8"),
+    (84, "Portamento Control"),
+    (88, "High Resolution Velocity"),
+    (91, "Reverb Send"),
+    (92, "Tremolo Depth"),
+    (93, "Chorus Send"),
+    (94, "Detune"),
+    (95, "Phaser Depth"),
+    (96, "Data Increment"),
+    (97, "Data Decrement"),
+    (98, "NRPN LSB"),
+    (99, "NRPN MSB"),
+    (100, "RPN LSB"),
+    (101, "RPN MSB"),
+];
+
+/// Names of the controllers of a device.
+#[derive(Clone, Debug)]
+pub struct CcNames {
+    names: HashMap<u8, String>,
+}
+
+impl Default for CcNames {
+    fn default() -> Self {
+        CcNames::standard()
+    }
+}
+
+impl CcNames {
+    /// Create a map containing the controller names defined by the MIDI standard.
+    pub fn standard() -> Self {
+        let names = STANDARD_NAMES.iter()
+                                  .map(|(cc, name)| (*cc, name.to_string()))
+                                  .collect();
+        CcNames{names}
+    }
+
+    /// Read a name map from a file, overriding existing names.
+    pub fn load(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let file = File::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ',');
+            let cc = parts.next().unwrap_or("").trim().parse::<u8>();
+            let name = parts.next().unwrap_or("").trim();
+            match cc {
+                Ok(cc) if cc < 128 && !name.is_empty() => {
+                    self.names.insert(cc, name.to_string());
+                }
+                _ => return Err(format!("{}:{}: Expected \"number, name\"", filename, i + 1).into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The name of a controller, if known.
+    pub fn name(&self, controller: u8) -> Option<&str> {
+        self.names.get(&controller).map(|n| n.as_str())
+    }
+}
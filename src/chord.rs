@@ -0,0 +1,344 @@
+//! Chord generator and harmonizer.
+//!
+//! A route with a chord setting plays a chord for every note: either fixed
+//! intervals in semitones ("notes=0,4,7" for a major triad), or diatonic
+//! intervals in scale degrees within a key and scale ("degrees=0,2,4
+//! key=D scale=minor" for the triads of D minor). Notes outside the scale are
+//! harmonized like the scale note below them and keep their distance to it.
+//! With quantize, incoming notes are moved to the nearest note of the scale
+//! first (down for ties).
+//!
+//! Every NoteOff ends the notes its NoteOn started, so changing the chord
+//! while notes are held doesn't leave notes hanging. Notes shared by held
+//! chords end with the last of them.
+
+use super::midi::{note_name, parse_note_name};
+
+use std::collections::HashMap;
+
+const MAX_NOTES: usize = 8;
+const MAX_SEMITONES: i8 = 48;
+const MAX_DEGREES: i8 = 28;
+
+/// Scales for diatonic intervals and quantization.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scale {
+    Major,
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    HarmonicMinor,
+    Pentatonic,
+}
+
+impl Scale {
+    pub fn parse(name: &str) -> Option<Scale> {
+        match name {
+            "major" => Some(Scale::Major),
+            "minor" => Some(Scale::Minor),
+            "dorian" => Some(Scale::Dorian),
+            "phrygian" => Some(Scale::Phrygian),
+            "lydian" => Some(Scale::Lydian),
+            "mixolydian" => Some(Scale::Mixolydian),
+            "locrian" => Some(Scale::Locrian),
+            "harmonic" => Some(Scale::HarmonicMinor),
+            "pentatonic" => Some(Scale::Pentatonic),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scale::Major => "major",
+            Scale::Minor => "minor",
+            Scale::Dorian => "dorian",
+            Scale::Phrygian => "phrygian",
+            Scale::Lydian => "lydian",
+            Scale::Mixolydian => "mixolydian",
+            Scale::Locrian => "locrian",
+            Scale::HarmonicMinor => "harmonic",
+            Scale::Pentatonic => "pentatonic",
+        }
+    }
+
+    /// Semitones of the scale notes above the key.
+    pub fn steps(self) -> &'static [i32] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::Pentatonic => &[0, 2, 4, 7, 9],
+        }
+    }
+}
+
+/// Intervals of the chord notes above the played note.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Intervals {
+    Semitones(Vec<i8>),
+    Degrees(Vec<i8>), // Scale degrees, 2 = a third
+}
+
+/// Chord settings of a route.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChordOptions {
+    pub intervals: Intervals,
+    pub key: u8, // 0 = C - 11 = B
+    pub scale: Scale,
+    pub quantize: bool,
+}
+
+impl Default for ChordOptions {
+    fn default() -> Self {
+        ChordOptions{intervals: Intervals::Semitones(vec!(0)), key: 0, scale: Scale::Major, quantize: false}
+    }
+}
+
+impl ChordOptions {
+    /// Parse a space separated list of settings, e.g. "degrees=0,2,4 key=D".
+    ///
+    /// Settings are notes=semitones or degrees=scale degrees (comma
+    /// separated, up to 8), key=note name (C by default), scale=major, minor,
+    /// dorian, phrygian, lydian, mixolydian, locrian, harmonic or pentatonic
+    /// (major by default) and quantize.
+    pub fn parse(s: &str) -> Result<ChordOptions, String> {
+        let mut options = ChordOptions::default();
+        for term in s.split_whitespace() {
+            let mut parts = term.splitn(2, '=');
+            let key = parts.next().unwrap_or("").to_lowercase();
+            let value = parts.next();
+            let list = |v: Option<&str>, max: i8| -> Result<Vec<i8>, String> {
+                let values = v.unwrap_or("").split(',')
+                                            .map(|i| i.trim().parse::<i8>().ok().filter(|i| (-max..=max).contains(i)))
+                                            .collect::<Option<Vec<i8>>>();
+                match values {
+                    Some(values) if !values.is_empty() && values.len() <= MAX_NOTES => Ok(values),
+                    _ => Err(format!("Invalid intervals in '{}' (up to {} of -{} - {})", term, MAX_NOTES, max, max)),
+                }
+            };
+            match (key.as_str(), value) {
+                ("notes", v) => options.intervals = Intervals::Semitones(list(v, MAX_SEMITONES)?),
+                ("degrees", v) => options.intervals = Intervals::Degrees(list(v, MAX_DEGREES)?),
+                ("key", v) => {
+                    options.key = v.and_then(|v| parse_note_name(&format!("{}4", v)))
+                                   .map(|k| k % 12)
+                                   .ok_or_else(|| format!("Invalid key in '{}'", term))?;
+                }
+                ("scale", v) => options.scale = v.and_then(Scale::parse).ok_or_else(|| format!("Unknown scale in '{}'", term))?,
+                ("quantize", None) => options.quantize = true,
+                _ => return Err(format!("Unknown chord setting '{}'", term)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// The settings in the form accepted by parse().
+    pub fn describe(&self) -> String {
+        let list = |values: &[i8]| values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",");
+        let mut terms = vec!(match &self.intervals {
+            Intervals::Semitones(semitones) => format!("notes={}", list(semitones)),
+            Intervals::Degrees(degrees) => format!("degrees={}", list(degrees)),
+        });
+        if self.uses_scale() {
+            terms.push(format!("key={}", note_name(60 + self.key).trim_end_matches('4')));
+            terms.push(format!("scale={}", self.scale.name()));
+        }
+        if self.quantize {
+            terms.push("quantize".to_string());
+        }
+        terms.join(" ")
+    }
+
+    fn uses_scale(&self) -> bool {
+        self.quantize || matches!(self.intervals, Intervals::Degrees(_))
+    }
+
+    /// Move a note to the nearest note of the scale.
+    pub fn quantize(&self, key: u8) -> u8 {
+        let relative = (key as i32 - self.key as i32).rem_euclid(12);
+        let mut nearest = 0;
+        for &step in self.scale.steps().iter().chain(&[12]) {
+            if (relative - step).abs() < (relative - nearest).abs() {
+                nearest = step;
+            }
+        }
+        (key as i32 + nearest - relative).clamp(0, 127) as u8
+    }
+
+    /// The notes of the chord for a played note.
+    pub fn notes(&self, key: u8) -> Vec<u8> {
+        let key = if self.quantize { self.quantize(key) } else { key };
+        let mut notes: Vec<u8> = vec!();
+        let mut add = |note: i32| {
+            if (0..128).contains(&note) && !notes.contains(&(note as u8)) {
+                notes.push(note as u8);
+            }
+        };
+        match &self.intervals {
+            Intervals::Semitones(semitones) => {
+                for &s in semitones {
+                    add(key as i32 + s as i32);
+                }
+            }
+            Intervals::Degrees(degrees) => {
+                let steps = self.scale.steps();
+                let len = steps.len() as i32;
+                let distance = key as i32 - self.key as i32;
+                let (octave, relative) = (distance.div_euclid(12), distance.rem_euclid(12));
+                // The scale note at or below the played note
+                let degree = steps.iter().rposition(|&s| s <= relative).unwrap_or(0) as i32;
+                let offset = relative - steps[degree as usize];
+                for &d in degrees {
+                    let degree = degree + d as i32;
+                    let note = self.key as i32 + 12 * (octave + degree.div_euclid(len))
+                               + steps[degree.rem_euclid(len) as usize] + offset;
+                    add(note);
+                }
+            }
+        }
+        notes
+    }
+}
+
+/// The sounding chords of a route.
+#[derive(Default)]
+pub struct Chords {
+    held: HashMap<(u8, u8), Vec<u8>>, // Notes started by a played note, by channel and key
+    sounding: HashMap<(u8, u8), u32>, // Number of held chords with a note, by channel and note
+}
+
+impl Chords {
+    pub fn new() -> Self {
+        Chords::default()
+    }
+
+    /// Expand a note message to the messages of its chord.
+    ///
+    /// NoteOffs and aftertouch go to the notes started by their NoteOn, also
+    /// if the options have changed since. Returns None for other messages,
+    /// and for NoteOns without options, which are forwarded unchanged.
+    pub fn process(&mut self, options: Option<&ChordOptions>, message: &[u8]) -> Option<Vec<[u8; 3]>> {
+        if message.len() != 3 {
+            return None;
+        }
+        let (status, channel, key, value) = (message[0] & 0xF0, message[0] & 0x0F, message[1], message[2]);
+        match status {
+            0x90 if value > 0 => {
+                let options = options?;
+                let notes = options.notes(key);
+                // A repeated NoteOn without NoteOff ends its last chord first
+                let mut messages = self.release(channel, key, 0);
+                for &note in &notes {
+                    *self.sounding.entry((channel, note)).or_insert(0) += 1;
+                    messages.push([message[0], note, value]);
+                }
+                self.held.insert((channel, key), notes);
+                Some(messages)
+            }
+            0x80 | 0x90 => {
+                if !self.held.contains_key(&(channel, key)) {
+                    return None;
+                }
+                Some(self.release(channel, key, value))
+            }
+            0xA0 => {
+                let notes = self.held.get(&(channel, key))?;
+                Some(notes.iter().map(|&note| [message[0], note, value]).collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// End the chord of a played note, returns the NoteOffs of the notes no
+    /// other chord holds.
+    fn release(&mut self, channel: u8, key: u8, velocity: u8) -> Vec<[u8; 3]> {
+        let mut messages = vec!();
+        for note in self.held.remove(&(channel, key)).unwrap_or_default() {
+            let count = self.sounding.entry((channel, note)).or_insert(1);
+            *count -= 1;
+            if *count == 0 {
+                self.sounding.remove(&(channel, note));
+                messages.push([0x80 | channel, note, velocity]);
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options() {
+        let options = ChordOptions::parse("degrees=0,2,4 key=F# scale=minor quantize").unwrap();
+        assert_eq!(options, ChordOptions{intervals: Intervals::Degrees(vec!(0, 2, 4)), key: 6, scale: Scale::Minor, quantize: true});
+        assert_eq!(options.describe(), "degrees=0,2,4 key=F# scale=minor quantize");
+        assert_eq!(ChordOptions::parse("notes=0,4,7").unwrap().describe(), "notes=0,4,7");
+        assert_eq!(ChordOptions::parse("key=Bb").unwrap().key, 10);
+        assert!(ChordOptions::parse("notes=0,4,x").is_err());
+        assert!(ChordOptions::parse("notes=0,100").is_err());
+        assert!(ChordOptions::parse("notes=0,1,2,3,4,5,6,7,8").is_err());
+        assert!(ChordOptions::parse("scale=klingon").is_err());
+        assert!(ChordOptions::parse("key=H").is_err());
+        assert!(ChordOptions::parse("spread").is_err());
+    }
+
+    #[test]
+    fn builds_chords() {
+        let fixed = ChordOptions::parse("notes=0,4,7,-12").unwrap();
+        assert_eq!(fixed.notes(60), vec!(60, 64, 67, 48));
+        assert_eq!(fixed.notes(125), vec!(125, 113)); // Out of range
+        // The triads of C major: C major, D minor, B diminished
+        let diatonic = ChordOptions::parse("degrees=0,2,4").unwrap();
+        assert_eq!(diatonic.notes(60), vec!(60, 64, 67));
+        assert_eq!(diatonic.notes(62), vec!(62, 65, 69));
+        assert_eq!(diatonic.notes(59), vec!(59, 62, 65));
+        assert_eq!(diatonic.notes(61), vec!(61, 65, 68)); // Like C, a semitone up
+        // A third below and the octave in E minor
+        let below = ChordOptions::parse("degrees=0,-2,7 key=E scale=minor").unwrap();
+        assert_eq!(below.notes(64), vec!(64, 60, 76));
+        assert_eq!(below.notes(66), vec!(66, 62, 78));
+    }
+
+    #[test]
+    fn quantizes_notes() {
+        let options = ChordOptions::parse("key=D scale=pentatonic quantize").unwrap();
+        // D E F# A B
+        let quantized: Vec<u8> = (60..72).map(|k| options.quantize(k)).collect();
+        assert_eq!(quantized, vec!(59, 62, 62, 62, 64, 64, 66, 66, 69, 69, 69, 71));
+        assert_eq!(options.notes(61), vec!(62));
+        assert_eq!(options.quantize(127), 126);
+        // Down for ties, also below the octave: B between A# and C in C minor
+        assert_eq!(ChordOptions::parse("scale=minor quantize").unwrap().quantize(71), 70);
+    }
+
+    #[test]
+    fn ends_chords_after_changes() {
+        let mut chords = Chords::new();
+        let major = ChordOptions::parse("notes=0,4,7").unwrap();
+        let minor = ChordOptions::parse("notes=0,3,7").unwrap();
+        assert_eq!(chords.process(Some(&major), &[0x90, 60, 100]), Some(vec!([0x90, 60, 100], [0x90, 64, 100], [0x90, 67, 100])));
+        // The shape changes while C is held, G is in both chords
+        assert_eq!(chords.process(Some(&minor), &[0x90, 67, 90]), Some(vec!([0x90, 67, 90], [0x90, 70, 90], [0x90, 74, 90])));
+        assert_eq!(chords.process(Some(&minor), &[0xA0, 60, 20]), Some(vec!([0xA0, 60, 20], [0xA0, 64, 20], [0xA0, 67, 20])));
+        assert_eq!(chords.process(Some(&minor), &[0x80, 60, 0]), Some(vec!([0x80, 60, 0], [0x80, 64, 0])));
+        assert_eq!(chords.process(None, &[0x90, 67, 0]), Some(vec!([0x80, 67, 0], [0x80, 70, 0], [0x80, 74, 0])));
+        // A repeated NoteOn ends the chord it started before
+        chords.process(Some(&major), &[0x91, 60, 100]);
+        assert_eq!(chords.process(Some(&minor), &[0x91, 60, 100]),
+                   Some(vec!([0x81, 60, 0], [0x81, 64, 0], [0x81, 67, 0], [0x91, 60, 100], [0x91, 63, 100], [0x91, 67, 100])));
+        // Without options, notes without chord pass
+        assert_eq!(chords.process(None, &[0x90, 62, 100]), None);
+        assert_eq!(chords.process(None, &[0x80, 62, 0]), None);
+        assert_eq!(chords.process(Some(&major), &[0xB0, 7, 100]), None);
+    }
+}
@@ -0,0 +1,321 @@
+//! MIDI clock generator.
+//!
+//! Sends TimingClock messages at a given tempo to one or more outputs, along
+//! with Start, Stop, Continue and Song Position Pointer on command. The clock
+//! runs in its own thread. Tick times are calculated from a fixed reference
+//! point instead of adding up intervals, so the clock doesn't drift. Swing
+//! delays every second 16th note.
+
+use super::backend::Output;
+use super::MidiMessage;
+
+use std::cell::Cell;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const CLOCKS_PER_QUARTER: u64 = 24;
+const CLOCKS_PER_16TH: u64 = 6;
+const SPIN_TIME: Duration = Duration::from_micros(300); // Busy wait for the last part of an interval
+
+/// Tempo range of the generator.
+pub const MIN_BPM: f64 = 20.0;
+pub const MAX_BPM: f64 = 300.0;
+/// Swing range in percent, 50 is straight.
+pub const MIN_SWING: f64 = 50.0;
+pub const MAX_SWING: f64 = 75.0;
+
+/// Commands for the clock thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockCommand {
+    Start,
+    Stop,
+    Continue,
+    SetBpm(f64),
+    SetSwing(f64),  // Percentage of an 8th note used by the first 16th (50 - 75)
+    Locate(u16),    // Song position in 16th notes
+    Quit,
+}
+
+/// Sends MIDI clock from its own thread, controlled by commands.
+pub struct ClockGenerator {
+    tx: Sender<ClockCommand>,
+    handle: Option<JoinHandle<()>>,
+    bpm: Cell<f64>,
+}
+
+struct ClockState {
+    outputs: Vec<Output>,
+    bpm: f64,
+    swing: f64,
+    running: bool,     // Transport is playing
+    tick: u64,         // Clock counter, aligned to the song position while playing
+    position: u64,     // Song position in clocks
+    ref_tick: u64,     // Tick and time of the last tempo change
+    ref_time: Instant,
+    send_error: bool,
+}
+
+impl ClockGenerator {
+    /// Start sending clock to the given outputs.
+    ///
+    /// If running is set, a Start message is sent immediately.
+    pub fn new(outputs: Vec<Output>, bpm: f64, swing: f64, running: bool) -> ClockGenerator {
+        let (tx, rx) = channel();
+        let bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+        let handle = thread::spawn(move || {
+            let mut state = ClockState{
+                outputs,
+                bpm,
+                swing: swing.clamp(MIN_SWING, MAX_SWING),
+                running: false,
+                tick: 0,
+                position: 0,
+                ref_tick: 0,
+                ref_time: Instant::now(),
+                send_error: false,
+            };
+            if running {
+                state.handle_command(ClockCommand::Start);
+            }
+            state.run(rx);
+        });
+        ClockGenerator{tx, handle: Some(handle), bpm: Cell::new(bpm)}
+    }
+
+    /// Send a command to the clock thread.
+    pub fn send(&self, command: ClockCommand) {
+        if let ClockCommand::SetBpm(bpm) = command {
+            self.bpm.set(bpm.clamp(MIN_BPM, MAX_BPM));
+        }
+        self.tx.send(command).ok();
+    }
+
+    /// Current tempo of the clock.
+    pub fn bpm(&self) -> f64 {
+        self.bpm.get()
+    }
+}
+
+/// Parse an interactive command. Bpm is the current tempo, used for relative
+/// tempo changes.
+pub fn parse_command(line: &str, bpm: f64) -> Option<ClockCommand> {
+    let mut words = line.split_whitespace();
+    let command = words.next()?;
+    // An invalid value rejects the command, NaN would stop the clock timing
+    let value = match words.next() {
+        Some(v) => Some(v.parse::<f64>().ok().filter(|v| v.is_finite())?),
+        None => None,
+    };
+    match (command, value) {
+        ("start", _) => Some(ClockCommand::Start),
+        ("stop", _) => Some(ClockCommand::Stop),
+        ("continue", _) | ("cont", _) => Some(ClockCommand::Continue),
+        ("bpm", Some(bpm)) => Some(ClockCommand::SetBpm(bpm)),
+        ("+", v) => Some(ClockCommand::SetBpm(bpm + v.unwrap_or(1.0))),
+        ("-", v) => Some(ClockCommand::SetBpm(bpm - v.unwrap_or(1.0))),
+        ("swing", Some(swing)) => Some(ClockCommand::SetSwing(swing)),
+        ("pos", Some(pos)) if (0.0..16384.0).contains(&pos) => Some(ClockCommand::Locate(pos as u16)),
+        _ => None,
+    }
+}
+
+impl Drop for ClockGenerator {
+    fn drop(&mut self) {
+        self.send(ClockCommand::Quit);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl ClockState {
+    /// Length of a clock interval without swing, in usec.
+    fn interval(&self) -> f64 {
+        60000000.0 / (self.bpm * CLOCKS_PER_QUARTER as f64)
+    }
+
+    /// Time of a tick relative to the start of the song, in usec.
+    ///
+    /// With swing, the first 16th of every 8th note is longer than the
+    /// second one.
+    fn tick_time(&self, tick: u64) -> f64 {
+        let interval = self.interval();
+        let pair = CLOCKS_PER_16TH * 2;
+        let base = (tick / pair) as f64 * pair as f64 * interval;
+        let phase = tick % pair;
+        let first = 2.0 * self.swing / 100.0 * interval;  // Interval during the first 16th
+        let second = 2.0 * interval - first;                // Interval during the second 16th
+        let offset = if phase <= CLOCKS_PER_16TH {
+            phase as f64 * first
+        } else {
+            CLOCKS_PER_16TH as f64 * first + (phase - CLOCKS_PER_16TH) as f64 * second
+        };
+        base + offset
+    }
+
+    fn tick_instant(&self, tick: u64) -> Instant {
+        let offset = self.tick_time(tick) - self.tick_time(self.ref_tick);
+        self.ref_time + Duration::from_micros(offset.max(0.0) as u64)
+    }
+
+    /// Use the current tick as new reference, after the timing has changed.
+    fn rebase(&mut self, time: Instant) {
+        self.ref_tick = self.tick;
+        self.ref_time = time;
+    }
+
+    fn send(&mut self, m: MidiMessage) {
+        let bytes = m.encode();
+        for out in self.outputs.iter_mut() {
+            if out.send(&bytes).is_err() && !self.send_error {
+                eprintln!("Error when sending clock ...");
+                self.send_error = true;
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: ClockCommand) {
+        let last_tick = self.tick_instant(self.tick);
+        match command {
+            ClockCommand::Start => {
+                self.position = 0;
+                self.tick = 0;
+                self.running = true;
+                self.rebase(last_tick);
+                self.send(MidiMessage::Start);
+            }
+            ClockCommand::Stop => {
+                self.running = false;
+                self.send(MidiMessage::Stop);
+            }
+            ClockCommand::Continue => {
+                if !self.running {
+                    self.running = true;
+                    self.tick = self.position;
+                    self.rebase(last_tick);
+                    self.send(MidiMessage::Continue);
+                }
+            }
+            ClockCommand::SetBpm(bpm) => {
+                self.rebase(last_tick);
+                self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+            }
+            ClockCommand::SetSwing(swing) => {
+                self.rebase(last_tick);
+                self.swing = swing.clamp(MIN_SWING, MAX_SWING);
+            }
+            ClockCommand::Locate(position) => {
+                // Devices only accept a new position while stopped
+                let was_running = self.running;
+                if was_running {
+                    self.send(MidiMessage::Stop);
+                    self.running = false;
+                }
+                self.position = position as u64 * CLOCKS_PER_16TH;
+                self.send(MidiMessage::SongPos{position});
+                if was_running {
+                    self.handle_command(ClockCommand::Continue);
+                }
+            }
+            ClockCommand::Quit => (),
+        }
+    }
+
+    fn run(&mut self, rx: Receiver<ClockCommand>) {
+        loop {
+            let next = self.tick_instant(self.tick + 1);
+            // Wait for commands until shortly before the next tick
+            let now = Instant::now();
+            if next > now + SPIN_TIME {
+                match rx.recv_timeout(next - now - SPIN_TIME) {
+                    Ok(ClockCommand::Quit) | Err(RecvTimeoutError::Disconnected) => break,
+                    Ok(command) => {
+                        self.handle_command(command);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                }
+            }
+            while Instant::now() < next {
+                std::hint::spin_loop();
+            }
+            self.tick += 1;
+            if self.running {
+                self.position += 1;
+            }
+            self.send(MidiMessage::TimingClock);
+        }
+        if self.running {
+            self.send(MidiMessage::Stop);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::backend::Backend;
+
+    fn state(outputs: Vec<Output>, bpm: f64, swing: f64) -> ClockState {
+        ClockState{outputs, bpm, swing, running: false, tick: 0, position: 0, ref_tick: 0, ref_time: Instant::now(),
+                   send_error: false}
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn calculates_tick_times() {
+        // 125 BPM = 20 msec per clock
+        let clock = state(vec!(), 125.0, 50.0);
+        for tick in [0u64, 1, 6, 7, 12, 100].iter() {
+            assert!(close(clock.tick_time(*tick), *tick as f64 * 20000.0));
+        }
+
+        // With 75% swing the first 16th takes 3/4 of the 8th note
+        let clock = state(vec!(), 125.0, 75.0);
+        assert!(close(clock.tick_time(1), 30000.0));
+        assert!(close(clock.tick_time(6), 180000.0));
+        assert!(close(clock.tick_time(7), 190000.0));
+        assert!(close(clock.tick_time(12), 240000.0));
+        assert!(close(clock.tick_time(18), 420000.0));
+    }
+
+    #[test]
+    fn locates_while_running() {
+        let backend = MockBackend::new(&[], &["synth"]);
+        let mut clock = state(vec!(backend.connect_output(0).unwrap()), 120.0, 50.0);
+        clock.handle_command(ClockCommand::Start);
+        clock.handle_command(ClockCommand::Locate(8));
+        assert_eq!(clock.position, 48);
+        assert_eq!(clock.tick, 48);
+        assert_eq!(backend.sent(0), vec!(vec!(0xFA), vec!(0xFC), vec!(0xF2, 8, 0), vec!(0xFB)));
+        // Tempo values are kept in range
+        clock.handle_command(ClockCommand::SetBpm(1000.0));
+        assert!(close(clock.bpm, MAX_BPM));
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("start", 120.0), Some(ClockCommand::Start));
+        assert_eq!(parse_command(" cont ", 120.0), Some(ClockCommand::Continue));
+        assert_eq!(parse_command("bpm 98.5", 120.0), Some(ClockCommand::SetBpm(98.5)));
+        assert_eq!(parse_command("+", 120.0), Some(ClockCommand::SetBpm(121.0)));
+        assert_eq!(parse_command("- 10", 120.0), Some(ClockCommand::SetBpm(110.0)));
+        assert_eq!(parse_command("swing 60", 120.0), Some(ClockCommand::SetSwing(60.0)));
+        assert_eq!(parse_command("pos 16", 120.0), Some(ClockCommand::Locate(16)));
+        assert_eq!(parse_command("pos 16384", 120.0), None);
+        assert_eq!(parse_command("bpm", 120.0), None);
+        assert_eq!(parse_command("tempo 100", 120.0), None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for line in ["bpm nan", "bpm inf", "+ nan", "- -inf", "swing NaN", "+ fast"].iter() {
+            assert_eq!(parse_command(line, 120.0), None, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Transformation of MIDI clock when forwarding.
+//!
+//! Every route can divide or multiply the clock, delay clock and transport
+//! messages by a fixed time, or drop clock or transport messages completely.
+//! Multiplied clocks are interpolated using the measured clock interval and
+//! sent by a Scheduler, as are delayed messages. The number of clocks is kept
+//! exact: interpolated clocks that are still pending when the next clock
+//! arrives (because the tempo went up) are sent immediately.
+
+use super::avg::Stats;
+use super::scheduler::Scheduler;
+use super::MidiMessage;
+
+use std::time::{Duration, Instant};
+
+const WINDOW: usize = 6;           // Average over a quarter of a beat, to follow tempo changes quickly
+const GAP_FACTOR: f64 = 4.0;       // Intervals longer than this times the average are gaps
+const MAX_INTERVAL: u64 = 500000;  // Longest valid interval in usec (5 BPM)
+const MAX_FACTOR: u32 = 24;
+const MAX_DELAY: u64 = 1000;       // Longest delay in msec
+const INTERPOLATED_TAG: u32 = 1;   // Scheduler tag of the interpolated clocks
+
+/// Clock settings of a route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockOptions {
+    pub divide: u32,
+    pub multiply: u32,
+    pub delay: Duration,
+    pub pass_clock: bool,
+    pub pass_transport: bool,
+}
+
+impl Default for ClockOptions {
+    fn default() -> Self {
+        ClockOptions{divide: 1, multiply: 1, delay: Duration::from_millis(0), pass_clock: true, pass_transport: true}
+    }
+}
+
+impl ClockOptions {
+    /// Parse a space separated list of settings, e.g. "div=2 delay=5".
+    ///
+    /// Settings are div=n, mul=n (1 - 24), delay=msec, noclock and
+    /// notransport.
+    pub fn parse(s: &str) -> Result<ClockOptions, String> {
+        let mut options = ClockOptions::default();
+        for term in s.split_whitespace() {
+            let mut parts = term.splitn(2, '=');
+            let key = parts.next().unwrap_or("").to_lowercase();
+            let value = parts.next();
+            let factor = |v: Option<&str>| match v.and_then(|v| v.parse::<u32>().ok()) {
+                Some(n) if (1..=MAX_FACTOR).contains(&n) => Ok(n),
+                _ => Err(format!("Invalid clock factor in '{}' (1 - {})", term, MAX_FACTOR)),
+            };
+            match (key.as_str(), value) {
+                ("div", v) => options.divide = factor(v)?,
+                ("mul", v) => options.multiply = factor(v)?,
+                ("delay", v) => match v.and_then(|v| v.parse::<u64>().ok()) {
+                    Some(ms) if ms <= MAX_DELAY => options.delay = Duration::from_millis(ms),
+                    _ => return Err(format!("Invalid clock delay in '{}' (0 - {} msec)", term, MAX_DELAY)),
+                },
+                ("noclock", None) => options.pass_clock = false,
+                ("notransport", None) => options.pass_transport = false,
+                _ => return Err(format!("Unknown clock setting '{}'", term)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// True if the settings change anything.
+    pub fn is_active(&self) -> bool {
+        *self != ClockOptions::default()
+    }
+
+    /// The settings in the form accepted by parse().
+    pub fn describe(&self) -> String {
+        let mut terms = vec!();
+        if self.divide > 1 {
+            terms.push(format!("div={}", self.divide));
+        }
+        if self.multiply > 1 {
+            terms.push(format!("mul={}", self.multiply));
+        }
+        if self.delay > Duration::from_millis(0) {
+            terms.push(format!("delay={}", self.delay.as_millis()));
+        }
+        if !self.pass_clock {
+            terms.push("noclock".to_string());
+        }
+        if !self.pass_transport {
+            terms.push("notransport".to_string());
+        }
+        terms.join(" ")
+    }
+
+    /// True if messages have to be sent later, which needs a Scheduler.
+    pub fn needs_scheduler(&self) -> bool {
+        self.multiply > 1 || self.delay > Duration::from_millis(0)
+    }
+}
+
+/// Transforms the clock and transport messages of a route.
+pub struct ClockTransform {
+    options: ClockOptions,
+    scheduler: Option<Scheduler>,
+    count: u32,                 // Received clocks since the last Start/ Continue
+    last_clock: Option<Instant>,
+    intervals: Stats<u64>,
+}
+
+impl ClockTransform {
+    /// Create a transform, the scheduler is needed if needs_scheduler() is
+    /// set for the options.
+    pub fn new(options: ClockOptions, scheduler: Option<Scheduler>) -> ClockTransform {
+        ClockTransform{options, scheduler, count: 0, last_clock: None, intervals: Stats::new(WINDOW)}
+    }
+
+    /// Handle a message received at the given time.
+    ///
+    /// Returns false if the message is no clock or transport message and has
+    /// to be forwarded as usual. Otherwise the transformed messages are either
+    /// passed to send or queued in the scheduler.
+    pub fn process<F>(&mut self, now: Instant, m: &MidiMessage, mut send: F) -> bool
+            where F: FnMut(&[u8]) {
+        match m {
+            MidiMessage::TimingClock => {
+                let interval = self.measure(now);
+                if !self.options.pass_clock {
+                    return true;
+                }
+                let count = self.count;
+                self.count = self.count.wrapping_add(1);
+                if !count.is_multiple_of(self.options.divide) {
+                    return true;
+                }
+                let time = now + self.options.delay;
+                let bytes = m.encode();
+                match self.scheduler.as_ref() {
+                    Some(scheduler) => {
+                        // Clocks of the last interval that haven't been sent yet
+                        let missed = scheduler.cancel(INTERPOLATED_TAG);
+                        for _ in 0..=missed {
+                            scheduler.schedule(time, &bytes, 0);
+                        }
+                        if let Some(interval) = interval {
+                            let multiply = self.options.multiply;
+                            let step = interval * self.options.divide as f64 / multiply as f64;
+                            for i in 1..multiply {
+                                let offset = Duration::from_micros((step * i as f64) as u64);
+                                scheduler.schedule(time + offset, &bytes, INTERPOLATED_TAG);
+                            }
+                        }
+                    }
+                    None => send(&bytes),
+                }
+                true
+            }
+            MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop | MidiMessage::SongPos{..} => {
+                if let MidiMessage::Start | MidiMessage::Continue = m {
+                    // Let the divided clock start on the first clock after Start
+                    self.count = 0;
+                }
+                if !self.options.pass_transport {
+                    return true;
+                }
+                match self.scheduler.as_ref() {
+                    Some(scheduler) => {
+                        scheduler.cancel(INTERPOLATED_TAG);
+                        scheduler.schedule(now + self.options.delay, &m.encode(), 0);
+                    }
+                    None => send(&m.encode()),
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Add the time of a clock, returns the average interval in usec.
+    fn measure(&mut self, now: Instant) -> Option<f64> {
+        let last = self.last_clock.replace(now)?;
+        let interval = now.duration_since(last).as_micros() as u64;
+        let is_gap = interval > MAX_INTERVAL
+            || self.intervals.mean().is_some_and(|mean| interval as f64 > mean * GAP_FACTOR);
+        if is_gap {
+            self.intervals.clear();
+            return None;
+        }
+        Some(self.intervals.add_value(interval))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::backend::Backend;
+
+    use std::sync::{Arc, Mutex};
+
+    /// Feeds messages at the given times (msec after start), returns the
+    /// directly sent messages.
+    fn feed(transform: &mut ClockTransform, start: Instant, messages: &[(u64, MidiMessage)]) -> Vec<Vec<u8>> {
+        let mut sent = vec!();
+        for (time, m) in messages {
+            let now = start + Duration::from_millis(*time);
+            assert!(transform.process(now, m, |bytes| sent.push(bytes.to_vec())));
+        }
+        sent
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = ClockOptions::parse("div=2 delay=5 notransport").unwrap();
+        assert_eq!(options, ClockOptions{divide: 2, delay: Duration::from_millis(5), pass_transport: false,
+                                         ..ClockOptions::default()});
+        assert_eq!(options.describe(), "div=2 delay=5 notransport");
+        assert!(options.needs_scheduler());
+        assert!(!ClockOptions::parse("").unwrap().is_active());
+        assert!(ClockOptions::parse("mul=25").is_err());
+        assert!(ClockOptions::parse("delay=2000").is_err());
+        assert!(ClockOptions::parse("swing").is_err());
+    }
+
+    #[test]
+    fn divides_from_start() {
+        let mut transform = ClockTransform::new(ClockOptions::parse("div=3").unwrap(), None);
+        let start = Instant::now();
+        let mut messages = vec!((0, MidiMessage::TimingClock), (20, MidiMessage::Start));
+        messages.extend((1..8).map(|i| (20 + i * 20, MidiMessage::TimingClock)));
+        let sent = feed(&mut transform, start, &messages);
+        // The count starts again with the first clock after Start
+        assert_eq!(sent, vec!(vec!(0xF8), vec!(0xFA), vec!(0xF8), vec!(0xF8), vec!(0xF8)));
+        // Other messages are left to the caller
+        assert!(!transform.process(start, &MidiMessage::NoteOn{channel: 0, key: 60, velocity: 100}, |_| ()));
+    }
+
+    #[test]
+    fn drops_clock_or_transport() {
+        let messages = [(0, MidiMessage::Start), (20, MidiMessage::TimingClock), (40, MidiMessage::Stop)];
+        let mut transform = ClockTransform::new(ClockOptions::parse("noclock").unwrap(), None);
+        assert_eq!(feed(&mut transform, Instant::now(), &messages), vec!(vec!(0xFA), vec!(0xFC)));
+        let mut transform = ClockTransform::new(ClockOptions::parse("notransport").unwrap(), None);
+        assert_eq!(feed(&mut transform, Instant::now(), &messages), vec!(vec!(0xF8)));
+    }
+
+    #[test]
+    fn multiplies_and_delays() {
+        let backend = MockBackend::new(&[], &["synth"]);
+        let output = Arc::new(Mutex::new(backend.connect_output(0).unwrap()));
+        let options = ClockOptions::parse("mul=4 delay=10").unwrap();
+        let mut transform = ClockTransform::new(options, Some(Scheduler::new(output, None)));
+        let start = Instant::now();
+        feed(&mut transform, start, &[(0, MidiMessage::TimingClock), (20, MidiMessage::TimingClock)]);
+        // The first clock has no interval, the second is followed by three
+        // interpolated ones every 5 msec
+        let sent = backend.wait_for_sent(0, 5);
+        assert!(start.elapsed() >= Duration::from_millis(45));
+        assert_eq!(sent, vec!(vec!(0xF8); 5));
+    }
+
+    #[test]
+    fn sends_missed_clocks_at_once() {
+        let backend = MockBackend::new(&[], &["synth"]);
+        let output = Arc::new(Mutex::new(backend.connect_output(0).unwrap()));
+        let mut transform = ClockTransform::new(ClockOptions::parse("mul=4").unwrap(), Some(Scheduler::new(output, None)));
+        // Far in the future, so nothing is sent while checking the queue
+        let start = Instant::now() + Duration::from_secs(60);
+        feed(&mut transform, start, &[(0, MidiMessage::TimingClock), (20, MidiMessage::TimingClock)]);
+
+        // The next clock comes early: the three pending interpolated clocks
+        // are sent along with it, three new ones follow
+        feed(&mut transform, start, &[(30, MidiMessage::TimingClock)]);
+        let scheduler = transform.scheduler.as_ref().unwrap();
+        assert_eq!(scheduler.cancel(INTERPOLATED_TAG), 3);
+        assert_eq!(scheduler.cancel(0), 1 + 1 + 4);
+
+        // Stop cancels the interpolated clocks
+        feed(&mut transform, start, &[(40, MidiMessage::TimingClock), (45, MidiMessage::Stop)]);
+        let scheduler = transform.scheduler.as_ref().unwrap();
+        assert_eq!(scheduler.cancel(INTERPOLATED_TAG), 0);
+        assert_eq!(scheduler.cancel(0), 2);
+        assert!(backend.sent(0).is_empty());
+    }
+}
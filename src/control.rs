@@ -0,0 +1,291 @@
+//! Changing the routes while running.
+//!
+//! A control socket (a Unix domain socket) accepts commands, one per line.
+//! Every command is answered with any number of lines, followed by a line
+//! with "ok" or "error <message>":
+//!
+//! * `routes`: list the routes
+//! * `add <inport> <inchannel> <outport> <outchannel>`: add a route,
+//!   answers its number
+//! * `remove <route>`: remove a route
+//! * `mute <route>`, `unmute <route>`: stop or resume forwarding
+//! * `transpose <route> <semitones>`: transpose the forwarded notes
+//! * `channel <route> <inchannel> <outchannel>`: change the channels
+//! * `chord <route> <settings>`, `chord <route> off`: play a chord for every
+//!   note (settings like "degrees=0,2,4 key=D scale=minor", see chord)
+//! * `panic`: Sustain off, All Sound Off and All Notes Off on all outputs
+//!
+//! Ports are given by number, routes by the number shown by "routes".
+
+use super::backend::Backend;
+use super::chord::ChordOptions;
+use super::net::is_timeout;
+use super::router::{Config, Router};
+
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How often threads check for closing
+
+/// A command of the control protocol.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Routes,
+    Add{in_port: usize, in_channel: u8, out_port: usize, out_channel: u8},
+    Remove(usize),
+    Mute(usize),
+    Unmute(usize),
+    Transpose(usize, i8),
+    Channel{route: usize, in_channel: u8, out_channel: u8},
+    Chord(usize, Option<ChordOptions>),
+    Panic,
+}
+
+impl Command {
+    /// Parse a command line like "transpose 2 -12".
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |i: usize| -> Result<usize, String> {
+            let word = words.get(i).ok_or("Missing argument")?;
+            word.parse().map_err(|_| format!("Invalid number '{}'", word))
+        };
+        let channel = |i: usize| -> Result<u8, String> {
+            match number(i)? {
+                c if c <= 16 => Ok(c as u8),
+                _ => Err(format!("Invalid channel '{}'", words[i])),
+            }
+        };
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args.len()),
+            None => return Err("Empty command".to_string()),
+        };
+        let (expected, usage) = match command {
+            "routes" => (0, "routes"),
+            "panic" => (0, "panic"),
+            "remove" => (1, "remove <route>"),
+            "mute" => (1, "mute <route>"),
+            "unmute" => (1, "unmute <route>"),
+            "transpose" => (2, "transpose <route> <semitones>"),
+            "channel" => (3, "channel <route> <inchannel> <outchannel>"),
+            "add" => (4, "add <inport> <inchannel> <outport> <outchannel>"),
+            "chord" => (2, "chord <route> <settings>|off"),
+            _ => return Err(format!("Unknown command '{}'", command)),
+        };
+        // The chord settings are several words
+        if args != expected && !(command == "chord" && args > expected) {
+            return Err(format!("Usage: {}", usage));
+        }
+        Ok(match command {
+            "routes" => Command::Routes,
+            "panic" => Command::Panic,
+            "remove" => Command::Remove(number(1)?),
+            "mute" => Command::Mute(number(1)?),
+            "unmute" => Command::Unmute(number(1)?),
+            "transpose" => {
+                let semitones = words[2].parse::<i8>()
+                                        .ok()
+                                        .filter(|s| (-127..=127).contains(s))
+                                        .ok_or_else(|| format!("Invalid transposition '{}'", words[2]))?;
+                Command::Transpose(number(1)?, semitones)
+            }
+            "channel" => Command::Channel{route: number(1)?, in_channel: channel(2)?, out_channel: channel(3)?},
+            "chord" => {
+                let settings = words[2..].join(" ");
+                let chord = if settings == "off" { None } else { Some(ChordOptions::parse(&settings)?) };
+                Command::Chord(number(1)?, chord)
+            }
+            _ => Command::Add{in_port: number(1)?, in_channel: channel(2)?, out_port: number(3)?, out_channel: channel(4)?},
+        })
+    }
+}
+
+/// Apply a command to the router, returns the lines of the answer.
+pub fn execute(command: Command, router: &mut Router, backend: &dyn Backend) -> Result<Vec<String>, String> {
+    match command {
+        Command::Routes => Ok(router.routes().iter().map(|r| {
+            let mut line = format!("{}: {}", r.route, r.config.name());
+            if r.config.transpose != 0 {
+                line += &format!(", transpose {:+}", r.config.transpose);
+            }
+            if let Some(chord) = r.config.chord.as_ref() {
+                line += &format!(", chord {}", chord.describe());
+            }
+            if let Some(arp) = r.config.arp.as_ref() {
+                line += &format!(", arpeggio {}", arp.describe());
+            }
+            if r.muted {
+                line += ", muted";
+            }
+            line
+        }).collect()),
+        Command::Add{in_port, in_channel, out_port, out_channel} => {
+            let config = Config{in_port, in_channel, out_port, out_channel, ..Config::default()};
+            let route = router.add(backend, config).map_err(|err| err.to_string())?;
+            Ok(vec!(route.to_string()))
+        }
+        Command::Remove(route) => router.remove(route).map(|_| vec!()),
+        Command::Mute(route) => router.set_muted(route, true).map(|_| vec!()),
+        Command::Unmute(route) => router.set_muted(route, false).map(|_| vec!()),
+        Command::Transpose(route, semitones) => router.set_transpose(route, semitones).map(|_| vec!()),
+        Command::Channel{route, in_channel, out_channel} => router.set_channels(route, in_channel, out_channel).map(|_| vec!()),
+        Command::Chord(route, chord) => router.set_chord(route, chord).map(|_| vec!()),
+        Command::Panic => {
+            let outputs = router.panic();
+            Ok(vec!(format!("Reset {} outputs", outputs)))
+        }
+    }
+}
+
+/// The control socket, which is removed when dropped.
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    running: AtomicBool,
+}
+
+impl ControlServer {
+    /// Create the socket. A socket left over by an instance which is not
+    /// running anymore is replaced.
+    pub fn start(path: &str) -> Result<ControlServer, Box<dyn Error>> {
+        let path = Path::new(path);
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("'{}' exists and is not a socket", path.display()).into());
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("'{}' is used by another instance", path.display()).into());
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(ControlServer{path: path.to_path_buf(), listener, running: AtomicBool::new(true)})
+    }
+
+    /// Handle the connections until stop() is called.
+    pub fn serve(&self, router: &Mutex<Router>, backend: &(dyn Backend + Sync)) {
+        thread::scope(|s| {
+            while self.running.load(Ordering::SeqCst) {
+                match self.listener.accept() {
+                    Ok((stream, _)) => {
+                        s.spawn(move || {
+                            if let Err(err) = self.handle(stream, router, backend) {
+                                eprintln!("Control connection closed: {}", err);
+                            }
+                        });
+                    }
+                    Err(ref err) if is_timeout(err) => thread::sleep(POLL_INTERVAL),
+                    Err(err) => {
+                        eprintln!("Error when accepting control connections: {}", err);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Close all connections, serve() returns.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    fn handle(&self, stream: UnixStream, router: &Mutex<Router>, backend: &dyn Backend) -> Result<(), Box<dyn Error>> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while self.running.load(Ordering::SeqCst) {
+            // Bytes read before a timeout stay in line
+            match reader.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(ref err) if is_timeout(err) => continue,
+                Err(err) => return Err(err.into()),
+            }
+            if line.trim().is_empty() {
+                line.clear();
+                continue;
+            }
+            let result = Command::parse(&line).and_then(|command| execute(command, &mut router.lock().unwrap(), backend));
+            let answer = match result {
+                Ok(lines) => lines.into_iter().map(|l| l + "\n").collect::<String>() + "ok\n",
+                Err(err) => format!("error {}\n", err),
+            };
+            writer.write_all(answer.as_bytes())?;
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// A connection to the control socket of a running instance.
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    pub fn connect(path: &str) -> Result<ControlClient, Box<dyn Error>> {
+        let stream = UnixStream::connect(path).map_err(|err| format!("Can't connect to '{}': {}", path, err))?;
+        Ok(ControlClient{reader: BufReader::new(stream.try_clone()?), writer: stream})
+    }
+
+    /// Send a command, returns the lines of the answer. An error answer is
+    /// returned as error.
+    pub fn command(&mut self, command: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        writeln!(self.writer, "{}", command.trim())?;
+        let mut lines = vec!();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err("Connection closed".into());
+            }
+            let line = line.trim_end();
+            if line == "ok" {
+                return Ok(lines);
+            }
+            if let Some(err) = line.strip_prefix("error ") {
+                return Err(err.into());
+            }
+            lines.push(line.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse(" routes "), Ok(Command::Routes));
+        assert_eq!(Command::parse("add 1 0 2 10"), Ok(Command::Add{in_port: 1, in_channel: 0, out_port: 2, out_channel: 10}));
+        assert_eq!(Command::parse("mute 3"), Ok(Command::Mute(3)));
+        assert_eq!(Command::parse("transpose 0 -12"), Ok(Command::Transpose(0, -12)));
+        assert_eq!(Command::parse("channel 2 1 16"), Ok(Command::Channel{route: 2, in_channel: 1, out_channel: 16}));
+        assert_eq!(Command::parse("chord 1 notes=0,7 quantize"), Ok(Command::Chord(1, Some(ChordOptions::parse("notes=0,7 quantize").unwrap()))));
+        assert_eq!(Command::parse("chord 1 off"), Ok(Command::Chord(1, None)));
+        assert_eq!(Command::parse("chord 1"), Err("Usage: chord <route> <settings>|off".to_string()));
+        assert_eq!(Command::parse("panic"), Ok(Command::Panic));
+        assert_eq!(Command::parse("channel 2 1 17"), Err("Invalid channel '17'".to_string()));
+        assert_eq!(Command::parse("transpose 0 up"), Err("Invalid transposition 'up'".to_string()));
+        assert_eq!(Command::parse("remove"), Err("Usage: remove <route>".to_string()));
+        assert_eq!(Command::parse("solo 1"), Err("Unknown command 'solo'".to_string()));
+        assert!(Command::parse("").is_err());
+    }
+}
@@ -1,132 +1,544 @@
-use super::Avg;
+//! Formatting of received messages.
+//!
+//! A Display shows the messages of an input port as colored text, JSON or CSV
+//! lines, along with the decoded parameters, tempo and timecode.
+
 use super::MidiMessage;
+use super::ccnames::CcNames;
+use super::filter::Filter;
+use super::midi::{note_name, ChannelMode};
+use super::mtc::{MtcDecoder, Timecode};
+use super::rpn::{Decoded, ParamDecoder, ParamEvent};
+use super::tempo::TempoTracker;
+
+use std::collections::HashMap;
 
+/// Escape sequences for the parts of a text line.
 pub struct Colors {
     c_normal: &'static str,
     c_param: &'static str,
     c_value: &'static str,
 }
 
+/// No colors.
 pub const COLORS_BW: Colors = Colors{ c_normal: "", c_param: "", c_value: "" };
+/// Terminal colors.
 pub const COLORS_TC: Colors = Colors{ c_normal: "\x1b[30m", c_param: "\x1b[32m", c_value:"\x1b[34m" };
 
+/// How timestamps are printed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeFormat {
+    Absolute, // Timestamp as received from the MIDI driver (usec)
+    Relative, // Seconds since the first shown message
+    Delta,    // Microseconds since the previous shown message
+}
+
+impl TimeFormat {
+    /// Parse a format name as given on the command line.
+    pub fn parse(name: &str) -> Option<TimeFormat> {
+        match name {
+            "absolute" => Some(TimeFormat::Absolute),
+            "relative" => Some(TimeFormat::Relative),
+            "delta" => Some(TimeFormat::Delta),
+            _ => None,
+        }
+    }
+}
+
+/// Format of the monitor output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text, // Human readable, optionally colored
+    Json, // One JSON object per line
+    Csv,  // One CSV row per message, see CSV_HEADER
+}
+
+impl OutputFormat {
+    /// Parse a format name as given on the command line.
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        match name {
+            "text" => Some(OutputFormat::Text),
+            "json" => Some(OutputFormat::Json),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Column names of the CSV output.
+pub const CSV_HEADER: &str = "timestamp,port,port_name,type,channel,data1,data2,bytes";
+
+/// Settings for the monitor output.
+#[derive(Clone)]
+pub struct MonitorOptions {
+    pub show_time: bool,   // Show system real-time messages
+    pub note_names: bool,  // Show note names instead of numbers
+    pub show_hex: bool,    // Show the raw bytes of a message
+    pub collapse_cc: bool, // Collapse runs of the same controller
+    pub time_format: TimeFormat,
+    pub format: OutputFormat,
+    pub filter: Filter,
+    pub assemble_params: bool,                  // Assemble RPN/ NRPN and 14-bit controllers
+    pub use_tui: bool,                          // Show the data in the terminal UI
+    pub cc_names: CcNames,                      // Controller names for all ports
+    pub port_cc_names: HashMap<usize, CcNames>, // Controller names for specific ports
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions{
+            show_time: false,
+            note_names: false,
+            show_hex: false,
+            collapse_cc: false,
+            time_format: TimeFormat::Absolute,
+            format: OutputFormat::Text,
+            filter: Filter::default(),
+            assemble_params: false,
+            use_tui: false,
+            cc_names: CcNames::standard(),
+            port_cc_names: HashMap::new(),
+        }
+    }
+}
+
+impl MonitorOptions {
+    /// Returns the options for monitoring the given port.
+    pub fn for_port(&self, port: usize) -> MonitorOptions {
+        let mut options = self.clone();
+        if let Some(names) = self.port_cc_names.get(&port) {
+            options.cc_names = names.clone();
+        }
+        options
+    }
+}
+
+/// A run of repeated messages for the same controller.
+struct CcRun {
+    port: usize,
+    channel: u8,
+    controller: u8,
+    value: u8,
+    count: usize,
+    timestamp: u64,
+}
+
+/// Formats and prints the messages received on a port.
 pub struct Display {
     colors: &'static Colors,
-    show_time: bool,
+    options: MonitorOptions,
+    port_name: String,
     bpm: f64,
-    last_clock: u64, // Timestamp of last TimingClock message (usec)
-    avg: Avg,
+    tempo: TempoTracker,
+    first_shown: Option<u64>, // Timestamp of the first shown message
+    last_shown: Option<u64>,  // Timestamp of the previous shown message
+    cc_run: Option<CcRun>,
+    params: ParamDecoder,
+    mtc: MtcDecoder,
+    last_mtc: Option<Timecode>, // Last decoded timecode
+}
+
+/// Returns the name of the message type.
+pub fn type_name(m: &MidiMessage) -> &'static str {
+    match *m {
+        MidiMessage::NoteOn{..} => "NoteOn",
+        MidiMessage::NoteOff{..} => "NoteOff",
+        MidiMessage::KeyAT{..} => "Aftertouch",
+        MidiMessage::ControlChg{..} => "ControlChg",
+        MidiMessage::ChannelMode{mode, ..} => match mode {
+            ChannelMode::AllSoundOff => "AllSoundOff",
+            ChannelMode::ResetAllControllers => "ResetAllControllers",
+            ChannelMode::LocalControl(_) => "LocalControl",
+            ChannelMode::AllNotesOff => "AllNotesOff",
+            ChannelMode::OmniOff => "OmniOff",
+            ChannelMode::OmniOn => "OmniOn",
+            ChannelMode::MonoOn(_) => "MonoMode",
+            ChannelMode::PolyOn => "PolyMode",
+        },
+        MidiMessage::ProgramChg{..} => "ProgramChg",
+        MidiMessage::ChannelAT{..} => "ChannelAftertouch",
+        MidiMessage::Pitchbend{..} => "Pitchbend",
+        MidiMessage::SongPos{..} => "SongPosition",
+        MidiMessage::QuarterFrame{..} => "MtcQuarterFrame",
+        MidiMessage::MtcFullFrame{..} => "MtcFullFrame",
+        MidiMessage::TimingClock => "TimingClock",
+        MidiMessage::Start => "Start",
+        MidiMessage::Continue => "Continue",
+        MidiMessage::Stop => "Stop",
+        MidiMessage::ActiveSensing => "ActiveSensing",
+        MidiMessage::Reset => "Reset",
+        MidiMessage::Other{status: 0xF0} => "SysEx",
+        MidiMessage::Other{..} => "Other",
+    }
+}
+
+/// Returns the name and the formatted parameters of a message.
+///
+/// Used both for the plain monitor output and the terminal UI.
+pub fn describe(m: &MidiMessage, note_names: bool, cc_names: &CcNames) -> (&'static str, String) {
+    let key_str = |key: u8| if note_names { note_name(key) } else { key.to_string() };
+    let params = match *m {
+        MidiMessage::NoteOn{key, velocity, ..} => format!("key={} velocity={}", key_str(key), velocity),
+        MidiMessage::NoteOff{key, velocity, ..} => format!("key={} velocity={}", key_str(key), velocity),
+        MidiMessage::KeyAT{key, pressure, ..} => format!("key={} pressure={}", key_str(key), pressure),
+        MidiMessage::ControlChg{controller, value, ..} => match cc_names.name(controller) {
+            Some(name) => format!("controller={} ({}) value={}", controller, name, value),
+            None => format!("controller={} value={}", controller, value),
+        },
+        MidiMessage::ChannelMode{mode: ChannelMode::LocalControl(on), ..} => (if on { "on" } else { "off" }).to_string(),
+        MidiMessage::ChannelMode{mode: ChannelMode::MonoOn(channels), ..} => format!("channels={}", channels),
+        MidiMessage::ProgramChg{program, ..} => format!("program={}", program),
+        MidiMessage::ChannelAT{pressure, ..} => format!("pressure={}", pressure),
+        MidiMessage::Pitchbend{pitch, ..} => format!("pitch={}", pitch),
+        MidiMessage::SongPos{position} => format!("position={}", position),
+        MidiMessage::QuarterFrame{piece, value} => format!("piece={} value={}", piece, value),
+        MidiMessage::MtcFullFrame{timecode} => format!("{} ({})", timecode, timecode.rate.name()),
+        MidiMessage::Other{status} => format!("status={:02x}", status),
+        _ => String::new(),
+    };
+    (type_name(m), params)
+}
+
+/// Quote a string for JSON output.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Quote a string for CSV output if required.
+fn csv_string(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Format a message as a single JSON object.
+pub fn format_json(timestamp: u64, port: usize, port_name: &str, m: &MidiMessage, message: &[u8]) -> String {
+    format!("{{\"timestamp\":{},\"port\":{},\"port_name\":{},{}}}",
+            timestamp, port, json_string(port_name), json_fields(m, message))
+}
+
+/// The JSON fields describing a message: type, channel, parameters and
+/// bytes.
+pub fn json_fields(m: &MidiMessage, message: &[u8]) -> String {
+    let mut fields = format!("\"type\":\"{}\"", type_name(m));
+    if let Some(channel) = m.channel() {
+        fields += &format!(",\"channel\":{}", channel + 1);
+    }
+    for (field, value) in m.params() {
+        fields += &format!(",\"{}\":{}", field, value);
+    }
+    let bytes: Vec<String> = message.iter().map(|b| b.to_string()).collect();
+    fields += &format!(",\"bytes\":[{}]", bytes.join(","));
+    fields
+}
+
+/// Format a message as a CSV row matching CSV_HEADER.
+pub fn format_csv(timestamp: u64, port: usize, port_name: &str, m: &MidiMessage, message: &[u8]) -> String {
+    let name = type_name(m);
+    let channel = m.channel().map(|c| (c + 1).to_string()).unwrap_or_default();
+    let params = m.params();
+    let data = |i: usize| params.get(i).map(|(_, v)| v.to_string()).unwrap_or_default();
+    let bytes: Vec<String> = message.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{},{},{},{},{},{},{},{}", timestamp, port, csv_string(port_name), name, channel,
+            data(0), data(1), bytes.join(" "))
 }
 
 impl Display {
-    pub fn new(colors: &'static Colors, show_time: bool) -> Self {
+    /// Create a display for the messages of an input port.
+    pub fn new(colors: &'static Colors, options: MonitorOptions, port_name: &str) -> Self {
         Display{
             colors,
-            show_time,
+            options,
+            port_name: port_name.to_string(),
             bpm: 0.0,
-            last_clock: 0,
-            avg: Avg::new(48), // Average over 2 quarters (2 * 24 timestamps)
+            tempo: TempoTracker::new(),
+            first_shown: None,
+            last_shown: None,
+            cc_run: None,
+            params: ParamDecoder::new(),
+            mtc: MtcDecoder::new(),
+            last_mtc: None,
+        }
+    }
+
+    /// Only show messages matching filter.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.options.filter = filter;
+    }
+
+    /// Show or hide system real-time messages.
+    pub fn set_show_time(&mut self, show_time: bool) {
+        self.options.show_time = show_time;
+    }
+
+    fn format_time(&mut self, timestamp: u64) -> String {
+        let first = *self.first_shown.get_or_insert(timestamp);
+        let last = self.last_shown.replace(timestamp).unwrap_or(timestamp);
+        match self.options.time_format {
+            TimeFormat::Absolute => timestamp.to_string(),
+            TimeFormat::Relative => format!("{:.6}", timestamp.saturating_sub(first) as f64 / 1000000.0),
+            TimeFormat::Delta => format!("+{}", timestamp.saturating_sub(last)),
         }
     }
 
-    fn print_tpc(&self, timestamp: u64, port: usize, channel: u8) {
-        print!("{} Port {} Ch {} {}", timestamp, port, channel, self.colors.c_param);
+    fn format_tpc(&mut self, timestamp: u64, port: usize, channel: u8) -> String {
+        format!("{} Port {} Ch {} {}", self.format_time(timestamp), port, channel, self.colors.c_param)
     }
 
-    fn print_tp(&self, timestamp: u64, port: usize) {
-        print!("{} Port {} {}", timestamp, port, self.colors.c_param);
+    fn format_tp(&mut self, timestamp: u64, port: usize) -> String {
+        format!("{} Port {} {}", self.format_time(timestamp), port, self.colors.c_param)
     }
 
-    fn print_footer(&self) {
-        println!("{}", self.colors.c_normal);
+    fn format_hex(&self, message: &[u8]) -> String {
+        if !self.options.show_hex || message.is_empty() {
+            return String::new();
+        }
+        let bytes: Vec<String> = message.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(" {}[{}]", self.colors.c_normal, bytes.join(" "))
+    }
+
+    fn format_line(&mut self, timestamp: u64, port: usize, m: &MidiMessage, message: &[u8], suffix: &str) -> String {
+        let mut line = match m.channel() {
+            Some(channel) => self.format_tpc(timestamp, port, channel + 1),
+            None => self.format_tp(timestamp, port),
+        };
+        let (name, params) = describe(m, self.options.note_names, &self.options.cc_names);
+        line += name;
+        if !params.is_empty() {
+            line += &format!(" {}{}", self.colors.c_value, params);
+        }
+        line += suffix;
+        line += &self.format_hex(message);
+        line += self.colors.c_normal;
+        line
     }
 
-    fn calc_bpm(&mut self, timestamp: u64) {
-        if self.last_clock != 0 {
-            // We have a previous TS, so we can calculate the current BPM
-            let diff = (timestamp - self.last_clock) * 24; // Diff is in usec
-            let bpm = 60000000.0 / diff as f64;
-            let bpm = self.avg.add_value(bpm);
-            // Calculate up to 1 decimal of BPM
-            let bpm = (bpm * 10.0).round() / 10.0;
-            if bpm != self.bpm {
-                println!("{} BPM {}", timestamp, bpm);
-                self.bpm = bpm;
+    fn format_event(&mut self, timestamp: u64, port: usize, e: &ParamEvent, message: &[u8]) -> String {
+        let mut line = self.format_tpc(timestamp, port, e.channel() + 1);
+        let (name, mut params) = e.describe();
+        if let ParamEvent::Controller14{controller, value, ..} = *e {
+            if let Some(cc_name) = self.options.cc_names.name(controller) {
+                params = format!("controller={} ({}) value={}", controller, cc_name, value);
             }
         }
-        self.last_clock = timestamp;
+        line += &format!("{} {}{}", name, self.colors.c_value, params);
+        line += &self.format_hex(message);
+        line += self.colors.c_normal;
+        line
     }
 
-    pub fn show_message(&mut self, timestamp: u64, in_port: usize, message: &[u8]) {
+    /// Current tempo as calculated from the received TimingClock messages.
+    pub fn bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Tempo measured from the received clock.
+    pub fn tempo(&self) -> &TempoTracker {
+        &self.tempo
+    }
+
+    /// Current MIDI Time Code, if received.
+    pub fn timecode(&self) -> Option<Timecode> {
+        self.mtc.timecode()
+    }
+
+    /// Feed a message to the MTC decoder.
+    ///
+    /// Returns the timecode if it should be shown, which is the case once per
+    /// second and when the time jumps.
+    fn update_mtc(&mut self, m: &MidiMessage) -> Option<Timecode> {
+        let timecode = self.mtc.process(m)?;
+        let last = self.last_mtc.replace(timecode);
+        let is_next = last.is_some_and(|l| timecode.to_frames() == l.to_frames() + 1 && timecode.seconds == l.seconds);
+        if is_next && !matches!(m, MidiMessage::MtcFullFrame{..}) { None } else { Some(timecode) }
+    }
+
+    /// Feed a message to the tempo tracker.
+    ///
+    /// Returns the new BPM value if it changed.
+    fn update_tempo(&mut self, timestamp: u64, m: &MidiMessage) -> Option<f64> {
+        if !self.tempo.process(timestamp, m) {
+            return None;
+        }
+        // Calculate up to 1 decimal of BPM
+        let bpm = (self.tempo.bpm()? * 10.0).round() / 10.0;
+        if bpm != self.bpm {
+            self.bpm = bpm;
+            return Some(bpm);
+        }
+        None
+    }
+
+    /// Summary of the monitored session, empty if there is nothing to report.
+    pub fn summary(&self, in_port: usize) -> Vec<String> {
+        let mut lines = vec!();
+        if let Some(tempo) = self.tempo.summary() {
+            lines.push(format!("Tempo on port {} ({}):", in_port, self.port_name));
+            lines.extend(tempo.into_iter().map(|l| format!("  {}", l)));
+        }
+        lines
+    }
+
+    /// Format a received message according to the monitor options.
+    ///
+    /// Returns the lines to show, which can be empty if the message is
+    /// filtered, or contain an additional summary of collapsed controllers.
+    pub fn format_message(&mut self, timestamp: u64, in_port: usize, message: &[u8]) -> Vec<String> {
+        let mut lines = vec!();
         let m = MidiMessage::parse(message);
-        match m {
-            MidiMessage::NoteOn{channel, key, velocity} => {
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("NoteOn {}key={} velocity={}", self.colors.c_value, key, velocity);
-            }
-            MidiMessage::NoteOff{channel, key, velocity} => {
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("NoteOff {}key={} velocity={}", self.colors.c_value, key, velocity);
-            }
-            MidiMessage::KeyAT{channel, key, pressure} => {
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("Aftertouch {}key={} pressure={}", self.colors.c_value, key, pressure);
-            }
-            MidiMessage::ControlChg{channel, controller, value} => {
-                // TODO: Show channel mode messages (120 - 127)
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("ControlChg {}controller={} value={}", self.colors.c_value, controller, value);
-            }
-            MidiMessage::ProgramChg{channel, program} => {
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("ProgramChg {}program={}", self.colors.c_value, program);
-            }
-            MidiMessage::ChannelAT{channel, pressure} => {
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("ChannelAftertouch {}pressure={}", self.colors.c_value, pressure);
-            }
-            MidiMessage::Pitchbend{channel, pitch} => {
-                self.print_tpc(timestamp, in_port, channel + 1);
-                print!("Pitchbend {}pitch={}", self.colors.c_value, pitch);
-            }
-            MidiMessage::SongPos{position} => {
-                if !self.show_time { return; }
-                self.print_tp(timestamp, in_port);
-                print!("SongPosition {}position={}", self.colors.c_value, position);
+        let new_bpm = self.update_tempo(timestamp, &m);
+        let new_mtc = self.update_mtc(&m);
+        if m.is_realtime() && !self.options.show_time {
+            return lines;
+        }
+        // The decoder has to see all messages to keep track of the parameter state
+        let decoded = if self.options.assemble_params && self.options.format == OutputFormat::Text {
+            self.params.process(&m)
+        } else {
+            Decoded::Pass
+        };
+        if !self.options.filter.matches(in_port, &m) {
+            return lines;
+        }
+        match decoded {
+            Decoded::Pass => (),
+            Decoded::Pending => return lines,
+            Decoded::Event(e) => {
+                lines.push(self.format_event(timestamp, in_port, &e, message));
+                return lines;
             }
-            MidiMessage::TimingClock => {
-                if !self.show_time { return; }
-                self.calc_bpm(timestamp);
-                return;
-                //self.print_tp(timestamp, in_port);
-                //print!("TimingClock");
+        }
+        match self.options.format {
+            OutputFormat::Json => {
+                lines.push(format_json(timestamp, in_port, &self.port_name, &m, message));
+                return lines;
             }
-            MidiMessage::Start => {
-                if !self.show_time { return; }
-                self.print_tp(timestamp, in_port);
-                print!("Start");
+            OutputFormat::Csv => {
+                lines.push(format_csv(timestamp, in_port, &self.port_name, &m, message));
+                return lines;
             }
-            MidiMessage::Continue => {
-                if !self.show_time { return; }
-                self.print_tp(timestamp, in_port);
-                print!("Continue");
+            OutputFormat::Text => (),
+        }
+        if let MidiMessage::TimingClock = m {
+            if let Some(bpm) = new_bpm {
+                let mut line = format!("{} BPM {}", self.format_time(timestamp), bpm);
+                if let Some(jitter) = self.tempo.jitter() {
+                    line += &format!(" (jitter {:.0} usec, max {:.0} usec)", jitter.std_dev, jitter.max_deviation);
+                }
+                lines.push(line);
             }
-            MidiMessage::Stop => {
-                if !self.show_time { return; }
-                self.print_tp(timestamp, in_port);
-                print!("Stop");
+            return lines;
+        }
+        if let MidiMessage::QuarterFrame{..} = m {
+            // Quarter frames are shown as the assembled timecode
+            if let Some(timecode) = new_mtc {
+                lines.push(format!("{} MTC {} ({})", self.format_tp(timestamp, in_port), timecode, timecode.rate.name()));
             }
-            MidiMessage::ActiveSensing => {
-                if !self.show_time { return; }
-                self.print_tp(timestamp, in_port);
-                print!("ActiveSensing");
+            return lines;
+        }
+
+        if self.options.collapse_cc {
+            if let (MidiMessage::ControlChg{channel, controller, value}, Some(run)) = (m, self.cc_run.as_mut()) {
+                if run.channel == channel && run.controller == controller {
+                    run.value = value;
+                    run.count += 1;
+                    run.timestamp = timestamp;
+                    return lines;
+                }
             }
-            MidiMessage::Reset => {
-                if !self.show_time { return; }
-                self.print_tp(timestamp, in_port);
-                print!("Reset");
+            lines.extend(self.flush());
+            if let MidiMessage::ControlChg{channel, controller, value} = m {
+                self.cc_run = Some(CcRun{port: in_port, channel, controller, value, count: 0, timestamp});
             }
         }
-        self.print_footer();
+
+        lines.push(self.format_line(timestamp, in_port, &m, message, ""));
+        lines
+    }
+
+    /// Ends the current run of collapsed controllers. Returns the line with
+    /// its last value, if it wasn't shown yet.
+    pub fn flush(&mut self) -> Option<String> {
+        let run = self.cc_run.take().filter(|run| run.count > 0)?;
+        let last = MidiMessage::ControlChg{channel: run.channel, controller: run.controller, value: run.value};
+        let suffix = format!(" ({} repeats collapsed)", run.count);
+        Some(self.format_line(run.timestamp, run.port, &last, &[], &suffix))
+    }
+
+    /// Print a received message to stdout.
+    pub fn show_message(&mut self, timestamp: u64, in_port: usize, message: &[u8]) {
+        for line in self.format_message(timestamp, in_port, message) {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flushes_collapsed_controllers() {
+        let options = MonitorOptions{collapse_cc: true, ..MonitorOptions::default()};
+        let mut display = Display::new(&COLORS_BW, options, "keys");
+        assert_eq!(display.format_message(0, 0, &[0xB0, 7, 10]).len(), 1);
+        assert!(display.format_message(1000, 0, &[0xB0, 7, 20]).is_empty());
+        assert!(display.format_message(2000, 0, &[0xB0, 7, 30]).is_empty());
+
+        let line = display.flush().unwrap();
+        assert!(line.contains("30") && line.ends_with("(2 repeats collapsed)"), "{}", line);
+        assert_eq!(display.flush(), None);
+
+        // A single message isn't repeated
+        assert_eq!(display.format_message(3000, 0, &[0xB0, 7, 40]).len(), 1);
+        assert_eq!(display.flush(), None);
+    }
+
+    #[test]
+    fn writes_sysex_as_json() {
+        let options = MonitorOptions{format: OutputFormat::Json, ..MonitorOptions::default()};
+        let mut display = Display::new(&COLORS_BW, options, "keys");
+        let lines = display.format_message(1500, 2, &[0xF0, 0x43, 0x10, 0x4C, 0xF7]);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("{\"timestamp\":1500,\"port\":2,\"port_name\":\"keys\","), "{}", lines[0]);
+        assert!(lines[0].ends_with("\"bytes\":[240,67,16,76,247]}"), "{}", lines[0]);
+        assert!(display.format_message(1600, 2, &[0xF8]).is_empty());
+    }
+
+    #[test]
+    fn shows_mtc_without_real_time() {
+        let mut display = Display::new(&COLORS_BW, MonitorOptions::default(), "keys");
+        let full_frame = [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 2, 3, 4, 0xF7];
+        let lines = display.format_message(0, 0, &full_frame);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("01:02:03:04 (25 fps)"), "{}", lines[0]);
+
+        // A cycle of quarter frames for 01:02:03:10, which is shown as it jumps
+        let values = [10, 0, 3, 0, 2, 0, 1, 2];
+        let mut lines = vec!();
+        for (piece, value) in values.iter().enumerate() {
+            lines.extend(display.format_message(1000, 0, &[0xF1, (piece as u8) << 4 | value]));
+        }
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("MTC 01:02:03:12 (25 fps)"), "{}", lines[0]);
+
+        // Real-time messages are hidden
+        assert!(display.format_message(2000, 0, &[0xF8]).is_empty());
+        assert!(display.format_message(2000, 0, &[0xFE]).is_empty());
     }
 }
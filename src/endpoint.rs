@@ -0,0 +1,199 @@
+//! Endpoints besides the system MIDI ports.
+//!
+//! Routes address ports by number. Endpoints like network connections are
+//! given by a spec (e.g. "udp:0.0.0.0:5004") instead, and added to an
+//! Endpoints backend, which numbers them after the ports of the system
+//! backend. Several routes can read from the same endpoint, the endpoint is
+//! only opened once and its messages are passed to all of them. An input
+//! and an output with the same spec share the endpoint, so both directions
+//! of an RTP-MIDI session use the same session.
+
+use super::backend::{Backend, InputCallback, InputConnection, Output};
+use super::net::NetEndpoint;
+use super::osc::OscEndpoint;
+use super::pipe::PipeEndpoint;
+use super::rtpmidi::RtpEndpoint;
+use super::serial::SerialEndpoint;
+
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A route source or destination besides the system ports.
+pub trait Endpoint: Send + Sync {
+    /// The spec of the endpoint, used as port name.
+    fn name(&self) -> String;
+
+    /// Call callback for every received message.
+    fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>>;
+
+    /// Open the endpoint for sending.
+    fn connect_output(&self) -> Result<Output, Box<dyn Error>>;
+}
+
+/// Create the endpoint for a spec. Known types are "udp:host:port",
+/// "tcp:host:port", "rtpmidi:host:port", "rtpmidi:port", "osc:host:port",
+/// "serial:path[:baud]", "-[:format]" and "fifo:path[:format]".
+pub fn parse(spec: &str) -> Result<Arc<dyn Endpoint>, String> {
+    if let Some(endpoint) = NetEndpoint::parse(spec) {
+        return Ok(Arc::new(endpoint?));
+    }
+    if let Some(endpoint) = RtpEndpoint::parse(spec) {
+        return Ok(Arc::new(endpoint?));
+    }
+    if let Some(endpoint) = OscEndpoint::parse(spec) {
+        return Ok(Arc::new(endpoint?));
+    }
+    if let Some(endpoint) = SerialEndpoint::parse(spec) {
+        return Ok(Arc::new(endpoint?));
+    }
+    if let Some(endpoint) = PipeEndpoint::parse(spec) {
+        return Ok(Arc::new(endpoint?));
+    }
+    Err(format!("Unknown port '{}'", spec))
+}
+
+type Callbacks = Arc<Mutex<Vec<(u64, InputCallback)>>>;
+
+/// An input endpoint shared by several routes.
+struct SharedInput {
+    endpoint: Arc<dyn Endpoint>,
+    callbacks: Callbacks,
+    connection: Arc<Mutex<Option<Box<dyn InputConnection>>>>,
+}
+
+struct SharedConnection {
+    id: u64,
+    callbacks: Callbacks,
+    connection: Arc<Mutex<Option<Box<dyn InputConnection>>>>,
+}
+
+impl InputConnection for SharedConnection {}
+
+impl Drop for SharedConnection {
+    fn drop(&mut self) {
+        let mut callbacks = self.callbacks.lock().unwrap();
+        callbacks.retain(|(id, _)| *id != self.id);
+        if callbacks.is_empty() {
+            // Close the endpoint with the last route
+            drop(callbacks);
+            self.connection.lock().unwrap().take();
+        }
+    }
+}
+
+/// A backend with additional endpoints.
+pub struct Endpoints<B: Backend> {
+    backend: B,
+    inputs: Vec<SharedInput>,
+    outputs: Vec<Arc<dyn Endpoint>>,
+    known: Vec<Arc<dyn Endpoint>>, // Added or parsed so far, by name
+    next_id: AtomicU64,
+}
+
+impl<B: Backend> Endpoints<B> {
+    pub fn new(backend: B) -> Self {
+        Endpoints{backend, inputs: vec!(), outputs: vec!(), known: vec!(), next_id: AtomicU64::new(0)}
+    }
+
+    /// Add a configured endpoint, which is used for ports with its name
+    /// instead of parsing the spec.
+    pub fn add(&mut self, endpoint: Arc<dyn Endpoint>) {
+        self.known.retain(|e| e.name() != endpoint.name());
+        self.known.push(endpoint);
+    }
+
+    fn endpoint(&mut self, spec: &str) -> Result<Arc<dyn Endpoint>, String> {
+        if let Some(endpoint) = self.known.iter().find(|e| e.name() == spec) {
+            return Ok(endpoint.clone());
+        }
+        let endpoint = parse(spec)?;
+        self.known.push(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    /// Returns the input port number of a port given as number or endpoint
+    /// spec. New endpoints are added.
+    pub fn input_port(&mut self, spec: &str) -> Result<usize, Box<dyn Error>> {
+        let spec = spec.trim();
+        if let Ok(port) = spec.parse() {
+            return Ok(port);
+        }
+        let ports = self.backend.input_ports()?.len();
+        if let Some(i) = self.inputs.iter().position(|i| i.endpoint.name() == spec) {
+            return Ok(ports + i);
+        }
+        let endpoint = self.endpoint(spec)?;
+        self.inputs.push(SharedInput{
+            endpoint,
+            callbacks: Arc::new(Mutex::new(vec!())),
+            connection: Arc::new(Mutex::new(None)),
+        });
+        Ok(ports + self.inputs.len() - 1)
+    }
+
+    /// Returns the output port number of a port given as number or endpoint
+    /// spec. New endpoints are added.
+    pub fn output_port(&mut self, spec: &str) -> Result<usize, Box<dyn Error>> {
+        let spec = spec.trim();
+        if let Ok(port) = spec.parse() {
+            return Ok(port);
+        }
+        let ports = self.backend.output_ports()?.len();
+        if let Some(i) = self.outputs.iter().position(|e| e.name() == spec) {
+            return Ok(ports + i);
+        }
+        let endpoint = self.endpoint(spec)?;
+        self.outputs.push(endpoint);
+        Ok(ports + self.outputs.len() - 1)
+    }
+}
+
+impl<B: Backend> Backend for Endpoints<B> {
+    fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut names = self.backend.input_ports()?;
+        names.extend(self.inputs.iter().map(|i| i.endpoint.name()));
+        Ok(names)
+    }
+
+    fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut names = self.backend.output_ports()?;
+        names.extend(self.outputs.iter().map(|e| e.name()));
+        Ok(names)
+    }
+
+    fn connect_input(&self, port: usize, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let ports = self.backend.input_ports()?.len();
+        if port < ports {
+            return self.backend.connect_input(port, callback);
+        }
+        let input = self.inputs.get(port - ports).ok_or("Invalid port number")?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        input.callbacks.lock().unwrap().push((id, callback));
+        let mut connection = input.connection.lock().unwrap();
+        if connection.is_none() {
+            let callbacks = input.callbacks.clone();
+            let result = input.endpoint.connect_input(Box::new(move |timestamp, message| {
+                for (_, callback) in callbacks.lock().unwrap().iter_mut() {
+                    callback(timestamp, message);
+                }
+            }));
+            match result {
+                Ok(c) => *connection = Some(c),
+                Err(err) => {
+                    input.callbacks.lock().unwrap().retain(|(i, _)| *i != id);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(Box::new(SharedConnection{id, callbacks: input.callbacks.clone(), connection: input.connection.clone()}))
+    }
+
+    fn connect_output(&self, port: usize) -> Result<Output, Box<dyn Error>> {
+        let ports = self.backend.output_ports()?.len();
+        if port < ports {
+            return self.backend.connect_output(port);
+        }
+        self.outputs.get(port - ports).ok_or("Invalid port number")?.connect_output()
+    }
+}
@@ -0,0 +1,233 @@
+//! Filter expressions for the monitor.
+//!
+//! A filter consists of whitespace-separated terms, all of which must match
+//! for a message to be shown. Each term has the form `key=values` or
+//! `key!=values`, with a comma-separated list of values or ranges:
+//!
+//! * `type=note,cc` - message types (see TYPES)
+//! * `port=1`       - input port
+//! * `ch=1-4,10`    - MIDI channel (1 - 16)
+//! * `cc=1,7,64`    - controller number, including channel mode messages
+//! * `note=C3-C5`   - note number or name of note and key aftertouch messages
+//!
+//! `key=values` only passes messages that have the field with one of the
+//! given values, `key!=values` drops messages that have one of the values.
+
+use super::midi::{parse_note_name, MidiMessage};
+
+/// Known message type names for the `type` key.
+const TYPES: [&str; 20] = ["note", "noteon", "noteoff", "keyat", "cc", "mode", "program", "chanat",
+                           "pitchbend", "songpos", "mtc", "sysex", "realtime", "clock", "start", "continue",
+                           "stop", "sensing", "reset", "channel"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Type,
+    Port,
+    Channel,
+    Controller,
+    Note,
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    field: Field,
+    negate: bool,
+    types: Vec<&'static str>,
+    ranges: Vec<(usize, usize)>,
+}
+
+/// A parsed filter expression.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    terms: Vec<Term>,
+    expression: String,
+}
+
+fn has_type(m: &MidiMessage, name: &str) -> bool {
+    match name {
+        "note" => matches!(m, MidiMessage::NoteOn{..} | MidiMessage::NoteOff{..}),
+        "noteon" => matches!(m, MidiMessage::NoteOn{..}),
+        "noteoff" => matches!(m, MidiMessage::NoteOff{..}),
+        "keyat" => matches!(m, MidiMessage::KeyAT{..}),
+        "cc" => matches!(m, MidiMessage::ControlChg{..}),
+        "mode" => matches!(m, MidiMessage::ChannelMode{..}),
+        "program" => matches!(m, MidiMessage::ProgramChg{..}),
+        "chanat" => matches!(m, MidiMessage::ChannelAT{..}),
+        "pitchbend" => matches!(m, MidiMessage::Pitchbend{..}),
+        "songpos" => matches!(m, MidiMessage::SongPos{..}),
+        "mtc" => matches!(m, MidiMessage::QuarterFrame{..} | MidiMessage::MtcFullFrame{..}),
+        "sysex" => matches!(m, MidiMessage::MtcFullFrame{..} | MidiMessage::Other{status: 0xF0}),
+        "realtime" => matches!(m, MidiMessage::TimingClock | MidiMessage::Start | MidiMessage::Continue
+                                  | MidiMessage::Stop | MidiMessage::ActiveSensing | MidiMessage::Reset),
+        "clock" => matches!(m, MidiMessage::TimingClock),
+        "start" => matches!(m, MidiMessage::Start),
+        "continue" => matches!(m, MidiMessage::Continue),
+        "stop" => matches!(m, MidiMessage::Stop),
+        "sensing" => matches!(m, MidiMessage::ActiveSensing),
+        "reset" => matches!(m, MidiMessage::Reset),
+        "channel" => m.channel().is_some(),
+        _ => false,
+    }
+}
+
+/// Parse a single number, or a note name for note fields.
+fn parse_value(field: Field, value: &str) -> Option<usize> {
+    if let Ok(v) = value.parse::<usize>() {
+        return Some(v);
+    }
+    if field == Field::Note {
+        return parse_note_name(value).map(|v| v as usize);
+    }
+    None
+}
+
+/// Parse a value or a range of values. Since note names can contain a minus
+/// sign (C-1), every dash is tried as range separator.
+fn parse_range(field: Field, value: &str) -> Option<(usize, usize)> {
+    if let Some(v) = parse_value(field, value) {
+        return Some((v, v));
+    }
+    for (i, _) in value.match_indices('-') {
+        if let (Some(from), Some(to)) = (parse_value(field, &value[..i]), parse_value(field, &value[i + 1..])) {
+            return Some((from.min(to), from.max(to)));
+        }
+    }
+    None
+}
+
+impl Term {
+    fn parse(term: &str) -> Result<Term, String> {
+        let (key, negate, values) = if let Some(pos) = term.find("!=") {
+            (&term[..pos], true, &term[pos + 2..])
+        } else if let Some(pos) = term.find('=') {
+            (&term[..pos], false, &term[pos + 1..])
+        } else {
+            return Err(format!("Missing '=' in filter term '{}'", term));
+        };
+        let field = match key {
+            "type" => Field::Type,
+            "port" => Field::Port,
+            "ch" | "channel" => Field::Channel,
+            "cc" => Field::Controller,
+            "note" => Field::Note,
+            _ => return Err(format!("Unknown filter key '{}'", key)),
+        };
+        let mut t = Term{field, negate, types: vec!(), ranges: vec!()};
+        for value in values.split(',').filter(|v| !v.is_empty()) {
+            if field == Field::Type {
+                let name = TYPES.iter().find(|n| **n == value.to_lowercase())
+                                .ok_or(format!("Unknown message type '{}'", value))?;
+                t.types.push(name);
+            } else {
+                let range = parse_range(field, value)
+                                .ok_or(format!("Invalid value '{}' for '{}'", value, key))?;
+                t.ranges.push(range);
+            }
+        }
+        if t.types.is_empty() && t.ranges.is_empty() {
+            return Err(format!("No values given for '{}'", key));
+        }
+        Ok(t)
+    }
+
+    fn value(&self, port: usize, m: &MidiMessage) -> Option<usize> {
+        match (self.field, *m) {
+            (Field::Port, _) => Some(port),
+            (Field::Channel, _) => m.channel().map(|c| c as usize + 1),
+            (Field::Controller, MidiMessage::ControlChg{controller, ..}) => Some(controller as usize),
+            (Field::Controller, MidiMessage::ChannelMode{mode, ..}) => Some(mode.to_controller().0 as usize),
+            (Field::Note, MidiMessage::NoteOn{key, ..})
+            | (Field::Note, MidiMessage::NoteOff{key, ..})
+            | (Field::Note, MidiMessage::KeyAT{key, ..}) => Some(key as usize),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, port: usize, m: &MidiMessage) -> bool {
+        let found = if self.field == Field::Type {
+            self.types.iter().any(|t| has_type(m, t))
+        } else {
+            match self.value(port, m) {
+                Some(v) => self.ranges.iter().any(|(from, to)| v >= *from && v <= *to),
+                None => false,
+            }
+        };
+        found != self.negate
+    }
+}
+
+impl Filter {
+    /// Parse a filter expression. An empty expression passes all messages.
+    pub fn parse(expression: &str) -> Result<Filter, String> {
+        let terms = expression.split_whitespace()
+                              .map(Term::parse)
+                              .collect::<Result<Vec<Term>, String>>()?;
+        Ok(Filter{terms, expression: expression.trim().to_string()})
+    }
+
+    /// The expression this filter was created from.
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    /// True if a message received on port passes the filter.
+    pub fn matches(&self, port: usize, m: &MidiMessage) -> bool {
+        self.terms.iter().all(|t| t.matches(port, m))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(channel: u8, key: u8) -> MidiMessage {
+        MidiMessage::NoteOn{channel, key, velocity: 100}
+    }
+
+    #[test]
+    fn empty_filter_passes_all() {
+        let f = Filter::parse("").unwrap();
+        assert!(f.matches(0, &note_on(0, 60)));
+        assert!(f.matches(3, &MidiMessage::TimingClock));
+    }
+
+    #[test]
+    fn all_terms_must_match() {
+        let f = Filter::parse("ch=1-4 type=note,cc").unwrap();
+        assert!(f.matches(0, &note_on(3, 60)));
+        assert!(!f.matches(0, &note_on(4, 60)));
+        assert!(!f.matches(0, &MidiMessage::ProgramChg{channel: 0, program: 1}));
+        // System messages have no channel
+        assert!(!f.matches(0, &MidiMessage::TimingClock));
+    }
+
+    #[test]
+    fn negated_terms_drop_matches() {
+        let f = Filter::parse("type!=realtime port!=2").unwrap();
+        assert!(f.matches(0, &note_on(0, 60)));
+        assert!(!f.matches(0, &MidiMessage::TimingClock));
+        assert!(!f.matches(2, &note_on(0, 60)));
+    }
+
+    #[test]
+    fn notes_by_name_and_controllers() {
+        let f = Filter::parse("note=C-1-B-1,C4").unwrap();
+        assert!(f.matches(0, &note_on(0, 11)));
+        assert!(f.matches(0, &note_on(0, 60)));
+        assert!(!f.matches(0, &note_on(0, 61)));
+        let f = Filter::parse("cc=7,121").unwrap();
+        assert!(f.matches(0, &MidiMessage::parse(&[0xB0, 7, 100])));
+        assert!(f.matches(0, &MidiMessage::parse(&[0xB0, 121, 0])));
+        assert!(!f.matches(0, &MidiMessage::parse(&[0xB0, 1, 0])));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        assert!(Filter::parse("ch").is_err());
+        assert!(Filter::parse("foo=1").is_err());
+        assert!(Filter::parse("type=bar").is_err());
+        assert!(Filter::parse("note=X9").is_err());
+        assert!(Filter::parse("ch=").is_err());
+    }
+}
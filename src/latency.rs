@@ -0,0 +1,274 @@
+//! Loopback latency and jitter measurement.
+//!
+//! Probe messages with a sequence number are sent to an output port, which is
+//! connected (by cable or software) to an input port. The receive times are
+//! taken from the midir timestamps, which are captured by the driver and not
+//! affected by the scheduling of the callback. Since the timestamps have an
+//! unknown origin, they are mapped to the send clock with the smallest
+//! difference between the callback time and the timestamp seen during the
+//! test.
+
+use super::avg::Stats;
+use super::backend::Backend;
+
+use std::error::Error;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NOTE_CHANNEL: u8 = 15;
+const SYSEX_ID: u8 = 0x7D; // Non-commercial
+const TIMEOUT: Duration = Duration::from_secs(1); // Time to wait for the last probes
+const BUCKETS: u64 = 10;
+const BAR_WIDTH: usize = 40;
+
+/// Type of the probe messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Probe {
+    Note,  // Note on channel 16, the key carries the sequence number modulo 128
+    SysEx, // SysEx with the complete sequence number
+}
+
+impl Probe {
+    /// Parse a probe name as given on the command line.
+    pub fn parse(name: &str) -> Option<Probe> {
+        match name {
+            "note" => Some(Probe::Note),
+            "sysex" => Some(Probe::SysEx),
+            _ => None,
+        }
+    }
+
+    fn encode(self, seq: u32) -> Vec<u8> {
+        match self {
+            Probe::Note => vec!(0x90 | NOTE_CHANNEL, (seq % 128) as u8, 64),
+            Probe::SysEx => vec!(0xF0, SYSEX_ID, (seq >> 21) as u8 & 0x7F, (seq >> 14) as u8 & 0x7F,
+                                 (seq >> 7) as u8 & 0x7F, seq as u8 & 0x7F, 0xF7),
+        }
+    }
+
+    /// Returns the (possibly partial) sequence number of a probe.
+    fn decode(self, message: &[u8]) -> Option<u32> {
+        match (self, message) {
+            (Probe::Note, [status, key, velocity]) if *status == 0x90 | NOTE_CHANNEL && *velocity > 0 => Some(*key as u32),
+            (Probe::SysEx, [0xF0, SYSEX_ID, a, b, c, d, 0xF7]) => {
+                Some((*a as u32) << 21 | (*b as u32) << 14 | (*c as u32) << 7 | *d as u32)
+            }
+            _ => None,
+        }
+    }
+
+    /// The note off following a note probe.
+    fn release(self, seq: u32) -> Option<Vec<u8>> {
+        match self {
+            Probe::Note => Some(vec!(0x80 | NOTE_CHANNEL, (seq % 128) as u8, 0)),
+            Probe::SysEx => None,
+        }
+    }
+}
+
+/// A probe that came back.
+struct Received {
+    seq: u32,
+    timestamp: u64, // midir timestamp in usec
+    arrival: Instant,
+}
+
+/// Round-trip times of a test run.
+pub struct LatencyReport {
+    sent: usize,
+    latencies: Vec<u64>, // usec
+    unexpected: usize,   // Probes that couldn't be matched
+}
+
+/// Send count probes with the given interval and collect the replies.
+pub fn run_test(backend: &dyn Backend, in_port: usize, out_port: usize,
+                probe: Probe, count: usize, interval: Duration) -> Result<LatencyReport, Box<dyn Error>> {
+    let mut conn_out = backend.connect_output(out_port)?;
+    let (tx, rx) = channel();
+    let conn_in = backend.connect_input(in_port, Box::new(move |timestamp, message| {
+        let arrival = Instant::now();
+        if let Some(seq) = probe.decode(message) {
+            tx.send(Received{seq, timestamp, arrival}).ok();
+        }
+    }))?;
+
+    let base = Instant::now();
+    let mut sent_at = Vec::with_capacity(count);
+    let mut received = vec!();
+    for seq in 0..count as u32 {
+        let time = base + interval * seq;
+        let now = Instant::now();
+        if time > now {
+            thread::sleep(time - now);
+        }
+        sent_at.push(Instant::now());
+        conn_out.send(&probe.encode(seq))?;
+        if let Some(release) = probe.release(seq) {
+            conn_out.send(&release)?;
+        }
+        received.extend(rx.try_iter());
+    }
+    let deadline = Instant::now() + TIMEOUT;
+    while received.len() < count {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok(r) => received.push(r),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    drop(conn_in);
+
+    Ok(match_probes(probe, base, &sent_at, &received))
+}
+
+/// Match the received probes with the sent ones and calculate the round-trip
+/// times.
+fn match_probes(probe: Probe, base: Instant, sent_at: &[Instant], received: &[Received]) -> LatencyReport {
+    let since_base = |t: Instant| t.duration_since(base).as_micros() as i64;
+    // Offset from midir timestamps to the send clock
+    let offset = received.iter()
+                         .map(|r| since_base(r.arrival) - r.timestamp as i64)
+                         .min()
+                         .unwrap_or(0);
+    let mut done = vec!(false; sent_at.len());
+    let mut latencies = vec!();
+    let mut unexpected = 0;
+    for r in received {
+        let received = r.timestamp as i64 + offset;
+        // Note probes are ambiguous, use the latest open probe with that key
+        // sent before the reply
+        let seq = match probe {
+            Probe::Note => (r.seq as usize..sent_at.len()).step_by(128)
+                                                         .rfind(|s| !done[*s] && since_base(sent_at[*s]) <= received),
+            Probe::SysEx => Some(r.seq as usize).filter(|s| *s < sent_at.len() && !done[*s]),
+        };
+        match seq {
+            Some(seq) => {
+                done[seq] = true;
+                latencies.push((received - since_base(sent_at[seq])).max(0) as u64);
+            }
+            None => unexpected += 1,
+        }
+    }
+    LatencyReport{sent: sent_at.len(), latencies, unexpected}
+}
+
+impl LatencyReport {
+    /// Statistics and histogram of the round-trip times.
+    pub fn summary(&self) -> Vec<String> {
+        let lost = self.sent - self.latencies.len();
+        let mut lines = vec!(format!("Probes: {} sent, {} received, {} lost", self.sent, self.latencies.len(), lost));
+        if self.unexpected > 0 {
+            lines.push(format!("Unexpected replies: {}", self.unexpected));
+        }
+        let mut stats = Stats::new(self.latencies.len());
+        for latency in self.latencies.iter() {
+            stats.add_value(*latency);
+        }
+        let (min, max) = match (stats.min(), stats.max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return lines,
+        };
+        lines.push(format!("Round trip: min {} usec, average {:.0} usec, max {} usec",
+                           min, stats.mean().unwrap_or(0.0), max));
+        lines.push(format!("Percentiles: 50% {:.0} usec, 95% {:.0} usec, 99% {:.0} usec",
+                           stats.median().unwrap_or(0.0), stats.percentile(95.0).unwrap_or(0.0),
+                           stats.percentile(99.0).unwrap_or(0.0)));
+        lines.push(format!("Jitter: std-dev {:.0} usec, max deviation {:.0} usec",
+                           stats.std_dev().unwrap_or(0.0), stats.max_deviation().unwrap_or(0.0)));
+        lines.push("Histogram:".to_string());
+        lines.extend(self.histogram(min, max));
+        lines
+    }
+
+    fn histogram(&self, min: u64, max: u64) -> Vec<String> {
+        let width = ((max - min) / BUCKETS + 1).max(1);
+        let mut buckets = vec!(0usize; ((max - min) / width + 1) as usize);
+        for latency in self.latencies.iter() {
+            buckets[((latency - min) / width) as usize] += 1;
+        }
+        let largest = buckets.iter().copied().max().unwrap_or(1).max(1);
+        buckets.iter().enumerate().map(|(i, n)| {
+            let from = min + i as u64 * width;
+            let bar = "#".repeat((n * BAR_WIDTH).div_ceil(largest));
+            format!("  {:>7} - {:>7} usec | {:<w$} {}", from, from + width - 1, bar, n, w = BAR_WIDTH)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A probe received latency usec after it was sent, with the callback
+    /// running delay usec later.
+    fn reply(base: Instant, sent_at: &[Instant], seq: u32, sent: usize, latency: u64, delay: u64) -> Received {
+        let received = sent_at[sent] + Duration::from_micros(latency);
+        Received{seq, timestamp: 5000000 + received.duration_since(base).as_micros() as u64,
+                 arrival: received + Duration::from_micros(delay)}
+    }
+
+    #[test]
+    fn encodes_probes() {
+        for &seq in [0u32, 127, 128, 1 << 20, (1 << 28) - 1].iter() {
+            assert_eq!(Probe::SysEx.decode(&Probe::SysEx.encode(seq)), Some(seq));
+            assert_eq!(Probe::Note.decode(&Probe::Note.encode(seq)), Some(seq % 128));
+        }
+        assert_eq!(Probe::Note.decode(&Probe::Note.release(5).unwrap()), None);
+        assert_eq!(Probe::Note.decode(&[0x90, 60, 100]), None);
+        assert_eq!(Probe::SysEx.release(5), None);
+    }
+
+    #[test]
+    fn matches_sysex_probes() {
+        let base = Instant::now();
+        let sent_at: Vec<Instant> = (0..3).map(|i| base + Duration::from_millis(10 * i)).collect();
+        // The timestamps are mapped by the reply with the shortest callback delay
+        let received = vec!(reply(base, &sent_at, 0, 0, 1000, 0), reply(base, &sent_at, 1, 1, 2000, 500),
+                            reply(base, &sent_at, 0, 1, 300, 0));
+        let report = match_probes(Probe::SysEx, base, &sent_at, &received);
+        assert_eq!(report.sent, 3);
+        assert_eq!(report.latencies, vec!(1000, 2000));
+        assert_eq!(report.unexpected, 1);
+    }
+
+    #[test]
+    fn matches_note_probes() {
+        let base = Instant::now();
+        let sent_at: Vec<Instant> = (0..129).map(|i| base + Duration::from_millis(i)).collect();
+        // Key 0 is used by probe 0 and 128
+        let received = vec!(reply(base, &sent_at, 0, 0, 500, 0), reply(base, &sent_at, 0, 128, 700, 10));
+        let report = match_probes(Probe::Note, base, &sent_at, &received);
+        assert_eq!(report.latencies, vec!(500, 700));
+        assert_eq!(report.unexpected, 0);
+    }
+
+    #[test]
+    fn summarizes_latencies() {
+        let report = LatencyReport{sent: 5, latencies: vec!(100, 450, 120, 130), unexpected: 2};
+        let summary = report.summary();
+        assert_eq!(summary[..5], ["Probes: 5 sent, 4 received, 1 lost", "Unexpected replies: 2",
+                                  "Round trip: min 100 usec, average 200 usec, max 450 usec",
+                                  "Percentiles: 50% 125 usec, 95% 402 usec, 99% 440 usec",
+                                  "Jitter: std-dev 145 usec, max deviation 250 usec"]);
+        // 10 buckets of 36 usec
+        let histogram = &summary[6..];
+        assert_eq!(histogram.len(), 10);
+        assert_eq!(histogram[0], format!("      100 -     135 usec | {} 3", "#".repeat(BAR_WIDTH)));
+        assert_eq!(histogram[1], format!("      136 -     171 usec | {} 0", " ".repeat(BAR_WIDTH)));
+        assert_eq!(histogram[9], format!("      424 -     459 usec | {:<40} 1", "#".repeat(14)));
+    }
+
+    #[test]
+    fn summarizes_lost_probes() {
+        let report = LatencyReport{sent: 3, latencies: vec!(), unexpected: 0};
+        assert_eq!(report.summary(), vec!("Probes: 3 sent, 0 received, 3 lost"));
+        // All latencies in one bucket
+        let report = LatencyReport{sent: 2, latencies: vec!(250, 250), unexpected: 0};
+        assert_eq!(report.summary().last().unwrap(), &format!("      250 -     250 usec | {} 2", "#".repeat(BAR_WIDTH)));
+    }
+}
@@ -0,0 +1,80 @@
+//! MIDI Toolbox: A little helper for terminal MIDI handling
+//!
+//! The library behind the `miditool` binary, for tools that need the same
+//! message handling:
+//!
+//! * [`midi`]: parsing and encoding of MIDI messages ([`MidiMessage`])
+//! * [`router`]: the routing engine, forwarding between ports with the
+//!   [`chord`], [`arpeggiator`], [`clocktransform`] and [`rpn`] parameter
+//!   mapping applied
+//! * [`session`]: routes together with monitoring, recording and the
+//!   terminal UI, as run by the binary
+//! * [`control`]: changing the routes while running, through a control
+//!   socket
+//! * [`backend`]: access to the MIDI ports, through midir or in memory
+//! * [`endpoint`], [`net`]: routes to and from other endpoints, like other
+//!   miditool instances over UDP or TCP
+//! * [`rtpmidi`], [`rtpjournal`]: RTP-MIDI (AppleMIDI) sessions
+//! * [`osc`]: conversion between MIDI and Open Sound Control
+//! * [`serial`]: DIN MIDI over serial ports
+//! * [`pipe`]: messages through stdin, stdout and named pipes
+//! * [`stream`]: splitting byte streams into messages and back
+//! * [`filter`]: filter expressions like "ch=1-4 type=note,cc"
+//! * [`display`]: text, JSON and CSV formatting of messages
+//! * [`recording`]: recording formats and playback
+//! * [`clock`], [`mtc`]: MIDI clock and MIDI Time Code generators
+//! * [`traffic`], [`latency`]: statistics and loopback latency tests
+//! * [`avg`]: moving window statistics and exponential moving averages
+//! * [`web`]: streaming to browsers over WebSocket
+//!
+//! Routing a port with the in-memory backend:
+//!
+//! ```
+//! use miditool::backend::mock::MockBackend;
+//! use miditool::router::{Config, Router, Sinks};
+//! use miditool::traffic::Traffic;
+//!
+//! let backend = MockBackend::new(&["keyboard"], &["synth"]);
+//! let config = Config{in_port: 0, out_port: 0, out_channel: 3, ..Config::default()};
+//! let router = Router::start(&backend, &[config], &Sinks::default(), &Traffic::new()).unwrap();
+//! backend.receive(0, 0, &[0x90, 60, 100]);
+//! assert_eq!(backend.sent(0), vec!(vec!(0x92, 60, 100)));
+//! router.close();
+//! ```
+//!
+//! TODO:
+//! * Send a MIDI file to a device
+
+pub mod arpeggiator;
+pub mod avg;
+pub mod backend;
+pub mod ccnames;
+pub mod chord;
+pub mod clock;
+pub mod clocktransform;
+pub mod control;
+pub mod display;
+pub mod endpoint;
+pub mod filter;
+pub mod latency;
+pub mod midi;
+pub mod mtc;
+pub mod net;
+pub mod osc;
+pub mod pipe;
+pub mod recording;
+pub mod router;
+pub mod rpn;
+pub mod rtpjournal;
+pub mod rtpmidi;
+pub mod scheduler;
+pub mod session;
+pub mod serial;
+pub mod stream;
+pub mod tempo;
+pub mod traffic;
+pub mod tui;
+pub mod web;
+pub mod worker;
+
+pub use midi::MidiMessage;
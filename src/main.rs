@@ -4,50 +4,57 @@
 //! * Forward MIDI data between different ports
 //! * Transform MIDI data (e.g. change the channel)
 //! * Monitor the received data
-//! * Log the received data to a file
+//! * Log the received data to a file and play it back
+//! * Show the received data in an interactive terminal UI
+//! * Generate MIDI clock
+//! * Generate and decode MIDI Time Code
+//! * Measure the round-trip latency of a MIDI loopback
+//! * Watch the received data from a browser
+//! * Change the routes while running through a control socket
 //!
-//! TODO:
-//! * Replay a previously captured file
-//! * Send a MIDI file to a device
-
-mod avg;
-use avg::Avg;
-
-mod display;
-use display::{Display, Colors, COLORS_BW, COLORS_TC};
-
-mod midi;
-use midi::MidiMessage;
+//! The command line interface of the miditool library.
+
+use miditool::arpeggiator::ArpOptions;
+use miditool::backend::{Backend, MidirBackend};
+use miditool::ccnames::CcNames;
+use miditool::chord::ChordOptions;
+use miditool::clock::{self, ClockCommand, ClockGenerator};
+use miditool::clocktransform::ClockOptions;
+use miditool::control::{ControlClient, ControlServer};
+use miditool::endpoint::Endpoints;
+use miditool::display::{MonitorOptions, OutputFormat, TimeFormat, COLORS_BW, COLORS_TC, CSV_HEADER};
+use miditool::filter::Filter;
+use miditool::latency::{self, Probe};
+use miditool::mtc::{FrameRate, MtcGenerator, Timecode};
+use miditool::osc::{Mapping, OscEndpoint};
+use miditool::pipe;
+use miditool::recording::{self, RecordFormat};
+use miditool::router::Config;
+use miditool::rpn;
+use miditool::session::{Services, Session, SessionOptions};
+use miditool::traffic::Traffic;
+use miditool::tui::{self, Tui};
+use miditool::web::WebServer;
 
 extern crate clap;
-use clap::{Arg, App};
-
-extern crate midir;
-use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputConnection, MidiIO, Ignore};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
 extern crate regex;
 use regex::Regex;
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::stdin;
 use std::io::prelude::*;
 use std::io::BufReader;
-
-struct Config {
-    in_port: usize,
-    in_channel: u8,
-    out_port: usize,
-    out_channel: u8,
-}
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 fn main() {
-    let mut config = Config{
-        in_port: std::usize::MAX,
-        in_channel: 0,
-        out_port: std::usize::MAX,
-        out_channel: 0,
-    };
+    let mut config = Config::default();
+    let mut backend = Endpoints::new(MidirBackend::new("MIDI Toolbox"));
 
     let matches = App::new("MIDIToolbox")
                         .version("0.2.0")
@@ -59,13 +66,20 @@ fn main() {
                         .arg(Arg::with_name("inport")
                             .short("i")
                             .long("inport")
-                            .help("Selects the MIDI port to receive MIDI events on (0 - n, default 0)")
-                            .takes_value(true))
+                            .help("Selects the MIDI port to receive MIDI events on (0 - n, default 0), or a network endpoint to listen on (udp:host:port, tcp:host:port, rtpmidi:host:port, rtpmidi:port, osc:host:port, serial:path[:baud]), or - for stdin or fifo:path for a named pipe (with :raw, :hex or :json for the format)")
+                            .takes_value(true)
+                            .allow_hyphen_values(true))
                         .arg(Arg::with_name("outport")
                             .short("o")
                             .long("outport")
-                            .help("Selects the MIDI port to send MIDI events to (0 - n, default OFF)")
-                            .takes_value(true))
+                            .help("Selects the MIDI port to send MIDI events to (0 - n, default OFF), or a network endpoint to send to (udp:host:port, tcp:host:port, rtpmidi:host:port, rtpmidi:port, osc:host:port, serial:path[:baud]), or - for stdout or fifo:path for a named pipe (with :raw, :hex or :json for the format)")
+                            .takes_value(true)
+                            .allow_hyphen_values(true))
+                        .arg(Arg::with_name("transpose")
+                            .long("transpose")
+                            .help("Transpose the forwarded notes by the given number of semitones")
+                            .takes_value(true)
+                            .allow_hyphen_values(true))
                         .arg(Arg::with_name("inchannel")
                             .short("c")
                             .long("inchannel")
@@ -85,6 +99,19 @@ fn main() {
                             .long("write")
                             .help("Record the received MIDI events to a file")
                             .takes_value(true))
+                        .arg(Arg::with_name("recordformat")
+                            .long("record-format")
+                            .help("Format of the recorded files: hex (one message per line, default) or timed (with the timestamp in usec)")
+                            .possible_values(&["hex", "timed"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("play")
+                            .long("play")
+                            .help("Play a recorded file to the output port (-o) and exit")
+                            .takes_value(true))
+                        .arg(Arg::with_name("playspeed")
+                            .long("play-speed")
+                            .help("Speed factor for playing timed recordings (default 1.0)")
+                            .takes_value(true))
                         .arg(Arg::with_name("list")
                             .short("l")
                             .long("list")
@@ -92,7 +119,7 @@ fn main() {
                         .arg(Arg::with_name("configfile")
                             .short("r")
                             .long("read")
-                            .help("Read a CSV file containing a multiplex/ demultiplex setup. Each line consists of a single entry of the form \"inport, inchannel, outport, outchannel\"")
+                            .help("Read a CSV file containing a multiplex/ demultiplex setup. Each line consists of a single entry of the form \"inport, inchannel, outport, outchannel[, clock settings]\"")
                             .takes_value(true))
                         .arg(Arg::with_name("blackwhite")
                             .short("b")
@@ -102,23 +129,239 @@ fn main() {
                             .short("t")
                             .long("show-timing")
                             .help("Show system real-time messages."))
+                        .arg(Arg::with_name("filter")
+                            .short("f")
+                            .long("filter")
+                            .help("Only show messages matching a filter expression, e.g. \"ch=1-4 type=note,cc\". Keys: type, port, ch, cc, note. Use key!=values to exclude.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("notenames")
+                            .long("note-names")
+                            .help("Show note names (C#4) instead of note numbers."))
+                        .arg(Arg::with_name("hex")
+                            .long("hex")
+                            .help("Show the raw bytes of every message."))
+                        .arg(Arg::with_name("collapse")
+                            .long("collapse-cc")
+                            .help("Collapse repeated messages of the same controller into a single line."))
+                        .arg(Arg::with_name("timestamps")
+                            .long("timestamps")
+                            .help("Timestamp format: absolute (default), relative (seconds since first message) or delta (usec since previous message)")
+                            .possible_values(&["absolute", "relative", "delta"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("format")
+                            .long("format")
+                            .help("Output format of the monitor: text (default), json (one object per line) or csv")
+                            .possible_values(&["text", "json", "csv"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("ccnames")
+                            .long("cc-names")
+                            .help("Read controller names from a file with lines of the form \"number, name\". Prefix the filename with \"port:\" to use the names for a single input port only. Can be given multiple times.")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1))
+                        .arg(Arg::with_name("params")
+                            .long("params")
+                            .help("Assemble RPN/ NRPN parameter changes and 14-bit controllers in the monitor."))
+                        .arg(Arg::with_name("mapparam")
+                            .long("map-param")
+                            .help("Change the number of an RPN/ NRPN parameter when forwarding, e.g. \"nrpn:1234=nrpn:42\". Can be given multiple times.")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1))
+                        .arg(Arg::with_name("clock")
+                            .long("clock")
+                            .help("Send MIDI clock with the given tempo (BPM) to the ports given with --clock-out. The clock can be controlled by entering commands.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("clockout")
+                            .long("clock-out")
+                            .help("Comma separated list of MIDI ports to send the clock to")
+                            .takes_value(true))
+                        .arg(Arg::with_name("swing")
+                            .long("swing")
+                            .help("Swing of the generated clock in percent (50 = straight (default) - 75)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("clockstart")
+                            .long("clock-start")
+                            .help("Send Start when the clock starts"))
+                        .arg(Arg::with_name("mtc")
+                            .long("mtc")
+                            .help("Send MIDI Time Code with the given frame rate (24, 25, 29.97 (drop frame) or 30) to the ports given with --mtc-out")
+                            .takes_value(true))
+                        .arg(Arg::with_name("mtcout")
+                            .long("mtc-out")
+                            .help("Comma separated list of MIDI ports to send MTC to")
+                            .takes_value(true))
+                        .arg(Arg::with_name("mtcstart")
+                            .long("mtc-start")
+                            .help("Start time of the MTC as hh:mm:ss:ff (default 00:00:00:00)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("mtcchase")
+                            .long("mtc-chase")
+                            .help("Follow the MIDI clock, Start, Stop, Continue and Song Position received on the given input port instead of running freely. The start time is the start of the song.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("latency")
+                            .long("latency")
+                            .help("Measure the round-trip latency by sending the given number of probe messages to the output port (-o) and receiving them on the input port (-i)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("probe")
+                            .long("probe")
+                            .help("Type of the latency probes: note (on channel 16, default) or sysex")
+                            .possible_values(&["note", "sysex"])
+                            .takes_value(true))
+                        .arg(Arg::with_name("probeinterval")
+                            .long("probe-interval")
+                            .help("Time between two latency probes in msec (default 20)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("clocktransform")
+                            .long("clock-transform")
+                            .help("Transform forwarded clock, e.g. \"div=2 delay=5\". Settings: div=n, mul=n (1 - 24), delay=msec, noclock, notransport. Used for all routes without own settings in the config file.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("arp")
+                            .long("arp")
+                            .help("Arpeggiate the forwarded notes, e.g. \"mode=updown oct=2 rate=16\". Settings: mode=up|down|updown|random|played, oct=n (1 - 4), gate=percent, rate=1|2|4|8|16|32 (note value, with t for triplets), bpm=tempo (default 120) or clock to follow the incoming clock. Used for all routes.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("chord")
+                            .long("chord")
+                            .help("Play a chord for every forwarded note, e.g. \"notes=0,4,7\" or \"degrees=0,2,4 key=D scale=minor\". Settings: notes=semitones or degrees=scale degrees (comma separated), key=note name, scale=major|minor|dorian|phrygian|lydian|mixolydian|locrian|harmonic|pentatonic, quantize (move played notes into the scale). Used for all routes.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("statsjson")
+                            .long("stats-json")
+                            .help("Write the traffic statistics of all ports and routes to a JSON file on exit")
+                            .takes_value(true))
+                        .arg(Arg::with_name("web")
+                            .long("web")
+                            .help("Stream the received MIDI events to browsers over WebSocket and serve ports, routes and statistics over HTTP. Listens on localhost with a port alone (e.g. 8080), use an address like 0.0.0.0:8080 to allow other hosts.")
+                            .takes_value(true))
+                        .arg(Arg::with_name("control")
+                            .long("control")
+                            .help("Accept commands to change the routes while running on a Unix domain socket with the given path, see the control subcommand")
+                            .takes_value(true))
+                        .arg(Arg::with_name("tui")
+                            .short("u")
+                            .long("tui")
+                            .help("Show the received data in an interactive terminal UI."))
+                        .subcommand(SubCommand::with_name("control")
+                            .about("Send commands to the control socket of a running instance. Without a command, commands are read from stdin, one per line. Commands: routes, add <inport> <inchannel> <outport> <outchannel>, remove <route>, mute <route>, unmute <route>, transpose <route> <semitones>, channel <route> <inchannel> <outchannel>, chord <route> <settings>|off, panic")
+                            .arg(Arg::with_name("socket")
+                                .help("Path of the control socket")
+                                .required(true))
+                            .arg(Arg::with_name("command")
+                                .help("The command, e.g. \"mute 1\"")
+                                .multiple(true)
+                                .allow_hyphen_values(true)))
                         .get_matches();
-    let in_port = matches.value_of("inport").unwrap_or("");
-    config.in_port = in_port.parse().unwrap_or(std::usize::MAX);
+    if let Some(matches) = matches.subcommand_matches("control") {
+        if let Err(err) = send_commands(matches) {
+            println!("Error: {}", err);
+        }
+        return;
+    }
+    // Ports are given as number or as endpoint, e.g. "udp:0.0.0.0:5004"
+    let in_port = matches.value_of("inport").map(|p| backend.input_port(p)).transpose();
+    let out_port = matches.value_of("outport").map(|p| backend.output_port(p)).transpose();
+    match (in_port, out_port) {
+        (Ok(in_port), Ok(out_port)) => {
+            config.in_port = in_port.unwrap_or(usize::MAX);
+            config.out_port = out_port.unwrap_or(usize::MAX);
+        }
+        (Err(err), _) | (_, Err(err)) => {
+            println!("Error: {}", err);
+            return;
+        }
+    }
     let in_channel = matches.value_of("inchannel").unwrap_or("0");
     config.in_channel = in_channel.parse().unwrap_or(0);
-    let out_port = matches.value_of("outport").unwrap_or("");
-    config.out_port = out_port.parse().unwrap_or(std::usize::MAX);
     let out_channel = matches.value_of("outchannel").unwrap_or("0");
     config.out_channel = out_channel.parse().unwrap_or(0);
+    config.transpose = match matches.value_of("transpose").unwrap_or("0").parse() {
+        Ok(t) => t,
+        Err(_) => {
+            println!("Error: Invalid transposition");
+            return;
+        }
+    };
     let monitor = matches.is_present("monitor");
     let list = matches.is_present("list");
-    let record = matches.is_present("write");
-    let outfile = matches.value_of("write").unwrap_or("");
-    let show_time = matches.is_present("timing");
+    let record_format = RecordFormat::parse(matches.value_of("recordformat").unwrap_or("hex"))
+                                      .unwrap_or(RecordFormat::Hex);
+    let recording = matches.value_of("write").map(|outfile| (outfile, record_format));
+    let use_tui = matches.is_present("tui");
+    let filter = match Filter::parse(matches.value_of("filter").unwrap_or("")) {
+        Ok(f) => f,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    config.clock = match ClockOptions::parse(matches.value_of("clocktransform").unwrap_or("")) {
+        Ok(c) => c,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    if let Some(settings) = matches.value_of("arp") {
+        config.arp = match ArpOptions::parse(settings) {
+            Ok(a) => Some(a),
+            Err(err) => {
+                println!("Error: {}", err);
+                return;
+            }
+        };
+    }
+    if let Some(settings) = matches.value_of("chord") {
+        config.chord = match ChordOptions::parse(settings) {
+            Ok(c) => Some(c),
+            Err(err) => {
+                println!("Error: {}", err);
+                return;
+            }
+        };
+    }
+    let format = OutputFormat::parse(matches.value_of("format").unwrap_or("text"))
+                               .unwrap_or(OutputFormat::Text);
+
+    let (cc_names, port_cc_names) = match load_cc_names(matches.values_of("ccnames").map(|v| v.collect()).unwrap_or_default()) {
+        Ok(names) => names,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    let options = MonitorOptions{
+        show_time: matches.is_present("timing"),
+        note_names: matches.is_present("notenames"),
+        show_hex: matches.is_present("hex"),
+        collapse_cc: matches.is_present("collapse"),
+        time_format: TimeFormat::parse(matches.value_of("timestamps").unwrap_or("absolute"))
+                                 .unwrap_or(TimeFormat::Absolute),
+        format,
+        filter,
+        assemble_params: matches.is_present("params"),
+        use_tui,
+        cc_names,
+        port_cc_names,
+    };
 
     if list {
-        match list_all_ports() {
+        match list_all_ports(&backend) {
+            Ok(_) => (),
+            Err(err) => println!("Error: {}", err)
+        }
+        return;
+    }
+
+    if let Some(count) = matches.value_of("latency") {
+        let probe = Probe::parse(matches.value_of("probe").unwrap_or("note")).unwrap_or(Probe::Note);
+        match measure_latency(&backend, &config, count, probe, matches.value_of("probeinterval").unwrap_or("20")) {
+            Ok(_) => (),
+            Err(err) => println!("Error: {}", err)
+        }
+        return;
+    }
+
+    if let Some(file) = matches.value_of("play") {
+        match play_file(&backend, &config, file, matches.value_of("playspeed").unwrap_or("1")) {
             Ok(_) => (),
             Err(err) => println!("Error: {}", err)
         }
@@ -126,7 +369,7 @@ fn main() {
     }
 
     // Set colors to use for output
-    let colors = if matches.is_present("blackwhite") {
+    let colors = if matches.is_present("blackwhite") || format != OutputFormat::Text {
         &COLORS_BW
     } else {
         &COLORS_TC
@@ -134,192 +377,429 @@ fn main() {
 
     let mut configs: Vec<Config> = vec!();
     if matches.is_present("configfile") {
-        let re = Regex::new(r"(\d*),(\d*),(\d*),(\d)").unwrap();
+        let re = Regex::new(r"^\s*([^,\s]+)\s*,\s*(\d+)\s*,\s*([^,\s]+)\s*,\s*(\d+)\s*(?:,(.*))?$").unwrap();
         let configfile = matches.value_of("configfile").unwrap_or("");
         let file = File::open(configfile).unwrap(); // TODO: Show error
-        let buf_reader = BufReader::new(file);
-        let lines = buf_reader.lines();
+        let lines: Vec<String> = BufReader::new(file).lines().map_while(Result::ok).collect();
+        // OSC mappings first, the routes use the configured endpoints
+        if let Err(err) = add_osc_mappings(&mut backend, &lines) {
+            println!("Error: {}", err);
+            return;
+        }
         for line in lines {
-            let line = if let Ok(l) = line { l } else { continue; };
-            let cap = re.captures(&line).unwrap();
-            if cap.len() == 5 {
+            if let Some(cap) = re.captures(&line) {
+                let clock = match cap.get(5) {
+                    Some(settings) => match ClockOptions::parse(settings.as_str()) {
+                        Ok(c) => c,
+                        Err(err) => {
+                            println!("Error: {}", err);
+                            return;
+                        }
+                    },
+                    None => config.clock,
+                };
+                let (in_port, out_port) = match (backend.input_port(&cap[1]), backend.output_port(&cap[3])) {
+                    (Ok(in_port), Ok(out_port)) => (in_port, out_port),
+                    (Err(err), _) | (_, Err(err)) => {
+                        println!("Error: {}", err);
+                        return;
+                    }
+                };
                 let c = Config{
-                    in_port: cap[1].parse().unwrap(),
-                    in_channel: cap[2].parse().unwrap(),
-                    out_port: cap[3].parse().unwrap(),
-                    out_channel: cap[4].parse().unwrap(),
+                    in_port,
+                    in_channel: cap[2].parse().unwrap_or(0),
+                    out_port,
+                    out_channel: cap[4].parse().unwrap_or(0),
+                    transpose: config.transpose,
+                    param_maps: vec!(),
+                    clock,
+                    arp: config.arp,
+                    chord: config.chord.clone(),
                 };
                 configs.push(c);
             }
         }
-    } else {
+    } else if config.in_port < usize::MAX || !(matches.is_present("clock") || matches.is_present("mtc")) {
         configs.push(config);
     }
 
-    match receive_data(&configs, monitor, record, outfile, colors, show_time) {
-        Ok(_) => (),
-        Err(err) => println!("Error: {}", err)
+    let mut param_maps = vec!();
+    for map in matches.values_of("mapparam").into_iter().flatten() {
+        let mut parts = map.splitn(2, '=');
+        match (parts.next().and_then(rpn::parse_param), parts.next().and_then(rpn::parse_param)) {
+            (Some(from), Some(to)) => param_maps.push((from, to)),
+            _ => {
+                println!("Error: Invalid parameter mapping '{}'", map);
+                return;
+            }
+        }
+    }
+    for config in configs.iter_mut() {
+        config.param_maps = param_maps.clone();
+    }
+
+    let clock = if let Some(bpm) = matches.value_of("clock") {
+        match start_clock(&backend, bpm, matches.value_of("clockout").unwrap_or(""),
+                          matches.value_of("swing").unwrap_or("50"), matches.is_present("clockstart")) {
+            Ok(c) => Some(c),
+            Err(err) => {
+                println!("Error: {}", err);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let mtc = if let Some(rate) = matches.value_of("mtc") {
+        match start_mtc(&backend, rate, matches.value_of("mtcout").unwrap_or(""),
+                        matches.value_of("mtcstart").unwrap_or("00:00:00:00"), matches.value_of("mtcchase")) {
+            Ok(g) => Some(g),
+            Err(err) => {
+                println!("Error: {}", err);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let web = if let Some(address) = matches.value_of("web") {
+        match WebServer::start(address) {
+            Ok(server) => {
+                eprintln!("Web server listening on http://{}/", server.local_addr());
+                Some(server)
+            }
+            Err(err) => {
+                println!("Error: Can't start the web server on '{}': {}", address, err);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let control = if let Some(path) = matches.value_of("control") {
+        match ControlServer::start(path) {
+            Ok(server) => Some(server),
+            Err(err) => {
+                println!("Error: Can't create the control socket '{}': {}", path, err);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let services = Services{clock, mtc, web, control};
+    let session_options = SessionOptions{monitor, recording, colors, display: &options};
+    let traffic = match receive_data(&backend, &configs, &session_options, &services) {
+        Ok(t) => t,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    if let Some(filename) = matches.value_of("statsjson") {
+        let result = File::create(filename).and_then(|mut f| writeln!(f, "{}", traffic.to_json()));
+        if let Err(err) = result {
+            println!("Error: Can't write statistics to '{}': {}", filename, err);
+        }
+    }
+}
+
+/// Add the OSC endpoints with mappings in the config file, lines of the form
+/// "osc:host:port,type,address[,range]".
+fn add_osc_mappings<B: Backend>(backend: &mut Endpoints<B>, lines: &[String]) -> Result<(), Box<dyn Error>> {
+    let re = Regex::new(r"^\s*(osc:[^,\s]+)\s*,\s*([^,\s]+)\s*,\s*(/[^,\s]*)\s*(?:,\s*([^,\s]+)\s*)?$").unwrap();
+    let mut endpoints: Vec<(String, Vec<Mapping>)> = vec!();
+    for line in lines {
+        if let Some(cap) = re.captures(line) {
+            let mapping = Mapping::parse(&cap[2], &cap[3], cap.get(4).map(|r| r.as_str()))?;
+            match endpoints.iter_mut().find(|(spec, _)| *spec == cap[1]) {
+                Some((_, mappings)) => mappings.push(mapping),
+                None => endpoints.push((cap[1].to_string(), vec!(mapping))),
+            }
+        }
     }
+    for (spec, mappings) in endpoints {
+        let mut endpoint = OscEndpoint::parse(&spec).unwrap()?;
+        endpoint.set_mappings(mappings);
+        backend.add(Arc::new(endpoint));
+    }
+    Ok(())
 }
 
 /// Receive data from a MIDI in port and optionally forward it.
 ///
 /// If no output port has been defined, the data is only read, written to file
-/// if configured, and written to stdout if configured.
-fn receive_data(configs: &[Config],
-                do_monitor: bool,
-                do_record: bool,
-                outfile: &str,
-                colors: &'static Colors,
-                show_time: bool)
-        -> Result<(), Box<dyn Error>> {
-
-    let mut conn_list = vec!();
-
+/// if configured, and written to stdout, the terminal UI or the web server if
+/// configured.
+/// Returns the traffic statistics of the session.
+fn receive_data(backend: &(dyn Backend + Sync),
+                configs: &[Config],
+                options: &SessionOptions,
+                services: &Services)
+        -> Result<Traffic, Box<dyn Error>> {
+
+    let in_port_names = backend.input_ports()?;
+    let out_port_names = backend.output_ports()?;
     for config in configs {
-        let mut display = Display::new(colors, show_time);
-        let mut midi_in = MidiInput::new("MIDI input")?;
-        midi_in.ignore(Ignore::None);
-        let conf_in_port = config.in_port;
-        let in_port = get_in_port(config, &midi_in)?;
-        let in_channel = config.in_channel;
-
-        let do_forward = config.out_port < std::usize::MAX;
-        let mut conn_out = get_out_connection(config)?;
-        let mut message_out: [u8; 3] = [0x00, 0x00, 0x00];
-        let out_channel = config.out_channel;
-
-        let mut file = if do_record {
-            let mut filename = outfile.to_string();
-            filename += "_p";
-            filename += &config.in_port.to_string();
-            Some(File::create(filename)?)
+        show_route(config, &in_port_names, &out_port_names)?;
+    }
+    let mut session = Session::start(backend, configs, options, services)?;
+    if options.monitor && !options.display.use_tui && options.display.format == OutputFormat::Csv {
+        println!("{}", CSV_HEADER);
+    }
+    let traffic = session.traffic().clone();
+    let tui_feed = session.take_tui();
+
+    // The control socket changes the routes while the UI or the prompt runs
+    thread::scope(|s| -> Result<(), Box<dyn Error>> {
+        if let Some(control) = services.control.as_ref() {
+            let router = session.router();
+            s.spawn(move || control.serve(router, backend));
+        }
+        let result = if let Some(feed) = tui_feed {
+            tui::spawn_key_reader(feed.tx);
+            let mut tui = Tui::new(feed.ports, feed.port_names, options.display, traffic.clone());
+            tui.run(feed.rx).map(|_| {
+                for line in tui.summary() {
+                    eprintln!("{}", line);
+                }
+            })
+        } else if session.reads_stdin() {
+            wait_for_stdin();
+            Ok(())
         } else {
-            None
+            wait_for_exit(services.clock.as_ref(), &traffic)
         };
+        if let Some(control) = services.control.as_ref() {
+            control.stop();
+        }
+        result
+    })?;
 
-        let conn_in = midi_in.connect(&in_port, "MIDI forward", move |timestamp, message, _| {
-
-            if in_channel > 0 && (message[0] & 0x0F) != in_channel - 1 {
-                return; // Not listening on this channel
-            }
+    // The workers finish the remaining messages
+    for line in session.close() {
+        eprintln!("{}", line);
+    }
+    for line in traffic.summary() {
+        eprintln!("{}", line);
+    }
 
-            if do_forward {
-                // Filter some messages (for Push2)
-                let m = MidiMessage::parse(message);
-                match m {
-                    MidiMessage::NoteOn{channel: _, key, velocity: _} => {
-                        if key <= 10 {
-                            return;
-                        }
-                    }
-                    _ => (),
-                }
+    Ok(traffic)
+}
 
-                // Forward data to configured output port
-                if out_channel < 16 && out_channel != in_channel {
-                    // Adjust MIDI channel
-                    message_out[0] = message[0] & 0xF0 | out_channel - 1;
-                } else {
-                    message_out[0] = message[0];
-                }
-                if message.len() > 1 {
-                    message_out[1] = message[1];
-                    if message.len() == 3 {
-                        message_out[2] = message[2];
-                    }
-                }
-                if let Some(c) = conn_out.as_mut() {
-                    c.send(&message_out).unwrap_or_else(|_| println!("Error when forwarding message ..."));
+/// Send the commands of the control subcommand and print the answers.
+///
+/// Without a command on the command line, the commands are read from stdin
+/// until it is closed.
+fn send_commands(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut client = ControlClient::connect(matches.value_of("socket").unwrap_or(""))?;
+    let command: Vec<&str> = matches.values_of("command").map(|words| words.collect()).unwrap_or_default();
+    if !command.is_empty() {
+        for line in client.command(&command.join(" "))? {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    for command in stdin().lock().lines() {
+        let command = command?;
+        if command.trim().is_empty() {
+            continue;
+        }
+        match client.command(&command) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
                 }
             }
+            Err(err) => println!("Error: {}", err),
+        }
+    }
+    Ok(())
+}
 
-            if do_monitor {
-                // Print received data to screen
-                display.show_message(timestamp, conf_in_port, message);
-            }
+/// Start the clock generator with the command line settings.
+fn start_clock(backend: &dyn Backend, bpm: &str, ports: &str, swing: &str, running: bool) -> Result<ClockGenerator, Box<dyn Error>> {
+    let bpm = bpm.parse::<f64>().ok().filter(|b| b.is_finite()).ok_or("Invalid tempo")?;
+    let swing = swing.parse::<f64>().ok().filter(|s| s.is_finite()).ok_or("Invalid swing")?;
+    let mut outputs = vec!();
+    for port in ports.split(',').filter(|p| !p.is_empty()) {
+        let port: usize = port.trim().parse().map_err(|_| "Invalid port number")?;
+        eprintln!("Sending clock to '{}'", port_name(&backend.output_ports()?, port)?);
+        outputs.push(backend.connect_output(port)?);
+    }
+    if outputs.is_empty() {
+        return Err("No clock output port given".into());
+    }
+    Ok(ClockGenerator::new(outputs, bpm, swing, running))
+}
 
-            if do_record {
-                // Write received data to file
-                if let Some(f) = file.as_mut() {
-                    let line = if message.len() == 3 {
-                        format!("{:02x} {:02x} {:02x}\n", message[0], message[1], message[2])
-                    } else if message.len() == 2 {
-                        format!("{:02x} {:02x}\n", message[0], message[1])
-                    } else {
-                        "\n".to_string()
-                    };
-                    f.write_all(line.as_bytes()).unwrap();
-                }
-            }
-        }, ())?;
-        conn_list.push(conn_in);
+/// Start the MTC generator with the command line settings.
+fn start_mtc(backend: &dyn Backend, rate: &str, ports: &str, start: &str, chase: Option<&str>) -> Result<MtcGenerator, Box<dyn Error>> {
+    let rate = FrameRate::parse(rate).ok_or("Invalid frame rate, use 24, 25, 29.97 or 30")?;
+    let start = Timecode::parse(start, rate).ok_or("Invalid start time")?;
+    let chase = match chase {
+        Some(port) => Some(port.trim().parse::<usize>().map_err(|_| "Invalid port number")?),
+        None => None,
+    };
+    let mut outputs = vec!();
+    for port in ports.split(',').filter(|p| !p.is_empty()) {
+        let port: usize = port.trim().parse().map_err(|_| "Invalid port number")?;
+        eprintln!("Sending MTC ({}) to '{}'", rate.name(), port_name(&backend.output_ports()?, port)?);
+        outputs.push(backend.connect_output(port)?);
+    }
+    if outputs.is_empty() {
+        return Err("No MTC output port given".into());
     }
+    Ok(MtcGenerator::new(outputs, start, chase))
+}
 
-    println!("Press return to exit.");
-    let mut input = String::new();
-    stdin().read_line(&mut input)?;
+/// Run the loopback latency test between the ports of the config.
+fn measure_latency(backend: &dyn Backend, config: &Config, count: &str, probe: Probe, interval: &str) -> Result<(), Box<dyn Error>> {
+    let count: usize = count.parse().map_err(|_| "Invalid number of probes")?;
+    let interval: u64 = interval.parse().map_err(|_| "Invalid probe interval")?;
+    if config.in_port == usize::MAX || !config.forwards() {
+        return Err("The latency test needs an input and an output port (-i, -o)".into());
+    }
+    show_route(config, &backend.input_ports()?, &backend.output_ports()?)?;
+    eprintln!("Sending {} probes, {} msec apart ...", count, interval);
+    let report = latency::run_test(backend, config.in_port, config.out_port, probe, count, Duration::from_millis(interval))?;
+    for line in report.summary() {
+        println!("{}", line);
+    }
+    Ok(())
+}
 
+/// Play a recorded file to the output port of the config.
+fn play_file(backend: &dyn Backend, config: &Config, file: &str, speed: &str) -> Result<(), Box<dyn Error>> {
+    let speed: f64 = speed.parse().map_err(|_| "Invalid playback speed")?;
+    if !config.forwards() {
+        return Err("Playing a file needs an output port (-o)".into());
+    }
+    let messages = recording::read(BufReader::new(File::open(file)?))?;
+    let mut out = backend.connect_output(config.out_port)?;
+    eprintln!("Playing {} messages from '{}' to '{}'", messages.len(), file,
+              port_name(&backend.output_ports()?, config.out_port)?);
+    recording::play(&messages, &mut *out, speed)?;
     Ok(())
 }
 
-fn get_in_port(config: &Config, midi_in: &MidiInput) -> Result<MidiInputPort, Box<dyn Error>> {
-    let conf_in_port = config.in_port;
-    let in_port = get_port(midi_in, conf_in_port)?;
-    let in_port_name = midi_in.port_name(&in_port)?;
-    print!("Reading from '{}'", in_port_name);
-    if config.in_channel > 0 {
-        print!(", channel {}", config.in_channel);
-    } else {
-        print!(", all channels");
+/// Wait until the messages from stdin are read, it isn't available for
+/// commands.
+fn wait_for_stdin() {
+    eprintln!("Reading messages from stdin until it is closed.");
+    while !pipe::stdin_closed() {
+        thread::sleep(Duration::from_millis(100));
     }
-    Ok(in_port)
 }
 
-fn get_out_connection(config: &Config) -> Result<Option<MidiOutputConnection>, Box<dyn Error>> {
-    let do_forward = config.out_port < std::usize::MAX;
-    let conn_out: Option<MidiOutputConnection> = if do_forward {
-        let midi_out = MidiOutput::new("MIDI output")?;
-        let out_port = get_port(&midi_out, config.out_port)?;
-        let out_port_name = midi_out.port_name(&out_port)?;
-        print!(", forwarding to '{}'", out_port_name);
+/// Wait for the user to exit, handling commands to show the traffic
+/// statistics and clock commands if a clock is running.
+fn wait_for_exit(clock: Option<&ClockGenerator>, traffic: &Traffic) -> Result<(), Box<dyn Error>> {
+    if clock.is_some() {
+        eprintln!("Clock commands: start, stop, continue, bpm <tempo>, + [n], - [n], swing <percent>, pos <16th notes>");
+    }
+    eprintln!("Enter \"stats\" to show the traffic statistics. Press return to exit.");
+    loop {
+        let mut input = String::new();
+        if stdin().read_line(&mut input)? == 0 || input.trim().is_empty() {
+            break;
+        }
+        if input.trim() == "stats" {
+            for line in traffic.summary() {
+                eprintln!("{}", line);
+            }
+            continue;
+        }
+        let clock = match clock {
+            Some(c) => c,
+            None => {
+                eprintln!("Unknown command '{}'", input.trim());
+                continue;
+            }
+        };
+        match clock::parse_command(&input, clock.bpm()) {
+            Some(command) => {
+                clock.send(command);
+                if let ClockCommand::SetBpm(_) = command {
+                    eprintln!("Tempo {} BPM", clock.bpm());
+                }
+            }
+            None => eprintln!("Unknown command '{}'", input.trim()),
+        }
+    }
+    Ok(())
+}
+
+/// Load the controller name maps given on the command line.
+///
+/// Maps without port prefix apply to all ports, maps with a "port:" prefix
+/// extend the common names for that port only.
+fn load_cc_names(files: Vec<&str>) -> Result<(CcNames, HashMap<usize, CcNames>), Box<dyn Error>> {
+    let mut port_files = vec!();
+    let mut cc_names = CcNames::standard();
+    for file in files {
+        let port = file.find(':').and_then(|pos| file[..pos].parse::<usize>().ok().map(|p| (p, &file[pos + 1..])));
+        match port {
+            Some(port_file) => port_files.push(port_file),
+            None => cc_names.load(file)?,
+        }
+    }
+    let mut port_cc_names: HashMap<usize, CcNames> = HashMap::new();
+    for (port, file) in port_files {
+        port_cc_names.entry(port).or_insert_with(|| cc_names.clone()).load(file)?;
+    }
+    Ok((cc_names, port_cc_names))
+}
+
+/// Print the ports and channels of a route, returns the input port name.
+fn show_route(config: &Config, in_port_names: &[String], out_port_names: &[String]) -> Result<String, Box<dyn Error>> {
+    let in_port_name = port_name(in_port_names, config.in_port)?;
+    eprint!("Reading from '{}'", in_port_name);
+    if config.in_channel > 0 {
+        eprint!(", channel {}", config.in_channel);
+    } else {
+        eprint!(", all channels");
+    }
+    if config.forwards() {
+        eprint!(", forwarding to '{}'", port_name(out_port_names, config.out_port)?);
         if config.out_channel > 0 {
-            println!(", channel {}", config.out_channel);
+            eprintln!(", channel {}", config.out_channel);
         } else {
-            println!(", all channels");
+            eprintln!(", all channels");
+        }
+        if config.clock.is_active() {
+            eprintln!("Clock transform: {}", config.clock.describe());
+        }
+        if let Some(chord) = config.chord.as_ref() {
+            eprintln!("Chord: {}", chord.describe());
+        }
+        if let Some(arp) = config.arp.as_ref() {
+            eprintln!("Arpeggiator: {}", arp.describe());
         }
-        Some(midi_out.connect(&out_port, "MIDI forward")?)
     } else {
-        println!("");
-        None
-    };
-    Ok(conn_out)
+        eprintln!();
+    }
+    Ok(in_port_name)
 }
 
-fn get_port<T: MidiIO>(midi_io: &T, port: usize) -> Result<T::Port, Box<dyn Error>> {
-    let midi_ports = midi_io.ports();
-    let port = midi_ports.get(port)
-                         .ok_or("Invalid port number")?;
-    Ok(port.clone())
+fn port_name(names: &[String], port: usize) -> Result<String, Box<dyn Error>> {
+    Ok(names.get(port).ok_or("Invalid port number")?.clone())
 }
 
-fn list_all_ports()
+fn list_all_ports(backend: &dyn Backend)
         -> Result<(), Box<dyn Error>> {
-    let mut midi_in = MidiInput::new("MIDI input")?;
-    midi_in.ignore(Ignore::None);
-    list_ports(&midi_in, "input")?;
-
-    let midi_out = MidiOutput::new("MIDI output")?;
-    list_ports(&midi_out, "output")
+    list_ports(&backend.input_ports()?, "input");
+    list_ports(&backend.output_ports()?, "output");
+    Ok(())
 }
 
-fn list_ports<T: MidiIO>(midi_io: &T, descr: &str)
-        -> Result<(), Box<dyn Error>> {
+fn list_ports(names: &[String], descr: &str) {
     println!("\nAvailable {} ports:", descr);
-    let midi_ports = midi_io.ports();
-    for (i, p) in midi_ports.iter().enumerate() {
-        println!("{}: {}", i, midi_io.port_name(p)?);
+    for (i, name) in names.iter().enumerate() {
+        println!("{}: {}", i, name);
     }
-    Ok(())
 }
-
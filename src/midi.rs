@@ -1,39 +1,189 @@
+//! MIDI messages and note names.
 
+use super::mtc::Timecode;
+
+/// Channel mode messages, sent as controllers 120 - 127.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelMode {
+    AllSoundOff,
+    ResetAllControllers,
+    LocalControl(bool),
+    AllNotesOff,
+    OmniOff,
+    OmniOn,
+    MonoOn(u8), // Number of channels, 0 = number of voices
+    PolyOn,
+}
+
+impl ChannelMode {
+    fn from_controller(controller: u8, value: u8) -> Option<ChannelMode> {
+        match controller {
+            120 => Some(ChannelMode::AllSoundOff),
+            121 => Some(ChannelMode::ResetAllControllers),
+            122 => Some(ChannelMode::LocalControl(value >= 64)),
+            123 => Some(ChannelMode::AllNotesOff),
+            124 => Some(ChannelMode::OmniOff),
+            125 => Some(ChannelMode::OmniOn),
+            126 => Some(ChannelMode::MonoOn(value)),
+            127 => Some(ChannelMode::PolyOn),
+            _ => None,
+        }
+    }
+
+    /// Returns the controller number and value used to send this message.
+    pub fn to_controller(self) -> (u8, u8) {
+        match self {
+            ChannelMode::AllSoundOff => (120, 0),
+            ChannelMode::ResetAllControllers => (121, 0),
+            ChannelMode::LocalControl(on) => (122, if on { 127 } else { 0 }),
+            ChannelMode::AllNotesOff => (123, 0),
+            ChannelMode::OmniOff => (124, 0),
+            ChannelMode::OmniOn => (125, 0),
+            ChannelMode::MonoOn(channels) => (126, channels),
+            ChannelMode::PolyOn => (127, 0),
+        }
+    }
+}
+
+/// A decoded MIDI message. Channels are 0 - 15.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MidiMessage {
     NoteOff    {channel: u8, key: u8, velocity: u8},
     NoteOn     {channel: u8, key: u8, velocity: u8},
     KeyAT      {channel: u8, key: u8, pressure: u8},
     ControlChg {channel: u8, controller: u8, value: u8},
+    ChannelMode{channel: u8, mode: ChannelMode},
     ProgramChg {channel: u8, program: u8},
     ChannelAT  {channel: u8, pressure: u8},
     Pitchbend  {channel: u8, pitch: i16},
     SongPos    {position: u16},
+    QuarterFrame{piece: u8, value: u8}, // MIDI Time Code
+    MtcFullFrame{timecode: Timecode},
     TimingClock,
     Start,
     Continue,
     Stop,
     ActiveSensing,
     Reset,
+    /// Other system messages, including SysEx. Only the status byte is kept.
+    Other      {status: u8},
 }
 
 impl MidiMessage {
+    /// Returns the channel (0 - 15) of channel messages, None for system messages.
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff{channel, ..}
+            | MidiMessage::NoteOn{channel, ..}
+            | MidiMessage::KeyAT{channel, ..}
+            | MidiMessage::ControlChg{channel, ..}
+            | MidiMessage::ChannelMode{channel, ..}
+            | MidiMessage::ProgramChg{channel, ..}
+            | MidiMessage::ChannelAT{channel, ..}
+            | MidiMessage::Pitchbend{channel, ..} => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// True for system common and system real-time messages.
+    pub fn is_system(&self) -> bool {
+        self.channel().is_none()
+    }
+
+    /// True for system real-time messages (0xF8 - 0xFF).
+    pub fn is_realtime(&self) -> bool {
+        match *self {
+            MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::ActiveSensing
+            | MidiMessage::Reset => true,
+            MidiMessage::Other{status} => status >= 0xF8,
+            _ => false,
+        }
+    }
+
+    /// Returns the data fields of a message (excluding the channel) by name.
+    pub fn params(&self) -> Vec<(&'static str, i32)> {
+        match *self {
+            MidiMessage::NoteOff{key, velocity, ..}
+            | MidiMessage::NoteOn{key, velocity, ..} => vec!(("key", key as i32), ("velocity", velocity as i32)),
+            MidiMessage::KeyAT{key, pressure, ..} => vec!(("key", key as i32), ("pressure", pressure as i32)),
+            MidiMessage::ControlChg{controller, value, ..} => vec!(("controller", controller as i32), ("value", value as i32)),
+            MidiMessage::ChannelMode{mode, ..} => {
+                let (controller, value) = mode.to_controller();
+                vec!(("controller", controller as i32), ("value", value as i32))
+            }
+            MidiMessage::ProgramChg{program, ..} => vec!(("program", program as i32)),
+            MidiMessage::ChannelAT{pressure, ..} => vec!(("pressure", pressure as i32)),
+            MidiMessage::Pitchbend{pitch, ..} => vec!(("pitch", pitch as i32)),
+            MidiMessage::SongPos{position} => vec!(("position", position as i32)),
+            MidiMessage::QuarterFrame{piece, value} => vec!(("piece", piece as i32), ("value", value as i32)),
+            MidiMessage::MtcFullFrame{timecode} => vec!(("hours", timecode.hours as i32), ("minutes", timecode.minutes as i32),
+                                                        ("seconds", timecode.seconds as i32), ("frames", timecode.frames as i32)),
+            MidiMessage::Other{status} => vec!(("status", status as i32)),
+            _ => vec!(),
+        }
+    }
+
+    /// Encode the message into its MIDI byte representation.
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            MidiMessage::NoteOff{channel, key, velocity} => vec!(0x80 | channel, key, velocity),
+            MidiMessage::NoteOn{channel, key, velocity} => vec!(0x90 | channel, key, velocity),
+            MidiMessage::KeyAT{channel, key, pressure} => vec!(0xA0 | channel, key, pressure),
+            MidiMessage::ControlChg{channel, controller, value} => vec!(0xB0 | channel, controller, value),
+            MidiMessage::ChannelMode{channel, mode} => {
+                let (controller, value) = mode.to_controller();
+                vec!(0xB0 | channel, controller, value)
+            }
+            MidiMessage::ProgramChg{channel, program} => vec!(0xC0 | channel, program),
+            MidiMessage::ChannelAT{channel, pressure} => vec!(0xD0 | channel, pressure),
+            MidiMessage::Pitchbend{channel, pitch} => {
+                let value = (pitch + 0x2000) as u16;
+                vec!(0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8)
+            }
+            MidiMessage::SongPos{position} => vec!(0xF2, (position & 0x7F) as u8, (position >> 7) as u8),
+            MidiMessage::QuarterFrame{piece, value} => vec!(0xF1, (piece & 0x07) << 4 | (value & 0x0F)),
+            MidiMessage::MtcFullFrame{timecode} => timecode.full_frame(),
+            MidiMessage::TimingClock => vec!(0xF8),
+            MidiMessage::Start => vec!(0xFA),
+            MidiMessage::Continue => vec!(0xFB),
+            MidiMessage::Stop => vec!(0xFC),
+            MidiMessage::ActiveSensing => vec!(0xFE),
+            MidiMessage::Reset => vec!(0xFF),
+            MidiMessage::Other{status} => vec!(status),
+        }
+    }
+
+    /// Decode a message from its bytes. Unknown and incomplete messages are
+    /// returned as Other, missing data bytes are read as 0. Byte streams are
+    /// split into messages by stream::StreamParser first.
     pub fn parse(message: &[u8]) -> MidiMessage {
         let param = if message.len() > 1 { message[1] } else { 0 };
         let value = if message.len() > 2 { message[2] } else { 0 };
 
         match message[0] {
-            // System Real-Time Messages
+            // System Common Messages
+            0xF0 => match Timecode::parse_full_frame(message) {
+                Some(timecode) => MidiMessage::MtcFullFrame{timecode},
+                None => MidiMessage::Other{status: 0xF0},
+            },
+            0xF1 => MidiMessage::QuarterFrame{piece: param >> 4 & 0x07, value: param & 0x0F},
             0xF2 => {
                 let mut position: u16 = param as u16;
                 position |= (value as u16) << 7;
                 MidiMessage::SongPos{position}
             }
+            // System Real-Time Messages
             0xF8 => MidiMessage::TimingClock,
             0xFA => MidiMessage::Start,
             0xFB => MidiMessage::Continue,
             0xFC => MidiMessage::Stop,
             0xFE => MidiMessage::ActiveSensing,
             0xFF => MidiMessage::Reset,
+            0xF3..=0xFF => MidiMessage::Other{status: message[0]},
             _ => {
                 let channel = message[0] & 0x0F;
                 match message[0] & 0xF0 {
@@ -41,7 +191,10 @@ impl MidiMessage {
                     0x90 => MidiMessage::NoteOn{channel, key: param, velocity: value},
                     0x80 => MidiMessage::NoteOff{channel, key: param, velocity: value},
                     0xA0 => MidiMessage::KeyAT{channel, key: param, pressure: value},
-                    0xB0 => MidiMessage::ControlChg{channel, controller: param, value},
+                    0xB0 => match ChannelMode::from_controller(param, value) {
+                        Some(mode) => MidiMessage::ChannelMode{channel, mode},
+                        None => MidiMessage::ControlChg{channel, controller: param, value},
+                    },
                     0xC0 => MidiMessage::ProgramChg{channel, program: param},
                     0xD0 => MidiMessage::ChannelAT{channel, pressure: param},
                     0xE0 => {
@@ -50,9 +203,78 @@ impl MidiMessage {
                         pitch -= 0x2000;
                         MidiMessage::Pitchbend{channel, pitch}
                     },
-                    _ => panic!("Got message {}", message[0]),
+                    _ => MidiMessage::Other{status: message[0]}, // Data byte without status
                 }
             }
         }
     }
 }
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Returns the name of a note number, with key 60 being C4.
+pub fn note_name(key: u8) -> String {
+    format!("{}{}", NOTE_NAMES[(key % 12) as usize], (key / 12) as i32 - 1)
+}
+
+/// Parse a note name like "C4", "F#2", "Eb-1" into the note number.
+pub fn parse_note_name(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let base: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (offset, octave) = if let Some(o) = rest.strip_prefix('#') {
+        (1, o)
+    } else if let Some(o) = rest.strip_prefix('b') {
+        (-1, o)
+    } else {
+        (0, rest)
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let key = (octave + 1) * 12 + base + offset;
+    if (0..128).contains(&key) { Some(key as u8) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_encode_channel_messages() {
+        let messages: [&[u8]; 8] = [&[0x80, 60, 0], &[0x91, 60, 100], &[0xA2, 61, 30], &[0xB3, 7, 90],
+                                    &[0xB4, 123, 0], &[0xC5, 12], &[0xD6, 40], &[0xEF, 0x00, 0x40]];
+        for bytes in messages.iter() {
+            assert_eq!(MidiMessage::parse(bytes).encode(), bytes.to_vec());
+        }
+        assert_eq!(MidiMessage::parse(&[0xEF, 0x00, 0x40]), MidiMessage::Pitchbend{channel: 15, pitch: 0});
+        assert_eq!(MidiMessage::parse(&[0xB4, 123, 0]), MidiMessage::ChannelMode{channel: 4, mode: ChannelMode::AllNotesOff});
+    }
+
+    #[test]
+    fn parse_system_messages() {
+        assert_eq!(MidiMessage::parse(&[0xF2, 0x10, 0x01]), MidiMessage::SongPos{position: 0x90});
+        assert_eq!(MidiMessage::parse(&[0xF1, 0x35]), MidiMessage::QuarterFrame{piece: 3, value: 5});
+        assert_eq!(MidiMessage::parse(&[0xF8]), MidiMessage::TimingClock);
+        assert_eq!(MidiMessage::parse(&[0xF0, 0x7D, 0x01, 0xF7]), MidiMessage::Other{status: 0xF0});
+        assert_eq!(MidiMessage::parse(&[0xF6]), MidiMessage::Other{status: 0xF6});
+        // A data byte without status doesn't panic
+        assert_eq!(MidiMessage::parse(&[0x40]), MidiMessage::Other{status: 0x40});
+    }
+
+    #[test]
+    fn note_names() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(parse_note_name("C4"), Some(60));
+        assert_eq!(parse_note_name("F#2"), Some(42));
+        assert_eq!(parse_note_name("Eb-1"), Some(3));
+    }
+}
@@ -0,0 +1,595 @@
+//! MIDI Time Code.
+//!
+//! MTC transmits a SMPTE timecode as 8 quarter frame messages (0xF1), each
+//! carrying one nibble, so a complete timecode takes two frames. Jumps are
+//! sent as a full frame SysEx message. The MtcDecoder assembles the quarter
+//! frames into a running timecode, the MtcGenerator sends MTC, either running
+//! freely or chasing the MIDI clock and Song Position received on an input.
+
+use super::avg::Stats;
+use super::backend::Output;
+use super::MidiMessage;
+
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const CLOCKS_PER_16TH: u64 = 6;
+const SPIN_TIME: Duration = Duration::from_micros(300); // Busy wait for the last part of an interval
+const CLOCK_WINDOW: usize = 24;
+
+/// MTC frame rates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    Fps2997Drop, // 29.97 fps drop frame
+    Fps30,
+}
+
+impl FrameRate {
+    /// Rate from the 2 bit code used in MTC.
+    pub fn from_code(code: u8) -> FrameRate {
+        match code & 0x03 {
+            0 => FrameRate::Fps24,
+            1 => FrameRate::Fps25,
+            2 => FrameRate::Fps2997Drop,
+            _ => FrameRate::Fps30,
+        }
+    }
+
+    /// The rate code of quarter frame piece 7 and full frames.
+    pub fn code(self) -> u8 {
+        match self {
+            FrameRate::Fps24 => 0,
+            FrameRate::Fps25 => 1,
+            FrameRate::Fps2997Drop => 2,
+            FrameRate::Fps30 => 3,
+        }
+    }
+
+    /// Parse a rate given as 24, 25, 29.97 (drop frame) or 30.
+    pub fn parse(s: &str) -> Option<FrameRate> {
+        match s.to_lowercase().as_str() {
+            "24" => Some(FrameRate::Fps24),
+            "25" => Some(FrameRate::Fps25),
+            "29.97" | "29.97df" | "2997" => Some(FrameRate::Fps2997Drop),
+            "30" => Some(FrameRate::Fps30),
+            _ => None,
+        }
+    }
+
+    /// Number of frame numbers per second.
+    pub fn frames(self) -> u64 {
+        match self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps25 => 25,
+            FrameRate::Fps2997Drop | FrameRate::Fps30 => 30,
+        }
+    }
+
+    /// Actual frames per second.
+    pub fn fps(self) -> f64 {
+        match self {
+            FrameRate::Fps2997Drop => 30000.0 / 1001.0,
+            rate => rate.frames() as f64,
+        }
+    }
+
+    /// Name for display, e.g. "29.97 fps drop frame".
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameRate::Fps24 => "24 fps",
+            FrameRate::Fps25 => "25 fps",
+            FrameRate::Fps2997Drop => "29.97 fps drop frame",
+            FrameRate::Fps30 => "30 fps",
+        }
+    }
+}
+
+/// A SMPTE timecode with its frame rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    pub rate: FrameRate,
+}
+
+impl Timecode {
+    /// Parse a timecode given as hh:mm:ss:ff (or hh:mm:ss;ff).
+    pub fn parse(s: &str, rate: FrameRate) -> Option<Timecode> {
+        let parts: Vec<u64> = s.split([':', ';'])
+                               .map(|p| p.trim().parse().ok())
+                               .collect::<Option<Vec<u64>>>()?;
+        if parts.len() != 4 || parts[0] > 23 || parts[1] > 59 || parts[2] > 59 || parts[3] >= rate.frames() {
+            return None;
+        }
+        let tc = Timecode{hours: parts[0] as u8, minutes: parts[1] as u8, seconds: parts[2] as u8,
+                          frames: parts[3] as u8, rate};
+        // Frames dropped in drop frame mode don't exist
+        if Timecode::from_frames(tc.to_frames(), rate) == tc { Some(tc) } else { None }
+    }
+
+    /// Number of frames since 00:00:00:00.
+    pub fn to_frames(self) -> u64 {
+        let fps = self.rate.frames();
+        let minutes = self.hours as u64 * 60 + self.minutes as u64;
+        let frames = (minutes * 60 + self.seconds as u64) * fps + self.frames as u64;
+        if self.rate == FrameRate::Fps2997Drop {
+            // Frame numbers 0 and 1 are skipped every minute, except every 10th minute
+            frames - 2 * (minutes - minutes / 10)
+        } else {
+            frames
+        }
+    }
+
+    /// Timecode of a frame count, wrapping around after 24 hours.
+    pub fn from_frames(frames: u64, rate: FrameRate) -> Timecode {
+        let fps = rate.frames();
+        let mut frames = frames;
+        if rate == FrameRate::Fps2997Drop {
+            let frames_per_10min = 17982; // 10 * 60 * 30 - 9 * 2
+            let frames_per_min = 1798;    // 60 * 30 - 2
+            let tens = frames / frames_per_10min;
+            let rest = frames % frames_per_10min;
+            frames += 18 * tens;
+            if rest >= 2 {
+                frames += 2 * ((rest - 2) / frames_per_min);
+            }
+        }
+        let frames = frames % (24 * 3600 * fps);
+        Timecode{
+            hours: (frames / (3600 * fps)) as u8,
+            minutes: (frames / (60 * fps) % 60) as u8,
+            seconds: (frames / fps % 60) as u8,
+            frames: (frames % fps) as u8,
+            rate,
+        }
+    }
+
+    /// Real time since 00:00:00:00 in seconds.
+    pub fn to_seconds(self) -> f64 {
+        self.to_frames() as f64 / self.rate.fps()
+    }
+
+    /// The timecode of a time in seconds since 00:00:00:00.
+    pub fn from_seconds(seconds: f64, rate: FrameRate) -> Timecode {
+        Timecode::from_frames((seconds.max(0.0) * rate.fps()) as u64, rate)
+    }
+
+    /// The timecode a number of frames later.
+    pub fn add_frames(self, frames: u64) -> Timecode {
+        Timecode::from_frames(self.to_frames() + frames, self.rate)
+    }
+
+    /// Value of a quarter frame message (0 - 7).
+    pub fn quarter_frame(self, piece: u8) -> u8 {
+        match piece & 0x07 {
+            0 => self.frames & 0x0F,
+            1 => self.frames >> 4 & 0x01,
+            2 => self.seconds & 0x0F,
+            3 => self.seconds >> 4 & 0x03,
+            4 => self.minutes & 0x0F,
+            5 => self.minutes >> 4 & 0x03,
+            6 => self.hours & 0x0F,
+            _ => (self.hours >> 4 & 0x01) | self.rate.code() << 1,
+        }
+    }
+
+    /// Assemble a timecode from the values of all 8 quarter frames.
+    pub fn from_quarter_frames(values: &[u8; 8]) -> Timecode {
+        Timecode{
+            hours: ((values[7] & 0x01) << 4 | values[6]).min(23),
+            minutes: ((values[5] & 0x03) << 4 | values[4]).min(59),
+            seconds: ((values[3] & 0x03) << 4 | values[2]).min(59),
+            frames: (values[1] & 0x01) << 4 | values[0],
+            rate: FrameRate::from_code(values[7] >> 1),
+        }
+    }
+
+    /// Encode the timecode as full frame SysEx message.
+    pub fn full_frame(self) -> Vec<u8> {
+        vec!(0xF0, 0x7F, 0x7F, 0x01, 0x01, self.rate.code() << 5 | self.hours,
+             self.minutes, self.seconds, self.frames, 0xF7)
+    }
+
+    /// Parse a full frame SysEx message.
+    pub fn parse_full_frame(message: &[u8]) -> Option<Timecode> {
+        match *message {
+            [0xF0, 0x7F, _, 0x01, 0x01, hr, mn, sc, fr, 0xF7] => Some(Timecode{
+                hours: (hr & 0x1F).min(23),
+                minutes: mn.min(59),
+                seconds: sc.min(59),
+                frames: fr,
+                rate: FrameRate::from_code(hr >> 5),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let separator = if self.rate == FrameRate::Fps2997Drop { ';' } else { ':' };
+        write!(f, "{:02}:{:02}:{:02}{}{:02}", self.hours, self.minutes, self.seconds, separator, self.frames)
+    }
+}
+
+/// Assembles received quarter frames into a running timecode.
+#[derive(Default)]
+pub struct MtcDecoder {
+    values: [u8; 8],
+    next_piece: u8,  // Expected piece, quarter frames are only used in order
+    complete: bool,  // All pieces since piece 0 have been received
+    timecode: Option<Timecode>,
+}
+
+impl MtcDecoder {
+    /// Create a decoder without a timecode.
+    pub fn new() -> Self {
+        MtcDecoder::default()
+    }
+
+    /// Current timecode, if one has been received.
+    pub fn timecode(&self) -> Option<Timecode> {
+        self.timecode
+    }
+
+    /// Feed a message to the decoder, returns the timecode if it changed.
+    ///
+    /// The timecode advances by one frame every 4 quarter frames.
+    pub fn process(&mut self, m: &MidiMessage) -> Option<Timecode> {
+        match *m {
+            MidiMessage::QuarterFrame{piece, value} => {
+                if piece == 0 {
+                    self.complete = true;
+                } else if piece != self.next_piece {
+                    self.complete = false;
+                }
+                self.values[piece as usize & 0x07] = value;
+                self.next_piece = (piece + 1) & 0x07;
+                match piece {
+                    7 if self.complete => {
+                        // The assembled time was valid at piece 0, two frames ago
+                        let tc = Timecode::from_quarter_frames(&self.values).add_frames(2);
+                        self.timecode = Some(tc);
+                        self.timecode
+                    }
+                    3 => {
+                        self.timecode = Some(self.timecode?.add_frames(1));
+                        self.timecode
+                    }
+                    _ => None,
+                }
+            }
+            MidiMessage::MtcFullFrame{timecode} => {
+                self.complete = false;
+                self.timecode = Some(timecode);
+                self.timecode
+            }
+            MidiMessage::Stop | MidiMessage::Reset => {
+                self.complete = false;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Commands for the MTC generator thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MtcCommand {
+    Start,
+    Stop,
+    Continue,
+    Locate(Timecode),
+    /// A clock or transport message received at the given time, in chase mode.
+    Chase(MidiMessage, Instant),
+    Quit,
+}
+
+/// Sends MTC from its own thread, controlled by commands.
+pub struct MtcGenerator {
+    tx: Sender<MtcCommand>,
+    handle: Option<JoinHandle<()>>,
+    chase_port: Option<usize>,
+}
+
+struct MtcState {
+    outputs: Vec<Output>,
+    rate: FrameRate,
+    running: bool,
+    start: Timecode,       // Time of 00:00 in the song, when chasing
+    quarter: u64,          // Next quarter frame to send, counted from 00:00:00:00
+    ref_quarter: u64,      // Quarter frame and time of the last start or locate
+    ref_time: Instant,
+    clock_intervals: Stats<u64>, // Intervals of the chased clock in usec
+    last_clock: Option<Instant>,
+    send_error: bool,
+}
+
+impl MtcGenerator {
+    /// Start sending MTC to the given outputs, starting at the given time.
+    ///
+    /// If chase_port is set, the generator waits for clock and transport
+    /// messages from that port (passed in with Chase commands), with the
+    /// start time corresponding to the start of the song. Otherwise it runs
+    /// freely right away.
+    pub fn new(outputs: Vec<Output>, start: Timecode, chase_port: Option<usize>) -> MtcGenerator {
+        let (tx, rx) = channel();
+        let handle = thread::spawn(move || {
+            let mut state = MtcState{
+                outputs,
+                rate: start.rate,
+                running: false,
+                start,
+                quarter: 0,
+                ref_quarter: 0,
+                ref_time: Instant::now(),
+                clock_intervals: Stats::new(CLOCK_WINDOW),
+                last_clock: None,
+                send_error: false,
+            };
+            state.locate(start);
+            if chase_port.is_none() {
+                state.handle_command(MtcCommand::Continue);
+            }
+            state.run(rx);
+        });
+        MtcGenerator{tx, handle: Some(handle), chase_port}
+    }
+
+    /// Send a command to the MTC thread.
+    pub fn send(&self, command: MtcCommand) {
+        self.tx.send(command).ok();
+    }
+
+    /// Sender for passing chased messages from the MIDI callbacks.
+    pub fn sender(&self) -> Sender<MtcCommand> {
+        self.tx.clone()
+    }
+
+    /// Input port that is chased, if any.
+    pub fn chase_port(&self) -> Option<usize> {
+        self.chase_port
+    }
+}
+
+impl Drop for MtcGenerator {
+    fn drop(&mut self) {
+        self.send(MtcCommand::Quit);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl MtcState {
+    /// Length of a quarter frame in usec.
+    fn interval(&self) -> f64 {
+        1000000.0 / (self.rate.fps() * 4.0)
+    }
+
+    fn quarter_instant(&self, quarter: u64) -> Instant {
+        let offset = (quarter - self.ref_quarter) as f64 * self.interval();
+        self.ref_time + Duration::from_micros(offset as u64)
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        for out in self.outputs.iter_mut() {
+            if out.send(bytes).is_err() && !self.send_error {
+                eprintln!("Error when sending MTC ...");
+                self.send_error = true;
+            }
+        }
+    }
+
+    /// Jump to a new time, which is announced with a full frame message.
+    fn locate(&mut self, timecode: Timecode) {
+        let timecode = Timecode::from_frames(timecode.to_frames(), self.rate);
+        self.quarter = timecode.to_frames() * 4;
+        self.send(&timecode.full_frame());
+    }
+
+    fn send_quarter_frame(&mut self) {
+        // Every group of 8 quarter frames carries the time of its first one
+        let piece = (self.quarter % 8) as u8;
+        let timecode = Timecode::from_frames((self.quarter - piece as u64) / 4, self.rate);
+        let m = MidiMessage::QuarterFrame{piece, value: timecode.quarter_frame(piece)};
+        self.send(&m.encode());
+        self.quarter += 1;
+    }
+
+    fn handle_command(&mut self, command: MtcCommand) {
+        match command {
+            MtcCommand::Start => {
+                let start = self.start;
+                self.locate(start);
+                self.handle_command(MtcCommand::Continue);
+            }
+            MtcCommand::Stop => self.running = false,
+            MtcCommand::Continue => {
+                self.running = true;
+                self.ref_quarter = self.quarter;
+                self.ref_time = Instant::now();
+            }
+            MtcCommand::Locate(timecode) => {
+                self.locate(timecode);
+                self.ref_quarter = self.quarter;
+                self.ref_time = Instant::now();
+            }
+            MtcCommand::Chase(m, time) => self.chase(m, time),
+            MtcCommand::Quit => (),
+        }
+    }
+
+    /// Follow the transport of the chased clock.
+    fn chase(&mut self, m: MidiMessage, time: Instant) {
+        match m {
+            MidiMessage::TimingClock => {
+                if let Some(last) = self.last_clock.replace(time) {
+                    self.clock_intervals.add_value(time.duration_since(last).as_micros() as u64);
+                }
+            }
+            MidiMessage::Start => {
+                self.handle_command(MtcCommand::Start);
+                self.ref_time = time;
+            }
+            MidiMessage::Continue => {
+                self.handle_command(MtcCommand::Continue);
+                self.ref_time = time;
+            }
+            MidiMessage::Stop => {
+                self.handle_command(MtcCommand::Stop);
+                self.last_clock = None;
+            }
+            MidiMessage::SongPos{position} => {
+                // The position is in 16th notes, converted with the measured tempo
+                if let Some(interval) = self.clock_intervals.mean() {
+                    let seconds = position as u64 as f64 * CLOCKS_PER_16TH as f64 * interval / 1000000.0;
+                    let timecode = Timecode::from_seconds(self.start.to_seconds() + seconds, self.rate);
+                    self.handle_command(MtcCommand::Locate(timecode));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn run(&mut self, rx: Receiver<MtcCommand>) {
+        loop {
+            if !self.running {
+                match rx.recv() {
+                    Ok(MtcCommand::Quit) | Err(_) => break,
+                    Ok(command) => self.handle_command(command),
+                }
+                continue;
+            }
+            let next = self.quarter_instant(self.quarter);
+            // Wait for commands until shortly before the next quarter frame
+            let now = Instant::now();
+            if next > now + SPIN_TIME {
+                match rx.recv_timeout(next - now - SPIN_TIME) {
+                    Ok(MtcCommand::Quit) | Err(RecvTimeoutError::Disconnected) => break,
+                    Ok(command) => {
+                        self.handle_command(command);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                }
+            }
+            while Instant::now() < next {
+                std::hint::spin_loop();
+            }
+            self.send_quarter_frame();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tc(s: &str, rate: FrameRate) -> Timecode {
+        Timecode::parse(s, rate).unwrap()
+    }
+
+    fn quarter_frames(timecode: Timecode, pieces: &[u8]) -> Vec<MidiMessage> {
+        pieces.iter().map(|&piece| MidiMessage::QuarterFrame{piece, value: timecode.quarter_frame(piece)}).collect()
+    }
+
+    #[test]
+    fn converts_drop_frame_timecodes() {
+        let df = FrameRate::Fps2997Drop;
+        for &(s, frames) in [("00:00:00;00", 0), ("00:00:59;29", 1799), ("00:01:00;02", 1800), ("00:02:00;02", 3598),
+                             ("00:09:59;29", 17981), ("00:10:00;00", 17982), ("00:11:00;02", 19782),
+                             ("01:00:00;00", 107892)].iter() {
+            assert_eq!(tc(s, df).to_frames(), frames, "{}", s);
+            assert_eq!(Timecode::from_frames(frames, df).to_string(), s);
+        }
+        // Frames 0 and 1 don't exist at the start of most minutes
+        assert_eq!(Timecode::parse("00:01:00;00", df), None);
+        assert_eq!(Timecode::parse("00:01:00;01", df), None);
+        assert!(Timecode::parse("00:10:00;01", df).is_some());
+        assert_eq!(tc("00:00:59;29", df).add_frames(1).to_string(), "00:01:00;02");
+        assert!((tc("00:10:00;00", df).to_seconds() - 600.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn converts_timecodes() {
+        let fps25 = FrameRate::Fps25;
+        assert_eq!(tc("01:02:03:04", fps25).to_frames(), (3723 * 25) + 4);
+        assert_eq!(Timecode::from_seconds(3723.2, fps25).to_string(), "01:02:03:05");
+        assert_eq!(Timecode::parse("00:00:00:25", fps25), None);
+        assert_eq!(Timecode::parse("24:00:00:00", fps25), None);
+        // Wraps around after 24 hours
+        assert_eq!(tc("23:59:59:24", fps25).add_frames(2).to_string(), "00:00:00:01");
+        assert_eq!(tc("23:59:59;29", FrameRate::Fps2997Drop).add_frames(1).to_string(), "00:00:00;00");
+    }
+
+    #[test]
+    fn assembles_quarter_frames() {
+        let start = tc("01:02:03:04", FrameRate::Fps25);
+        let mut decoder = MtcDecoder::new();
+        // Pieces before the first piece 0 are ignored
+        let mut results: Vec<Option<Timecode>> = quarter_frames(start, &[5, 6, 7]).iter().map(|m| decoder.process(m)).collect();
+        assert_eq!(results, vec!(None; 3));
+
+        results = quarter_frames(start, &[0, 1, 2, 3, 4, 5, 6, 7]).iter().map(|m| decoder.process(m)).collect();
+        // The time of piece 0 plus the two frames it took to send all pieces
+        assert_eq!(results[7].unwrap().to_string(), "01:02:03:06");
+        assert_eq!(results[..7], [None; 7]);
+        assert_eq!(decoder.timecode().unwrap().rate, FrameRate::Fps25);
+
+        // The next frame is counted at piece 3, then assembled again
+        let next = start.add_frames(2);
+        results = quarter_frames(next, &[0, 1, 2, 3, 4, 5, 6, 7]).iter().map(|m| decoder.process(m)).collect();
+        assert_eq!(results[3].unwrap().to_string(), "01:02:03:07");
+        assert_eq!(results[7].unwrap().to_string(), "01:02:03:08");
+    }
+
+    #[test]
+    fn ignores_quarter_frames_out_of_order() {
+        let start = tc("00:10:00;00", FrameRate::Fps2997Drop);
+        let mut decoder = MtcDecoder::new();
+        // Running backwards the pieces come in reverse order
+        for m in quarter_frames(start, &[7, 6, 5, 4, 3, 2, 1, 0, 7, 6, 5, 4]).iter() {
+            assert_eq!(decoder.process(m), None);
+        }
+        // A missing piece invalidates the cycle
+        for m in quarter_frames(start, &[0, 1, 2, 4, 5, 6, 7]).iter() {
+            assert_eq!(decoder.process(m), None);
+        }
+        let results: Vec<Option<Timecode>> = quarter_frames(start, &[0, 1, 2, 3, 4, 5, 6, 7]).iter()
+                                                                                          .map(|m| decoder.process(m))
+                                                                                          .collect();
+        assert_eq!(results[7].unwrap().to_string(), "00:10:00;02");
+        // Stop ends the cycle
+        decoder.process(&quarter_frames(start, &[0])[0]);
+        decoder.process(&MidiMessage::Stop);
+        let results: Vec<Option<Timecode>> = quarter_frames(start, &[1, 2, 3, 4, 5, 6, 7]).iter()
+                                                                                       .map(|m| decoder.process(m))
+                                                                                       .collect();
+        assert_eq!(results[6], None);
+    }
+
+    #[test]
+    fn encodes_full_frames() {
+        let timecode = tc("10:20:30;15", FrameRate::Fps2997Drop);
+        let message = timecode.full_frame();
+        assert_eq!(message, vec!(0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x4A, 20, 30, 15, 0xF7));
+        assert_eq!(Timecode::parse_full_frame(&message), Some(timecode));
+        // Any device ID
+        assert_eq!(Timecode::parse_full_frame(&[0xF0, 0x7F, 0x10, 0x01, 0x01, 0x61, 0, 0, 0, 0xF7]),
+                   Some(tc("01:00:00:00", FrameRate::Fps30)));
+        assert_eq!(Timecode::parse_full_frame(&[0xF0, 0x7F, 0x7F, 0x01, 0x02, 0x61, 0, 0, 0, 0xF7]), None);
+        assert_eq!(Timecode::parse_full_frame(&message[..9]), None);
+
+        let mut decoder = MtcDecoder::new();
+        assert_eq!(decoder.process(&MidiMessage::parse(&message)), Some(timecode));
+        assert_eq!(decoder.timecode(), Some(timecode));
+    }
+}
@@ -0,0 +1,428 @@
+//! MIDI over UDP and TCP.
+//!
+//! Messages are sent as frames with a sequence number and the timestamp of
+//! the sender (usec since the output was opened):
+//!
+//! ```text
+//! 0   2  magic "MT"
+//! 2   1  protocol version (1)
+//! 3   1  reserved (0)
+//! 4   4  sequence number, big endian
+//! 8   8  timestamp in usec, big endian
+//! 16  2  length of the message, big endian
+//! 18  n  the complete message, including SysEx
+//! ```
+//!
+//! UDP sends a datagram per frame, TCP a stream of frames. Receivers count
+//! gaps in the sequence numbers as lost messages. A TCP output connects to
+//! the receiver and reconnects in the background when the connection is
+//! lost or the receiver stops reading; messages sent while disconnected
+//! fail. Endpoints are given as
+//! "udp:host:port" or "tcp:host:port": inputs listen on the address,
+//! outputs send to it.
+
+use super::backend::{InputCallback, InputConnection, Output, OutputConnection};
+use super::endpoint::Endpoint;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 2] = b"MT";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 18;
+const MAX_DATAGRAM: usize = 65536;
+const MAX_MESSAGE: usize = 65000; // Fits into a UDP datagram with the header
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How often threads check for closing
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_millis(50); // Longest a forwarding callback waits for the peer
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(5);
+
+/// A message with its sequence number and timestamp.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub seq: u32,
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(0);
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Decode a frame from the start of buf. Returns the frame and its size,
+    /// or None if buf doesn't hold a complete frame yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Frame, usize)>, String> {
+        if buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        if &buf[0..2] != MAGIC {
+            return Err("Invalid frame".to_string());
+        }
+        if buf[2] != VERSION {
+            return Err(format!("Unsupported protocol version {}", buf[2]));
+        }
+        let seq = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&buf[8..16]);
+        let len = u16::from_be_bytes([buf[16], buf[17]]) as usize;
+        if buf.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let data = buf[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        Ok(Some((Frame{seq, timestamp: u64::from_be_bytes(timestamp), data}, HEADER_SIZE + len)))
+    }
+}
+
+/// Detects lost frames from the sequence numbers of a sender.
+#[derive(Default)]
+pub struct SeqTracker {
+    expected: Option<u32>,
+}
+
+impl SeqTracker {
+    /// Check the sequence number of a received frame, returns the number of
+    /// frames missing before it. Late frames don't count.
+    pub fn check(&mut self, seq: u32) -> u32 {
+        let lost = match self.expected {
+            Some(expected) => {
+                let gap = seq.wrapping_sub(expected);
+                if gap >= 1 << 31 {
+                    return 0; // Older than expected
+                }
+                gap
+            }
+            None => 0,
+        };
+        self.expected = Some(seq.wrapping_add(1));
+        lost
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// A UDP or TCP endpoint.
+pub struct NetEndpoint {
+    protocol: Protocol,
+    address: String,
+}
+
+impl NetEndpoint {
+    /// Parse "udp:host:port" or "tcp:host:port". Returns None for other
+    /// endpoint types.
+    pub fn parse(spec: &str) -> Option<Result<NetEndpoint, String>> {
+        let (protocol, address) = if let Some(address) = spec.strip_prefix("udp:") {
+            (Protocol::Udp, address)
+        } else if let Some(address) = spec.strip_prefix("tcp:") {
+            (Protocol::Tcp, address)
+        } else {
+            return None;
+        };
+        let has_port = address.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        if !has_port {
+            return Some(Err(format!("Invalid address '{}', use host:port", address)));
+        }
+        Some(Ok(NetEndpoint{protocol, address: address.to_string()}))
+    }
+
+    fn resolve(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        let addr = self.address.to_socket_addrs()?
+                               .next()
+                               .ok_or_else(|| format!("Unknown host '{}'", self.address))?;
+        Ok(addr)
+    }
+}
+
+impl Endpoint for NetEndpoint {
+    fn name(&self) -> String {
+        match self.protocol {
+            Protocol::Udp => format!("udp:{}", self.address),
+            Protocol::Tcp => format!("tcp:{}", self.address),
+        }
+    }
+
+    fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let addr = self.resolve()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        match self.protocol {
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(addr)?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                thread::spawn(move || receive_udp(socket, callback, thread_running));
+            }
+            Protocol::Tcp => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                thread::spawn(move || accept_tcp(listener, callback, thread_running));
+            }
+        }
+        Ok(Box::new(NetInput{running}))
+    }
+
+    fn connect_output(&self) -> Result<Output, Box<dyn Error>> {
+        let addr = self.resolve()?;
+        let start = Instant::now();
+        match self.protocol {
+            Protocol::Udp => {
+                let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Ok(Box::new(UdpOutput{socket, seq: 0, start}))
+            }
+            Protocol::Tcp => {
+                let stream = Arc::new(Mutex::new(None));
+                let running = Arc::new(AtomicBool::new(true));
+                let (thread_stream, thread_running) = (stream.clone(), running.clone());
+                thread::spawn(move || connect_tcp(addr, thread_stream, thread_running));
+                Ok(Box::new(TcpOutput{stream, running, seq: 0, start}))
+            }
+        }
+    }
+}
+
+/// Stops the receiving threads when dropped.
+struct NetInput {
+    running: Arc<AtomicBool>,
+}
+
+impl InputConnection for NetInput {}
+
+impl Drop for NetInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
+fn report_lost(lost: u32, from: SocketAddr) {
+    if lost > 0 {
+        eprintln!("Lost {} messages from {}", lost, from);
+    }
+}
+
+fn receive_udp(socket: UdpSocket, mut callback: InputCallback, running: Arc<AtomicBool>) {
+    let mut buf = vec!(0u8; MAX_DATAGRAM);
+    let mut senders: HashMap<SocketAddr, SeqTracker> = HashMap::new();
+    while running.load(Ordering::SeqCst) {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(ref err) if is_timeout(err) => continue,
+            Err(err) => {
+                eprintln!("Error when receiving from {}: {}", socket.local_addr().map(|a| a.to_string()).unwrap_or_default(), err);
+                return;
+            }
+        };
+        // A datagram can hold several frames, invalid datagrams are dropped
+        let mut datagram = &buf[..len];
+        while let Ok(Some((frame, size))) = Frame::decode(datagram) {
+            report_lost(senders.entry(from).or_default().check(frame.seq), from);
+            if !frame.data.is_empty() {
+                callback(frame.timestamp, &frame.data);
+            }
+            datagram = &datagram[size..];
+        }
+    }
+}
+
+fn accept_tcp(listener: TcpListener, callback: InputCallback, running: Arc<AtomicBool>) {
+    let callback = Arc::new(Mutex::new(callback));
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, from)) => {
+                eprintln!("Connection from {}", from);
+                let (callback, running) = (callback.clone(), running.clone());
+                thread::spawn(move || {
+                    if let Err(err) = receive_tcp(stream, from, callback, running) {
+                        eprintln!("Connection from {} closed: {}", from, err);
+                    }
+                });
+            }
+            Err(ref err) if is_timeout(err) => thread::sleep(POLL_INTERVAL),
+            Err(err) => {
+                eprintln!("Error when accepting connections: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+fn receive_tcp(mut stream: TcpStream, from: SocketAddr, callback: Arc<Mutex<InputCallback>>,
+               running: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut buf = vec!();
+    let mut chunk = [0u8; 4096];
+    let mut seq = SeqTracker::default();
+    while running.load(Ordering::SeqCst) {
+        let len = match stream.read(&mut chunk) {
+            Ok(0) => return Err("Closed by sender".into()),
+            Ok(len) => len,
+            Err(ref err) if is_timeout(err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        buf.extend_from_slice(&chunk[..len]);
+        let mut pos = 0;
+        while let Some((frame, size)) = Frame::decode(&buf[pos..])? {
+            report_lost(seq.check(frame.seq), from);
+            if !frame.data.is_empty() {
+                (callback.lock().unwrap())(frame.timestamp, &frame.data);
+            }
+            pos += size;
+        }
+        buf.drain(..pos);
+    }
+    Ok(())
+}
+
+/// Keeps a connection to addr open until running is cleared.
+fn connect_tcp(addr: SocketAddr, stream: Arc<Mutex<Option<TcpStream>>>, running: Arc<AtomicBool>) {
+    let mut delay = RECONNECT_MIN;
+    let mut connected = false;
+    while running.load(Ordering::SeqCst) {
+        if stream.lock().unwrap().is_some() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        if connected {
+            eprintln!("Connection to {} lost, reconnecting", addr);
+            connected = false;
+        }
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(s) => {
+                s.set_nodelay(true).ok();
+                s.set_write_timeout(Some(WRITE_TIMEOUT)).ok();
+                eprintln!("Connected to {}", addr);
+                *stream.lock().unwrap() = Some(s);
+                connected = true;
+                delay = RECONNECT_MIN;
+            }
+            Err(_) => {
+                thread::sleep(delay);
+                delay = (delay * 2).min(RECONNECT_MAX);
+            }
+        }
+    }
+}
+
+fn next_frame(seq: &mut u32, start: Instant, message: &[u8]) -> Result<Frame, Box<dyn Error>> {
+    if message.len() > MAX_MESSAGE {
+        return Err(format!("Message too long for the network ({} bytes)", message.len()).into());
+    }
+    let frame = Frame{seq: *seq, timestamp: start.elapsed().as_micros() as u64, data: message.to_vec()};
+    *seq = seq.wrapping_add(1);
+    Ok(frame)
+}
+
+struct UdpOutput {
+    socket: UdpSocket,
+    seq: u32,
+    start: Instant,
+}
+
+impl OutputConnection for UdpOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let frame = next_frame(&mut self.seq, self.start, message)?;
+        self.socket.send(&frame.encode())?;
+        Ok(())
+    }
+}
+
+struct TcpOutput {
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    running: Arc<AtomicBool>,
+    seq: u32,
+    start: Instant,
+}
+
+impl OutputConnection for TcpOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let frame = next_frame(&mut self.seq, self.start, message)?;
+        let mut stream = self.stream.lock().unwrap();
+        let result = match stream.as_mut() {
+            Some(s) => s.write_all(&frame.encode()),
+            None => return Err("Not connected".into()),
+        };
+        if result.is_err() {
+            // Also after a timeout, when the peer stopped reading. Let the
+            // connect thread reconnect.
+            *stream = None;
+        }
+        Ok(result?)
+    }
+}
+
+impl Drop for TcpOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = Frame{seq: 0x01020304, timestamp: 1_250_000, data: vec!(0xF0, 0x7D, 0x01, 0x02, 0xF7)};
+        let bytes = frame.encode();
+        assert_eq!(&bytes[..4], &[b'M', b'T', 1, 0]);
+        assert_eq!(Frame::decode(&bytes).unwrap(), Some((frame, bytes.len())));
+    }
+
+    #[test]
+    fn decodes_partial_and_invalid_frames() {
+        let bytes = Frame{seq: 1, timestamp: 2, data: vec!(0x90, 60, 100)}.encode();
+        assert_eq!(Frame::decode(&bytes[..10]).unwrap(), None);
+        assert_eq!(Frame::decode(&bytes[..bytes.len() - 1]).unwrap(), None);
+        assert!(Frame::decode(b"XX\x01\x00 garbage garbage").is_err());
+        let mut other_version = bytes.clone();
+        other_version[2] = 9;
+        assert!(Frame::decode(&other_version).is_err());
+    }
+
+    #[test]
+    fn counts_lost_frames() {
+        let mut seq = SeqTracker::default();
+        assert_eq!(seq.check(5), 0);
+        assert_eq!(seq.check(6), 0);
+        assert_eq!(seq.check(9), 2);
+        // Late and repeated frames don't count
+        assert_eq!(seq.check(7), 0);
+        assert_eq!(seq.check(10), 0);
+        // Wrap around
+        let mut seq = SeqTracker::default();
+        assert_eq!(seq.check(u32::MAX), 0);
+        assert_eq!(seq.check(1), 1);
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(NetEndpoint::parse("udp:127.0.0.1:5004").unwrap().unwrap().name(), "udp:127.0.0.1:5004");
+        assert_eq!(NetEndpoint::parse("tcp:localhost:5004").unwrap().unwrap().name(), "tcp:localhost:5004");
+        assert!(NetEndpoint::parse("udp:localhost").unwrap().is_err());
+        assert!(NetEndpoint::parse("3").is_none());
+    }
+}
@@ -0,0 +1,569 @@
+//! Open Sound Control over UDP.
+//!
+//! An "osc:host:port" endpoint converts between MIDI and OSC messages with a
+//! list of mappings. A mapping is given by a MIDI message type with optional
+//! channel and number, an OSC address and an optional value range, e.g. in
+//! the config file:
+//!
+//! ```text
+//! osc:127.0.0.1:9000,cc:1:7,/mixer/volume,0..100
+//! osc:127.0.0.1:9000,note,/midi/ch{channel}/note/{number},0..1
+//! osc:127.0.0.1:9000,program,/scene
+//! ```
+//!
+//! Types are note, keyat, cc, program, chanat and pitchbend, channels are
+//! 1 - 16 or * for all. The address can contain {channel} and {number}
+//! (note or controller number), at most one per path segment. The value
+//! (velocity, controller value, program, pressure or pitch) is sent as float
+//! scaled to the range, or as the MIDI value (int) without range. Received
+//! OSC messages are converted back by matching the address, with the first
+//! argument as value. Every mapping that matches a message converts it.
+//! Endpoints without own mappings use DEFAULT_MAPPINGS.
+
+use super::backend::{InputCallback, InputConnection, Output, OutputConnection};
+use super::endpoint::Endpoint;
+use super::midi::{parse_note_name, MidiMessage};
+use super::net::is_timeout;
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_PACKET: usize = 65536;
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How often threads check for closing
+
+/// Mappings of endpoints without own mappings.
+pub const DEFAULT_MAPPINGS: [(&str, &str, Option<&str>); 6] = [
+    ("note", "/midi/ch{channel}/note/{number}", Some("0..1")),
+    ("keyat", "/midi/ch{channel}/keyat/{number}", Some("0..1")),
+    ("cc", "/midi/ch{channel}/cc/{number}", Some("0..1")),
+    ("program", "/midi/ch{channel}/program", None),
+    ("chanat", "/midi/ch{channel}/chanat", Some("0..1")),
+    ("pitchbend", "/midi/ch{channel}/pitchbend", Some("-1..1")),
+];
+
+/// An OSC argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            OscArg::Int(i) => Some(*i as f32),
+            OscArg::Float(f) => Some(*f),
+            OscArg::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            OscArg::String(_) => None,
+        }
+    }
+}
+
+/// An OSC message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+fn write_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend_from_slice(s.as_bytes());
+    bytes.push(0);
+    bytes.resize((bytes.len() + 3) & !3, 0);
+}
+
+fn read_string(buf: &[u8], pos: &mut usize) -> Result<String, String> {
+    let rest = buf.get(*pos..).unwrap_or_default();
+    let len = rest.iter().position(|b| *b == 0).ok_or("Unterminated string")?;
+    let s = String::from_utf8_lossy(&rest[..len]).to_string();
+    *pos += (len + 4) & !3;
+    Ok(s)
+}
+
+fn read_bytes<'a>(buf: &'a [u8], pos: &mut usize, n: usize) -> Result<&'a [u8], String> {
+    let bytes = buf.get(*pos..*pos + n).ok_or("Message too short")?;
+    *pos += n;
+    Ok(bytes)
+}
+
+impl OscMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec!();
+        write_string(&mut bytes, &self.address);
+        let tags: String = self.args.iter().map(|a| match a {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::String(_) => 's',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+        }).collect();
+        write_string(&mut bytes, &format!(",{}", tags));
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(i) => bytes.extend_from_slice(&i.to_be_bytes()),
+                OscArg::Float(f) => bytes.extend_from_slice(&f.to_be_bytes()),
+                OscArg::String(s) => write_string(&mut bytes, s),
+                OscArg::Bool(_) => (),
+            }
+        }
+        bytes
+    }
+
+    fn decode(buf: &[u8]) -> Result<OscMessage, String> {
+        let mut pos = 0;
+        let address = read_string(buf, &mut pos)?;
+        if !address.starts_with('/') {
+            return Err(format!("Invalid address '{}'", address));
+        }
+        let mut args = vec!();
+        if pos >= buf.len() {
+            return Ok(OscMessage{address, args}); // No type tags
+        }
+        let tags = read_string(buf, &mut pos)?;
+        for tag in tags.chars().skip(1) {
+            let arg = match tag {
+                'i' => OscArg::Int(i32::from_be_bytes(read_bytes(buf, &mut pos, 4)?.try_into().unwrap())),
+                'f' => OscArg::Float(f32::from_be_bytes(read_bytes(buf, &mut pos, 4)?.try_into().unwrap())),
+                'h' => OscArg::Int(i64::from_be_bytes(read_bytes(buf, &mut pos, 8)?.try_into().unwrap()) as i32),
+                'd' => OscArg::Float(f64::from_be_bytes(read_bytes(buf, &mut pos, 8)?.try_into().unwrap()) as f32),
+                's' => OscArg::String(read_string(buf, &mut pos)?),
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                _ => return Err(format!("Unsupported argument type '{}'", tag)),
+            };
+            args.push(arg);
+        }
+        Ok(OscMessage{address, args})
+    }
+
+    /// Decode a packet, a message or a bundle of them. The time tags of
+    /// bundles are ignored.
+    pub fn decode_packet(buf: &[u8]) -> Result<Vec<OscMessage>, String> {
+        if !buf.starts_with(b"#bundle\0") {
+            return Ok(vec!(OscMessage::decode(buf)?));
+        }
+        let mut messages = vec!();
+        let mut pos = 16; // After the time tag
+        while pos < buf.len() {
+            let size = u32::from_be_bytes(read_bytes(buf, &mut pos, 4)?.try_into().unwrap()) as usize;
+            messages.extend(OscMessage::decode_packet(read_bytes(buf, &mut pos, size)?)?);
+        }
+        Ok(messages)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Note,
+    KeyAt,
+    Cc,
+    Program,
+    ChanAt,
+    Pitchbend,
+}
+
+impl Kind {
+    fn has_number(self) -> bool {
+        matches!(self, Kind::Note | Kind::KeyAt | Kind::Cc)
+    }
+
+    /// Scale a MIDI value to 0 - 1. The pitch wheel center is 0.5.
+    fn normalize(self, value: i32) -> f32 {
+        match self {
+            Kind::Pitchbend if value >= 0 => 0.5 + value as f32 / 8191.0 / 2.0,
+            Kind::Pitchbend => 0.5 + value as f32 / 8192.0 / 2.0,
+            _ => value as f32 / 127.0,
+        }
+    }
+
+    fn denormalize(self, value: f32) -> i32 {
+        let value = value.clamp(0.0, 1.0);
+        match self {
+            Kind::Pitchbend if value >= 0.5 => ((value - 0.5) * 2.0 * 8191.0).round() as i32,
+            Kind::Pitchbend => ((value - 0.5) * 2.0 * 8192.0).round() as i32,
+            _ => (value * 127.0).round() as i32,
+        }
+    }
+}
+
+/// A conversion between a MIDI message type and an OSC address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    kind: Kind,
+    channel: Option<u8>, // 0 - 15, None for all
+    number: Option<u8>,  // Note or controller, None for all
+    address: String,
+    range: Option<(f32, f32)>,
+}
+
+impl Mapping {
+    /// Parse a mapping from the type (e.g. "cc:1:7"), the OSC address and
+    /// the value range (e.g. "0..1").
+    pub fn parse(kind: &str, address: &str, range: Option<&str>) -> Result<Mapping, String> {
+        let mut parts = kind.split(':');
+        let kind = match parts.next().unwrap_or("") {
+            "note" => Kind::Note,
+            "keyat" => Kind::KeyAt,
+            "cc" => Kind::Cc,
+            "program" => Kind::Program,
+            "chanat" => Kind::ChanAt,
+            "pitchbend" => Kind::Pitchbend,
+            other => return Err(format!("Unknown message type '{}'", other)),
+        };
+        let channel = match parts.next() {
+            None | Some("*") => None,
+            Some(c) => match c.parse::<u8>() {
+                Ok(c) if (1..=16).contains(&c) => Some(c - 1),
+                _ => return Err(format!("Invalid channel '{}'", c)),
+            },
+        };
+        let number = match parts.next() {
+            None | Some("*") => None,
+            Some(n) if kind.has_number() => {
+                match n.parse::<u8>().ok().or_else(|| parse_note_name(n)) {
+                    Some(n) if n < 128 => Some(n),
+                    _ => return Err(format!("Invalid number '{}'", n)),
+                }
+            }
+            Some(_) => return Err("Only notes and controllers have a number".to_string()),
+        };
+        if parts.next().is_some() {
+            return Err(format!("Invalid message type '{}', use type:channel:number", kind_name(kind)));
+        }
+        if !address.starts_with('/') {
+            return Err(format!("Invalid OSC address '{}'", address));
+        }
+        for segment in address.split('/') {
+            if let Some((_, rest)) = segment.split_once('{') {
+                match rest.split_once('}') {
+                    Some(("channel", suffix)) | Some(("number", suffix)) if !suffix.contains('{') => (),
+                    _ => return Err(format!("Invalid placeholder in '{}', use {{channel}} or {{number}}", segment)),
+                }
+            }
+        }
+        let range = match range {
+            Some(r) => {
+                let parsed = r.split_once("..").and_then(|(min, max)| Some((min.parse().ok()?, max.parse().ok()?)));
+                match parsed {
+                    Some((min, max)) if min != max => Some((min, max)),
+                    _ => return Err(format!("Invalid range '{}', use min..max", r)),
+                }
+            }
+            None => None,
+        };
+        Ok(Mapping{kind, channel, number, address: address.to_string(), range})
+    }
+
+    /// The OSC message for a MIDI message, if the mapping applies.
+    pub fn to_osc(&self, message: &MidiMessage) -> Option<OscMessage> {
+        let (kind, channel, number, value) = match *message {
+            MidiMessage::NoteOn{channel, key, velocity} => (Kind::Note, channel, Some(key), velocity as i32),
+            MidiMessage::NoteOff{channel, key, ..} => (Kind::Note, channel, Some(key), 0),
+            MidiMessage::KeyAT{channel, key, pressure} => (Kind::KeyAt, channel, Some(key), pressure as i32),
+            MidiMessage::ControlChg{channel, controller, value} => (Kind::Cc, channel, Some(controller), value as i32),
+            MidiMessage::ChannelMode{channel, mode} => {
+                let (controller, value) = mode.to_controller();
+                (Kind::Cc, channel, Some(controller), value as i32)
+            }
+            MidiMessage::ProgramChg{channel, program} => (Kind::Program, channel, None, program as i32),
+            MidiMessage::ChannelAT{channel, pressure} => (Kind::ChanAt, channel, None, pressure as i32),
+            MidiMessage::Pitchbend{channel, pitch} => (Kind::Pitchbend, channel, None, pitch as i32),
+            _ => return None,
+        };
+        if kind != self.kind || self.channel.is_some_and(|c| c != channel)
+            || (self.number.is_some() && self.number != number) {
+            return None;
+        }
+        let address = self.address.replace("{channel}", &(channel + 1).to_string())
+                                  .replace("{number}", &number.map(|n| n.to_string()).unwrap_or_default());
+        let arg = match self.range {
+            Some((min, max)) => OscArg::Float(min + kind.normalize(value) * (max - min)),
+            None => OscArg::Int(value),
+        };
+        Some(OscMessage{address, args: vec!(arg)})
+    }
+
+    /// The MIDI message for an OSC message, if the address matches.
+    pub fn to_midi(&self, message: &OscMessage) -> Option<MidiMessage> {
+        let (channel, number) = self.match_address(&message.address)?;
+        let channel = channel.or(self.channel).unwrap_or(0);
+        let number = number.or(self.number);
+        let value = message.args.first()?.as_f32()?;
+        let value = match self.range {
+            Some((min, max)) => self.kind.denormalize((value - min) / (max - min)),
+            None => value.round() as i32,
+        };
+        let data = value.clamp(0, 127) as u8;
+        Some(match self.kind {
+            Kind::Note if data > 0 => MidiMessage::NoteOn{channel, key: number?, velocity: data},
+            Kind::Note => MidiMessage::NoteOff{channel, key: number?, velocity: 0},
+            Kind::KeyAt => MidiMessage::KeyAT{channel, key: number?, pressure: data},
+            Kind::Cc => MidiMessage::ControlChg{channel, controller: number?, value: data},
+            Kind::Program => MidiMessage::ProgramChg{channel, program: data},
+            Kind::ChanAt => MidiMessage::ChannelAT{channel, pressure: data},
+            Kind::Pitchbend => MidiMessage::Pitchbend{channel, pitch: value.clamp(-8192, 8191) as i16},
+        })
+    }
+
+    /// Match an address against the template, returns the channel and
+    /// number of the placeholders.
+    fn match_address(&self, address: &str) -> Option<(Option<u8>, Option<u8>)> {
+        let template: Vec<&str> = self.address.split('/').collect();
+        let segments: Vec<&str> = address.split('/').collect();
+        if template.len() != segments.len() {
+            return None;
+        }
+        let (mut channel, mut number) = (None, None);
+        for (t, s) in template.iter().zip(segments) {
+            let (prefix, name, suffix) = match t.split_once('{').and_then(|(p, rest)| Some((p, rest.split_once('}')?))) {
+                Some((prefix, (name, suffix))) => (prefix, name, suffix),
+                None if *t == s => continue,
+                None => return None,
+            };
+            let value: u8 = s.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok()?;
+            match name {
+                "channel" if (1..=16).contains(&value) => channel = Some(value - 1),
+                "number" if value < 128 => number = Some(value),
+                _ => return None,
+            }
+        }
+        if self.channel.is_some_and(|c| channel.is_some_and(|ch| ch != c))
+            || self.number.is_some_and(|n| number.is_some_and(|num| num != n)) {
+            return None;
+        }
+        Some((channel, number))
+    }
+}
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Note => "note",
+        Kind::KeyAt => "keyat",
+        Kind::Cc => "cc",
+        Kind::Program => "program",
+        Kind::ChanAt => "chanat",
+        Kind::Pitchbend => "pitchbend",
+    }
+}
+
+/// An "osc:host:port" endpoint. Inputs listen on the address, outputs send
+/// to it.
+pub struct OscEndpoint {
+    address: String,
+    mappings: Arc<Vec<Mapping>>,
+}
+
+impl OscEndpoint {
+    /// Parse "osc:host:port", with the default mappings. Returns None for
+    /// other endpoint types.
+    pub fn parse(spec: &str) -> Option<Result<OscEndpoint, String>> {
+        let address = spec.strip_prefix("osc:")?;
+        let has_port = address.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        if !has_port {
+            return Some(Err(format!("Invalid address '{}', use host:port", address)));
+        }
+        let mappings = DEFAULT_MAPPINGS.iter()
+                                       .map(|(kind, address, range)| Mapping::parse(kind, address, *range).unwrap())
+                                       .collect();
+        Some(Ok(OscEndpoint{address: address.to_string(), mappings: Arc::new(mappings)}))
+    }
+
+    /// Use these mappings instead of the default ones.
+    pub fn set_mappings(&mut self, mappings: Vec<Mapping>) {
+        self.mappings = Arc::new(mappings);
+    }
+
+    fn resolve(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        let addr = self.address.to_socket_addrs()?
+                               .next()
+                               .ok_or_else(|| format!("Unknown host '{}'", self.address))?;
+        Ok(addr)
+    }
+}
+
+impl Endpoint for OscEndpoint {
+    fn name(&self) -> String {
+        format!("osc:{}", self.address)
+    }
+
+    fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let socket = UdpSocket::bind(self.resolve()?)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let running = Arc::new(AtomicBool::new(true));
+        let (mappings, thread_running) = (self.mappings.clone(), running.clone());
+        thread::spawn(move || receive(socket, mappings, callback, thread_running));
+        Ok(Box::new(OscInput{running}))
+    }
+
+    fn connect_output(&self) -> Result<Output, Box<dyn Error>> {
+        let addr = self.resolve()?;
+        let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(addr)?;
+        Ok(Box::new(OscOutput{socket, mappings: self.mappings.clone()}))
+    }
+}
+
+/// Stops the receiving thread when dropped.
+struct OscInput {
+    running: Arc<AtomicBool>,
+}
+
+impl InputConnection for OscInput {}
+
+impl Drop for OscInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn receive(socket: UdpSocket, mappings: Arc<Vec<Mapping>>, mut callback: InputCallback, running: Arc<AtomicBool>) {
+    let start = Instant::now();
+    let mut buf = vec!(0u8; MAX_PACKET);
+    while running.load(Ordering::SeqCst) {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(ref err) if is_timeout(err) => continue,
+            Err(err) => {
+                eprintln!("Error when receiving OSC: {}", err);
+                return;
+            }
+        };
+        let messages = match OscMessage::decode_packet(&buf[..len]) {
+            Ok(m) => m,
+            Err(err) => {
+                eprintln!("Invalid OSC packet from {}: {}", from, err);
+                continue;
+            }
+        };
+        let timestamp = start.elapsed().as_micros() as u64;
+        for message in messages {
+            for midi in mappings.iter().filter_map(|m| m.to_midi(&message)) {
+                callback(timestamp, &midi.encode());
+            }
+        }
+    }
+}
+
+struct OscOutput {
+    socket: UdpSocket,
+    mappings: Arc<Vec<Mapping>>,
+}
+
+impl OutputConnection for OscOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        if message.is_empty() {
+            return Ok(());
+        }
+        let midi = MidiMessage::parse(message);
+        for osc in self.mappings.iter().filter_map(|m| m.to_osc(&midi)) {
+            self.socket.send(&osc.encode())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Vec<Mapping> {
+        OscEndpoint::parse("osc:127.0.0.1:9000").unwrap().unwrap().mappings.to_vec()
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = OscMessage{address: "/midi/ch1/cc/7".to_string(),
+                                 args: vec!(OscArg::Float(0.5), OscArg::Int(-3), OscArg::String("abcd".to_string()), OscArg::Bool(true))};
+        let bytes = message.encode();
+        assert_eq!(&bytes[..24], b"/midi/ch1/cc/7\0\0,fisT\0\0\0");
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(OscMessage::decode_packet(&bytes).unwrap(), vec!(message.clone()));
+
+        let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+        for m in [&message, &message] {
+            let bytes = m.encode();
+            bundle.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            bundle.extend(bytes);
+        }
+        assert_eq!(OscMessage::decode_packet(&bundle).unwrap(), vec!(message.clone(), message));
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        assert!(OscMessage::decode_packet(b"/abc").is_err());
+        assert!(OscMessage::decode_packet(b"abc\0").is_err());
+        assert!(OscMessage::decode_packet(b"/abc\0\0\0\0,i\0\0\0\0").is_err());
+        assert!(OscMessage::decode_packet(b"/abc\0\0\0\0,x\0\0").is_err());
+        assert_eq!(OscMessage::decode_packet(b"/abc\0\0\0\0").unwrap()[0].args, vec!());
+    }
+
+    #[test]
+    fn converts_with_default_mappings() {
+        let mappings = defaults();
+        let convert = |bytes: &[u8]| -> Vec<OscMessage> {
+            mappings.iter().filter_map(|m| m.to_osc(&MidiMessage::parse(bytes))).collect()
+        };
+        let osc = |address: &str, arg: OscArg| vec!(OscMessage{address: address.to_string(), args: vec!(arg)});
+        assert_eq!(convert(&[0xB0, 7, 127]), osc("/midi/ch1/cc/7", OscArg::Float(1.0)));
+        // Channel mode messages are controllers too
+        assert_eq!(convert(&[0xB0, 123, 0]), osc("/midi/ch1/cc/123", OscArg::Float(0.0)));
+        assert_eq!(convert(&[0xB1, 122, 127]), osc("/midi/ch2/cc/122", OscArg::Float(1.0)));
+        assert_eq!(convert(&[0x92, 60, 0]), osc("/midi/ch3/note/60", OscArg::Float(0.0)));
+        assert_eq!(convert(&[0xC9, 5]), osc("/midi/ch10/program", OscArg::Int(5)));
+        assert_eq!(convert(&[0xE0, 0, 0x40]), osc("/midi/ch1/pitchbend", OscArg::Float(0.0)));
+        assert_eq!(convert(&[0xE0, 0x7F, 0x7F]), osc("/midi/ch1/pitchbend", OscArg::Float(1.0)));
+        assert_eq!(convert(&[0xF8]), vec!());
+
+        let midi = |address: &str, arg: OscArg| -> Vec<Vec<u8>> {
+            let message = OscMessage{address: address.to_string(), args: vec!(arg)};
+            mappings.iter().filter_map(|m| m.to_midi(&message)).map(|m| m.encode()).collect()
+        };
+        assert_eq!(midi("/midi/ch1/cc/7", OscArg::Float(0.5)), vec!(vec!(0xB0, 7, 64)));
+        assert_eq!(midi("/midi/ch16/note/60", OscArg::Float(1.0)), vec!(vec!(0x9F, 60, 127)));
+        assert_eq!(midi("/midi/ch16/note/60", OscArg::Int(0)), vec!(vec!(0x8F, 60, 0)));
+        assert_eq!(midi("/midi/ch2/pitchbend", OscArg::Float(-1.0)), vec!(vec!(0xE1, 0, 0)));
+        assert_eq!(midi("/midi/ch17/cc/7", OscArg::Float(0.5)), Vec::<Vec<u8>>::new());
+        assert_eq!(midi("/midi/ch1/cc/x", OscArg::Float(0.5)), Vec::<Vec<u8>>::new());
+        assert_eq!(midi("/other", OscArg::Float(0.5)), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn scales_configured_mappings() {
+        let volume = Mapping::parse("cc:2:7", "/mixer/volume", Some("0..100")).unwrap();
+        assert_eq!(volume.to_osc(&MidiMessage::parse(&[0xB1, 7, 127])).unwrap().args, vec!(OscArg::Float(100.0)));
+        assert_eq!(volume.to_osc(&MidiMessage::parse(&[0xB0, 7, 127])), None);
+        assert_eq!(volume.to_osc(&MidiMessage::parse(&[0xB1, 8, 127])), None);
+        let message = OscMessage{address: "/mixer/volume".to_string(), args: vec!(OscArg::Float(50.0))};
+        assert_eq!(volume.to_midi(&message), Some(MidiMessage::ControlChg{channel: 1, controller: 7, value: 64}));
+
+        // Inverted range, and a placeholder within a segment
+        let fader = Mapping::parse("cc:*:7", "/fader{channel}", Some("1..0")).unwrap();
+        let message = OscMessage{address: "/fader4".to_string(), args: vec!(OscArg::Float(1.0))};
+        assert_eq!(fader.to_midi(&message), Some(MidiMessage::ControlChg{channel: 3, controller: 7, value: 0}));
+
+        // Without range the MIDI value is used
+        let drum = Mapping::parse("note:10:C3", "/drum/kick", None).unwrap();
+        let message = OscMessage{address: "/drum/kick".to_string(), args: vec!(OscArg::Int(100))};
+        assert_eq!(drum.to_midi(&message), Some(MidiMessage::NoteOn{channel: 9, key: 48, velocity: 100}));
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        assert!(Mapping::parse("sysex", "/a", None).is_err());
+        assert!(Mapping::parse("cc:17", "/a", None).is_err());
+        assert!(Mapping::parse("program:1:5", "/a", None).is_err());
+        assert!(Mapping::parse("cc:1:7:1", "/a", None).is_err());
+        assert!(Mapping::parse("cc", "a", None).is_err());
+        assert!(Mapping::parse("cc", "/a/{value}", None).is_err());
+        assert!(Mapping::parse("cc", "/a", Some("0-1")).is_err());
+        assert!(Mapping::parse("cc", "/a", Some("1..1")).is_err());
+    }
+}
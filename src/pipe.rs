@@ -0,0 +1,441 @@
+//! MIDI through stdin, stdout and named pipes.
+//!
+//! The endpoint "-" reads messages from stdin and writes them to stdout,
+//! "fifo:path" uses a named pipe (FIFO), which is created if it doesn't
+//! exist. Messages are passed in one of these formats, appended to the spec
+//! like "-:hex" or "fifo:/tmp/midi:json":
+//!
+//! * raw: the MIDI bytes (default), read with running status and real-time
+//!   messages anywhere (see stream)
+//! * hex: one message per line as hex bytes ("90 3c 64"), like recordings.
+//!   Timestamps of timed recordings are ignored.
+//! * json: one JSON object per line, as written by --format json. Only the
+//!   "bytes" array is read, written lines have the timestamp in usec since
+//!   the output was opened.
+//!
+//! Invalid lines are reported and skipped. A FIFO input stays open when a
+//! writer closes it, so any number of writers can send one after another
+//! (their last line needs a newline too). A FIFO output drops the messages
+//! while no process reads it. Reading stdin ends when it is closed, see
+//! stdin_closed().
+
+use super::backend::{InputCallback, InputConnection, Output, OutputConnection};
+use super::display::json_fields;
+use super::endpoint::Endpoint;
+use super::midi::MidiMessage;
+use super::recording::{self, RecordFormat};
+use super::stream::{message_len, StreamParser, MAX_SYSEX};
+
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How often threads check for closing
+const MAX_LINE: usize = 4 * MAX_SYSEX; // Longer lines are dropped
+
+static STDIN_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true once reading messages from stdin ended, usually because it
+/// was closed.
+pub fn stdin_closed() -> bool {
+    STDIN_CLOSED.load(Ordering::SeqCst)
+}
+
+/// Returns true if a port name is stdin or stdout.
+pub fn is_stdio(name: &str) -> bool {
+    name == "-" || name.starts_with("-:")
+}
+
+/// Format of the messages in a pipe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PipeFormat {
+    Raw,  // MIDI bytes
+    Hex,  // Hex bytes, one message per line
+    Json, // JSON objects, one message per line
+}
+
+impl PipeFormat {
+    pub fn parse(name: &str) -> Option<PipeFormat> {
+        match name {
+            "raw" => Some(PipeFormat::Raw),
+            "hex" => Some(PipeFormat::Hex),
+            "json" => Some(PipeFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PipeFormat::Raw => "raw",
+            PipeFormat::Hex => "hex",
+            PipeFormat::Json => "json",
+        }
+    }
+
+    /// Parse a line of the hex or JSON format. Returns None for empty lines
+    /// and comments.
+    pub fn parse_line(self, line: &str) -> Result<Option<Vec<u8>>, String> {
+        let message = match self {
+            PipeFormat::Raw => return Ok(None),
+            PipeFormat::Hex => recording::parse_line(line)?.map(|r| r.data),
+            PipeFormat::Json => parse_json_line(line)?,
+        };
+        match message {
+            Some(message) if !is_complete(&message) => {
+                Err(format!("Invalid message {}", message.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")))
+            }
+            message => Ok(message),
+        }
+    }
+
+    /// The bytes to write for a message.
+    fn encode(self, timestamp: u64, message: &[u8]) -> Vec<u8> {
+        match self {
+            PipeFormat::Raw => message.to_vec(),
+            PipeFormat::Hex => {
+                let mut line = vec!();
+                RecordFormat::Hex.write_line(&mut line, timestamp, message).unwrap();
+                line
+            }
+            PipeFormat::Json => {
+                format!("{{\"timestamp\":{},{}}}\n", timestamp, json_fields(&MidiMessage::parse(message), message)).into_bytes()
+            }
+        }
+    }
+}
+
+/// Returns the bytes of the "bytes" array of a JSON object.
+fn parse_json_line(line: &str) -> Result<Option<Vec<u8>>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.starts_with('{') {
+        return Err("Not a JSON object".to_string());
+    }
+    let pos = line.find("\"bytes\"").ok_or("No bytes in line")?;
+    let rest = line[pos + 7..].trim_start()
+                              .strip_prefix(':')
+                              .map(str::trim_start)
+                              .and_then(|r| r.strip_prefix('['))
+                              .ok_or("Invalid bytes")?;
+    let end = rest.find(']').ok_or("Invalid bytes")?;
+    let message = rest[..end].split(',')
+                             .map(str::trim)
+                             .filter(|b| !b.is_empty())
+                             .map(|b| b.parse::<u8>().map_err(|_| format!("Invalid byte '{}'", b)))
+                             .collect::<Result<Vec<u8>, String>>()?;
+    if message.is_empty() {
+        return Err("No message".to_string());
+    }
+    Ok(Some(message))
+}
+
+/// Returns true if the bytes are a single complete message.
+fn is_complete(message: &[u8]) -> bool {
+    match message.first() {
+        Some(0xF0) => message.last() == Some(&0xF7) && message[1..message.len() - 1].iter().all(|b| *b < 0x80),
+        Some(&status) if status >= 0x80 => message.len() == message_len(status) && message[1..].iter().all(|b| *b < 0x80),
+        _ => false,
+    }
+}
+
+/// Stdin and stdout, given as "-", or a named pipe, given as "fifo:path",
+/// with an optional format.
+pub struct PipeEndpoint {
+    path: Option<String>, // None for stdin and stdout
+    format: PipeFormat,
+}
+
+impl PipeEndpoint {
+    /// Returns None if the spec is no pipe.
+    pub fn parse(spec: &str) -> Option<Result<PipeEndpoint, String>> {
+        if is_stdio(spec) {
+            let format = match spec.strip_prefix("-:") {
+                Some(name) => match PipeFormat::parse(name) {
+                    Some(format) => format,
+                    None => return Some(Err(format!("Unknown format in '{}'", spec))),
+                },
+                None => PipeFormat::Raw,
+            };
+            return Some(Ok(PipeEndpoint{path: None, format}));
+        }
+        let rest = spec.strip_prefix("fifo:")?;
+        let (path, format) = match rest.rfind(':').and_then(|pos| PipeFormat::parse(&rest[pos + 1..]).map(|f| (pos, f))) {
+            Some((pos, format)) => (&rest[..pos], format),
+            None => (rest, PipeFormat::Raw),
+        };
+        if path.is_empty() {
+            return Some(Err(format!("Missing path in '{}'", spec)));
+        }
+        Some(Ok(PipeEndpoint{path: Some(path.to_string()), format}))
+    }
+}
+
+impl Endpoint for PipeEndpoint {
+    fn name(&self) -> String {
+        let name = match &self.path {
+            Some(path) => format!("fifo:{}", path),
+            None => "-".to_string(),
+        };
+        match self.format {
+            PipeFormat::Raw => name,
+            format => format!("{}:{}", name, format.name()),
+        }
+    }
+
+    fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let file = match &self.path {
+            Some(path) => {
+                create_fifo(path)?;
+                // Opened for writing too, to keep it open when writers close it
+                OpenOptions::new().read(true)
+                                  .write(true)
+                                  .open(path)
+                                  .map_err(|err| format!("Can't open '{}': {}", path, err))?
+            }
+            None => {
+                let fd = unsafe { libc::dup(libc::STDIN_FILENO) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                unsafe { File::from_raw_fd(fd) }
+            }
+        };
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let name = self.name();
+        let format = self.format;
+        let stdin = self.path.is_none();
+        thread::spawn(move || {
+            if let Err(err) = receive(file, &name, format, callback, thread_running) {
+                eprintln!("Error when reading from '{}': {}", name, err);
+            }
+            if stdin {
+                STDIN_CLOSED.store(true, Ordering::SeqCst);
+            }
+        });
+        Ok(Box::new(PipeInput{running}))
+    }
+
+    fn connect_output(&self) -> Result<Output, Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            create_fifo(path)?;
+        }
+        Ok(Box::new(PipeOutput{path: self.path.clone(), file: None, format: self.format, start: Instant::now()}))
+    }
+}
+
+/// Create a FIFO if the path doesn't exist.
+fn create_fifo(path: &str) -> Result<(), Box<dyn Error>> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => Ok(()),
+        Ok(_) => Err(format!("'{}' is not a FIFO", path).into()),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            let c_path = CString::new(Path::new(path).as_os_str().as_bytes())?;
+            if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                return Err(format!("Can't create '{}': {}", path, io::Error::last_os_error()).into());
+            }
+            Ok(())
+        }
+        Err(err) => Err(format!("Can't open '{}': {}", path, err).into()),
+    }
+}
+
+/// Stops the reading thread when dropped.
+struct PipeInput {
+    running: Arc<AtomicBool>,
+}
+
+impl InputConnection for PipeInput {}
+
+impl Drop for PipeInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+struct PipeOutput {
+    path: Option<String>,
+    file: Option<File>, // The FIFO, while it has a reader
+    format: PipeFormat,
+    start: Instant,
+}
+
+impl OutputConnection for PipeOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let bytes = self.format.encode(self.start.elapsed().as_micros() as u64, message);
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+                return Ok(());
+            }
+        };
+        if self.file.is_none() {
+            self.file = open_writer(path)?;
+        }
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(()), // No reader
+        };
+        if let Err(err) = file.write_all(&bytes) {
+            // Reopened with the next message
+            self.file = None;
+            if err.kind() != ErrorKind::BrokenPipe {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open a FIFO for writing, returns None if no process reads it.
+fn open_writer(path: &str) -> io::Result<Option<File>> {
+    let file = match OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.raw_os_error() == Some(libc::ENXIO) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    // Only opening doesn't block, writes wait for the reader
+    unsafe {
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(Some(file))
+}
+
+/// Wait up to POLL_INTERVAL until the file can be read.
+fn wait_readable(file: &File) -> io::Result<bool> {
+    let mut fds = libc::pollfd{fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0};
+    match unsafe { libc::poll(&mut fds, 1, POLL_INTERVAL.as_millis() as libc::c_int) } {
+        n if n > 0 => Ok(true),
+        0 => Ok(false),
+        _ => {
+            let err = io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+    }
+}
+
+fn receive(mut file: File, name: &str, format: PipeFormat, mut callback: InputCallback, running: Arc<AtomicBool>)
+        -> io::Result<()> {
+    let start = Instant::now();
+    let mut parser = StreamParser::new();
+    let mut reported = 0;
+    let mut line = vec!();
+    let mut too_long = false;
+    let handle_line = |line: &[u8], timestamp: u64, callback: &mut InputCallback| {
+        match format.parse_line(&String::from_utf8_lossy(line)) {
+            Ok(Some(message)) => callback(timestamp, &message),
+            Ok(None) => (),
+            Err(err) => eprintln!("Invalid line from '{}': {}", name, err),
+        }
+    };
+    let mut buf = [0u8; 4096];
+    while running.load(Ordering::SeqCst) {
+        if !wait_readable(&file)? {
+            continue;
+        }
+        let len = match file.read(&mut buf) {
+            Ok(len) => len,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let timestamp = start.elapsed().as_micros() as u64;
+        if len == 0 {
+            // Closed, a last line can lack the newline
+            if !line.is_empty() && !too_long {
+                handle_line(&line, timestamp, &mut callback);
+            }
+            return Ok(());
+        }
+        if format == PipeFormat::Raw {
+            parser.parse(&buf[..len], |message| callback(timestamp, message));
+            if parser.dropped() > reported {
+                eprintln!("Dropped {} invalid bytes from '{}'", parser.dropped() - reported, name);
+                reported = parser.dropped();
+            }
+            continue;
+        }
+        for &byte in &buf[..len] {
+            if byte == b'\n' {
+                if too_long {
+                    eprintln!("Invalid line from '{}': Line too long", name);
+                } else {
+                    handle_line(&line, timestamp, &mut callback);
+                }
+                line.clear();
+                too_long = false;
+            } else if line.len() < MAX_LINE {
+                line.push(byte);
+            } else {
+                line.clear();
+                too_long = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        let endpoint = PipeEndpoint::parse("-").unwrap().unwrap();
+        assert_eq!((endpoint.path, endpoint.format), (None, PipeFormat::Raw));
+        let endpoint = PipeEndpoint::parse("-:json").unwrap().unwrap();
+        assert_eq!((endpoint.name().as_str(), endpoint.format), ("-:json", PipeFormat::Json));
+        let endpoint = PipeEndpoint::parse("fifo:/tmp/midi:in:hex").unwrap().unwrap();
+        assert_eq!((endpoint.path.as_deref(), endpoint.format), (Some("/tmp/midi:in"), PipeFormat::Hex));
+        assert_eq!(endpoint.name(), "fifo:/tmp/midi:in:hex");
+        assert_eq!(PipeEndpoint::parse("fifo:/tmp/midi").unwrap().unwrap().name(), "fifo:/tmp/midi");
+        assert!(PipeEndpoint::parse("-:xml").unwrap().is_err());
+        assert!(PipeEndpoint::parse("fifo:").unwrap().is_err());
+        assert!(PipeEndpoint::parse("serial:/dev/ttyUSB0").is_none());
+    }
+
+    #[test]
+    fn parses_lines() {
+        assert_eq!(PipeFormat::Hex.parse_line("90 3c 64"), Ok(Some(vec!(0x90, 60, 100))));
+        assert_eq!(PipeFormat::Hex.parse_line("1250: f0 7d 01 f7"), Ok(Some(vec!(0xF0, 0x7D, 1, 0xF7))));
+        assert_eq!(PipeFormat::Hex.parse_line("# comment"), Ok(None));
+        assert_eq!(PipeFormat::Hex.parse_line("90 3c"), Err("Invalid message 90 3c".to_string()));
+        let line = r#"{"timestamp":12,"port":0,"port_name":"keys","type":"cc","channel":1,"controller":7,"value":100,"bytes":[176,7,100]}"#;
+        assert_eq!(PipeFormat::Json.parse_line(line), Ok(Some(vec!(0xB0, 7, 100))));
+        assert_eq!(PipeFormat::Json.parse_line(r#" { "bytes" : [ 248 ] } "#), Ok(Some(vec!(0xF8))));
+        assert_eq!(PipeFormat::Json.parse_line(""), Ok(None));
+        assert_eq!(PipeFormat::Json.parse_line(r#"{"type":"clock"}"#), Err("No bytes in line".to_string()));
+        assert_eq!(PipeFormat::Json.parse_line(r#"{"bytes":[144,300,1]}"#), Err("Invalid byte '300'".to_string()));
+    }
+
+    #[test]
+    fn encodes_messages() {
+        assert_eq!(PipeFormat::Raw.encode(5, &[0x90, 60, 100]), vec!(0x90, 60, 100));
+        assert_eq!(PipeFormat::Hex.encode(5, &[0x90, 60, 100]), b"90 3c 64\n".to_vec());
+        let line = String::from_utf8(PipeFormat::Json.encode(5, &[0xC1, 3])).unwrap();
+        assert!(line.starts_with("{\"timestamp\":5,\"type\":\""), "{}", line);
+        assert!(line.ends_with(",\"bytes\":[193,3]}\n"), "{}", line);
+        // Written lines are read again
+        assert_eq!(PipeFormat::Json.parse_line(&line), Ok(Some(vec!(0xC1, 3))));
+    }
+}
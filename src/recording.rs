@@ -0,0 +1,135 @@
+//! Recording formats and playback.
+//!
+//! Recordings are text files with one message per line, written as hex bytes
+//! ("90 3c 64"). The timed format prefixes every line with the timestamp of
+//! the message in usec ("1250000: 90 3c 64"), so it can be played back with
+//! the original timing. Empty lines and lines starting with '#' are ignored
+//! when reading.
+
+use super::backend::OutputConnection;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time between the messages of a recording without timestamps.
+pub const UNTIMED_GAP: Duration = Duration::from_millis(1);
+
+/// Format of recorded files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    Hex,   // Hex bytes only
+    Timed, // Timestamp and hex bytes
+}
+
+impl RecordFormat {
+    /// Parse a format name as given on the command line.
+    pub fn parse(name: &str) -> Option<RecordFormat> {
+        match name {
+            "hex" => Some(RecordFormat::Hex),
+            "timed" => Some(RecordFormat::Timed),
+            _ => None,
+        }
+    }
+
+    /// Write a message as a line in this format.
+    pub fn write_line<W: Write>(self, w: &mut W, timestamp: u64, message: &[u8]) -> io::Result<()> {
+        let bytes: Vec<String> = message.iter().map(|b| format!("{:02x}", b)).collect();
+        match self {
+            RecordFormat::Hex => writeln!(w, "{}", bytes.join(" ")),
+            RecordFormat::Timed => writeln!(w, "{}: {}", timestamp, bytes.join(" ")),
+        }
+    }
+}
+
+/// A message read from a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recorded {
+    pub timestamp: Option<u64>, // usec, only in the timed format
+    pub data: Vec<u8>,
+}
+
+/// Error when reading a recording, with the line number (starting at 1).
+#[derive(Debug)]
+pub struct ReadError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ReadError {}
+
+/// Parse a line of a recording in either format. Returns None for empty
+/// lines and comments.
+pub fn parse_line(line: &str) -> Result<Option<Recorded>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (timestamp, bytes) = match line.find(':') {
+        Some(pos) => {
+            let timestamp = line[..pos].trim().parse::<u64>()
+                                       .map_err(|_| format!("Invalid timestamp '{}'", line[..pos].trim()))?;
+            (Some(timestamp), &line[pos + 1..])
+        }
+        None => (None, line),
+    };
+    let data = bytes.split_whitespace()
+                    .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("Invalid byte '{}'", b)))
+                    .collect::<Result<Vec<u8>, String>>()?;
+    if data.is_empty() {
+        return Err("No message".to_string());
+    }
+    if data[0] < 0x80 {
+        return Err(format!("Message starts with data byte {:02x}", data[0]));
+    }
+    Ok(Some(Recorded{timestamp, data}))
+}
+
+/// Read all messages of a recording.
+pub fn read<R: BufRead>(r: R) -> Result<Vec<Recorded>, Box<dyn Error>> {
+    let mut messages = vec!();
+    for (i, line) in r.lines().enumerate() {
+        let parsed = parse_line(&line?).map_err(|message| ReadError{line: i + 1, message})?;
+        messages.extend(parsed);
+    }
+    Ok(messages)
+}
+
+/// Send recorded messages to an output.
+///
+/// Timed messages keep their distance to the first message of the
+/// recording, scaled by speed (2.0 plays twice as fast). Messages without
+/// timestamp are sent UNTIMED_GAP after the previous one. Returns the number
+/// of sent messages.
+pub fn play(messages: &[Recorded], out: &mut dyn OutputConnection, speed: f64) -> Result<usize, Box<dyn Error>> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err("Invalid playback speed".into());
+    }
+    let start = Instant::now();
+    let first = messages.iter().find_map(|m| m.timestamp);
+    let mut time = start;
+    for (i, m) in messages.iter().enumerate() {
+        time = match (m.timestamp, first) {
+            (Some(t), Some(first)) => {
+                let offset = Duration::try_from_secs_f64(t.saturating_sub(first) as f64 / 1e6 / speed).ok();
+                offset.and_then(|offset| start.checked_add(offset)).ok_or("Playback speed too slow")?
+            }
+            _ if i > 0 => time + UNTIMED_GAP,
+            _ => time,
+        };
+        let now = Instant::now();
+        if time > now {
+            thread::sleep(time - now);
+        }
+        out.send(&m.data)?;
+    }
+    Ok(messages.len())
+}
@@ -0,0 +1,872 @@
+//! Routing of received messages.
+//!
+//! A Route handles the messages received on one input for one line of the
+//! routing config: it filters by channel, forwards to the output with the
+//! chords, arpeggiator, clock transform and parameter mapping applied, and
+//! hands the messages to the monitor, recorder, terminal UI and web server
+//! queues. The Router connects the routes of a config to the ports of a
+//! backend. Routes can be added, removed, muted, transposed, get other
+//! channels and chords while running.
+
+use super::arpeggiator::{ArpOptions, Arpeggiator};
+use super::backend::{Backend, InputConnection};
+use super::chord::{ChordOptions, Chords};
+use super::clocktransform::{ClockOptions, ClockTransform};
+use super::mtc::MtcCommand;
+use super::rpn::{self, Decoded, ParamDecoder, ParamKind};
+use super::scheduler::{Scheduler, SharedOutput};
+use super::traffic::{SharedCounters, Traffic};
+use super::tui::Event;
+use super::worker::{QueueSender, Received};
+use super::MidiMessage;
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicU8, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Maps an RPN/ NRPN parameter to another one when forwarding.
+pub type ParamMap = ((ParamKind, u16), (ParamKind, u16));
+
+/// A line of the routing config.
+#[derive(Clone)]
+pub struct Config {
+    pub in_port: usize,
+    pub in_channel: u8,           // 1 - 16, 0 = all
+    pub out_port: usize,          // usize::MAX = don't forward
+    pub out_channel: u8,          // 1 - 16, 0 = unchanged
+    pub transpose: i8,            // Semitones added to notes
+    pub param_maps: Vec<ParamMap>,
+    pub clock: ClockOptions,
+    pub arp: Option<ArpOptions>,
+    pub chord: Option<ChordOptions>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config{
+            in_port: usize::MAX,
+            in_channel: 0,
+            out_port: usize::MAX,
+            out_channel: 0,
+            transpose: 0,
+            param_maps: vec!(),
+            clock: ClockOptions::default(),
+            arp: None,
+            chord: None,
+        }
+    }
+}
+
+impl Config {
+    /// True if the route has an output port.
+    pub fn forwards(&self) -> bool {
+        self.out_port < usize::MAX
+    }
+
+    /// Name of the route for the statistics, e.g. "1 -> 2 (channel 1 -> 3)".
+    pub fn name(&self) -> String {
+        let mut name = format!("{} -> {}", self.in_port, self.out_port);
+        if self.in_channel > 0 || self.out_channel > 0 {
+            let channel = |c: u8| if c > 0 { c.to_string() } else { "all".to_string() };
+            name += &format!(" (channel {} -> {})", channel(self.in_channel), channel(self.out_channel));
+        }
+        name
+    }
+}
+
+/// Where the received messages go besides the outputs.
+///
+/// The monitor gets the messages of every route, recorder, UI and web server
+/// only those of the first route of every input port, to avoid duplicates.
+#[derive(Clone, Default)]
+pub struct Sinks {
+    pub monitor: Option<QueueSender<Received>>,
+    pub record: Option<QueueSender<Received>>,
+    pub tui: Option<QueueSender<Event>>,
+    pub web: Option<QueueSender<Received>>,
+    pub mtc_chase: Option<(usize, Sender<MtcCommand>)>, // Port followed by the MTC generator
+}
+
+/// Settings of a route which can be changed while it is running.
+struct Settings {
+    in_channel: AtomicU8,
+    out_channel: AtomicU8,
+    transpose: AtomicI8,
+    muted: AtomicBool,
+    chord: Mutex<Option<ChordOptions>>,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Settings{
+            in_channel: AtomicU8::new(config.in_channel),
+            out_channel: AtomicU8::new(config.out_channel),
+            transpose: AtomicI8::new(config.transpose),
+            muted: AtomicBool::new(false),
+            chord: Mutex::new(config.chord.clone()),
+        }
+    }
+}
+
+/// The state of a route, owned by the callback of its input.
+pub struct Route {
+    route: usize,
+    in_port: usize,
+    settings: Arc<Settings>,
+    held: Box<[[Option<Held>; 128]; 16]>, // Settings of the sounding notes
+    chords: Chords,
+    out: Option<SharedOutput>,
+    clock_transform: Option<ClockTransform>,
+    arpeggiator: Option<Arpeggiator>,
+    param_maps: Vec<ParamMap>,
+    param_decoder: ParamDecoder,
+    selected: [Option<(ParamKind, u16)>; 16], // Mapped parameter selected on the output channels
+    port_counters: Option<SharedCounters>,
+    route_counters: SharedCounters,
+    monitor_tx: Option<QueueSender<Received>>,
+    record_tx: Option<QueueSender<Received>>,
+    tui_tx: Option<QueueSender<Event>>,
+    web_tx: Option<QueueSender<Received>>,
+    mtc_tx: Option<Sender<MtcCommand>>,
+}
+
+impl Route {
+    /// Handle a message received on the input port of the route.
+    pub fn receive(&mut self, timestamp: u64, message: &[u8]) {
+        if message.is_empty() {
+            return;
+        }
+        let m = MidiMessage::parse(message);
+        if let Some(counters) = self.port_counters.as_ref() {
+            counters.lock().unwrap().count(Instant::now(), &m, message.len());
+        }
+
+        let in_channel = self.settings.in_channel.load(Ordering::Relaxed);
+        if in_channel > 0 && message[0] < 0xF0 && (message[0] & 0x0F) != in_channel - 1 {
+            if self.out.is_some() {
+                self.route_counters.lock().unwrap().filtered += 1;
+            }
+            return; // Not listening on this channel
+        }
+
+        if let Some(tx) = self.mtc_tx.as_ref() {
+            if let MidiMessage::TimingClock | MidiMessage::Start | MidiMessage::Continue
+                    | MidiMessage::Stop | MidiMessage::SongPos{..} = m {
+                tx.send(MtcCommand::Chase(m, Instant::now())).ok();
+            }
+        }
+
+        if self.out.is_some() {
+            // Filter some messages (for Push2)
+            if let MidiMessage::NoteOn{channel: _, key, velocity: _} = m {
+                if key <= 10 {
+                    self.route_counters.lock().unwrap().filtered += 1;
+                    return;
+                }
+            }
+            if self.settings.muted.load(Ordering::Relaxed) {
+                self.route_counters.lock().unwrap().filtered += 1;
+                if let Some(arp) = self.arpeggiator.as_ref() {
+                    arp.release_all(); // Its NoteOffs don't arrive
+                }
+            } else {
+                self.forward(&m, message);
+            }
+        }
+
+        if let Some(tx) = self.monitor_tx.as_ref() {
+            // Print received data to screen
+            tx.send(Received{route: self.route, port: self.in_port, timestamp, data: message.to_vec()});
+        }
+
+        if let Some(tx) = self.tui_tx.as_ref() {
+            // Hand received data to the terminal UI
+            tx.send(Event::Midi{port: self.in_port, timestamp, data: message.to_vec()});
+        }
+
+        if let Some(tx) = self.record_tx.as_ref() {
+            // Write received data to file
+            tx.send(Received{route: self.route, port: self.in_port, timestamp, data: message.to_vec()});
+        }
+
+        if let Some(tx) = self.web_tx.as_ref() {
+            // Stream received data to the browser
+            tx.send(Received{route: self.route, port: self.in_port, timestamp, data: message.to_vec()});
+        }
+    }
+
+    /// Forward data to the output port.
+    fn forward(&mut self, m: &MidiMessage, message: &[u8]) {
+        if self.out.is_none() {
+            return;
+        }
+        let mut buf = [0u8; 3];
+        let (message, out_channel) = match map_note(&self.settings, &mut self.held, message, &mut buf) {
+            Some(mapped) => mapped,
+            None => {
+                self.route_counters.lock().unwrap().filtered += 1;
+                return; // Out of range
+            }
+        };
+        // Notes are expanded to chords, if set
+        let chord = if (0x80..0xB0).contains(&message[0]) {
+            let options = self.settings.chord.lock().unwrap();
+            self.chords.process(options.as_ref(), message)
+        } else {
+            None
+        };
+        match chord {
+            Some(messages) => {
+                for message in messages {
+                    self.send(&MidiMessage::parse(&message), &message, out_channel);
+                }
+            }
+            None => self.send(m, message, out_channel),
+        }
+    }
+
+    /// Send a message to the given output channel, with the arpeggiator,
+    /// clock transform and parameter mapping applied.
+    fn send(&mut self, m: &MidiMessage, message: &[u8], out_channel: u8) {
+        let out = match self.out.as_ref() {
+            Some(out) => out,
+            None => return,
+        };
+        // Notes are played by the arpeggiator, if there is one. It keeps
+        // track of the channels of its own notes.
+        if let Some(arp) = self.arpeggiator.as_ref() {
+            let current = self.settings.out_channel.load(Ordering::Relaxed);
+            if arp.process(Instant::now(), &MidiMessage::parse(message), current) {
+                return;
+            }
+        }
+        let counters = &self.route_counters;
+        // Clock and transport are sent by the clock transform, if there is one
+        let handled = match self.clock_transform.as_mut() {
+            Some(t) => t.process(Instant::now(), m, |bytes| forward_message(out, bytes, out_channel, counters)),
+            None => false,
+        };
+        if handled {
+            return;
+        }
+        // Data entry for a mapped parameter is sent after selecting the new
+        // parameter, everything else is forwarded as it is
+        if !self.param_maps.is_empty() {
+            if let MidiMessage::ControlChg{channel, controller, ..} = *m {
+                let selected = &mut self.selected[if out_channel > 0 { out_channel - 1 } else { channel } as usize & 0x0F];
+                let param = match self.param_decoder.process(m) {
+                    Decoded::Event(e) => e.param(),
+                    _ => None,
+                };
+                match self.param_maps.iter().find(|(from, _)| Some(*from) == param) {
+                    Some(&(_, to)) if *selected != Some(to) => {
+                        for m in rpn::select(channel, to).iter() {
+                            forward_message(out, &m.encode(), out_channel, counters);
+                        }
+                        *selected = Some(to);
+                    }
+                    Some(_) => (),
+                    None if rpn::is_selection(controller) => *selected = None,
+                    None => (),
+                }
+            }
+        }
+        forward_message(out, message, out_channel, counters);
+    }
+}
+
+/// Transposition and output channel of a sounding note.
+#[derive(Clone, Copy)]
+struct Held {
+    semitones: i8,
+    out_channel: u8,
+}
+
+/// Transpose a note message and select its output channel by the settings
+/// of the route. NoteOffs and aftertouch get the settings of their NoteOn,
+/// so changing them doesn't leave notes hanging. Returns the message with
+/// its output channel, or None for notes out of range.
+fn map_note<'a>(settings: &Settings, held: &mut [[Option<Held>; 128]; 16], message: &'a [u8],
+                buf: &'a mut [u8; 3]) -> Option<(&'a [u8], u8)> {
+    let status = message[0] & 0xF0;
+    let current = Held{semitones: settings.transpose.load(Ordering::Relaxed),
+                       out_channel: settings.out_channel.load(Ordering::Relaxed)};
+    if message.len() != 3 || !(0x80..=0xA0).contains(&status) {
+        return Some((message, current.out_channel));
+    }
+    let held = &mut held[(message[0] & 0x0F) as usize][(message[1] & 0x7F) as usize];
+    let note = match status {
+        0x90 if message[2] > 0 => {
+            *held = Some(current);
+            current
+        }
+        0xA0 => held.unwrap_or(current),
+        _ => held.take().unwrap_or(current),
+    };
+    if note.semitones == 0 {
+        return Some((message, note.out_channel));
+    }
+    let key = message[1] as i16 + note.semitones as i16;
+    if !(0..128).contains(&key) {
+        return None;
+    }
+    buf.copy_from_slice(message);
+    buf[1] = key as u8;
+    Some((&buf[..], note.out_channel))
+}
+
+/// Send a message to an output port and count it in the route statistics.
+///
+/// If an output channel is set (1 - 16), the channel of channel messages is
+/// changed accordingly. Only the first error of a route is printed.
+fn forward_message(out: &SharedOutput, message: &[u8], out_channel: u8, counters: &SharedCounters) {
+    let mut buf = [0u8; 3];
+    let message = if out_channel > 0 && message.len() <= 3 && message[0] < 0xF0 {
+        let buf = &mut buf[..message.len()];
+        buf.copy_from_slice(message);
+        buf[0] = (buf[0] & 0xF0) | ((out_channel - 1) & 0x0F);
+        &*buf
+    } else {
+        message
+    };
+    let result = out.lock().unwrap().send(message);
+    let mut counters = counters.lock().unwrap();
+    match result {
+        Ok(_) => counters.count(Instant::now(), &MidiMessage::parse(message), message.len()),
+        Err(_) => {
+            if counters.errors == 0 {
+                eprintln!("Error when forwarding message ...");
+            }
+            counters.errors += 1;
+        }
+    }
+}
+
+/// A connected route.
+struct RouteHandle {
+    config: Config,
+    settings: Arc<Settings>,
+    out: Option<SharedOutput>,
+    _input: Box<dyn InputConnection>, // Closed when dropped
+}
+
+/// A route with its current settings.
+pub struct RouteState {
+    pub route: usize,
+    pub config: Config,
+    pub muted: bool,
+}
+
+/// The connected routes of a config. Dropping the router closes the
+/// connections, which closes the queues of the sinks.
+///
+/// Routes are numbered in the order they were added, removed routes keep
+/// their number. The sinks of a port stay with its first route, even if it is
+/// removed.
+pub struct Router {
+    routes: Vec<Option<RouteHandle>>,
+    sinks: Sinks,
+    traffic: Traffic,
+    fed_ports: HashSet<usize>, // Ports whose first route feeds the sinks
+}
+
+impl Router {
+    /// Connect the routes of the configs. Ports and routes are added to the
+    /// traffic statistics.
+    pub fn start(backend: &dyn Backend, configs: &[Config], sinks: &Sinks, traffic: &Traffic) -> Result<Router, Box<dyn Error>> {
+        let mut router = Router{routes: vec!(), sinks: sinks.clone(), traffic: traffic.clone(), fed_ports: HashSet::new()};
+        for config in configs {
+            router.add(backend, config.clone())?;
+        }
+        Ok(router)
+    }
+
+    /// Connect another route, returns its number.
+    pub fn add(&mut self, backend: &dyn Backend, config: Config) -> Result<usize, Box<dyn Error>> {
+        let route = self.routes.len();
+        let in_port_name = backend.input_ports()?.get(config.in_port).ok_or("Invalid port number")?.clone();
+        if config.in_channel > 16 || config.out_channel > 16 {
+            return Err("Invalid channel".into());
+        }
+        let first_of_port = !self.fed_ports.contains(&config.in_port);
+        let out = if config.forwards() {
+            Some(Arc::new(Mutex::new(backend.connect_output(config.out_port)?)))
+        } else {
+            None
+        };
+        let route_counters = if config.forwards() { self.traffic.add_route(&config.name()) } else { SharedCounters::default() };
+        let clock_transform = match out.as_ref() {
+            Some(out) if config.clock.is_active() => {
+                let scheduler = if config.clock.needs_scheduler() {
+                    Some(Scheduler::new(out.clone(), Some(route_counters.clone())))
+                } else {
+                    None
+                };
+                Some(ClockTransform::new(config.clock, scheduler))
+            }
+            _ => None,
+        };
+        let arpeggiator = match (out.as_ref(), config.arp) {
+            (Some(out), Some(options)) => Some(Arpeggiator::new(options, out.clone(), Some(route_counters.clone()))),
+            _ => None,
+        };
+        let settings = Arc::new(Settings::new(&config));
+        let sinks = &self.sinks;
+        let mut r = Route{
+            route,
+            in_port: config.in_port,
+            settings: settings.clone(),
+            held: Box::new([[None; 128]; 16]),
+            chords: Chords::new(),
+            out: out.clone(),
+            clock_transform,
+            arpeggiator,
+            param_maps: config.param_maps.clone(),
+            param_decoder: ParamDecoder::new(),
+            selected: [None; 16],
+            port_counters: if first_of_port { Some(self.traffic.add_port(config.in_port, &in_port_name)) } else { None },
+            route_counters,
+            monitor_tx: sinks.monitor.clone(),
+            record_tx: if first_of_port { sinks.record.clone() } else { None },
+            tui_tx: if first_of_port { sinks.tui.clone() } else { None },
+            web_tx: if first_of_port { sinks.web.clone() } else { None },
+            mtc_tx: match sinks.mtc_chase.as_ref() {
+                Some((port, tx)) if first_of_port && *port == config.in_port => Some(tx.clone()),
+                _ => None,
+            },
+        };
+        let input = backend.connect_input(config.in_port, Box::new(move |timestamp, message| r.receive(timestamp, message)))?;
+        self.fed_ports.insert(config.in_port);
+        self.routes.push(Some(RouteHandle{config, settings, out, _input: input}));
+        Ok(route)
+    }
+
+    /// Disconnect a route.
+    pub fn remove(&mut self, route: usize) -> Result<(), String> {
+        self.routes.get_mut(route).and_then(|r| r.take()).map(|_| ()).ok_or_else(|| format!("No route {}", route))
+    }
+
+    fn settings(&self, route: usize) -> Result<&Settings, String> {
+        match self.routes.get(route) {
+            Some(Some(r)) => Ok(&r.settings),
+            _ => Err(format!("No route {}", route)),
+        }
+    }
+
+    /// Stop or resume forwarding. Notes sounding when muting are not ended,
+    /// see panic().
+    pub fn set_muted(&self, route: usize, muted: bool) -> Result<(), String> {
+        self.settings(route)?.muted.store(muted, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Set the semitones added to the forwarded notes.
+    pub fn set_transpose(&self, route: usize, semitones: i8) -> Result<(), String> {
+        self.settings(route)?.transpose.store(semitones, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Set the chord played for every note, None to forward the notes
+    /// unchanged. Held notes end with the chord they started.
+    pub fn set_chord(&self, route: usize, chord: Option<ChordOptions>) -> Result<(), String> {
+        *self.settings(route)?.chord.lock().unwrap() = chord;
+        Ok(())
+    }
+
+    /// Set the input and output channel (1 - 16, 0 = all/ unchanged). Notes
+    /// that are sounding end on the channel they were sent to.
+    pub fn set_channels(&self, route: usize, in_channel: u8, out_channel: u8) -> Result<(), String> {
+        if in_channel > 16 || out_channel > 16 {
+            return Err("Invalid channel".to_string());
+        }
+        let settings = self.settings(route)?;
+        settings.in_channel.store(in_channel, Ordering::Relaxed);
+        settings.out_channel.store(out_channel, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The connected routes.
+    pub fn routes(&self) -> Vec<RouteState> {
+        self.routes.iter().enumerate().filter_map(|(route, r)| {
+            let r = r.as_ref()?;
+            let mut config = r.config.clone();
+            config.in_channel = r.settings.in_channel.load(Ordering::Relaxed);
+            config.out_channel = r.settings.out_channel.load(Ordering::Relaxed);
+            config.transpose = r.settings.transpose.load(Ordering::Relaxed);
+            config.chord = r.settings.chord.lock().unwrap().clone();
+            Some(RouteState{route, config, muted: r.settings.muted.load(Ordering::Relaxed)})
+        }).collect()
+    }
+
+    /// Send Sustain off, All Sound Off and All Notes Off on all channels of
+    /// all outputs. Returns the number of outputs.
+    pub fn panic(&self) -> usize {
+        let mut ports = HashSet::new();
+        for r in self.routes.iter().flatten() {
+            let out = match r.out.as_ref() {
+                Some(out) if ports.insert(r.config.out_port) => out,
+                _ => continue,
+            };
+            let mut out = out.lock().unwrap();
+            for channel in 0..16 {
+                for controller in [64, 120, 123].iter() {
+                    out.send(&[0xB0 | channel, *controller, 0]).ok();
+                }
+            }
+        }
+        ports.len()
+    }
+
+    /// Close all input connections.
+    pub fn close(self) {
+        drop(self.routes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::recording::RecordFormat;
+    use crate::rpn;
+    use crate::worker;
+
+    fn config(in_port: usize, in_channel: u8, out_port: usize, out_channel: u8) -> Config {
+        Config{in_port, in_channel, out_port, out_channel, ..Config::default()}
+    }
+
+    fn start(backend: &MockBackend, configs: &[Config], traffic: &Traffic) -> Router {
+        Router::start(backend, configs, &Sinks::default(), traffic).unwrap()
+    }
+
+    #[test]
+    fn forwards_to_output_port() {
+        let backend = MockBackend::new(&["in"], &["out 0", "out 1"]);
+        let traffic = Traffic::new();
+        let _router = start(&backend, &[config(0, 0, 1, 0)], &traffic);
+        backend.receive(0, 100, &[0x90, 60, 100]);
+        backend.receive(0, 200, &[0xF0, 0x7D, 0x01, 0xF7]);
+        assert_eq!(backend.sent(0), Vec::<Vec<u8>>::new());
+        assert_eq!(backend.sent(1), vec!(vec!(0x90, 60, 100), vec!(0xF0, 0x7D, 0x01, 0xF7)));
+        let json = traffic.to_json();
+        assert!(json.contains("{\"port\":0,\"name\":\"in\",\"messages\":2,"), "{}", json);
+        assert!(json.contains("{\"route\":\"0 -> 1\",\"messages\":2,"), "{}", json);
+    }
+
+    #[test]
+    fn changes_output_channel() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let _router = start(&backend, &[config(0, 0, 0, 3)], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        backend.receive(0, 0, &[0xB5, 7, 90]);
+        backend.receive(0, 0, &[0xF8]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x92, 60, 100), vec!(0xB2, 7, 90), vec!(0xF8)));
+    }
+
+    #[test]
+    fn filters_input_channel() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let traffic = Traffic::new();
+        let _router = start(&backend, &[config(0, 2, 0, 0)], &traffic);
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        backend.receive(0, 0, &[0x91, 61, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x91, 61, 100)));
+        assert!(traffic.to_json().contains("\"route\":\"0 -> 0 (channel 2 -> all)\",\"messages\":1,\"bytes\":3,\"filtered\":1,"));
+    }
+
+    #[test]
+    fn filters_push2_pads() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let _router = start(&backend, &[config(0, 0, 0, 0)], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 10, 100]);
+        backend.receive(0, 0, &[0x90, 11, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x90, 11, 100)));
+    }
+
+    #[test]
+    fn splits_port_to_several_outputs() {
+        let backend = MockBackend::new(&["in"], &["out 0", "out 1"]);
+        let _router = start(&backend, &[config(0, 1, 0, 0), config(0, 2, 1, 1)], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        backend.receive(0, 0, &[0x91, 62, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x90, 60, 100)));
+        assert_eq!(backend.sent(1), vec!(vec!(0x90, 62, 100)));
+    }
+
+    #[test]
+    fn maps_parameters() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let mut c = config(0, 0, 0, 0);
+        c.param_maps = vec!((rpn::parse_param("nrpn:1234").unwrap(), rpn::parse_param("nrpn:42").unwrap()));
+        let _router = start(&backend, &[c], &Traffic::new());
+        // NRPN 1234 (MSB 9, LSB 82), data 64
+        for m in [[0xB0, 99, 9], [0xB0, 98, 82], [0xB0, 6, 64]].iter() {
+            backend.receive(0, 0, m);
+        }
+        assert_eq!(backend.sent(0), vec!(vec!(0xB0, 99, 9), vec!(0xB0, 98, 82), vec!(0xB0, 99, 0), vec!(0xB0, 98, 42),
+                                         vec!(0xB0, 6, 64)));
+
+        // Further data entry is mapped without selecting again
+        backend.receive(0, 0, &[0xB0, 38, 5]);
+        backend.receive(0, 0, &[0xB0, 96, 1]);
+        assert_eq!(backend.sent(0)[5..], [vec!(0xB0, 38, 5), vec!(0xB0, 96, 1)]);
+
+        // Other parameters, RPN null and 14-bit controllers pass unchanged
+        let other = [[0xB0, 101, 0], [0xB0, 100, 0], [0xB0, 6, 2], [0xB0, 101, 127], [0xB0, 100, 127],
+                     [0xB0, 7, 100], [0xB0, 39, 3], [0xB0, 7, 90]];
+        for m in other.iter() {
+            backend.receive(0, 0, m);
+        }
+        assert_eq!(backend.sent(0)[7..], other.iter().map(|m| m.to_vec()).collect::<Vec<_>>()[..]);
+
+        // The mapped parameter is selected again after that
+        for m in [[0xB0, 99, 9], [0xB0, 98, 82], [0xB0, 6, 1]].iter() {
+            backend.receive(0, 0, m);
+        }
+        assert_eq!(backend.sent(0)[15..], [vec!(0xB0, 99, 9), vec!(0xB0, 98, 82), vec!(0xB0, 99, 0), vec!(0xB0, 98, 42),
+                                           vec!(0xB0, 6, 1)]);
+    }
+
+    #[test]
+    fn divides_clock() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let mut c = config(0, 0, 0, 0);
+        c.clock = ClockOptions::parse("div=3").unwrap();
+        let _router = start(&backend, &[c], &Traffic::new());
+        backend.receive(0, 0, &[0xFA]);
+        for _ in 0..7 {
+            backend.receive(0, 0, &[0xF8]);
+        }
+        backend.receive(0, 0, &[0xFC]);
+        assert_eq!(backend.sent(0), vec!(vec!(0xFA), vec!(0xF8), vec!(0xF8), vec!(0xF8), vec!(0xFC)));
+    }
+
+    #[test]
+    fn drops_clock_and_transport() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let mut c = config(0, 0, 0, 0);
+        c.clock = ClockOptions::parse("noclock notransport").unwrap();
+        let _router = start(&backend, &[c], &Traffic::new());
+        for m in [0xFA, 0xF8, 0xFC].iter() {
+            backend.receive(0, 0, &[*m]);
+        }
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x90, 60, 100)));
+    }
+
+    #[test]
+    fn counts_forwarding_errors() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let traffic = Traffic::new();
+        let _router = start(&backend, &[config(0, 0, 0, 0)], &traffic);
+        backend.set_failing(true);
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        backend.receive(0, 0, &[0x80, 60, 0]);
+        backend.set_failing(false);
+        backend.receive(0, 0, &[0x90, 62, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x90, 62, 100)));
+        assert!(traffic.to_json().contains("\"route\":\"0 -> 0\",\"messages\":1,\"bytes\":3,\"filtered\":0,\"errors\":2,"));
+    }
+
+    #[test]
+    fn stops_when_closed() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let router = start(&backend, &[config(0, 0, 0, 0)], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        router.close();
+        backend.receive(0, 0, &[0x90, 62, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x90, 60, 100)));
+    }
+
+    #[test]
+    fn rejects_invalid_ports() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        assert!(Router::start(&backend, &[config(1, 0, 0, 0)], &Sinks::default(), &Traffic::new()).is_err());
+        assert!(Router::start(&backend, &[config(0, 0, 1, 0)], &Sinks::default(), &Traffic::new()).is_err());
+    }
+
+    #[test]
+    fn transposes_notes() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let traffic = Traffic::new();
+        let router = start(&backend, &[Config{transpose: 12, ..config(0, 0, 0, 0)}], &traffic);
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        router.set_transpose(0, -2).unwrap();
+        backend.receive(0, 0, &[0xA0, 60, 50]);
+        backend.receive(0, 0, &[0x80, 60, 0]);  // Ends the note transposed by 12
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        backend.receive(0, 0, &[0x90, 60, 0]);
+        backend.receive(0, 0, &[0x90, 1, 100]); // Out of range
+        backend.receive(0, 0, &[0xB0, 60, 1]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x90, 72, 100), vec!(0xA0, 72, 50), vec!(0x80, 72, 0),
+                                         vec!(0x90, 58, 100), vec!(0x90, 58, 0), vec!(0xB0, 60, 1)));
+        assert!(traffic.to_json().contains("\"route\":\"0 -> 0\",\"messages\":6,\"bytes\":18,\"filtered\":1,"));
+    }
+
+    #[test]
+    fn ends_notes_on_their_output_channel() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let c = Config{chord: Some(ChordOptions::parse("notes=0,7").unwrap()), ..config(0, 0, 0, 2)};
+        let router = start(&backend, &[c], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        router.set_channels(0, 0, 3).unwrap();
+        backend.receive(0, 0, &[0xA0, 60, 50]);
+        backend.receive(0, 0, &[0x90, 64, 100]);
+        backend.receive(0, 0, &[0x80, 60, 0]);
+        backend.receive(0, 0, &[0xB0, 7, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x91, 60, 100), vec!(0x91, 67, 100), vec!(0xA1, 60, 50),
+                                         vec!(0xA1, 67, 50), vec!(0x92, 64, 100), vec!(0x92, 71, 100),
+                                         vec!(0x81, 60, 0), vec!(0x81, 67, 0), vec!(0xB2, 7, 100)));
+    }
+
+    #[test]
+    fn changes_routes_while_running() {
+        let backend = MockBackend::new(&["in 0", "in 1"], &["out 0", "out 1"]);
+        let mut router = start(&backend, &[config(0, 0, 0, 0)], &Traffic::new());
+        assert_eq!(router.add(&backend, config(1, 0, 1, 0)).unwrap(), 1);
+        assert!(router.add(&backend, config(2, 0, 1, 0)).is_err());
+        router.set_muted(0, true).unwrap();
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        router.set_muted(0, false).unwrap();
+        router.set_channels(0, 2, 5).unwrap();
+        backend.receive(0, 0, &[0x90, 61, 100]);
+        backend.receive(0, 0, &[0x91, 62, 100]);
+        backend.receive(1, 0, &[0x90, 63, 100]);
+        router.remove(1).unwrap();
+        backend.receive(1, 0, &[0x90, 64, 100]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x94, 62, 100)));
+        assert_eq!(backend.sent(1), vec!(vec!(0x90, 63, 100)));
+
+        assert_eq!(router.remove(1), Err("No route 1".to_string()));
+        assert!(router.set_muted(2, true).is_err());
+        assert!(router.set_channels(0, 17, 0).is_err());
+        let routes: Vec<(usize, String)> = router.routes().into_iter().map(|r| (r.route, r.config.name())).collect();
+        assert_eq!(routes, vec!((0, "0 -> 0 (channel 2 -> 5)".to_string())));
+        router.close();
+    }
+
+    #[test]
+    fn resets_outputs_on_panic() {
+        let backend = MockBackend::new(&["in 0", "in 1"], &["out 0", "out 1"]);
+        let router = start(&backend, &[config(0, 0, 1, 0), config(1, 0, 1, 0), config(1, 0, usize::MAX, 0)], &Traffic::new());
+        assert_eq!(router.panic(), 1);
+        let sent = backend.sent(1);
+        assert_eq!(sent.len(), 48);
+        assert_eq!(&sent[..3], &[vec!(0xB0, 64, 0), vec!(0xB0, 120, 0), vec!(0xB0, 123, 0)]);
+        assert_eq!(sent[47], vec!(0xBF, 123, 0));
+    }
+
+    #[test]
+    fn plays_chords() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let c = Config{chord: Some(ChordOptions::parse("degrees=0,2,4").unwrap()), transpose: 2, ..config(0, 0, 0, 3)};
+        let router = start(&backend, &[c], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        router.set_chord(0, Some(ChordOptions::parse("notes=0,12").unwrap())).unwrap();
+        backend.receive(0, 0, &[0x90, 65, 90]);
+        backend.receive(0, 0, &[0x80, 60, 0]);
+        router.set_chord(0, None).unwrap();
+        backend.receive(0, 0, &[0x90, 40, 80]);
+        backend.receive(0, 0, &[0x90, 65, 0]);
+        backend.receive(0, 0, &[0xB0, 64, 127]);
+        assert_eq!(backend.sent(0), vec!(vec!(0x92, 62, 100), vec!(0x92, 65, 100), vec!(0x92, 69, 100),
+                                         vec!(0x92, 67, 90), vec!(0x92, 79, 90),
+                                         vec!(0x82, 62, 0), vec!(0x82, 65, 0), vec!(0x82, 69, 0),
+                                         vec!(0x92, 42, 80),
+                                         vec!(0x82, 67, 0), vec!(0x82, 79, 0),
+                                         vec!(0xB2, 64, 127)));
+        assert_eq!(router.routes()[0].config.chord, None);
+    }
+
+    /// The notes sent to output 0, waits until there are count of them.
+    fn sent_notes(backend: &MockBackend, count: usize) -> Vec<Vec<u8>> {
+        let is_note = |m: &&Vec<u8>| m[0] < 0xA0;
+        backend.wait_until(0, |sent| sent.iter().filter(is_note).count() >= count)
+               .iter().filter(is_note).cloned().collect()
+    }
+
+    #[test]
+    fn arpeggiates_with_clock() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let c = Config{arp: Some(ArpOptions::parse("mode=down rate=16 clock").unwrap()), ..config(0, 0, 0, 2)};
+        let router = start(&backend, &[c], &Traffic::new());
+        backend.receive(0, 0, &[0x90, 60, 100]);
+        backend.receive(0, 0, &[0x90, 64, 90]);
+        backend.receive(0, 0, &[0xFA]);
+        for _ in 0..13 {
+            backend.receive(0, 0, &[0xF8]);
+        }
+        assert_eq!(sent_notes(&backend, 6), vec!(vec!(0x91, 64, 90), vec!(0x81, 64, 0), vec!(0x91, 60, 100),
+                                                 vec!(0x81, 60, 0), vec!(0x91, 64, 90), vec!(0x81, 64, 0)));
+        // Clock and transport pass, no steps without held notes
+        backend.receive(0, 0, &[0x80, 60, 0]);
+        backend.receive(0, 0, &[0x90, 64, 0]);
+        for _ in 0..12 {
+            backend.receive(0, 0, &[0xF8]);
+        }
+        router.close();
+        assert_eq!(backend.sent(0).len(), 6 + 1 + 25);
+        assert_eq!(backend.sent(0).iter().filter(|m| m[0] < 0xA0).count(), 6);
+    }
+
+    #[test]
+    fn arpeggiates_with_clock_on_input_channel() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let c = Config{arp: Some(ArpOptions::parse("mode=up rate=16 clock").unwrap()), ..config(0, 3, 0, 2)};
+        let router = start(&backend, &[c], &Traffic::new());
+        backend.receive(0, 0, &[0x92, 60, 100]);
+        backend.receive(0, 0, &[0x90, 62, 100]); // Other channel
+        backend.receive(0, 0, &[0x92, 64, 90]);
+        // System messages have no channel and reach the arpeggiator
+        backend.receive(0, 0, &[0xFA]);
+        for _ in 0..13 {
+            backend.receive(0, 0, &[0xF8]);
+        }
+        assert_eq!(sent_notes(&backend, 6), vec!(vec!(0x91, 60, 100), vec!(0x81, 60, 0), vec!(0x91, 64, 90),
+                                                 vec!(0x81, 64, 0), vec!(0x91, 60, 100), vec!(0x81, 60, 0)));
+        router.close();
+        assert_eq!(backend.sent(0).iter().filter(|m| m[0] >= 0xF0).count(), 14);
+    }
+
+    #[test]
+    fn arpeggiates_with_internal_tempo() {
+        let backend = MockBackend::new(&["in"], &["out"]);
+        let c = Config{arp: Some(ArpOptions::parse("oct=2 rate=32 bpm=300 gate=100").unwrap()), ..config(0, 0, 0, 0)};
+        let router = start(&backend, &[c], &Traffic::new());
+        backend.receive(0, 0, &[0x92, 60, 100]);
+        let notes = sent_notes(&backend, 5);
+        assert_eq!(&notes[..5], &[vec!(0x92, 60, 100), vec!(0x82, 60, 0), vec!(0x92, 72, 100), vec!(0x82, 72, 0),
+                                  vec!(0x92, 60, 100)]);
+        // Closing ends the sounding note
+        router.close();
+        let notes = backend.sent(0);
+        assert_eq!(notes.iter().filter(|m| m[0] == 0x92).count(), notes.iter().filter(|m| m[0] == 0x82).count());
+    }
+
+    #[test]
+    fn feeds_monitor_and_recorder() {
+        let backend = MockBackend::new(&["in 0", "in 1"], &["out"]);
+        let (monitor_tx, monitor_rx) = worker::queue();
+        let (record_tx, record_rx) = worker::queue();
+        let sinks = Sinks{monitor: Some(monitor_tx), record: Some(record_tx), ..Sinks::default()};
+        let configs = [config(0, 0, 0, 0), config(0, 0, usize::MAX, 0), config(1, 0, usize::MAX, 0)];
+        let router = Router::start(&backend, &configs, &sinks, &Traffic::new()).unwrap();
+        drop(sinks);
+        backend.receive(0, 100, &[0x90, 60, 100]);
+        backend.receive(1, 200, &[0xB0, 7, 90]);
+        router.close();
+
+        // The monitor sees every route, the recorder the first route of every port
+        let monitored: Vec<(usize, usize, u64)> = monitor_rx.iter().map(|r| (r.route, r.port, r.timestamp)).collect();
+        assert_eq!(monitored, vec!((0, 0, 100), (1, 0, 100), (2, 1, 200)));
+        let files = vec!(Some(("p0".to_string(), vec!())), None, Some(("p1".to_string(), vec!())));
+        let files = worker::record(record_rx, files, |_| None, RecordFormat::Hex, Default::default());
+        let contents: Vec<Option<String>> = files.into_iter()
+                                                 .map(|f| f.map(|(_, data)| String::from_utf8(data).unwrap()))
+                                                 .collect();
+        assert_eq!(contents, vec!(Some("90 3c 64\n".to_string()), None, Some("b0 07 5a\n".to_string())));
+    }
+}
@@ -0,0 +1,284 @@
+//! Assembly of RPN/ NRPN parameter changes and 14-bit controllers.
+//!
+//! Parameter numbers and values are transmitted as sequences of controller
+//! messages (e.g. 99/98/6/38 for an NRPN). The ParamDecoder keeps the state
+//! of every channel and turns these sequences into single events, which are
+//! shown by the monitor or mapped to other parameters when forwarding.
+//!
+//! Data entry MSB and LSB are reported separately: the MSB results in an event
+//! with the LSB cleared, a following LSB in an event with the complete value.
+//! A controller 0 - 31 is treated as 14-bit controller once the matching LSB
+//! controller (32 - 63) has been received on that channel.
+
+use super::MidiMessage;
+
+const CC_DATA_MSB: u8 = 6;
+const CC_DATA_LSB: u8 = 38;
+const CC_DATA_INC: u8 = 96;
+const CC_DATA_DEC: u8 = 97;
+const CC_NRPN_LSB: u8 = 98;
+const CC_NRPN_MSB: u8 = 99;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+
+const RPN_NAMES: [&str; 7] = ["Pitch Bend Range", "Fine Tuning", "Coarse Tuning", "Tuning Program",
+                              "Tuning Bank", "Modulation Depth Range", "MPE Configuration"];
+
+/// Registered or non-registered parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamKind {
+    Rpn,
+    Nrpn,
+}
+
+/// A parameter change or 14-bit controller assembled from controller messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamEvent {
+    /// Data entry for a parameter. Fine is set if the value includes the LSB.
+    ParamChange{channel: u8, kind: ParamKind, param: u16, value: u16, fine: bool},
+    ParamIncrement{channel: u8, kind: ParamKind, param: u16, amount: u8},
+    ParamDecrement{channel: u8, kind: ParamKind, param: u16, amount: u8},
+    /// A controller 0 - 31 combined with its LSB controller.
+    Controller14{channel: u8, controller: u8, value: u16},
+}
+
+/// Result of feeding a message to the decoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decoded {
+    Pass,              // Not part of a parameter sequence
+    Pending,           // Consumed, part of an incomplete sequence
+    Event(ParamEvent), // A sequence was completed
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    kind: Option<ParamKind>, // Type of the currently selected parameter
+    param_msb: u8,
+    param_lsb: u8,
+    data_msb: Option<u8>,
+    cc_msb: [Option<u8>; 32],
+    cc_fine: [bool; 32], // LSB has been seen for this controller
+}
+
+impl ChannelState {
+    fn param(&self) -> u16 {
+        ((self.param_msb as u16) << 7) | self.param_lsb as u16
+    }
+}
+
+/// Assembles parameter changes and 14-bit controllers per channel.
+pub struct ParamDecoder {
+    channels: [ChannelState; 16],
+}
+
+impl Default for ParamDecoder {
+    fn default() -> Self {
+        ParamDecoder::new()
+    }
+}
+
+impl ParamDecoder {
+    /// Create a decoder without selected parameters.
+    pub fn new() -> Self {
+        ParamDecoder{channels: [ChannelState::default(); 16]}
+    }
+
+    /// Feed a message to the decoder.
+    pub fn process(&mut self, m: &MidiMessage) -> Decoded {
+        let (channel, controller, value) = match *m {
+            MidiMessage::ControlChg{channel, controller, value} => (channel, controller, value),
+            _ => return Decoded::Pass,
+        };
+        let state = &mut self.channels[channel as usize & 0x0F];
+        match controller {
+            CC_NRPN_MSB | CC_RPN_MSB => {
+                state.kind = Some(if controller == CC_NRPN_MSB { ParamKind::Nrpn } else { ParamKind::Rpn });
+                state.param_msb = value;
+                state.data_msb = None;
+                Decoded::Pending
+            }
+            CC_NRPN_LSB | CC_RPN_LSB => {
+                state.kind = Some(if controller == CC_NRPN_LSB { ParamKind::Nrpn } else { ParamKind::Rpn });
+                state.param_lsb = value;
+                state.data_msb = None;
+                if state.kind == Some(ParamKind::Rpn) && state.param() == 0x3FFF {
+                    state.kind = None; // RPN null, deselects the parameter
+                }
+                Decoded::Pending
+            }
+            _ => {
+                if let Some(kind) = state.kind {
+                    let param = state.param();
+                    match controller {
+                        CC_DATA_MSB => {
+                            state.data_msb = Some(value);
+                            let value = (value as u16) << 7;
+                            return Decoded::Event(ParamEvent::ParamChange{channel, kind, param, value, fine: false});
+                        }
+                        CC_DATA_LSB => {
+                            if let Some(msb) = state.data_msb {
+                                let value = ((msb as u16) << 7) | value as u16;
+                                return Decoded::Event(ParamEvent::ParamChange{channel, kind, param, value, fine: true});
+                            }
+                        }
+                        CC_DATA_INC => return Decoded::Event(ParamEvent::ParamIncrement{channel, kind, param, amount: value}),
+                        CC_DATA_DEC => return Decoded::Event(ParamEvent::ParamDecrement{channel, kind, param, amount: value}),
+                        _ => (),
+                    }
+                }
+                match controller {
+                    0..=31 => {
+                        let index = controller as usize;
+                        state.cc_msb[index] = Some(value);
+                        if state.cc_fine[index] {
+                            // The LSB is reset by a new MSB
+                            return Decoded::Event(ParamEvent::Controller14{channel, controller, value: (value as u16) << 7});
+                        }
+                        Decoded::Pass
+                    }
+                    32..=63 => {
+                        let index = controller as usize - 32;
+                        state.cc_fine[index] = true;
+                        match state.cc_msb[index] {
+                            Some(msb) => {
+                                let value = ((msb as u16) << 7) | value as u16;
+                                Decoded::Event(ParamEvent::Controller14{channel, controller: index as u8, value})
+                            }
+                            None => Decoded::Pass,
+                        }
+                    }
+                    _ => Decoded::Pass,
+                }
+            }
+        }
+    }
+}
+
+impl ParamEvent {
+    /// The channel (0 - 15) of the event.
+    pub fn channel(&self) -> u8 {
+        match *self {
+            ParamEvent::ParamChange{channel, ..}
+            | ParamEvent::ParamIncrement{channel, ..}
+            | ParamEvent::ParamDecrement{channel, ..}
+            | ParamEvent::Controller14{channel, ..} => channel,
+        }
+    }
+
+    /// Returns the name and the formatted parameters of the event.
+    pub fn describe(&self) -> (&'static str, String) {
+        let kind_name = |kind: ParamKind| if kind == ParamKind::Rpn { "RPN" } else { "NRPN" };
+        let param_str = |kind: ParamKind, param: u16| {
+            match RPN_NAMES.get(param as usize) {
+                Some(name) if kind == ParamKind::Rpn => format!("param={} ({})", param, name),
+                _ => format!("param={}", param),
+            }
+        };
+        match *self {
+            ParamEvent::ParamChange{kind, param, value, ..} => {
+                (kind_name(kind), format!("{} value={}", param_str(kind, param), value))
+            }
+            ParamEvent::ParamIncrement{kind, param, amount, ..} => {
+                (kind_name(kind), format!("{} increment={}", param_str(kind, param), amount))
+            }
+            ParamEvent::ParamDecrement{kind, param, amount, ..} => {
+                (kind_name(kind), format!("{} decrement={}", param_str(kind, param), amount))
+            }
+            ParamEvent::Controller14{controller, value, ..} => {
+                ("ControlChg14", format!("controller={} value={}", controller, value))
+            }
+        }
+    }
+
+    /// The parameter of a data entry event.
+    pub fn param(&self) -> Option<(ParamKind, u16)> {
+        match *self {
+            ParamEvent::ParamChange{kind, param, ..}
+            | ParamEvent::ParamIncrement{kind, param, ..}
+            | ParamEvent::ParamDecrement{kind, param, ..} => Some((kind, param)),
+            ParamEvent::Controller14{..} => None,
+        }
+    }
+}
+
+/// Returns true if the controller selects a parameter.
+pub fn is_selection(controller: u8) -> bool {
+    (CC_NRPN_LSB..=CC_RPN_MSB).contains(&controller)
+}
+
+/// The controller messages selecting a parameter.
+pub fn select(channel: u8, (kind, param): (ParamKind, u16)) -> [MidiMessage; 2] {
+    let (msb, lsb) = if kind == ParamKind::Rpn { (CC_RPN_MSB, CC_RPN_LSB) } else { (CC_NRPN_MSB, CC_NRPN_LSB) };
+    [MidiMessage::ControlChg{channel, controller: msb, value: (param >> 7) as u8 & 0x7F},
+     MidiMessage::ControlChg{channel, controller: lsb, value: param as u8 & 0x7F}]
+}
+
+/// Parse a parameter given as "nrpn:1234" or "rpn:0".
+pub fn parse_param(s: &str) -> Option<(ParamKind, u16)> {
+    let mut parts = s.splitn(2, ':');
+    let kind = match parts.next()?.to_lowercase().as_str() {
+        "rpn" => ParamKind::Rpn,
+        "nrpn" => ParamKind::Nrpn,
+        _ => return None,
+    };
+    let param: u16 = parts.next()?.parse().ok()?;
+    if param < 0x4000 { Some((kind, param)) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(decoder: &mut ParamDecoder, messages: &[[u8; 2]]) -> Vec<Decoded> {
+        messages.iter()
+                .map(|&[controller, value]| decoder.process(&MidiMessage::ControlChg{channel: 2, controller, value}))
+                .collect()
+    }
+
+    #[test]
+    fn assembles_nrpn() {
+        let mut decoder = ParamDecoder::new();
+        let change = |value, fine| Decoded::Event(ParamEvent::ParamChange{channel: 2, kind: ParamKind::Nrpn, param: 1234, value, fine});
+        assert_eq!(feed(&mut decoder, &[[99, 9], [98, 82], [6, 64], [38, 5], [38, 6]]),
+                   vec!(Decoded::Pending, Decoded::Pending, change(64 << 7, false), change((64 << 7) + 5, true),
+                        change((64 << 7) + 6, true)));
+        // Other channels have no parameter selected
+        assert_eq!(decoder.process(&MidiMessage::ControlChg{channel: 3, controller: 6, value: 1}), Decoded::Pass);
+    }
+
+    #[test]
+    fn handles_rpn_null_and_increments() {
+        let mut decoder = ParamDecoder::new();
+        let (kind, param) = (ParamKind::Rpn, 0);
+        assert_eq!(feed(&mut decoder, &[[101, 0], [100, 0], [96, 1], [97, 2]])[2..],
+                   [Decoded::Event(ParamEvent::ParamIncrement{channel: 2, kind, param, amount: 1}),
+                    Decoded::Event(ParamEvent::ParamDecrement{channel: 2, kind, param, amount: 2})]);
+        assert_eq!(feed(&mut decoder, &[[101, 127], [100, 127], [6, 64], [96, 1]]),
+                   vec!(Decoded::Pending, Decoded::Pending, Decoded::Pass, Decoded::Pass));
+    }
+
+    #[test]
+    fn assembles_14_bit_controllers() {
+        let mut decoder = ParamDecoder::new();
+        let cc14 = |value| Decoded::Event(ParamEvent::Controller14{channel: 2, controller: 7, value});
+        // The MSB is a plain controller until its LSB was seen
+        assert_eq!(feed(&mut decoder, &[[7, 100], [39, 3], [7, 90], [39, 4]]),
+                   vec!(Decoded::Pass, cc14((100 << 7) + 3), cc14(90 << 7), cc14((90 << 7) + 4)));
+    }
+
+    #[test]
+    fn passes_stray_data_lsb() {
+        let mut decoder = ParamDecoder::new();
+        assert_eq!(feed(&mut decoder, &[[38, 5]]), vec!(Decoded::Pass));
+        // Without a data entry MSB for the selected parameter
+        assert_eq!(feed(&mut decoder, &[[99, 0], [98, 1], [38, 5]]), vec!(Decoded::Pending, Decoded::Pending, Decoded::Pass));
+    }
+
+    #[test]
+    fn parses_params() {
+        assert_eq!(parse_param("nrpn:1234"), Some((ParamKind::Nrpn, 1234)));
+        assert_eq!(parse_param("RPN:0"), Some((ParamKind::Rpn, 0)));
+        assert_eq!(parse_param("nrpn:16384"), None);
+        assert_eq!(parse_param("cc:7"), None);
+    }
+}
@@ -0,0 +1,378 @@
+//! The recovery journal of RTP-MIDI (RFC 6295).
+//!
+//! Every RTP-MIDI packet carries a journal with the channel state that
+//! changed since the checkpoint, the last packet the receiver confirmed.
+//! After a packet loss the receiver compares the journal with its own state
+//! and sends the messages that repair the difference.
+//!
+//! Supported are the channel chapters P (program change), C (controllers),
+//! W (pitch wheel), N (notes) and T (channel pressure). Channel mode
+//! messages aren't journaled, except that "all notes off" and "all sound
+//! off" turn off the notes.
+
+/// A journaled value with the packet that changed it.
+type Item<T> = Option<(T, u64)>;
+
+const CHAPTER_P: u8 = 0x80;
+const CHAPTER_C: u8 = 0x40;
+const CHAPTER_W: u8 = 0x10;
+const CHAPTER_N: u8 = 0x08;
+const CHAPTER_T: u8 = 0x02;
+const UNSUPPORTED: u8 = 0x25; // Chapters M, E and A
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Program {
+    number: u8,
+    bank: Option<(u8, u8)>, // Bank select MSB and LSB sent before the program change
+}
+
+#[derive(Clone)]
+struct Channel {
+    program: Item<Program>,
+    controllers: [Item<u8>; 120],
+    pitch: Item<(u8, u8)>,
+    pressure: Item<u8>,
+    notes: [Item<Option<u8>>; 128], // Velocity of the notes that are on
+    bank: (Option<u8>, Option<u8>), // Current bank select, not journaled by itself
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel{program: None, controllers: [None; 120], pitch: None, pressure: None, notes: [None; 128], bank: (None, None)}
+    }
+}
+
+impl Channel {
+    fn is_empty(&self) -> bool {
+        self.program.is_none() && self.pitch.is_none() && self.pressure.is_none()
+            && self.controllers.iter().all(|c| c.is_none()) && self.notes.iter().all(|n| n.is_none())
+    }
+
+    fn forget(&mut self, checkpoint: u64) {
+        fn prune<T>(item: &mut Item<T>, checkpoint: u64) {
+            if item.as_ref().is_some_and(|(_, seq)| *seq <= checkpoint) {
+                *item = None;
+            }
+        }
+        prune(&mut self.program, checkpoint);
+        prune(&mut self.pitch, checkpoint);
+        prune(&mut self.pressure, checkpoint);
+        self.controllers.iter_mut().for_each(|c| prune(c, checkpoint));
+        self.notes.iter_mut().for_each(|n| prune(n, checkpoint));
+    }
+
+    fn encode(&self, index: usize) -> Vec<u8> {
+        let mut chapters = 0;
+        let mut bytes = vec!();
+        if let Some((program, _)) = self.program {
+            chapters |= CHAPTER_P;
+            let (msb, lsb) = program.bank.unwrap_or((0, 0));
+            let b = if program.bank.is_some() { 0x80 } else { 0 };
+            bytes.extend_from_slice(&[program.number, b | msb, lsb]);
+        }
+        let controllers: Vec<(usize, u8)> = self.controllers.iter().enumerate()
+                                                .filter_map(|(n, c)| c.map(|(value, _)| (n, value)))
+                                                .collect();
+        if !controllers.is_empty() {
+            chapters |= CHAPTER_C;
+            bytes.push(controllers.len() as u8 - 1);
+            for (number, value) in controllers {
+                bytes.extend_from_slice(&[number as u8, value]);
+            }
+        }
+        if let Some(((lsb, msb), _)) = self.pitch {
+            chapters |= CHAPTER_W;
+            bytes.extend_from_slice(&[lsb, msb]);
+        }
+        if self.notes.iter().any(|n| n.is_some()) {
+            chapters |= CHAPTER_N;
+            bytes.extend(self.encode_notes());
+        }
+        if let Some((pressure, _)) = self.pressure {
+            chapters |= CHAPTER_T;
+            bytes.push(pressure);
+        }
+        let len = bytes.len() + 3;
+        let mut journal = vec!((index as u8) << 3 | (len >> 8) as u8 & 0x03, len as u8, chapters);
+        journal.extend(bytes);
+        journal
+    }
+
+    /// Chapter N: a log for every note that is on, followed by a bit for
+    /// every note that is off, in the octets LOW to HIGH.
+    fn encode_notes(&self) -> Vec<u8> {
+        let on: Vec<(usize, u8)> = self.notes.iter().enumerate()
+                                       .filter_map(|(n, item)| match item {
+                                           Some((Some(velocity), _)) => Some((n, *velocity)),
+                                           _ => None,
+                                       })
+                                       .take(127)
+                                       .collect();
+        let off: Vec<usize> = self.notes.iter().enumerate()
+                                  .filter(|(_, item)| matches!(item, Some((None, _))))
+                                  .map(|(n, _)| n)
+                                  .collect();
+        let (low, high) = match (off.first(), off.last()) {
+            (Some(first), Some(last)) => (first / 8, last / 8),
+            _ => (15, 0), // No offbits
+        };
+        let mut bytes = vec!(on.len() as u8, (low << 4 | high) as u8);
+        for (note, velocity) in on {
+            bytes.extend_from_slice(&[note as u8, 0x80 | velocity]); // Y: play the note
+        }
+        if low <= high {
+            let mut offbits = vec!(0u8; high - low + 1);
+            for note in off {
+                offbits[note / 8 - low] |= 0x80 >> (note % 8);
+            }
+            bytes.extend(offbits);
+        }
+        bytes
+    }
+}
+
+/// Channel state for the recovery journal.
+///
+/// The sender updates it with every sent message and encodes it into every
+/// packet. The receiver updates it with the received messages and repairs
+/// it from the journal after a loss.
+#[derive(Clone)]
+pub struct Journal {
+    channels: Vec<Channel>,
+    checkpoint: u64,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal{channels: vec!(Channel::default(); 16), checkpoint: 0}
+    }
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a message, sent in packet seq.
+    pub fn update(&mut self, message: &[u8], seq: u64) {
+        if message.is_empty() || message[0] >= 0xF0 {
+            return;
+        }
+        let channel = &mut self.channels[(message[0] & 0x0F) as usize];
+        let data = |i: usize| message.get(i).copied().unwrap_or(0) & 0x7F;
+        match message[0] & 0xF0 {
+            0x80 => channel.notes[data(1) as usize] = Some((None, seq)),
+            0x90 => {
+                let velocity = data(2);
+                channel.notes[data(1) as usize] = Some(((velocity > 0).then_some(velocity), seq));
+            }
+            0xB0 => match data(1) {
+                120 | 123 => channel.notes = [Some((None, seq)); 128],
+                number if number < 120 => {
+                    match number {
+                        0 => channel.bank.0 = Some(data(2)),
+                        32 => channel.bank.1 = Some(data(2)),
+                        _ => (),
+                    }
+                    channel.controllers[number as usize] = Some((data(2), seq));
+                }
+                _ => (),
+            },
+            0xC0 => {
+                let bank = channel.bank.0.map(|msb| (msb, channel.bank.1.unwrap_or(0)));
+                channel.program = Some((Program{number: data(1), bank}, seq));
+            }
+            0xD0 => channel.pressure = Some((data(1), seq)),
+            0xE0 => channel.pitch = Some(((data(1), data(2)), seq)),
+            _ => (),
+        }
+    }
+
+    /// Forget the changes up to packet seq, the receiver has got them.
+    pub fn checkpoint(&mut self, seq: u64) {
+        if seq > self.checkpoint {
+            self.checkpoint = seq;
+            self.channels.iter_mut().for_each(|c| c.forget(seq));
+        }
+    }
+
+    /// Encode the journal of the changes since the checkpoint.
+    pub fn encode(&self) -> Vec<u8> {
+        let channels: Vec<Vec<u8>> = self.channels.iter().enumerate()
+                                         .filter(|(_, c)| !c.is_empty())
+                                         .map(|(i, c)| c.encode(i))
+                                         .collect();
+        let a = if channels.is_empty() { 0 } else { 0x20 };
+        let totchan = channels.len().saturating_sub(1) as u8;
+        let mut bytes = vec!(a | totchan);
+        bytes.extend_from_slice(&(self.checkpoint as u16).to_be_bytes());
+        bytes.extend(channels.concat());
+        bytes
+    }
+
+    /// Compare the state with a received journal. Returns the messages that
+    /// repair the differences and applies them.
+    pub fn recover(&mut self, journal: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let mut repairs = vec!();
+        let header = journal.get(..3).ok_or("Journal too short")?;
+        if header[0] & 0x40 != 0 {
+            return Err("System journal not supported".to_string());
+        }
+        if header[0] & 0x20 == 0 {
+            return Ok(repairs);
+        }
+        let mut pos = 3;
+        for _ in 0..=(header[0] & 0x0F) {
+            let channel = journal.get(pos..pos + 3).ok_or("Channel journal too short")?;
+            let len = ((channel[0] as usize & 0x03) << 8) | channel[1] as usize;
+            let chapters = journal.get(pos..pos + len).ok_or("Channel journal too short")?;
+            repairs.extend(self.recover_channel((channel[0] >> 3) & 0x0F, channel[2], &chapters[3..])?);
+            pos += len;
+        }
+        for message in &repairs {
+            self.update(message, 0);
+        }
+        Ok(repairs)
+    }
+
+    fn recover_channel(&self, index: u8, chapters: u8, bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        if chapters & UNSUPPORTED != 0 {
+            return Err(format!("Unsupported journal chapters {:02x}", chapters & UNSUPPORTED));
+        }
+        let channel = &self.channels[index as usize];
+        let mut repairs = vec!();
+        let mut bank_repaired = false;
+        let mut pos = 0;
+        let mut take = |n: usize| -> Result<&[u8], String> {
+            let chapter = bytes.get(pos..pos + n).ok_or("Chapter too short")?;
+            pos += n;
+            Ok(chapter)
+        };
+        if chapters & CHAPTER_P != 0 {
+            let p = take(3)?;
+            let bank = if p[1] & 0x80 != 0 { Some((p[1] & 0x7F, p[2] & 0x7F)) } else { None };
+            let program = Program{number: p[0] & 0x7F, bank};
+            if channel.program.map(|(p, _)| p) != Some(program) {
+                if let Some((msb, lsb)) = bank {
+                    repairs.push(vec!(0xB0 | index, 0, msb));
+                    repairs.push(vec!(0xB0 | index, 32, lsb));
+                    bank_repaired = true;
+                }
+                repairs.push(vec!(0xC0 | index, program.number));
+            }
+        }
+        if chapters & CHAPTER_C != 0 {
+            let count = take(1)?[0] as usize & 0x7F;
+            for log in take((count + 1) * 2)?.chunks(2) {
+                let (number, value) = (log[0] & 0x7F, log[1] & 0x7F);
+                if log[1] & 0x80 != 0 {
+                    return Err("Unsupported controller log".to_string());
+                }
+                let known = channel.controllers.get(number as usize).copied().flatten().map(|(v, _)| v);
+                let is_bank = number == 0 || number == 32;
+                if number < 120 && known != Some(value) && !(is_bank && bank_repaired) {
+                    repairs.push(vec!(0xB0 | index, number, value));
+                }
+            }
+        }
+        if chapters & CHAPTER_W != 0 {
+            let w = take(2)?;
+            let pitch = (w[0] & 0x7F, w[1] & 0x7F);
+            if channel.pitch.map(|(p, _)| p) != Some(pitch) {
+                repairs.push(vec!(0xE0 | index, pitch.0, pitch.1));
+            }
+        }
+        if chapters & CHAPTER_N != 0 {
+            let header = take(2)?;
+            let (count, low, high) = (header[0] as usize & 0x7F, (header[1] >> 4) as usize, (header[1] & 0x0F) as usize);
+            let is_on = |note: usize| matches!(channel.notes[note], Some((Some(_), _)));
+            for log in take(count * 2)?.chunks(2) {
+                let note = log[0] & 0x7F;
+                if log[1] & 0x80 != 0 && !is_on(note as usize) {
+                    repairs.push(vec!(0x90 | index, note, (log[1] & 0x7F).max(1)));
+                }
+            }
+            if low <= high {
+                for (octet, bits) in take(high - low + 1)?.iter().enumerate() {
+                    for bit in 0..8 {
+                        let note = (low + octet) * 8 + bit;
+                        if bits & (0x80 >> bit) != 0 && is_on(note) {
+                            repairs.push(vec!(0x80 | index, note as u8, 64));
+                        }
+                    }
+                }
+            }
+        }
+        if chapters & CHAPTER_T != 0 {
+            let pressure = take(1)?[0] & 0x7F;
+            if channel.pressure.map(|(p, _)| p) != Some(pressure) {
+                repairs.push(vec!(0xD0 | index, pressure));
+            }
+        }
+        Ok(repairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_lost_state() {
+        let mut sender = Journal::new();
+        let mut receiver = Journal::new();
+        let received: &[&[u8]] = &[&[0x90, 60, 100], &[0x91, 64, 90], &[0xB0, 7, 100]];
+        for (seq, message) in received.iter().enumerate() {
+            sender.update(message, seq as u64 + 1);
+            receiver.update(message, 0);
+        }
+        // These get lost
+        let lost: &[&[u8]] = &[&[0x80, 60, 0], &[0x90, 62, 80], &[0xB0, 0, 1], &[0xB0, 32, 2], &[0xC0, 5],
+                               &[0xB0, 7, 50], &[0xE1, 0, 0x50], &[0xD0, 30]];
+        for (seq, message) in lost.iter().enumerate() {
+            sender.update(message, seq as u64 + 4);
+        }
+
+        let repairs = receiver.recover(&sender.encode()).unwrap();
+        assert_eq!(repairs, vec!(vec!(0xB0, 0, 1), vec!(0xB0, 32, 2), vec!(0xC0, 5),
+                                 vec!(0xB0, 7, 50),
+                                 vec!(0x90, 62, 80), vec!(0x80, 60, 64), vec!(0xD0, 30),
+                                 vec!(0xE1, 0, 0x50)));
+        // The state is repaired
+        assert_eq!(receiver.recover(&sender.encode()).unwrap(), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn forgets_confirmed_changes() {
+        let mut journal = Journal::new();
+        assert_eq!(journal.encode(), vec!(0, 0, 0));
+        journal.update(&[0x90, 60, 100], 1);
+        journal.update(&[0xB0, 7, 100], 2);
+        journal.checkpoint(1);
+        // Only the controller is left
+        assert_eq!(journal.encode(), vec!(0x20, 0, 1, 0, 6, CHAPTER_C, 0, 7, 100));
+        journal.checkpoint(2);
+        assert_eq!(journal.encode(), vec!(0, 0, 2));
+    }
+
+    #[test]
+    fn turns_off_all_notes() {
+        let mut sender = Journal::new();
+        let mut receiver = Journal::new();
+        for journal in [&mut sender, &mut receiver] {
+            journal.update(&[0x92, 60, 100], 1);
+            journal.update(&[0x92, 67, 100], 2);
+        }
+        sender.checkpoint(2);
+        sender.update(&[0xB2, 123, 0], 3);
+        assert_eq!(receiver.recover(&sender.encode()).unwrap(), vec!(vec!(0x82, 60, 64), vec!(0x82, 67, 64)));
+    }
+
+    #[test]
+    fn rejects_invalid_journals() {
+        let mut journal = Journal::new();
+        assert!(journal.recover(&[0x20]).is_err());
+        assert!(journal.recover(&[0x20, 0, 0, 0, 9, CHAPTER_C, 0]).is_err());
+        assert!(journal.recover(&[0x40, 0, 0]).is_err());
+    }
+}
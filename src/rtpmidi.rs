@@ -0,0 +1,824 @@
+//! RTP-MIDI sessions (AppleMIDI, RFC 6295).
+//!
+//! A session uses two UDP ports: session control (invitations, end of the
+//! session, receiver feedback) on the given port and MIDI data with clock
+//! synchronization on the next one. "rtpmidi:host:port" invites the session
+//! at host and keeps inviting it while it isn't joined, "rtpmidi:port"
+//! accepts invitations from any number of peers on port and port + 1.
+//!
+//! Messages are sent one per RTP packet, to all joined peers, together
+//! with the recovery journal (see [`rtpjournal`](super::rtpjournal)).
+//! Received messages get the timestamp of the sender, converted to the
+//! local clock with the offset found by clock synchronization. Long SysEx
+//! messages are sent in segments.
+
+use super::backend::{InputCallback, InputConnection, Output, OutputConnection};
+use super::endpoint::Endpoint;
+use super::net::is_timeout;
+use super::rtpjournal::Journal;
+
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SESSION_NAME: &str = "MIDI Toolbox";
+const PROTOCOL_VERSION: u32 = 2;
+const PAYLOAD_TYPE: u8 = 0x61;
+const MAX_PACKET: usize = 65536;
+const SYSEX_SEGMENT: usize = 1000; // Data bytes per segment, keeps packets below the MTU
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How often threads check for closing
+const INVITE_INTERVAL: Duration = Duration::from_secs(1);
+const REJECTED_DELAY: Duration = Duration::from_secs(5); // Before inviting again after a rejection
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An AppleMIDI session control packet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exchange {
+    Invitation{token: u32, ssrc: u32, name: String},
+    Accepted{token: u32, ssrc: u32, name: String},
+    Rejected{token: u32, ssrc: u32},
+    End{token: u32, ssrc: u32},
+    /// Clock synchronization, timestamps in units of 100 usec.
+    Sync{ssrc: u32, count: u8, timestamps: [u64; 3]},
+    /// The last sequence number the receiver got.
+    Feedback{ssrc: u32, seq: u16},
+}
+
+impl Exchange {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec!(0xFF, 0xFF);
+        match self {
+            Exchange::Invitation{token, ssrc, name} | Exchange::Accepted{token, ssrc, name} => {
+                bytes.extend_from_slice(if let Exchange::Invitation{..} = self { b"IN" } else { b"OK" });
+                bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+                bytes.extend_from_slice(&token.to_be_bytes());
+                bytes.extend_from_slice(&ssrc.to_be_bytes());
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(0);
+            }
+            Exchange::Rejected{token, ssrc} | Exchange::End{token, ssrc} => {
+                bytes.extend_from_slice(if let Exchange::Rejected{..} = self { b"NO" } else { b"BY" });
+                bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+                bytes.extend_from_slice(&token.to_be_bytes());
+                bytes.extend_from_slice(&ssrc.to_be_bytes());
+            }
+            Exchange::Sync{ssrc, count, timestamps} => {
+                bytes.extend_from_slice(b"CK");
+                bytes.extend_from_slice(&ssrc.to_be_bytes());
+                bytes.extend_from_slice(&[*count, 0, 0, 0]);
+                for timestamp in timestamps {
+                    bytes.extend_from_slice(&timestamp.to_be_bytes());
+                }
+            }
+            Exchange::Feedback{ssrc, seq} => {
+                bytes.extend_from_slice(b"RS");
+                bytes.extend_from_slice(&ssrc.to_be_bytes());
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(&[0, 0]);
+            }
+        }
+        bytes
+    }
+
+    /// Decode a session control packet, None if buf isn't one.
+    pub fn decode(buf: &[u8]) -> Option<Exchange> {
+        if buf.len() < 4 || buf[0..2] != [0xFF, 0xFF] {
+            return None;
+        }
+        let u32_at = |pos: usize| buf.get(pos..pos + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+        let u64_at = |pos: usize| Some((u32_at(pos)? as u64) << 32 | u32_at(pos + 4)? as u64);
+        let name = || {
+            let name = buf.get(16..).unwrap_or_default();
+            let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).to_string()
+        };
+        match &buf[2..4] {
+            b"IN" => Some(Exchange::Invitation{token: u32_at(8)?, ssrc: u32_at(12)?, name: name()}),
+            b"OK" => Some(Exchange::Accepted{token: u32_at(8)?, ssrc: u32_at(12)?, name: name()}),
+            b"NO" => Some(Exchange::Rejected{token: u32_at(8)?, ssrc: u32_at(12)?}),
+            b"BY" => Some(Exchange::End{token: u32_at(8)?, ssrc: u32_at(12)?}),
+            b"CK" => Some(Exchange::Sync{ssrc: u32_at(4)?, count: *buf.get(8)?,
+                                         timestamps: [u64_at(12)?, u64_at(20)?, u64_at(28)?]}),
+            b"RS" => Some(Exchange::Feedback{ssrc: u32_at(4)?, seq: (u32_at(8)? >> 16) as u16}),
+            _ => None,
+        }
+    }
+}
+
+/// An RTP-MIDI packet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub seq: u16,
+    pub timestamp: u32, // Units of 100 usec
+    pub ssrc: u32,
+    pub commands: Vec<(u32, Vec<u8>)>, // Messages with the delta time to the previous one
+    pub journal: Option<Vec<u8>>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec!(0x80, PAYLOAD_TYPE);
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.ssrc.to_be_bytes());
+        let list = encode_commands(&self.commands);
+        let mut flags = 0;
+        if self.journal.is_some() {
+            flags |= 0x40;
+        }
+        if self.commands.first().is_some_and(|(delta, _)| *delta > 0) {
+            flags |= 0x20;
+        }
+        if list.len() > 0x0F {
+            bytes.extend_from_slice(&[0x80 | flags | (list.len() >> 8) as u8, list.len() as u8]);
+        } else {
+            bytes.push(flags | list.len() as u8);
+        }
+        bytes.extend(list);
+        if let Some(journal) = &self.journal {
+            bytes.extend_from_slice(journal);
+        }
+        bytes
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Packet, String> {
+        if buf.len() < 13 || buf[0] & 0xC0 != 0x80 {
+            return Err("Not an RTP packet".to_string());
+        }
+        if buf[1] & 0x7F != PAYLOAD_TYPE {
+            return Err(format!("Unknown payload type {}", buf[1] & 0x7F));
+        }
+        let seq = u16::from_be_bytes([buf[2], buf[3]]);
+        let timestamp = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let ssrc = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let flags = buf[12];
+        let (len, start) = if flags & 0x80 != 0 {
+            (((flags as usize & 0x0F) << 8) | *buf.get(13).ok_or("Packet too short")? as usize, 14)
+        } else {
+            (flags as usize & 0x0F, 13)
+        };
+        let list = buf.get(start..start + len).ok_or("Packet too short")?;
+        let commands = decode_commands(list, flags & 0x20 != 0)?;
+        let journal = if flags & 0x40 != 0 { Some(buf[start + len..].to_vec()) } else { None };
+        Ok(Packet{seq, timestamp, ssrc, commands, journal})
+    }
+}
+
+fn write_delta(list: &mut Vec<u8>, delta: u32) {
+    let delta = delta & 0x0FFF_FFFF;
+    for shift in [21, 14, 7] {
+        if delta >> shift != 0 {
+            list.push(0x80 | (delta >> shift) as u8 & 0x7F);
+        }
+    }
+    list.push(delta as u8 & 0x7F);
+}
+
+fn read_delta(list: &[u8], pos: &mut usize) -> Result<u32, String> {
+    let mut delta = 0;
+    for _ in 0..4 {
+        let b = *list.get(*pos).ok_or("Incomplete delta time")?;
+        *pos += 1;
+        delta = delta << 7 | (b & 0x7F) as u32;
+        if b & 0x80 == 0 {
+            return Ok(delta);
+        }
+    }
+    Err("Invalid delta time".to_string())
+}
+
+/// Number of data bytes after a status byte, SysEx excluded.
+fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+fn update_running_status(running: Option<u8>, status: u8) -> Option<u8> {
+    match status {
+        0x80..=0xEF => Some(status),
+        0xF0..=0xF7 => None, // System common messages cancel running status
+        _ => running,        // Realtime messages don't
+    }
+}
+
+/// Encode the MIDI list of a command section, with running status.
+fn encode_commands(commands: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut list = vec!();
+    let mut running = None;
+    for (i, (delta, message)) in commands.iter().filter(|(_, m)| !m.is_empty()).enumerate() {
+        if i > 0 || *delta > 0 {
+            write_delta(&mut list, *delta);
+        }
+        let status = message[0];
+        let skip = if running == Some(status) { 1 } else { 0 };
+        list.extend_from_slice(&message[skip..]);
+        running = update_running_status(running, status);
+    }
+    list
+}
+
+/// Decode the MIDI list of a command section. has_delta tells whether the
+/// first command has a delta time.
+fn decode_commands(list: &[u8], has_delta: bool) -> Result<Vec<(u32, Vec<u8>)>, String> {
+    let mut commands = vec!();
+    let mut running = None;
+    let mut pos = 0;
+    while pos < list.len() {
+        let delta = if has_delta || !commands.is_empty() { read_delta(list, &mut pos)? } else { 0 };
+        let first = *list.get(pos).ok_or("Missing command")?;
+        let status = if first >= 0x80 {
+            pos += 1;
+            first
+        } else {
+            running.ok_or("Missing status byte")?
+        };
+        let mut message = vec!(status);
+        if status == 0xF0 || status == 0xF7 {
+            // SysEx or SysEx segment, ends with F7, F0 (more segments follow) or F4 (cancelled)
+            let end = list[pos..].iter().position(|b| matches!(b, 0xF0 | 0xF4 | 0xF7))
+                                 .ok_or("Unterminated SysEx")?;
+            message.extend_from_slice(&list[pos..=pos + end]);
+            pos += end + 1;
+        } else {
+            let len = data_len(status);
+            message.extend_from_slice(list.get(pos..pos + len).ok_or("Incomplete command")?);
+            pos += len;
+        }
+        running = update_running_status(running, status);
+        commands.push((delta, message));
+    }
+    Ok(commands)
+}
+
+/// Split a long SysEx message into segments.
+fn sysex_segments(message: &[u8]) -> Vec<Vec<u8>> {
+    if message.len() <= SYSEX_SEGMENT + 2 || message[0] != 0xF0 || message.last() != Some(&0xF7) {
+        return vec!(message.to_vec());
+    }
+    let chunks: Vec<&[u8]> = message[1..message.len() - 1].chunks(SYSEX_SEGMENT).collect();
+    chunks.iter().enumerate().map(|(i, chunk)| {
+        let start = if i == 0 { 0xF0 } else { 0xF7 };
+        let end = if i == chunks.len() - 1 { 0xF7 } else { 0xF0 };
+        [&[start], *chunk, &[end]].concat()
+    }).collect()
+}
+
+/// Collect SysEx segments. Returns the complete message, if there is one.
+fn join_sysex(buffer: &mut Option<Vec<u8>>, command: Vec<u8>) -> Option<Vec<u8>> {
+    let (first, last) = (command[0], command[command.len() - 1]);
+    if (first != 0xF0 && first != 0xF7) || command.len() < 2 {
+        return Some(command);
+    }
+    let data = &command[1..command.len() - 1];
+    match (first, last) {
+        (0xF0, 0xF7) => Some(command),
+        (0xF0, 0xF0) => {
+            *buffer = Some([&[0xF0], data].concat());
+            None
+        }
+        (0xF7, 0xF0) => {
+            if let Some(b) = buffer.as_mut() {
+                b.extend_from_slice(data);
+            }
+            None
+        }
+        (0xF7, 0xF7) => buffer.take().map(|mut b| {
+            b.extend_from_slice(data);
+            b.push(0xF7);
+            b
+        }),
+        _ => {
+            *buffer = None; // Cancelled
+            None
+        }
+    }
+}
+
+/// Extend a 32 bit timestamp to the 64 bit value closest to near.
+fn extend_timestamp(timestamp: u32, near: u64) -> u64 {
+    let diff = timestamp.wrapping_sub(near as u32) as i32 as i64;
+    (near as i64 + diff).max(0) as u64
+}
+
+fn random() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default());
+    hasher.finish() as u32
+}
+
+/// A session endpoint, "rtpmidi:host:port" or "rtpmidi:port".
+pub struct RtpEndpoint {
+    host: Option<String>, // The session to join, None to accept invitations
+    port: u16,
+    session: Mutex<Weak<SessionHandle>>,
+}
+
+impl RtpEndpoint {
+    /// Parse "rtpmidi:host:port" or "rtpmidi:port". Returns None for other
+    /// endpoint types.
+    pub fn parse(spec: &str) -> Option<Result<RtpEndpoint, String>> {
+        let address = spec.strip_prefix("rtpmidi:")?;
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (Some(host.to_string()), port),
+            None => (None, address),
+        };
+        match port.parse::<u16>() {
+            Ok(port) if port < u16::MAX => Some(Ok(RtpEndpoint{host, port, session: Mutex::new(Weak::new())})),
+            _ => Some(Err(format!("Invalid address '{}', use host:port or port", address))),
+        }
+    }
+
+    /// The running session, started if there is none.
+    fn session(&self) -> Result<Arc<SessionHandle>, Box<dyn Error>> {
+        let mut session = self.session.lock().unwrap();
+        if let Some(handle) = session.upgrade() {
+            return Ok(handle);
+        }
+        let remote = match &self.host {
+            Some(host) => Some((host.as_str(), self.port).to_socket_addrs()?
+                                                         .next()
+                                                         .ok_or_else(|| format!("Unknown host '{}'", host))?),
+            None => None,
+        };
+        let handle = Arc::new(SessionHandle(Session::start(remote, self.port)?));
+        *session = Arc::downgrade(&handle);
+        Ok(handle)
+    }
+}
+
+impl Endpoint for RtpEndpoint {
+    fn name(&self) -> String {
+        match &self.host {
+            Some(host) => format!("rtpmidi:{}:{}", host, self.port),
+            None => format!("rtpmidi:{}", self.port),
+        }
+    }
+
+    fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let handle = self.session()?;
+        *handle.0.callback.lock().unwrap() = Some(callback);
+        Ok(Box::new(RtpInput{handle}))
+    }
+
+    fn connect_output(&self) -> Result<Output, Box<dyn Error>> {
+        Ok(Box::new(RtpOutput{handle: self.session()?}))
+    }
+}
+
+/// Ends the session when the last input or output is closed.
+struct SessionHandle(Arc<Session>);
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+struct RtpInput {
+    handle: Arc<SessionHandle>,
+}
+
+impl InputConnection for RtpInput {}
+
+impl Drop for RtpInput {
+    fn drop(&mut self) {
+        self.handle.0.callback.lock().unwrap().take();
+    }
+}
+
+struct RtpOutput {
+    handle: Arc<SessionHandle>,
+}
+
+impl OutputConnection for RtpOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.handle.0.send(message)
+    }
+}
+
+/// A peer that joined the session, or is joining it.
+struct Peer {
+    ssrc: u32,
+    name: String,
+    control: SocketAddr,
+    data: Option<SocketAddr>, // Set when joined
+    offset: Option<i64>,      // Clock of the peer minus ours, in 100 usec
+    last_seen: Instant,
+    last_sync: Instant,
+    received: Option<u16>,    // Sequence number of the last received packet
+    feedback: bool,           // Whether received changed since the last feedback
+    confirmed: Option<u64>,   // Our last packet the peer got
+    journal: Journal,         // State of the received messages
+    sysex: Option<Vec<u8>>,   // SysEx segments received so far
+}
+
+impl Peer {
+    fn new(ssrc: u32, name: String, control: SocketAddr) -> Self {
+        Peer{ssrc, name, control, data: None, offset: None, last_seen: Instant::now(), last_sync: Instant::now(),
+             received: None, feedback: false, confirmed: None, journal: Journal::new(), sysex: None}
+    }
+}
+
+/// The sending side of the session.
+struct Stream {
+    seq: u64, // Of the last sent packet
+    journal: Journal,
+}
+
+struct Session {
+    control: UdpSocket,
+    data: UdpSocket,
+    remote: Option<SocketAddr>, // Control port of the session to join
+    token: u32,
+    ssrc: u32,
+    start: Instant,
+    peers: Mutex<Vec<Peer>>,
+    next_invite: Mutex<Instant>,
+    callback: Mutex<Option<InputCallback>>,
+    stream: Mutex<Stream>,
+    running: AtomicBool,
+}
+
+impl Session {
+    fn start(remote: Option<SocketAddr>, port: u16) -> Result<Arc<Session>, Box<dyn Error>> {
+        let (control, data) = match remote {
+            Some(addr) => {
+                let any: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+                (UdpSocket::bind(any)?, UdpSocket::bind(any)?)
+            }
+            None => (UdpSocket::bind(("0.0.0.0", port))?, UdpSocket::bind(("0.0.0.0", port + 1))?),
+        };
+        control.set_read_timeout(Some(POLL_INTERVAL))?;
+        data.set_read_timeout(Some(POLL_INTERVAL))?;
+        let session = Arc::new(Session{
+            control, data, remote,
+            token: random(),
+            ssrc: random(),
+            start: Instant::now(),
+            peers: Mutex::new(vec!()),
+            next_invite: Mutex::new(Instant::now()),
+            callback: Mutex::new(None),
+            stream: Mutex::new(Stream{seq: 0, journal: Journal::new()}),
+            running: AtomicBool::new(true),
+        });
+        for data_port in [false, true] {
+            let session = session.clone();
+            thread::spawn(move || session.receive(data_port));
+        }
+        let maintained = session.clone();
+        thread::spawn(move || maintained.maintain());
+        Ok(session)
+    }
+
+    /// Local time in units of 100 usec.
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64 / 100
+    }
+
+    fn data_port(addr: SocketAddr) -> SocketAddr {
+        SocketAddr::new(addr.ip(), addr.port() + 1)
+    }
+
+    fn reply(&self, data_port: bool, exchange: Exchange, to: SocketAddr) {
+        let socket = if data_port { &self.data } else { &self.control };
+        socket.send_to(&exchange.encode(), to).ok();
+    }
+
+    fn receive(&self, data_port: bool) {
+        let socket = if data_port { &self.data } else { &self.control };
+        let mut buf = vec!(0u8; MAX_PACKET);
+        while self.running.load(Ordering::SeqCst) {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref err) if is_timeout(err) => continue,
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => continue, // A peer went away
+                Err(err) => {
+                    eprintln!("Error when receiving RTP-MIDI: {}", err);
+                    return;
+                }
+            };
+            if let Some(exchange) = Exchange::decode(&buf[..len]) {
+                self.handle_exchange(exchange, from, data_port);
+            } else if data_port {
+                match Packet::decode(&buf[..len]) {
+                    Ok(packet) => self.handle_packet(packet),
+                    Err(err) => eprintln!("Invalid RTP-MIDI packet from {}: {}", from, err),
+                }
+            }
+        }
+    }
+
+    fn handle_exchange(&self, exchange: Exchange, from: SocketAddr, data_port: bool) {
+        let mut peers = self.peers.lock().unwrap();
+        match exchange {
+            Exchange::Invitation{token, ssrc, name} => {
+                let accepted = Exchange::Accepted{token, ssrc: self.ssrc, name: SESSION_NAME.to_string()};
+                if self.remote.is_some() {
+                    self.reply(data_port, Exchange::Rejected{token, ssrc: self.ssrc}, from);
+                } else if !data_port {
+                    peers.retain(|p| p.ssrc != ssrc);
+                    peers.push(Peer::new(ssrc, name, from));
+                    self.reply(data_port, accepted, from);
+                } else if let Some(peer) = peers.iter_mut().find(|p| p.ssrc == ssrc) {
+                    if peer.data.is_none() {
+                        eprintln!("RTP-MIDI peer '{}' joined from {}", peer.name, from);
+                    }
+                    peer.data = Some(from);
+                    self.reply(data_port, accepted, from);
+                } else {
+                    self.reply(data_port, Exchange::Rejected{token, ssrc: self.ssrc}, from);
+                }
+            }
+            Exchange::Accepted{token, ssrc, name} if token == self.token && self.remote.is_some() => {
+                if !data_port {
+                    if peers.is_empty() {
+                        peers.push(Peer::new(ssrc, name, from));
+                    }
+                    let invitation = Exchange::Invitation{token, ssrc: self.ssrc, name: SESSION_NAME.to_string()};
+                    self.reply(true, invitation, Self::data_port(from));
+                } else if let Some(peer) = peers.iter_mut().find(|p| p.ssrc == ssrc && p.data.is_none()) {
+                    eprintln!("Joined RTP-MIDI session '{}' at {}", peer.name, peer.control);
+                    peer.data = Some(from);
+                    peer.last_sync = Instant::now();
+                    self.reply(true, Exchange::Sync{ssrc: self.ssrc, count: 0, timestamps: [self.now(), 0, 0]}, from);
+                }
+            }
+            Exchange::Accepted{..} => (),
+            Exchange::Rejected{..} => {
+                if self.remote.is_some() {
+                    eprintln!("Invitation rejected by {}", from);
+                    peers.clear();
+                    *self.next_invite.lock().unwrap() = Instant::now() + REJECTED_DELAY;
+                }
+            }
+            Exchange::End{ssrc, ..} => {
+                if let Some(peer) = peers.iter().find(|p| p.ssrc == ssrc) {
+                    eprintln!("RTP-MIDI peer '{}' left the session", peer.name);
+                }
+                peers.retain(|p| p.ssrc != ssrc);
+            }
+            Exchange::Sync{ssrc, count, timestamps} => {
+                let now = self.now();
+                if let Some(peer) = peers.iter_mut().find(|p| p.ssrc == ssrc) {
+                    peer.last_seen = Instant::now();
+                    let [t1, t2, t3] = timestamps.map(|t| t as i64);
+                    match count {
+                        0 => self.reply(true, Exchange::Sync{ssrc: self.ssrc, count: 1, timestamps: [timestamps[0], now, 0]}, from),
+                        1 => {
+                            self.reply(true, Exchange::Sync{ssrc: self.ssrc, count: 2, timestamps: [timestamps[0], t2 as u64, now]}, from);
+                            peer.offset = Some(t2 - (t1 + now as i64) / 2);
+                        }
+                        _ => peer.offset = Some((t1 + t3) / 2 - t2),
+                    }
+                }
+            }
+            Exchange::Feedback{ssrc, seq} => {
+                drop(peers);
+                self.confirm(ssrc, seq);
+            }
+        }
+    }
+
+    /// A peer got our packets up to seq, forget them in the journal once
+    /// all peers have them.
+    fn confirm(&self, ssrc: u32, seq: u16) {
+        let last = self.stream.lock().unwrap().seq;
+        let confirmed = last.saturating_sub((last as u16).wrapping_sub(seq) as u64);
+        let checkpoint = {
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(peer) = peers.iter_mut().find(|p| p.ssrc == ssrc) {
+                peer.confirmed = Some(confirmed);
+                peer.last_seen = Instant::now();
+            }
+            peers.iter().filter(|p| p.data.is_some()).map(|p| p.confirmed).min().flatten()
+        };
+        if let Some(checkpoint) = checkpoint {
+            self.stream.lock().unwrap().journal.checkpoint(checkpoint);
+        }
+    }
+
+    fn handle_packet(&self, packet: Packet) {
+        let mut messages = vec!();
+        {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = match peers.iter_mut().find(|p| p.ssrc == packet.ssrc && p.data.is_some()) {
+                Some(peer) => peer,
+                None => return,
+            };
+            peer.last_seen = Instant::now();
+            if let Some(last) = peer.received {
+                let lost = packet.seq.wrapping_sub(last.wrapping_add(1));
+                if lost >= 0x8000 {
+                    return; // Late or repeated packet
+                }
+                if lost > 0 {
+                    peer.sysex = None;
+                    match packet.journal.as_deref().map(|j| peer.journal.recover(j)) {
+                        Some(Ok(repairs)) => {
+                            eprintln!("Lost {} packets from '{}', recovered {} messages", lost, peer.name, repairs.len());
+                            messages.extend(repairs.into_iter().map(|r| (self.now() * 100, r)));
+                        }
+                        Some(Err(err)) => eprintln!("Lost {} packets from '{}': {}", lost, peer.name, err),
+                        None => eprintln!("Lost {} packets from '{}'", lost, peer.name),
+                    }
+                }
+            }
+            peer.received = Some(packet.seq);
+            peer.feedback = true;
+            let mut time = packet.timestamp;
+            for (delta, command) in packet.commands {
+                time = time.wrapping_add(delta);
+                if let Some(message) = join_sysex(&mut peer.sysex, command) {
+                    peer.journal.update(&message, 0);
+                    messages.push((self.local_time(peer.offset, time), message));
+                }
+            }
+        }
+        // The callback may send to this session
+        if let Some(callback) = self.callback.lock().unwrap().as_mut() {
+            for (timestamp, message) in messages {
+                callback(timestamp, &message);
+            }
+        }
+    }
+
+    /// Convert a timestamp of the peer to local usec.
+    fn local_time(&self, offset: Option<i64>, timestamp: u32) -> u64 {
+        let now = self.now();
+        match offset {
+            Some(offset) => extend_timestamp(timestamp.wrapping_sub(offset as u32), now) * 100,
+            None => now * 100,
+        }
+    }
+
+    fn send(&self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let addrs: Vec<SocketAddr> = self.peers.lock().unwrap().iter().filter_map(|p| p.data).collect();
+        if addrs.is_empty() {
+            return Err("Not connected".into());
+        }
+        for segment in sysex_segments(message) {
+            let bytes = {
+                let mut stream = self.stream.lock().unwrap();
+                stream.seq += 1;
+                let packet = Packet{seq: stream.seq as u16, timestamp: self.now() as u32, ssrc: self.ssrc,
+                                    commands: vec!((0, segment.clone())), journal: Some(stream.journal.encode())};
+                let seq = stream.seq;
+                stream.journal.update(&segment, seq);
+                packet.encode()
+            };
+            for addr in &addrs {
+                self.data.send_to(&bytes, addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Invite, synchronize clocks, send feedback and drop silent peers.
+    fn maintain(&self) {
+        let mut last_feedback = Instant::now();
+        while self.running.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(remote) = self.remote {
+                let mut next_invite = self.next_invite.lock().unwrap();
+                let invitation = Exchange::Invitation{token: self.token, ssrc: self.ssrc, name: SESSION_NAME.to_string()};
+                if Instant::now() >= *next_invite {
+                    if peers.is_empty() {
+                        self.reply(false, invitation, remote);
+                    } else if peers[0].data.is_none() {
+                        self.reply(true, invitation, Self::data_port(remote));
+                    }
+                    *next_invite = Instant::now() + INVITE_INTERVAL;
+                }
+                for peer in peers.iter_mut().filter(|p| p.data.is_some() && p.last_sync.elapsed() >= SYNC_INTERVAL) {
+                    peer.last_sync = Instant::now();
+                    let sync = Exchange::Sync{ssrc: self.ssrc, count: 0, timestamps: [self.now(), 0, 0]};
+                    self.reply(true, sync, peer.data.unwrap());
+                }
+            }
+            if last_feedback.elapsed() >= FEEDBACK_INTERVAL {
+                last_feedback = Instant::now();
+                for peer in peers.iter_mut().filter(|p| p.feedback) {
+                    peer.feedback = false;
+                    let feedback = Exchange::Feedback{ssrc: self.ssrc, seq: peer.received.unwrap_or_default()};
+                    self.reply(false, feedback, peer.control);
+                }
+            }
+            peers.retain(|p| {
+                let alive = p.last_seen.elapsed() < PEER_TIMEOUT;
+                if !alive {
+                    eprintln!("RTP-MIDI peer '{}' timed out", p.name);
+                }
+                alive
+            });
+        }
+    }
+
+    fn close(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        for peer in self.peers.lock().unwrap().drain(..) {
+            self.reply(false, Exchange::End{token: self.token, ssrc: self.ssrc}, peer.control);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_packets_round_trip() {
+        let packets = vec!(
+            Exchange::Invitation{token: 1, ssrc: 2, name: "Session".to_string()},
+            Exchange::Accepted{token: 1, ssrc: 3, name: String::new()},
+            Exchange::Rejected{token: 1, ssrc: 3},
+            Exchange::End{token: 1, ssrc: 2},
+            Exchange::Sync{ssrc: 2, count: 1, timestamps: [1, 1 << 40, 3]},
+            Exchange::Feedback{ssrc: 3, seq: 0x1234},
+        );
+        for packet in packets {
+            assert_eq!(Exchange::decode(&packet.encode()), Some(packet));
+        }
+        let invitation = Exchange::Invitation{token: 1, ssrc: 2, name: "A".to_string()}.encode();
+        assert_eq!(invitation, vec!(0xFF, 0xFF, b'I', b'N', 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, b'A', 0));
+        assert_eq!(Exchange::decode(&[0x80, 0x61, 0, 0]), None);
+        assert_eq!(Exchange::decode(b"\xFF\xFFCK\x00"), None);
+    }
+
+    #[test]
+    fn encodes_command_section() {
+        let packet = Packet{seq: 7, timestamp: 100, ssrc: 9, journal: None,
+                            commands: vec!((0, vec!(0x90, 60, 100)), (0, vec!(0x90, 64, 100)),
+                                           (200, vec!(0xF8)), (0, vec!(0x90, 67, 100)))};
+        let bytes = packet.encode();
+        assert_eq!(&bytes[12..], &[12, 0x90, 60, 100, 0, 64, 100, 0x81, 0x48, 0xF8, 0, 67, 100]);
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+
+        // Long header, delta time before the first command and a journal
+        let sysex = [&[0xF0], &[0x11; 20][..], &[0xF7]].concat();
+        let packet = Packet{seq: 8, timestamp: 0, ssrc: 9, journal: Some(vec!(0, 0, 7)),
+                            commands: vec!((5, sysex.clone()), (0, vec!(0xC1, 3)))};
+        let bytes = packet.encode();
+        assert_eq!(&bytes[12..14], &[0x80 | 0x40 | 0x20, 26]);
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        assert!(Packet::decode(&[0x80, 0x61, 0, 0]).is_err());
+        assert!(Packet::decode(&[0x80, 0x60, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        let header = [0x80, 0x61, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert!(Packet::decode(&[&header[..], &[2, 60, 100]].concat()).is_err()); // No status
+        assert!(Packet::decode(&[&header[..], &[2, 0x90, 60]].concat()).is_err()); // Incomplete
+        assert!(Packet::decode(&[&header[..], &[3, 0xF0, 1, 2]].concat()).is_err()); // Unterminated SysEx
+        assert!(Packet::decode(&[&header[..], &[5, 0x90]].concat()).is_err()); // Too short
+    }
+
+    #[test]
+    fn segments_long_sysex() {
+        let sysex = [&[0xF0], &[0x22; 2500][..], &[0xF7]].concat();
+        let segments = sysex_segments(&sysex);
+        assert_eq!(segments.len(), 3);
+        assert_eq!((segments[0][0], segments[0][1001]), (0xF0, 0xF0));
+        assert_eq!((segments[1][0], segments[1][1001]), (0xF7, 0xF0));
+        assert_eq!((segments[2][0], segments[2][501]), (0xF7, 0xF7));
+
+        let mut buffer = None;
+        assert_eq!(join_sysex(&mut buffer, segments[0].clone()), None);
+        assert_eq!(join_sysex(&mut buffer, segments[1].clone()), None);
+        assert_eq!(join_sysex(&mut buffer, segments[2].clone()), Some(sysex));
+        // Cancelled
+        assert_eq!(join_sysex(&mut buffer, segments[0].clone()), None);
+        assert_eq!(join_sysex(&mut buffer, vec!(0xF7, 0x01, 0xF4)), None);
+        assert_eq!(join_sysex(&mut buffer, segments[2].clone()), None);
+        assert_eq!(join_sysex(&mut buffer, vec!(0xB0, 7, 100)), Some(vec!(0xB0, 7, 100)));
+    }
+
+    #[test]
+    fn extends_timestamps() {
+        assert_eq!(extend_timestamp(10, 5), 10);
+        assert_eq!(extend_timestamp(u32::MAX, 1 << 32), (1 << 32) - 1);
+        assert_eq!(extend_timestamp(3, (1 << 32) - 2), (1 << 32) + 3);
+        assert_eq!(extend_timestamp(u32::MAX, 2), 0);
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(RtpEndpoint::parse("rtpmidi:5004").unwrap().unwrap().name(), "rtpmidi:5004");
+        assert_eq!(RtpEndpoint::parse("rtpmidi:studio.local:5004").unwrap().unwrap().name(), "rtpmidi:studio.local:5004");
+        assert!(RtpEndpoint::parse("rtpmidi:host").unwrap().is_err());
+        assert!(RtpEndpoint::parse("rtpmidi:65535").unwrap().is_err());
+        assert!(RtpEndpoint::parse("udp:1.2.3.4:5").is_none());
+    }
+}
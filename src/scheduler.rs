@@ -0,0 +1,173 @@
+//! Sends messages to an output at a given time.
+//!
+//! The MIDI callbacks can only react to incoming data. Messages that have to
+//! be sent later (delayed or generated clocks) are put into the queue of a
+//! Scheduler, which sends them from its own thread when they are due.
+//! Messages can be tagged, to remove them again before they are sent.
+
+use super::backend::Output;
+use super::traffic::SharedCounters;
+use super::MidiMessage;
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const SPIN_TIME: Duration = Duration::from_micros(300); // Busy wait for the last part of a delay
+
+/// An output shared between a callback and a Scheduler.
+pub type SharedOutput = Arc<Mutex<Output>>;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    time: Instant,
+    seq: u64, // Keeps the order of messages with the same time
+    tag: u32,
+    message: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: BinaryHeap<Reverse<Entry>>,
+    seq: u64,
+    quit: bool,
+}
+
+/// Sends queued messages from its own thread.
+pub struct Scheduler {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Create a scheduler for an output. Sent messages and errors are
+    /// counted in counters, if given.
+    pub fn new(output: SharedOutput, counters: Option<SharedCounters>) -> Scheduler {
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let thread_queue = queue.clone();
+        let handle = thread::spawn(move || Scheduler::run(thread_queue, output, counters));
+        Scheduler{queue, handle: Some(handle)}
+    }
+
+    /// Queue a message for sending at the given time.
+    pub fn schedule(&self, time: Instant, message: &[u8], tag: u32) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        queue.seq += 1;
+        let seq = queue.seq;
+        queue.entries.push(Reverse(Entry{time, seq, tag, message: message.to_vec()}));
+        cvar.notify_one();
+    }
+
+    /// Remove all queued messages with the given tag, returns the number of
+    /// removed messages.
+    pub fn cancel(&self, tag: u32) -> usize {
+        let (lock, _) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        let before = queue.entries.len();
+        let entries = std::mem::take(&mut queue.entries);
+        queue.entries = entries.into_iter().filter(|e| e.0.tag != tag).collect();
+        before - queue.entries.len()
+    }
+
+    fn run(queue: Arc<(Mutex<Queue>, Condvar)>, output: SharedOutput, counters: Option<SharedCounters>) {
+        let (lock, cvar) = &*queue;
+        let mut send_error = false;
+        loop {
+            let mut q = lock.lock().unwrap();
+            if q.quit {
+                break;
+            }
+            let next = match q.entries.peek() {
+                Some(e) => e.0.time,
+                None => {
+                    drop(cvar.wait(q).unwrap());
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            if next > now + SPIN_TIME {
+                // Wait until shortly before the message is due, or a new one arrives
+                drop(cvar.wait_timeout(q, next - now - SPIN_TIME).unwrap());
+                continue;
+            }
+            if next > now {
+                drop(q);
+                while Instant::now() < next {
+                    std::hint::spin_loop();
+                }
+                continue;
+            }
+            let entry = q.entries.pop().unwrap().0;
+            drop(q);
+            let result = output.lock().unwrap().send(&entry.message);
+            if result.is_err() && !send_error {
+                eprintln!("Error when sending scheduled message ...");
+                send_error = true;
+            }
+            if let Some(c) = counters.as_ref() {
+                let mut c = c.lock().unwrap();
+                match result {
+                    Ok(_) => c.count(Instant::now(), &MidiMessage::parse(&entry.message), entry.message.len()),
+                    Err(_) => c.errors += 1,
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        {
+            let (lock, cvar) = &*self.queue;
+            lock.lock().unwrap().quit = true;
+            cvar.notify_one();
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::backend::Backend;
+
+    fn start() -> (MockBackend, Scheduler) {
+        let backend = MockBackend::new(&[], &["synth"]);
+        let output = Arc::new(Mutex::new(backend.connect_output(0).unwrap()));
+        (backend, Scheduler::new(output, None))
+    }
+
+    #[test]
+    fn sends_in_time_order() {
+        let (backend, scheduler) = start();
+        let now = Instant::now();
+        // Messages with the same time are sent in the order they were queued
+        for note in 1..4 {
+            scheduler.schedule(now + Duration::from_millis(50), &[0x90, note, 100], 0);
+        }
+        scheduler.schedule(now + Duration::from_millis(20), &[0x90, 0, 100], 0);
+        let notes: Vec<u8> = backend.wait_for_sent(0, 4).iter().map(|m| m[1]).collect();
+        assert_eq!(notes, vec!(0, 1, 2, 3));
+        assert!(now.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn cancels_tagged_messages() {
+        let (backend, scheduler) = start();
+        let now = Instant::now();
+        scheduler.schedule(now + Duration::from_millis(50), &[0xF8], 7);
+        scheduler.schedule(now + Duration::from_millis(50), &[0xF8], 7);
+        scheduler.schedule(now + Duration::from_millis(60), &[0xFC], 0);
+        assert_eq!(scheduler.cancel(7), 2);
+        assert_eq!(scheduler.cancel(7), 0);
+        backend.wait_for_sent(0, 1);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(backend.sent(0), vec!(vec!(0xFC)));
+    }
+}
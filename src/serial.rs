@@ -0,0 +1,214 @@
+//! MIDI over serial ports.
+//!
+//! DIN MIDI through a UART or a USB-serial adapter is a plain byte stream:
+//! messages can omit the status byte of the previous message (running
+//! status), and real-time messages can appear anywhere, even within other
+//! messages. Received bytes are split into complete messages for the routes
+//! (see stream), sent messages use running status to save bandwidth.
+//!
+//! Endpoints are given as "serial:/dev/ttyUSB0" (31250 baud, the MIDI rate)
+//! or with the baud rate, e.g. "serial:/dev/ttyUSB0:115200". The device is
+//! set to raw 8N1 mode.
+
+use super::backend::{InputCallback, InputConnection, Output, OutputConnection};
+use super::endpoint::Endpoint;
+use super::stream::{StreamEncoder, StreamParser};
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+const MIDI_BAUD: u32 = 31250;
+const READ_TIMEOUT: libc::cc_t = 1; // In 1/10 sec, how often the reading thread checks for closing
+
+/// A serial port, given as "serial:path" or "serial:path:baud".
+pub struct SerialEndpoint {
+    path: String,
+    baud: u32,
+}
+
+impl SerialEndpoint {
+    /// Returns None if the spec is no serial port.
+    pub fn parse(spec: &str) -> Option<Result<SerialEndpoint, String>> {
+        let rest = spec.strip_prefix("serial:")?;
+        let (path, baud) = match rest.rfind(':') {
+            Some(pos) => match rest[pos + 1..].parse() {
+                Ok(baud) => (&rest[..pos], baud),
+                Err(_) => return Some(Err(format!("Invalid baud rate in '{}'", spec))),
+            },
+            None => (rest, MIDI_BAUD),
+        };
+        if path.is_empty() {
+            return Some(Err(format!("Missing device in '{}'", spec)));
+        }
+        Some(Ok(SerialEndpoint{path: path.to_string(), baud}))
+    }
+
+    fn open(&self) -> Result<File, Box<dyn Error>> {
+        let file = OpenOptions::new().read(true)
+                                     .write(true)
+                                     .custom_flags(libc::O_NOCTTY)
+                                     .open(&self.path)
+                                     .map_err(|err| format!("Can't open '{}': {}", self.path, err))?;
+        configure(&file, self.baud).map_err(|err| format!("Can't configure '{}': {}", self.path, err))?;
+        Ok(file)
+    }
+}
+
+impl Endpoint for SerialEndpoint {
+    fn name(&self) -> String {
+        if self.baud == MIDI_BAUD {
+            format!("serial:{}", self.path)
+        } else {
+            format!("serial:{}:{}", self.path, self.baud)
+        }
+    }
+
+    fn connect_input(&self, callback: InputCallback) -> Result<Box<dyn InputConnection>, Box<dyn Error>> {
+        let file = self.open()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let path = self.path.clone();
+        thread::spawn(move || {
+            if let Err(err) = receive(file, &path, callback, thread_running) {
+                eprintln!("Error when reading from '{}': {}", path, err);
+            }
+        });
+        Ok(Box::new(SerialInput{running}))
+    }
+
+    fn connect_output(&self) -> Result<Output, Box<dyn Error>> {
+        Ok(Box::new(SerialOutput{file: self.open()?, encoder: StreamEncoder::new(true)}))
+    }
+}
+
+/// Stops the reading thread when dropped.
+struct SerialInput {
+    running: Arc<AtomicBool>,
+}
+
+impl InputConnection for SerialInput {}
+
+impl Drop for SerialInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+struct SerialOutput {
+    file: File,
+    encoder: StreamEncoder,
+}
+
+impl OutputConnection for SerialOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.file.write_all(self.encoder.encode(message))?;
+        Ok(())
+    }
+}
+
+fn receive(mut file: File, path: &str, mut callback: InputCallback, running: Arc<AtomicBool>) -> io::Result<()> {
+    let start = Instant::now();
+    let mut parser = StreamParser::new();
+    let mut reported = 0;
+    let mut buf = [0u8; 1024];
+    while running.load(Ordering::SeqCst) {
+        // Returns 0 bytes after the read timeout
+        let len = match file.read(&mut buf) {
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let timestamp = start.elapsed().as_micros() as u64;
+        parser.parse(&buf[..len], |message| callback(timestamp, message));
+        if parser.dropped() > reported {
+            eprintln!("Dropped {} invalid bytes from '{}'", parser.dropped() - reported, path);
+            reported = parser.dropped();
+        }
+    }
+    Ok(())
+}
+
+/// Set raw 8N1 mode with the baud rate. Reads time out after READ_TIMEOUT.
+fn configure(file: &File, baud: u32) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = READ_TIMEOUT;
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    set_baud(fd, baud)
+}
+
+/// Linux allows any rate, including the 31250 baud of MIDI.
+#[cfg(target_os = "linux")]
+fn set_baud(fd: libc::c_int, baud: u32) -> io::Result<()> {
+    unsafe {
+        let mut termios: libc::termios2 = std::mem::zeroed();
+        if libc::ioctl(fd, libc::TCGETS2, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        termios.c_cflag &= !libc::CBAUD;
+        termios.c_cflag |= libc::BOTHER;
+        termios.c_ispeed = baud;
+        termios.c_ospeed = baud;
+        if libc::ioctl(fd, libc::TCSETS2, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Other systems only support the standard rates.
+#[cfg(not(target_os = "linux"))]
+fn set_baud(fd: libc::c_int, baud: u32) -> io::Result<()> {
+    let speed = match baud {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported baud rate {}", baud))),
+    };
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 || libc::cfsetspeed(&mut termios, speed) != 0
+                || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        let endpoint = SerialEndpoint::parse("serial:/dev/ttyUSB0").unwrap().unwrap();
+        assert_eq!((endpoint.path.as_str(), endpoint.baud), ("/dev/ttyUSB0", 31250));
+        assert_eq!(endpoint.name(), "serial:/dev/ttyUSB0");
+        let endpoint = SerialEndpoint::parse("serial:/dev/ttyACM1:115200").unwrap().unwrap();
+        assert_eq!((endpoint.path.as_str(), endpoint.baud), ("/dev/ttyACM1", 115200));
+        assert_eq!(endpoint.name(), "serial:/dev/ttyACM1:115200");
+        assert!(SerialEndpoint::parse("serial:/dev/ttyACM1:fast").unwrap().is_err());
+        assert!(SerialEndpoint::parse("udp:127.0.0.1:5004").is_none());
+    }
+}
@@ -0,0 +1,229 @@
+//! A running session: the routes with the workers for monitoring, recording,
+//! the terminal UI and the web server.
+//!
+//! The routes forward in the MIDI callbacks, everything else is handed to
+//! worker threads (see worker). Routes added while running are monitored,
+//! recorded and shown like the others. Closing the session closes the routes,
+//! waits for the workers and returns the summaries of the monitored ports.
+
+use super::backend::Backend;
+use super::clock::ClockGenerator;
+use super::control::ControlServer;
+use super::display::{Colors, Display, MonitorOptions};
+use super::mtc::MtcGenerator;
+use super::pipe;
+use super::recording::RecordFormat;
+use super::router::{Config, Router, Sinks};
+use super::traffic::Traffic;
+use super::tui::Event;
+use super::web::{self, WebServer};
+use super::worker::{self, QueueSender, Worker};
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+/// Generators and servers running alongside the routes.
+#[derive(Default)]
+pub struct Services {
+    pub clock: Option<ClockGenerator>,
+    pub mtc: Option<MtcGenerator>,
+    pub web: Option<WebServer>,
+    pub control: Option<ControlServer>,
+}
+
+/// What happens with the received messages besides forwarding.
+pub struct SessionOptions<'a> {
+    pub monitor: bool,
+    pub recording: Option<(&'a str, RecordFormat)>, // File name prefix and format
+    pub colors: &'static Colors,
+    pub display: &'a MonitorOptions,
+}
+
+/// The queue and ports of the terminal UI, which is run by the caller.
+pub struct TuiFeed {
+    pub tx: QueueSender<Event>,
+    pub rx: Receiver<Event>,
+    pub ports: Vec<usize>,         // Input ports of the routes
+    pub port_names: Vec<String>,   // Names of all input ports
+}
+
+/// Routes with their workers.
+pub struct Session {
+    router: Arc<Mutex<Router>>, // Shared with the web server
+    traffic: Traffic,
+    monitor: Worker<Vec<Option<Display>>>,
+    recorder: Worker<Vec<Option<(String, File)>>>,
+    streamer: Option<Worker<()>>,
+    tui: Option<TuiFeed>,
+    summaries: Option<Vec<(usize, usize)>>, // Route and input port of the monitored ports
+    reads_stdin: bool,
+}
+
+impl Session {
+    /// Start the routes of configs and the workers for the options.
+    ///
+    /// With the terminal UI, monitoring is off as the UI owns the terminal.
+    pub fn start(backend: &(dyn Backend + Sync), configs: &[Config], options: &SessionOptions, services: &Services)
+            -> Result<Session, Box<dyn Error>> {
+        let use_tui = options.display.use_tui;
+        let do_monitor = options.monitor && !use_tui;
+        let in_port_names = backend.input_ports()?;
+        let out_port_names = backend.output_ports()?;
+        let reads_stdin = configs.iter().any(|c| in_port_names.get(c.in_port).is_some_and(|n| pipe::is_stdio(n)));
+        let writes_stdout = configs.iter().any(|c| c.forwards() && out_port_names.get(c.out_port).is_some_and(|n| pipe::is_stdio(n)));
+        if reads_stdin && use_tui {
+            return Err("The terminal UI can't be used while reading messages from stdin".into());
+        }
+        if writes_stdout && do_monitor {
+            return Err("Can't monitor while writing messages to stdout".into());
+        }
+        let traffic = Traffic::new();
+
+        // Monitoring, recording and the UI are fed through queues, to keep
+        // slow output away from the forwarding
+        let (monitor_tx, monitor_rx) = worker::queue();
+        let (record_tx, record_rx) = worker::queue();
+        let (tui_tx, tui_rx) = worker::queue();
+        let (web_tx, web_rx) = worker::queue();
+        traffic.add_queue("monitor", monitor_tx.dropped());
+        traffic.add_queue("recording", record_tx.dropped());
+        traffic.add_queue("UI", tui_tx.dropped());
+        if services.web.is_some() {
+            traffic.add_queue("web", web_tx.dropped());
+        }
+        let mut displays = vec!();
+        let mut files = vec!();
+        let mut tui_ports = vec!();
+        let mut summaries = if do_monitor { Some(vec!()) } else { None };
+        let mut monitored = HashSet::new();
+
+        for (route, config) in configs.iter().enumerate() {
+            let in_port_name = in_port_names.get(config.in_port).ok_or("Invalid port number")?;
+
+            // Only the first route of every port feeds the UI, records and
+            // prints a summary, to avoid duplicates
+            let first_of_port = monitored.insert(config.in_port);
+            displays.push(if do_monitor {
+                Some(Display::new(options.colors, options.display.for_port(config.in_port), in_port_name))
+            } else {
+                None
+            });
+            if let Some(summaries) = summaries.as_mut().filter(|_| first_of_port) {
+                summaries.push((route, config.in_port));
+            }
+            if use_tui && first_of_port {
+                tui_ports.push(config.in_port);
+            }
+            files.push(match options.recording {
+                Some((outfile, _)) if first_of_port => {
+                    let filename = format!("{}_p{}", outfile, config.in_port);
+                    let file = File::create(&filename)?;
+                    Some((filename, file))
+                }
+                _ => None,
+            });
+        }
+
+        let sinks = Sinks{
+            monitor: if do_monitor { Some(monitor_tx.clone()) } else { None },
+            record: options.recording.map(|_| record_tx.clone()),
+            tui: if use_tui { Some(tui_tx.clone()) } else { None },
+            web: services.web.as_ref().map(|_| web_tx.clone()),
+            // The MTC generator follows the transport of the chased port
+            mtc_chase: services.mtc.as_ref().and_then(|g| g.chase_port().map(|port| (port, g.sender()))),
+        };
+        let router = Arc::new(Mutex::new(Router::start(backend, configs, &sinks, &traffic)?));
+        drop(sinks);
+
+        // Routes added while running get their display and file when their
+        // first message arrives
+        let dropped = monitor_tx.dropped();
+        let (colors, display_options, port_names) = (options.colors, options.display.clone(), in_port_names.clone());
+        let new_display = move |port: usize| {
+            let name = port_names.get(port).map(|n| n.as_str()).unwrap_or("");
+            Display::new(colors, display_options.for_port(port), name)
+        };
+        let monitor = Worker::spawn(move || worker::monitor(monitor_rx, displays, new_display, dropped));
+        let dropped = record_tx.dropped();
+        let format = options.recording.map(|(_, format)| format).unwrap_or(RecordFormat::Hex);
+        let outfile = options.recording.map(|(outfile, _)| outfile.to_string()).unwrap_or_default();
+        let new_file = move |port: usize| {
+            let filename = format!("{}_p{}", outfile, port);
+            match File::create(&filename) {
+                Ok(file) => Some((filename, file)),
+                Err(err) => {
+                    eprintln!("Error: Can't record to '{}': {}", filename, err);
+                    None
+                }
+            }
+        };
+        let recorder = Worker::spawn(move || worker::record(record_rx, files, new_file, format, dropped));
+        let streamer = match services.web.as_ref() {
+            Some(server) => {
+                server.set_status(&in_port_names, &out_port_names, &router, &traffic);
+                let clients = server.clients();
+                let port_names = in_port_names.clone();
+                Some(Worker::spawn(move || web::stream(web_rx, clients, port_names)))
+            }
+            None => None,
+        };
+        let tui = if use_tui { Some(TuiFeed{tx: tui_tx, rx: tui_rx, ports: tui_ports, port_names: in_port_names}) } else { None };
+
+        Ok(Session{router, traffic, monitor, recorder, streamer, tui, summaries, reads_stdin})
+    }
+
+    /// The router, for changing the routes while running.
+    pub fn router(&self) -> &Mutex<Router> {
+        &self.router
+    }
+
+    /// The traffic statistics of the routes.
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Whether a route reads from stdin, which then isn't available for
+    /// commands.
+    pub fn reads_stdin(&self) -> bool {
+        self.reads_stdin
+    }
+
+    /// The feed of the terminal UI, if it's used. Only returned once.
+    pub fn take_tui(&mut self) -> Option<TuiFeed> {
+        self.tui.take()
+    }
+
+    /// Close the routes and wait until the workers handled the remaining
+    /// messages. Returns the summaries of the monitored ports.
+    pub fn close(self) -> Vec<String> {
+        // Routes added while running are summarized for their first port
+        let mut summaries = self.summaries;
+        if let Some(summaries) = summaries.as_mut() {
+            for r in self.router.lock().unwrap().routes() {
+                if !summaries.iter().any(|(_, port)| *port == r.config.in_port) {
+                    summaries.push((r.route, r.config.in_port));
+                }
+            }
+        }
+
+        // Dropping the router closes the connections and the queues, the web
+        // server only holds a weak reference
+        drop(self.tui);
+        drop(self.router);
+        let displays = self.monitor.join().unwrap_or_default();
+        self.recorder.join();
+        if let Some(streamer) = self.streamer {
+            streamer.join();
+        }
+        let mut lines = vec!();
+        for (route, port) in summaries.into_iter().flatten() {
+            if let Some(Some(display)) = displays.get(route) {
+                lines.extend(display.summary(port));
+            }
+        }
+        lines
+    }
+}
@@ -0,0 +1,249 @@
+//! MIDI byte streams.
+//!
+//! MidiMessage::parse expects a single complete message, as delivered by the
+//! system MIDI APIs. Serial ports, pipes and files deliver a byte stream
+//! instead, split at arbitrary points:
+//!
+//! * channel messages can omit the status byte of the previous message
+//!   (running status)
+//! * real-time messages (0xF8 - 0xFF) can appear anywhere, even within
+//!   other messages and SysEx
+//! * SysEx messages can be split over any number of reads
+//!
+//! StreamParser splits such a stream into complete messages. It
+//! resynchronizes after garbage: data bytes without a status are dropped, a
+//! status byte ends an incomplete message, which is dropped too.
+//! StreamEncoder writes messages as a stream, optionally with running status.
+
+/// Longest SysEx message, longer ones are dropped.
+pub const MAX_SYSEX: usize = 65536;
+
+/// Number of bytes of a message with the given status, 0 for SysEx.
+pub fn message_len(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 3,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 2,
+        0xF0 => 0,
+        _ => 1,
+    }
+}
+
+/// Splits a MIDI byte stream into complete messages.
+#[derive(Default)]
+pub struct StreamParser {
+    status: u8,       // For running status, 0 = none
+    message: Vec<u8>, // Incomplete message, starting with its status
+    sysex: bool,      // Within a SysEx message
+    dropped: u64,
+}
+
+impl StreamParser {
+    pub fn new() -> Self {
+        StreamParser::default()
+    }
+
+    /// Parse the next bytes of the stream, calls emit for every complete
+    /// message.
+    pub fn parse(&mut self, bytes: &[u8], mut emit: impl FnMut(&[u8])) {
+        for &byte in bytes {
+            match byte {
+                0xF9 | 0xFD => self.dropped += 1, // Undefined real-time
+                0xF8..=0xFF => emit(&[byte]),     // Real-time, even within other messages
+                0xF7 => {
+                    if self.sysex {
+                        self.message.push(byte);
+                        emit(&self.message);
+                    } else {
+                        self.drop_message();
+                        self.dropped += 1;
+                    }
+                    self.message.clear();
+                    self.sysex = false;
+                    self.status = 0;
+                }
+                0x80..=0xF6 => {
+                    self.drop_message();
+                    self.message.push(byte);
+                    self.sysex = byte == 0xF0;
+                    // System common cancels running status
+                    self.status = if byte < 0xF0 { byte } else { 0 };
+                    match byte {
+                        0xF4 | 0xF5 => {
+                            // Undefined system common
+                            self.message.clear();
+                            self.dropped += 1;
+                        }
+                        0xF6 => {
+                            emit(&self.message);
+                            self.message.clear();
+                        }
+                        _ => (),
+                    }
+                }
+                _ => self.data(byte, &mut emit),
+            }
+        }
+    }
+
+    fn data(&mut self, byte: u8, emit: &mut impl FnMut(&[u8])) {
+        if self.message.is_empty() {
+            if self.status == 0 {
+                self.dropped += 1; // No status to apply the data to
+                return;
+            }
+            self.message.push(self.status);
+        }
+        if self.sysex {
+            if self.message.len() < MAX_SYSEX {
+                self.message.push(byte);
+            } else {
+                self.drop_message();
+                self.sysex = false;
+                self.dropped += 1;
+            }
+            return;
+        }
+        self.message.push(byte);
+        if self.message.len() == message_len(self.message[0]) {
+            emit(&self.message);
+            self.message.clear();
+        }
+    }
+
+    /// Drop an incomplete message.
+    fn drop_message(&mut self) {
+        self.dropped += self.message.len() as u64;
+        self.message.clear();
+    }
+
+    /// Parse the next bytes of the stream, returns the complete messages.
+    pub fn messages(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = vec!();
+        self.parse(bytes, |m| messages.push(m.to_vec()));
+        messages
+    }
+
+    /// Forget an incomplete message and the running status, e.g. after the
+    /// stream was interrupted.
+    pub fn reset(&mut self) {
+        self.drop_message();
+        self.status = 0;
+        self.sysex = false;
+    }
+
+    /// Number of bytes dropped so far, because they didn't make up a valid
+    /// message.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Writes messages as a byte stream.
+#[derive(Default)]
+pub struct StreamEncoder {
+    running_status: bool,
+    status: u8,
+}
+
+impl StreamEncoder {
+    /// With running_status, repeated status bytes of channel messages are
+    /// omitted.
+    pub fn new(running_status: bool) -> Self {
+        StreamEncoder{running_status, status: 0}
+    }
+
+    /// The bytes to send for a complete message.
+    pub fn encode<'a>(&mut self, message: &'a [u8]) -> &'a [u8] {
+        if !self.running_status {
+            return message;
+        }
+        match message.first() {
+            Some(&status) if status < 0x80 => (),
+            Some(&status) if status < 0xF0 => {
+                if status == self.status {
+                    return &message[1..];
+                }
+                self.status = status;
+            }
+            Some(&status) if status < 0xF8 => self.status = 0, // System common cancels running status
+            _ => (),
+        }
+        message
+    }
+
+    /// Send the next status byte, e.g. after the receiver was reconnected.
+    pub fn reset(&mut self) {
+        self.status = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_running_status() {
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.messages(&[0x90, 60, 100, 62, 100, 60]), vec!(vec!(0x90, 60, 100), vec!(0x90, 62, 100)));
+        assert_eq!(parser.messages(&[0, 0xC1, 5, 6]), vec!(vec!(0x90, 60, 0), vec!(0xC1, 5), vec!(0xC1, 6)));
+        // System common cancels running status
+        assert_eq!(parser.messages(&[0xF3, 1, 7, 0xF6, 8]), vec!(vec!(0xF3, 1), vec!(0xF6)));
+        assert_eq!(parser.dropped(), 2);
+    }
+
+    #[test]
+    fn parses_interleaved_real_time() {
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.messages(&[0xB0, 0xF8, 7, 0xFE, 100, 0xF8, 8]),
+                   vec!(vec!(0xF8), vec!(0xFE), vec!(0xB0, 7, 100), vec!(0xF8)));
+        // Running status survives real-time messages
+        assert_eq!(parser.messages(&[0xFA, 0]), vec!(vec!(0xFA), vec!(0xB0, 8, 0)));
+        // Undefined real-time is dropped without affecting the message
+        assert_eq!(parser.messages(&[0xE0, 0xF9, 0, 0xFD, 64]), vec!(vec!(0xE0, 0, 64)));
+        assert_eq!(parser.dropped(), 2);
+    }
+
+    #[test]
+    fn parses_split_sysex() {
+        let mut parser = StreamParser::new();
+        assert_eq!(parser.messages(&[0x90, 60, 100, 0xF0, 0x7D]), vec!(vec!(0x90, 60, 100)));
+        assert_eq!(parser.messages(&[1, 0xF8, 2]), vec!(vec!(0xF8)));
+        assert_eq!(parser.messages(&[3, 0xF7, 62, 100]), vec!(vec!(0xF0, 0x7D, 1, 2, 3, 0xF7)));
+        // No running status after SysEx
+        assert_eq!(parser.dropped(), 2);
+
+        let long = [&[0xF0][..], &vec!(0x11; MAX_SYSEX + 10)[..], &[0xF7, 0xC0, 1]].concat();
+        assert_eq!(parser.messages(&long), vec!(vec!(0xC0, 1)));
+    }
+
+    #[test]
+    fn resynchronizes_after_garbage() {
+        let mut parser = StreamParser::new();
+        // Data without status, an unterminated SysEx, an incomplete message,
+        // a stray EOX and undefined system common
+        assert_eq!(parser.messages(&[1, 2, 0xF0, 1, 2, 0x80, 60, 0xB0, 7, 0xF7, 0xF4, 3, 0xF5]), Vec::<Vec<u8>>::new());
+        assert_eq!(parser.dropped(), 2 + 3 + 2 + 2 + 1 + 1 + 1 + 1);
+        assert_eq!(parser.messages(&[0x80, 60, 0, 61, 0]), vec!(vec!(0x80, 60, 0), vec!(0x80, 61, 0)));
+
+        parser.reset();
+        assert_eq!(parser.messages(&[62, 0, 0x90]), Vec::<Vec<u8>>::new());
+        parser.reset();
+        assert_eq!(parser.messages(&[62, 0]), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn encodes_with_and_without_running_status() {
+        let messages: Vec<&[u8]> = vec!(&[0x90, 60, 100], &[0xF8], &[0x90, 62, 100], &[0x80, 60, 0], &[0xF2, 0, 0],
+                                        &[0x80, 62, 0], &[0xF0, 1, 0xF7], &[0x80, 63, 0]);
+        let encode = |mut encoder: StreamEncoder| -> Vec<u8> {
+            messages.iter().flat_map(|m| encoder.encode(m).to_vec()).collect()
+        };
+        assert_eq!(encode(StreamEncoder::new(true)),
+                   vec!(0x90, 60, 100, 0xF8, 62, 100, 0x80, 60, 0, 0xF2, 0, 0, 0x80, 62, 0, 0xF0, 1, 0xF7, 0x80, 63, 0));
+        assert_eq!(encode(StreamEncoder::new(false)), messages.concat());
+
+        // Parsing the stream gives the messages again
+        let messages: Vec<Vec<u8>> = messages.iter().map(|m| m.to_vec()).collect();
+        assert_eq!(StreamParser::new().messages(&encode(StreamEncoder::new(true))), messages);
+    }
+}
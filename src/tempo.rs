@@ -0,0 +1,254 @@
+//! Tempo detection from MIDI clock.
+//!
+//! The tracker averages the intervals between TimingClock messages (rather
+//! than the BPM values calculated from single intervals) and restarts the
+//! measurement on Start, Continue and Stop, and after gaps in the clock. It
+//! also collects the clock jitter and the tempo drift of the current run and
+//! statistics for the whole session.
+
+use super::avg::Stats;
+use super::MidiMessage;
+
+const CLOCKS_PER_QUARTER: f64 = 24.0;
+const WINDOW: usize = 48;           // Average over 2 quarters (2 * 24 clocks)
+const GAP_FACTOR: f64 = 4.0;        // Intervals longer than this times the average are gaps
+const MAX_INTERVAL: u64 = 500000;   // Longest valid interval in usec (5 BPM)
+
+/// Clock jitter in usec.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Jitter {
+    pub std_dev: f64,
+    pub max_deviation: f64,
+}
+
+/// Measures tempo, jitter and drift of a received MIDI clock.
+pub struct TempoTracker {
+    intervals: Stats<u64>,
+    num_intervals: usize,  // Number of intervals in the current run
+    last_clock: Option<u64>,
+    start_bpm: Option<f64>, // BPM of the first full window of the run
+
+    // Session statistics
+    clocks: u64,
+    runs: u64,
+    min_bpm: f64,
+    max_bpm: f64,
+    sum_bpm: f64,
+    num_bpm: u64,
+    sum_sq_deviation: f64,
+    num_deviation: u64,
+    session_max_deviation: f64,
+    last_drift: f64,
+}
+
+fn to_bpm(interval: f64) -> f64 {
+    60000000.0 / (interval * CLOCKS_PER_QUARTER)
+}
+
+impl Default for TempoTracker {
+    fn default() -> Self {
+        TempoTracker::new()
+    }
+}
+
+impl TempoTracker {
+    /// Create a tracker without measurements.
+    pub fn new() -> Self {
+        TempoTracker{
+            intervals: Stats::new(WINDOW),
+            num_intervals: 0,
+            last_clock: None,
+            start_bpm: None,
+            clocks: 0,
+            runs: 0,
+            min_bpm: f64::MAX,
+            max_bpm: 0.0,
+            sum_bpm: 0.0,
+            num_bpm: 0,
+            sum_sq_deviation: 0.0,
+            num_deviation: 0,
+            session_max_deviation: 0.0,
+            last_drift: 0.0,
+        }
+    }
+
+    /// Restart the measurement, keeping the session statistics.
+    pub fn reset(&mut self) {
+        self.intervals.clear();
+        self.num_intervals = 0;
+        self.last_clock = None;
+        self.start_bpm = None;
+    }
+
+    /// Handle a received message. Returns true if the tempo was updated.
+    pub fn process(&mut self, timestamp: u64, m: &MidiMessage) -> bool {
+        match m {
+            MidiMessage::TimingClock => self.clock(timestamp),
+            MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop => {
+                self.reset();
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Add a TimingClock timestamp (usec). Returns true if the tempo was updated.
+    pub fn clock(&mut self, timestamp: u64) -> bool {
+        self.clocks += 1;
+        let last = match self.last_clock.replace(timestamp) {
+            Some(last) => last,
+            None => return false,
+        };
+        if timestamp <= last {
+            // Out of order or duplicate timestamp, can't be used
+            self.last_clock = Some(last);
+            return false;
+        }
+        let interval = timestamp - last;
+        let is_gap = interval > MAX_INTERVAL
+            || (self.num_intervals > 0 && interval as f64 > self.mean_interval() * GAP_FACTOR);
+        if is_gap {
+            self.reset();
+            self.last_clock = Some(timestamp);
+            return false;
+        }
+
+        if self.num_intervals > 0 {
+            let deviation = (interval as f64 - self.mean_interval()).abs();
+            self.session_max_deviation = self.session_max_deviation.max(deviation);
+            self.sum_sq_deviation += deviation * deviation;
+            self.num_deviation += 1;
+        } else {
+            self.runs += 1;
+        }
+        self.intervals.add_value(interval);
+        self.num_intervals += 1;
+
+        let bpm = to_bpm(self.mean_interval());
+        if self.num_intervals >= WINDOW {
+            let start_bpm = *self.start_bpm.get_or_insert(bpm);
+            self.last_drift = bpm - start_bpm;
+            self.min_bpm = self.min_bpm.min(bpm);
+            self.max_bpm = self.max_bpm.max(bpm);
+            self.sum_bpm += bpm;
+            self.num_bpm += 1;
+        }
+        true
+    }
+
+    fn mean_interval(&self) -> f64 {
+        self.intervals.mean().unwrap_or(0.0)
+    }
+
+    /// Current tempo, if at least one clock interval has been measured.
+    pub fn bpm(&self) -> Option<f64> {
+        if self.num_intervals > 0 { Some(to_bpm(self.mean_interval())) } else { None }
+    }
+
+    /// Jitter of the clock intervals in the current window.
+    pub fn jitter(&self) -> Option<Jitter> {
+        if self.num_intervals < 2 {
+            return None;
+        }
+        Some(Jitter{std_dev: self.intervals.std_dev()?, max_deviation: self.intervals.max_deviation()?})
+    }
+
+    /// Change of the tempo in BPM since the start of the current run.
+    pub fn drift(&self) -> Option<f64> {
+        self.start_bpm.map(|_| self.last_drift)
+    }
+
+    /// Summary of the session, None if no clock has been received.
+    pub fn summary(&self) -> Option<Vec<String>> {
+        if self.clocks == 0 {
+            return None;
+        }
+        let mut lines = vec!(format!("Clocks: {}, runs: {}", self.clocks, self.runs));
+        if self.num_bpm > 0 {
+            lines.push(format!("BPM: average {:.2}, min {:.2}, max {:.2}",
+                               self.sum_bpm / self.num_bpm as f64, self.min_bpm, self.max_bpm));
+            lines.push(format!("Drift in last run: {:+.2} BPM", self.last_drift));
+        }
+        if self.num_deviation > 0 {
+            let std_dev = (self.sum_sq_deviation / self.num_deviation as f64).sqrt();
+            lines.push(format!("Jitter: std-dev {:.0} usec, max deviation {:.0} usec",
+                               std_dev, self.session_max_deviation));
+        }
+        Some(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    /// Feed clocks with the given intervals, starting at time t.
+    fn feed(tracker: &mut TempoTracker, mut t: u64, intervals: &[u64]) -> u64 {
+        tracker.clock(t);
+        for interval in intervals {
+            t += interval;
+            tracker.clock(t);
+        }
+        t
+    }
+
+    #[test]
+    fn measures_bpm() {
+        let mut tracker = TempoTracker::new();
+        assert!(!tracker.clock(1000));
+        assert_eq!(tracker.bpm(), None);
+        assert!(tracker.clock(21000)); // 20 msec = 125 BPM
+        assert!(close(tracker.bpm().unwrap(), 125.0));
+        assert!(tracker.jitter().is_none());
+        // Duplicate timestamps are ignored
+        assert!(!tracker.clock(21000));
+        assert!(tracker.clock(46000));
+        assert!(close(tracker.bpm().unwrap(), to_bpm(22500.0)));
+    }
+
+    #[test]
+    fn measures_jitter_and_drift() {
+        let mut tracker = TempoTracker::new();
+        let intervals: Vec<u64> = (0..WINDOW).map(|i| if i % 2 == 0 { 19000 } else { 21000 }).collect();
+        let t = feed(&mut tracker, 0, &intervals);
+        assert!(close(tracker.bpm().unwrap(), 125.0));
+        let jitter = tracker.jitter().unwrap();
+        assert!(close(jitter.std_dev, 1000.0) && close(jitter.max_deviation, 1000.0));
+        assert!(close(tracker.drift().unwrap(), 0.0));
+
+        // Slower clocks push the average down
+        feed(&mut tracker, t, &[25000; WINDOW]);
+        assert!(close(tracker.bpm().unwrap(), 100.0));
+        assert!(close(tracker.drift().unwrap(), -25.0));
+        let summary = tracker.summary().unwrap();
+        assert_eq!(summary[0], format!("Clocks: {}, runs: 1", 2 * WINDOW + 2));
+        assert!(summary[1].ends_with("min 100.00, max 125.00"), "{}", summary[1]);
+    }
+
+    #[test]
+    fn restarts_after_gaps_and_transport() {
+        let mut tracker = TempoTracker::new();
+        let t = feed(&mut tracker, 0, &[20000; 10]);
+        // An interval of more than 4 times the average starts a new run
+        assert!(!tracker.clock(t + 90000));
+        assert_eq!(tracker.bpm(), None);
+        assert!(tracker.clock(t + 115000));
+        assert!(close(tracker.bpm().unwrap(), 100.0));
+
+        assert!(!tracker.process(t + 120000, &MidiMessage::Stop));
+        assert_eq!(tracker.bpm(), None);
+        assert!(!tracker.clock(t + 140000));
+        assert!(tracker.clock(t + 160000));
+        assert!(close(tracker.bpm().unwrap(), 125.0));
+        assert!(tracker.summary().unwrap()[0].ends_with("runs: 3"));
+    }
+
+    #[test]
+    fn no_summary_without_clock() {
+        assert_eq!(TempoTracker::new().summary(), None);
+    }
+}
@@ -0,0 +1,174 @@
+//! Traffic statistics of ports and routes.
+//!
+//! Every input port counts the received messages, every route the forwarded
+//! ones, along with filtered messages and forwarding errors. Counters are
+//! shared between the MIDI callbacks and the code showing them, so they can be
+//! shown while running and summarized on exit.
+
+use super::display::{json_string, type_name};
+use super::MidiMessage;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Message counters of a port or route.
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    pub messages: u64,
+    pub bytes: u64,
+    pub filtered: u64, // Not forwarded because of a route setting
+    pub errors: u64,   // Failed sends
+    types: BTreeMap<&'static str, u64>,
+    channels: [u64; 16],
+    peak_rate: u64,    // Highest number of messages within a second
+    window_start: Option<Instant>,
+    window_count: u64,
+}
+
+/// Counters shared between a callback and the statistics.
+pub type SharedCounters = Arc<Mutex<Counters>>;
+
+impl Counters {
+    /// Count a message.
+    pub fn count(&mut self, now: Instant, m: &MidiMessage, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+        *self.types.entry(type_name(m)).or_insert(0) += 1;
+        if let Some(channel) = m.channel() {
+            self.channels[channel as usize & 0x0F] += 1;
+        }
+        match self.window_start {
+            Some(start) if now.duration_since(start) < RATE_WINDOW => self.window_count += 1,
+            _ => {
+                self.peak_rate = self.peak_rate.max(self.window_count);
+                self.window_start = Some(now);
+                self.window_count = 1;
+            }
+        }
+    }
+
+    /// Messages per second in the busiest second so far.
+    pub fn peak_rate(&self) -> u64 {
+        self.peak_rate.max(self.window_count)
+    }
+
+    fn summary(&self) -> Vec<String> {
+        let mut lines = vec!(format!("{} messages, {} bytes, peak {} msg/s", self.messages, self.bytes, self.peak_rate()));
+        if self.filtered > 0 || self.errors > 0 {
+            lines.push(format!("{} filtered, {} errors", self.filtered, self.errors));
+        }
+        if !self.types.is_empty() {
+            let types: Vec<String> = self.types.iter().map(|(t, n)| format!("{} {}", t, n)).collect();
+            lines.push(format!("Types: {}", types.join(", ")));
+        }
+        let channels: Vec<String> = self.channels.iter()
+                                        .enumerate()
+                                        .filter(|(_, n)| **n > 0)
+                                        .map(|(c, n)| format!("{}: {}", c + 1, n))
+                                        .collect();
+        if !channels.is_empty() {
+            lines.push(format!("Channels: {}", channels.join(", ")));
+        }
+        lines
+    }
+
+    fn to_json(&self) -> String {
+        let types: Vec<String> = self.types.iter().map(|(t, n)| format!("\"{}\":{}", t, n)).collect();
+        let channels: Vec<String> = self.channels.iter()
+                                        .enumerate()
+                                        .filter(|(_, n)| **n > 0)
+                                        .map(|(c, n)| format!("\"{}\":{}", c + 1, n))
+                                        .collect();
+        format!("\"messages\":{},\"bytes\":{},\"filtered\":{},\"errors\":{},\"peak_rate\":{},\"types\":{{{}}},\"channels\":{{{}}}",
+                self.messages, self.bytes, self.filtered, self.errors, self.peak_rate(),
+                types.join(","), channels.join(","))
+    }
+}
+
+struct PortTraffic {
+    port: usize,
+    name: String,
+    counters: SharedCounters,
+}
+
+struct RouteTraffic {
+    name: String,
+    counters: SharedCounters,
+}
+
+struct QueueTraffic {
+    name: String,
+    dropped: Arc<AtomicU64>, // Messages dropped because the queue was full
+}
+
+/// The counters of all ports and routes.
+#[derive(Clone, Default)]
+pub struct Traffic {
+    ports: Arc<Mutex<Vec<PortTraffic>>>,
+    routes: Arc<Mutex<Vec<RouteTraffic>>>,
+    queues: Arc<Mutex<Vec<QueueTraffic>>>,
+}
+
+impl Traffic {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Traffic::default()
+    }
+
+    /// Add the counters for the messages received on a port.
+    pub fn add_port(&self, port: usize, name: &str) -> SharedCounters {
+        let counters = SharedCounters::default();
+        self.ports.lock().unwrap().push(PortTraffic{port, name: name.to_string(), counters: counters.clone()});
+        counters
+    }
+
+    /// Add the counters for the messages forwarded by a route.
+    pub fn add_route(&self, name: &str) -> SharedCounters {
+        let counters = SharedCounters::default();
+        self.routes.lock().unwrap().push(RouteTraffic{name: name.to_string(), counters: counters.clone()});
+        counters
+    }
+
+    /// Add the counter of messages dropped by a worker queue.
+    pub fn add_queue(&self, name: &str, dropped: Arc<AtomicU64>) {
+        self.queues.lock().unwrap().push(QueueTraffic{name: name.to_string(), dropped});
+    }
+
+    /// The statistics as text lines.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec!();
+        for p in self.ports.lock().unwrap().iter() {
+            lines.push(format!("Received on port {} ({}):", p.port, p.name));
+            lines.extend(p.counters.lock().unwrap().summary().into_iter().map(|l| format!("  {}", l)));
+        }
+        for r in self.routes.lock().unwrap().iter() {
+            lines.push(format!("Forwarded {}:", r.name));
+            lines.extend(r.counters.lock().unwrap().summary().into_iter().map(|l| format!("  {}", l)));
+        }
+        for q in self.queues.lock().unwrap().iter() {
+            let dropped = q.dropped.load(Ordering::Relaxed);
+            if dropped > 0 {
+                lines.push(format!("Dropped by {} queue: {} messages", q.name, dropped));
+            }
+        }
+        lines
+    }
+
+    /// The statistics as a single JSON object.
+    pub fn to_json(&self) -> String {
+        let ports: Vec<String> = self.ports.lock().unwrap().iter().map(|p| {
+            format!("{{\"port\":{},\"name\":{},{}}}", p.port, json_string(&p.name), p.counters.lock().unwrap().to_json())
+        }).collect();
+        let routes: Vec<String> = self.routes.lock().unwrap().iter().map(|r| {
+            format!("{{\"route\":{},{}}}", json_string(&r.name), r.counters.lock().unwrap().to_json())
+        }).collect();
+        let queues: Vec<String> = self.queues.lock().unwrap().iter().map(|q| {
+            format!("{}:{}", json_string(&q.name), q.dropped.load(Ordering::Relaxed))
+        }).collect();
+        format!("{{\"ports\":[{}],\"routes\":[{}],\"dropped\":{{{}}}}}", ports.join(","), routes.join(","), queues.join(","))
+    }
+}
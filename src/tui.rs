@@ -0,0 +1,381 @@
+//! Full-screen terminal UI for monitoring the received data.
+//!
+//! Shows one panel per input port with the most recent messages, a matrix of
+//! the channel activity, bars for the last changed controllers and the
+//! current tempo. Ports of routes added while running get a panel with their
+//! first message.
+
+use super::display::{Display, MonitorOptions, OutputFormat, COLORS_BW};
+use super::filter::Filter;
+use super::mtc::Timecode;
+use super::traffic::Traffic;
+use super::worker::QueueSender;
+use super::MidiMessage;
+
+use termion::{clear, color, cursor, style};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{stdin, stdout, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_LINES: usize = 1000;    // Lines kept per port panel
+const ACTIVE_TIME: Duration = Duration::from_millis(250);
+const REDRAW_TIME: Duration = Duration::from_millis(40);
+
+const TYPE_NAMES: [&str; 7] = ["NoteOn", "NoteOff", "KeyAT", "CC", "Program", "ChanAT", "Pitch"];
+const MATRIX_ROW: u16 = 3;
+const MATRIX_WIDTH: u16 = 10 + 16 * 3;
+const PANEL_ROW: u16 = MATRIX_ROW + TYPE_NAMES.len() as u16 + 2;
+const MAX_CONTROLLERS: usize = TYPE_NAMES.len(); // CC bars shown beside the matrix rows
+
+/// Events handled by the UI loop.
+pub enum Event {
+    Midi{port: usize, timestamp: u64, data: Vec<u8>},
+    Key(Key),
+}
+
+struct Panel {
+    port: usize,
+    name: String,
+    lines: VecDeque<String>,
+    display: Display,
+}
+
+struct CcValue {
+    channel: u8,
+    controller: u8,
+    value: u8,
+}
+
+/// The interactive terminal UI.
+pub struct Tui {
+    panels: Vec<Panel>,
+    port_names: Vec<String>,
+    options: MonitorOptions, // For the displays of new panels
+    activity: [[Option<Instant>; 16]; TYPE_NAMES.len()],
+    controllers: VecDeque<CcValue>, // Most recently changed first
+    bpm: f64,
+    tempo_info: String, // Jitter and drift of the last clock source
+    timecode: Option<Timecode>, // Last received MIDI Time Code
+    paused: bool,
+    show_time: bool,
+    filter: Filter,
+    traffic: Traffic,
+    show_stats: bool,       // Show the traffic statistics instead of the port panels
+    prompt: Option<String>, // Input line while entering a filter
+    error: Option<String>,  // Last filter parse error
+}
+
+/// Start a thread that forwards key presses to the UI loop.
+pub fn spawn_key_reader(tx: QueueSender<Event>) {
+    thread::spawn(move || {
+        for key in stdin().keys() {
+            let key = if let Ok(k) = key { k } else { break; };
+            tx.send(Event::Key(key));
+        }
+    });
+}
+
+fn type_index(m: &MidiMessage) -> Option<usize> {
+    match m {
+        MidiMessage::NoteOn{..} => Some(0),
+        MidiMessage::NoteOff{..} => Some(1),
+        MidiMessage::KeyAT{..} => Some(2),
+        MidiMessage::ControlChg{..} | MidiMessage::ChannelMode{..} => Some(3),
+        MidiMessage::ProgramChg{..} => Some(4),
+        MidiMessage::ChannelAT{..} => Some(5),
+        MidiMessage::Pitchbend{..} => Some(6),
+        _ => None,
+    }
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
+
+impl Tui {
+    /// Create the UI with panels for the given input ports, port_names are the
+    /// names of all input ports.
+    pub fn new(ports: Vec<usize>, port_names: Vec<String>, options: &MonitorOptions, traffic: Traffic) -> Self {
+        let mut options = options.clone();
+        options.format = OutputFormat::Text;
+        let mut tui = Tui{
+            panels: vec!(),
+            port_names,
+            activity: [[None; 16]; TYPE_NAMES.len()],
+            controllers: VecDeque::new(),
+            bpm: 0.0,
+            tempo_info: String::new(),
+            timecode: None,
+            paused: false,
+            show_time: options.show_time,
+            filter: options.filter.clone(),
+            traffic,
+            show_stats: false,
+            prompt: None,
+            error: None,
+            options,
+        };
+        for port in ports {
+            tui.add_panel(port);
+        }
+        tui
+    }
+
+    /// Add a panel for a port, with the current filter and time setting.
+    fn add_panel(&mut self, port: usize) {
+        let name = self.port_names.get(port).cloned().unwrap_or_else(|| format!("port {}", port));
+        let mut display = Display::new(&COLORS_BW, self.options.for_port(port), &name);
+        display.set_filter(self.filter.clone());
+        display.set_show_time(self.show_time);
+        self.panels.push(Panel{port, name, lines: VecDeque::new(), display});
+    }
+
+    /// Run the UI until the user quits or all event senders are gone.
+    pub fn run(&mut self, rx: Receiver<Event>) -> Result<(), Box<dyn Error>> {
+        let mut screen = AlternateScreen::from(stdout().into_raw_mode()?);
+        write!(screen, "{}", cursor::Hide)?;
+        let mut last_draw = Instant::now() - REDRAW_TIME;
+        loop {
+            match rx.recv_timeout(REDRAW_TIME) {
+                Ok(Event::Midi{port, timestamp, data}) => self.handle_midi(port, timestamp, &data),
+                Ok(Event::Key(key)) => {
+                    if !self.handle_key(key) {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if last_draw.elapsed() >= REDRAW_TIME {
+                self.draw(&mut screen)?;
+                last_draw = Instant::now();
+            }
+        }
+        write!(screen, "{}", cursor::Show)?;
+        screen.flush()?;
+        Ok(())
+    }
+
+    fn handle_midi(&mut self, port: usize, timestamp: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let m = MidiMessage::parse(data);
+        let index = match self.panels.iter().position(|p| p.port == port) {
+            Some(index) => index,
+            None => {
+                self.add_panel(port);
+                self.panels.len() - 1
+            }
+        };
+        let panel = &mut self.panels[index];
+        // Always pass the message to the display, to keep the tempo up to date
+        let lines = panel.display.format_message(timestamp, port, data);
+        if let MidiMessage::TimingClock = m {
+            let tempo = panel.display.tempo();
+            self.bpm = panel.display.bpm();
+            self.tempo_info.clear();
+            if let Some(jitter) = tempo.jitter() {
+                self.tempo_info += &format!(" jitter {:.0}/{:.0} usec", jitter.std_dev, jitter.max_deviation);
+            }
+            if let Some(drift) = tempo.drift() {
+                self.tempo_info += &format!(" drift {:+.1}", drift);
+            }
+        }
+        if let MidiMessage::QuarterFrame{..} | MidiMessage::MtcFullFrame{..} = m {
+            self.timecode = panel.display.timecode();
+        }
+        if self.paused {
+            return;
+        }
+        panel.lines.extend(lines);
+        while panel.lines.len() > MAX_LINES {
+            panel.lines.pop_front();
+        }
+        if (m.is_realtime() && !self.show_time) || !self.filter.matches(port, &m) {
+            return;
+        }
+
+        if let (Some(index), Some(channel)) = (type_index(&m), m.channel()) {
+            self.activity[index][channel as usize] = Some(Instant::now());
+        }
+        if let MidiMessage::ControlChg{channel, controller, value} = m {
+            if let Some(pos) = self.controllers.iter().position(|c| c.channel == channel && c.controller == controller) {
+                self.controllers.remove(pos);
+            }
+            self.controllers.push_front(CcValue{channel, controller, value});
+            self.controllers.truncate(MAX_CONTROLLERS);
+        }
+    }
+
+    /// Handle a key press, returns false if the UI should exit.
+    fn handle_key(&mut self, key: Key) -> bool {
+        if let Some(input) = self.prompt.as_mut() {
+            match key {
+                Key::Char('\n') => {
+                    match Filter::parse(input) {
+                        Ok(filter) => {
+                            for panel in self.panels.iter_mut() {
+                                panel.display.set_filter(filter.clone());
+                            }
+                            self.filter = filter;
+                            self.error = None;
+                        }
+                        Err(e) => self.error = Some(e),
+                    }
+                    self.prompt = None;
+                }
+                Key::Char(c) => input.push(c),
+                Key::Backspace => { input.pop(); }
+                Key::Esc => self.prompt = None,
+                _ => (),
+            }
+            return true;
+        }
+        match key {
+            Key::Char('q') | Key::Esc | Key::Ctrl('c') => return false,
+            Key::Char('p') | Key::Char(' ') => self.paused = !self.paused,
+            Key::Char('c') => self.clear(),
+            Key::Char('s') => self.show_stats = !self.show_stats,
+            Key::Char('f') => self.prompt = Some(self.filter.expression().to_string()),
+            Key::Char('t') => {
+                self.show_time = !self.show_time;
+                for panel in self.panels.iter_mut() {
+                    panel.display.set_show_time(self.show_time);
+                }
+            }
+            _ => (),
+        }
+        true
+    }
+
+    /// Summary of the session for all ports.
+    pub fn summary(&self) -> Vec<String> {
+        self.panels.iter().flat_map(|p| p.display.summary(p.port)).collect()
+    }
+
+    fn clear(&mut self) {
+        for panel in self.panels.iter_mut() {
+            panel.lines.clear();
+        }
+        self.activity = [[None; 16]; TYPE_NAMES.len()];
+        self.controllers.clear();
+    }
+
+    fn draw(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let (width, height) = termion::terminal_size()?;
+        let mut buf = String::new();
+        buf += &format!("{}", clear::All);
+
+        // Header
+        let filter = match self.filter.expression() {
+            "" => "none",
+            e => e,
+        };
+        let mtc = match self.timecode {
+            Some(tc) => format!(" | MTC {}", tc),
+            None => String::new(),
+        };
+        let header = format!(" MIDI Tool | BPM {:.1}{}{} | Filter: {} | Real-time: {} {}",
+                             self.bpm, self.tempo_info, mtc, filter,
+                             if self.show_time { "on" } else { "off" },
+                             if self.paused { "| PAUSED" } else { "" });
+        buf += &format!("{}{}{:w$}{}", cursor::Goto(1, 1), style::Invert,
+                        truncate(&header, width as usize), style::Reset, w = width as usize);
+
+        self.draw_matrix(&mut buf);
+        if width > MATRIX_WIDTH + 20 {
+            self.draw_controllers(&mut buf, MATRIX_WIDTH + 3, width - MATRIX_WIDTH - 2);
+        }
+        if height > PANEL_ROW + 2 {
+            if self.show_stats {
+                self.draw_stats(&mut buf, width, height - PANEL_ROW);
+            } else {
+                self.draw_panels(&mut buf, width, height - PANEL_ROW);
+            }
+        }
+
+        // Footer
+        let footer = match (&self.prompt, &self.error) {
+            (Some(input), _) => format!("Filter (e.g. \"ch=1 type=note,cc\", empty for all): {}", input),
+            (None, Some(error)) => format!("Error: {}", error),
+            (None, None) => "q: quit  p: pause  c: clear  f: filter  t: real-time messages  s: statistics".to_string(),
+        };
+        buf += &format!("{}{}", cursor::Goto(1, height), truncate(&footer, width as usize));
+
+        out.write_all(buf.as_bytes())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn draw_matrix(&self, buf: &mut String) {
+        let now = Instant::now();
+        buf.push_str(&format!("{}{:10}", cursor::Goto(1, MATRIX_ROW), "Channel"));
+        for channel in 1..=16 {
+            buf.push_str(&format!("{:3}", channel));
+        }
+        for (row, name) in TYPE_NAMES.iter().enumerate() {
+            buf.push_str(&format!("{}{:10}", cursor::Goto(1, MATRIX_ROW + 1 + row as u16), name));
+            for time in self.activity[row].iter() {
+                match time {
+                    Some(t) if now.duration_since(*t) < ACTIVE_TIME => {
+                        buf.push_str(&format!("  {}#{}", color::Fg(color::Green), color::Fg(color::Reset)));
+                    }
+                    Some(_) => buf.push_str("  ."),
+                    None => buf.push_str("   "),
+                }
+            }
+        }
+    }
+
+    fn draw_controllers(&self, buf: &mut String, x: u16, width: u16) {
+        buf.push_str(&format!("{}Controllers", cursor::Goto(x, MATRIX_ROW)));
+        let bar_width = (width as usize).saturating_sub(16).min(32);
+        for (row, cc) in self.controllers.iter().enumerate() {
+            let filled = cc.value as usize * bar_width / 127;
+            buf.push_str(&format!("{}Ch{:2} CC{:3} [{}{}] {:3}",
+                                  cursor::Goto(x, MATRIX_ROW + 1 + row as u16),
+                                  cc.channel + 1, cc.controller,
+                                  "=".repeat(filled), " ".repeat(bar_width - filled),
+                                  cc.value));
+        }
+    }
+
+    fn draw_stats(&self, buf: &mut String, width: u16, height: u16) {
+        buf.push_str(&format!("{}{}Traffic statistics{}", cursor::Goto(1, PANEL_ROW), style::Bold, style::Reset));
+        for (row, line) in self.traffic.summary().iter().take(height as usize - 1).enumerate() {
+            buf.push_str(&format!("{}{}", cursor::Goto(1, PANEL_ROW + 1 + row as u16),
+                                  truncate(line, width as usize)));
+        }
+    }
+
+    fn draw_panels(&self, buf: &mut String, width: u16, height: u16) {
+        if self.panels.is_empty() {
+            return;
+        }
+        let panel_width = (width as usize / self.panels.len()).max(1);
+        let num_lines = height as usize - 1;
+        for (i, panel) in self.panels.iter().enumerate() {
+            let x = (i * panel_width + 1) as u16;
+            let mut title = format!("Port {}: {}", panel.port, panel.name);
+            if panel.display.bpm() > 0.0 {
+                title += &format!(" ({:.1} BPM)", panel.display.bpm());
+            }
+            buf.push_str(&format!("{}{}{}{}", cursor::Goto(x, PANEL_ROW), style::Bold,
+                                  truncate(&title, panel_width - 1), style::Reset));
+            let skip = panel.lines.len().saturating_sub(num_lines);
+            for (row, line) in panel.lines.iter().skip(skip).enumerate() {
+                buf.push_str(&format!("{}{}", cursor::Goto(x, PANEL_ROW + 1 + row as u16),
+                                      truncate(line, panel_width - 1)));
+            }
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! Embedded web server for monitoring from a browser.
+//!
+//! Received messages are streamed as JSON objects (the format of the JSON
+//! monitor) over a WebSocket at "/ws". Simple HTTP endpoints return the
+//! state of the session as JSON:
+//!
+//! * "/ports": the input and output ports
+//! * "/routes": the current routes with their ports and channels
+//! * "/stats": the traffic statistics
+//!
+//! "/" serves a small page showing the stream. The server binds to
+//! localhost unless an address is given explicitly, e.g. "0.0.0.0:8080".
+//! Only GET requests are supported, every connection handles one request.
+
+use super::display::{format_json, json_string};
+use super::net::is_timeout;
+use super::router::Router;
+use super::traffic::Traffic;
+use super::worker::Received;
+use super::MidiMessage;
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How often the server checks for closing
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);    // Slower WebSocket clients are dropped
+const MAX_REQUEST: usize = 8192;
+const MAX_FRAME: usize = 65536;
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>MIDI Toolbox</title>
+<style>
+body { font-family: monospace; background: #111; color: #ddd; margin: 1em; }
+#status { color: #888; }
+#messages div { white-space: nowrap; }
+</style>
+</head>
+<body>
+<div id="status">Connecting ...</div>
+<div id="messages"></div>
+<script>
+const status = document.getElementById("status");
+const messages = document.getElementById("messages");
+const ws = new WebSocket((location.protocol == "https:" ? "wss://" : "ws://") + location.host + "/ws");
+ws.onopen = () => status.textContent = "Connected";
+ws.onclose = () => status.textContent = "Disconnected";
+ws.onmessage = (event) => {
+  const m = JSON.parse(event.data);
+  const fields = Object.entries(m).filter(([k]) => !["timestamp", "port", "port_name", "type", "bytes"].includes(k));
+  const line = document.createElement("div");
+  line.textContent = [m.port_name, m.type].concat(fields.map(([k, v]) => k + " " + v)).join("  ");
+  messages.prepend(line);
+  while (messages.childNodes.length > 200) {
+    messages.removeChild(messages.lastChild);
+  }
+};
+</script>
+</body>
+</html>
+"#;
+
+/// A parsed HTTP request.
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>, // Names in lower case
+}
+
+impl Request {
+    /// Parse the request line and headers, without the body.
+    pub fn parse(head: &str) -> Option<Request> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?.to_string();
+        let path = request_line.next()?.to_string();
+        if !request_line.next()?.starts_with("HTTP/") {
+            return None;
+        }
+        let mut headers = vec!();
+        for line in lines.filter(|l| !l.is_empty()) {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            headers.push((name, parts.next()?.trim().to_string()));
+        }
+        Some(Request{method, path, headers})
+    }
+
+    /// Value of a header, names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// True for a WebSocket handshake.
+    fn is_upgrade(&self) -> bool {
+        self.header("upgrade").map(|u| u.eq_ignore_ascii_case("websocket")).unwrap_or(false)
+    }
+}
+
+/// The WebSocket clients receiving the stream.
+#[derive(Clone, Default)]
+pub struct Clients {
+    streams: Arc<Mutex<Vec<(usize, TcpStream)>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl Clients {
+    /// Number of connected clients.
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send a text message to all clients. Clients failing to receive it are
+    /// disconnected.
+    pub fn broadcast(&self, text: &str) {
+        let frame = encode_frame(OPCODE_TEXT, text.as_bytes());
+        self.streams.lock().unwrap().retain(|(_, stream)| {
+            if (&*stream).write_all(&frame).is_ok() {
+                return true;
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+            false
+        });
+    }
+
+    fn add(&self, stream: TcpStream) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.streams.lock().unwrap().push((id, stream));
+        id
+    }
+
+    /// Send a frame to a single client, writes are serialized with the
+    /// broadcasts.
+    fn send(&self, id: usize, frame: &[u8]) {
+        let streams = self.streams.lock().unwrap();
+        if let Some((_, stream)) = streams.iter().find(|(i, _)| *i == id) {
+            let _ = (&*stream).write_all(frame);
+        }
+    }
+
+    fn remove(&self, id: usize) {
+        self.streams.lock().unwrap().retain(|(i, stream)| {
+            if *i == id {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            *i != id
+        });
+    }
+
+    fn close_all(&self) {
+        for (_, stream) in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// The state served over HTTP, set when the routes are started. The routes
+/// are read from the router for every request, to show the changes made
+/// while running.
+struct Status {
+    in_port_names: Vec<String>,
+    out_port_names: Vec<String>,
+    router: Weak<Mutex<Router>>,
+    traffic: Traffic,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status{in_port_names: vec!(), out_port_names: vec!(), router: Weak::new(), traffic: Traffic::new()}
+    }
+}
+
+impl Status {
+    fn ports_json(&self) -> String {
+        let ports = |names: &[String]| -> String {
+            let ports: Vec<String> = names.iter()
+                                          .enumerate()
+                                          .map(|(port, name)| format!("{{\"port\":{},\"name\":{}}}", port, json_string(name)))
+                                          .collect();
+            ports.join(",")
+        };
+        format!("{{\"inputs\":[{}],\"outputs\":[{}]}}", ports(&self.in_port_names), ports(&self.out_port_names))
+    }
+
+    fn routes_json(&self) -> String {
+        let port = |names: &[String], port: usize| -> String {
+            match names.get(port) {
+                Some(name) => format!("{},\"name\":{}", port, json_string(name)),
+                None => "null,\"name\":null".to_string(),
+            }
+        };
+        // The router is gone once the session is closed
+        let routes = self.router.upgrade().map(|router| router.lock().unwrap().routes()).unwrap_or_default();
+        let routes: Vec<String> = routes.iter().map(|r| {
+            format!("{{\"route\":{},\"name\":{},\"in\":{{\"port\":{},\"channel\":{}}},\"out\":{{\"port\":{},\"channel\":{}}},\"muted\":{}}}",
+                    r.route, json_string(&r.config.name()),
+                    port(&self.in_port_names, r.config.in_port), r.config.in_channel,
+                    port(&self.out_port_names, r.config.out_port), r.config.out_channel, r.muted)
+        }).collect();
+        format!("[{}]", routes.join(","))
+    }
+}
+
+/// The web server, stops accepting connections and disconnects all clients
+/// when dropped.
+pub struct WebServer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    status: Arc<Mutex<Status>>,
+    clients: Clients,
+}
+
+impl WebServer {
+    /// Listen on an address like "0.0.0.0:8080". A port alone listens on
+    /// localhost.
+    pub fn start(address: &str) -> Result<WebServer, Box<dyn Error>> {
+        let addr = match address.parse::<u16>() {
+            Ok(port) => SocketAddr::from(([127, 0, 0, 1], port)),
+            Err(_) => address.to_socket_addrs()
+                             .map_err(|err| format!("Invalid address '{}': {}", address, err))?
+                             .next()
+                             .ok_or_else(|| format!("Invalid address '{}'", address))?,
+        };
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let server = WebServer{
+            addr: listener.local_addr()?,
+            running: Arc::new(AtomicBool::new(true)),
+            status: Arc::new(Mutex::new(Status::default())),
+            clients: Clients::default(),
+        };
+        let (running, status, clients) = (server.running.clone(), server.status.clone(), server.clients.clone());
+        thread::spawn(move || accept(listener, running, status, clients));
+        Ok(server)
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The clients of the message stream, see stream().
+    pub fn clients(&self) -> Clients {
+        self.clients.clone()
+    }
+
+    /// Set the ports, routes and statistics served over HTTP. The server
+    /// doesn't keep the router alive.
+    pub fn set_status(&self, in_port_names: &[String], out_port_names: &[String], router: &Arc<Mutex<Router>>, traffic: &Traffic) {
+        let mut status = self.status.lock().unwrap();
+        status.in_port_names = in_port_names.to_vec();
+        status.out_port_names = out_port_names.to_vec();
+        status.router = Arc::downgrade(router);
+        status.traffic = traffic.clone();
+    }
+}
+
+impl Drop for WebServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.clients.close_all();
+    }
+}
+
+/// Send the received messages to the WebSocket clients until the queue is
+/// closed.
+pub fn stream(rx: Receiver<Received>, clients: Clients, port_names: Vec<String>) {
+    for r in rx {
+        if clients.is_empty() {
+            continue;
+        }
+        let m = MidiMessage::parse(&r.data);
+        let port_name = port_names.get(r.port).map(|n| n.as_str()).unwrap_or("");
+        clients.broadcast(&format_json(r.timestamp, r.port, port_name, &m, &r.data));
+    }
+}
+
+fn accept(listener: TcpListener, running: Arc<AtomicBool>, status: Arc<Mutex<Status>>, clients: Clients) {
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, from)) => {
+                let (status, clients) = (status.clone(), clients.clone());
+                thread::spawn(move || {
+                    if let Err(err) = handle(stream, &status, &clients) {
+                        eprintln!("Web connection from {} closed: {}", from, err);
+                    }
+                });
+            }
+            Err(ref err) if is_timeout(err) => thread::sleep(POLL_INTERVAL),
+            Err(err) => {
+                eprintln!("Error when accepting web connections: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+/// Answer a request, or run a WebSocket connection until it is closed.
+fn handle(mut stream: TcpStream, status: &Mutex<Status>, clients: &Clients) -> Result<(), Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let request = match read_request(&mut stream)? {
+        Some(request) => request,
+        None => return respond(&mut stream, "400 Bad Request", "text/plain", "Bad request\n"),
+    };
+    if request.method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", "Method not allowed\n");
+    }
+    let path = request.path.split('?').next().unwrap_or("");
+    match path {
+        "/" => respond(&mut stream, "200 OK", "text/html; charset=utf-8", INDEX_HTML),
+        "/ports" => {
+            let ports = status.lock().unwrap().ports_json();
+            respond(&mut stream, "200 OK", "application/json", &ports)
+        }
+        "/routes" => {
+            let routes = status.lock().unwrap().routes_json();
+            respond(&mut stream, "200 OK", "application/json", &routes)
+        }
+        "/stats" => {
+            let traffic = status.lock().unwrap().traffic.clone();
+            respond(&mut stream, "200 OK", "application/json", &traffic.to_json())
+        }
+        "/ws" if request.is_upgrade() => {
+            let key = request.header("sec-websocket-key").ok_or("Missing WebSocket key")?;
+            write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                   accept_key(key))?;
+            stream.set_read_timeout(None)?;
+            let id = clients.add(stream.try_clone()?);
+            let result = receive_frames(&mut stream, id, clients);
+            clients.remove(id);
+            result
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found\n"),
+    }
+}
+
+/// Read the request head, returns None if it is invalid.
+fn read_request(stream: &mut TcpStream) -> Result<Option<Request>, Box<dyn Error>> {
+    let mut head = vec!();
+    let mut chunk = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err("Closed by client".into());
+        }
+        head.extend_from_slice(&chunk[..len]);
+    }
+    Ok(std::str::from_utf8(&head).ok().and_then(Request::parse))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{}",
+           status, content_type, body.len(), body)?;
+    Ok(())
+}
+
+/// Handle the frames sent by a WebSocket client. Messages from the client
+/// are ignored, pings are answered.
+fn receive_frames(stream: &mut TcpStream, id: usize, clients: &Clients) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (opcode, payload) = match read_frame(stream) {
+            Ok(frame) => frame,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(ref err) if is_timeout(err) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        match opcode {
+            OPCODE_CLOSE => {
+                clients.send(id, &encode_frame(OPCODE_CLOSE, &payload));
+                return Ok(());
+            }
+            OPCODE_PING => clients.send(id, &encode_frame(OPCODE_PONG, &payload)),
+            _ => (),
+        }
+    }
+}
+
+/// Encode an unmasked frame, as sent by servers.
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec!(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read a frame, returns the opcode and the unmasked payload. Fragmented
+/// messages are returned frame by frame.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_FRAME as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    if header[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec!(0u8; len as usize);
+    reader.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((header[0] & 0x0F, payload))
+}
+
+/// The Sec-WebSocket-Accept value for a client key.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), WEBSOCKET_GUID).as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_and_encodes() {
+        let hex: Vec<String> = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex.concat(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Man"), "TWFu");
+        // The example of RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn encodes_and_reads_frames() {
+        assert_eq!(encode_frame(OPCODE_TEXT, b"Hi"), vec!(0x81, 2, b'H', b'i'));
+        let long = vec!(7u8; 300);
+        let frame = encode_frame(OPCODE_TEXT, &long);
+        assert_eq!(&frame[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(read_frame(&mut &frame[..]).unwrap(), (OPCODE_TEXT, long));
+
+        // Masked "Hello" of RFC 6455
+        let masked = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(read_frame(&mut &masked[..]).unwrap(), (OPCODE_TEXT, b"Hello".to_vec()));
+        assert!(read_frame(&mut &masked[..5]).is_err());
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request = Request::parse("GET /ws HTTP/1.1\r\nHost: stage\r\nUpgrade: WebSocket\r\nSec-WebSocket-Key: abc\r\n\r\n").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/ws"));
+        assert_eq!(request.header("Sec-WebSocket-Key"), Some("abc"));
+        assert_eq!(request.header("Origin"), None);
+        assert!(request.is_upgrade());
+        assert!(!Request::parse("GET /ports HTTP/1.0\r\n\r\n").unwrap().is_upgrade());
+        assert_eq!(Request::parse("GET /ports\r\n\r\n"), None);
+        assert_eq!(Request::parse("GET /ports HTTP/1.1\r\nBroken header\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Worker threads for monitoring and recording.
+//!
+//! The MIDI callbacks forward messages right away and hand everything else to
+//! worker threads through bounded queues, so a slow terminal or a disk stall
+//! doesn't delay forwarding. If a queue is full, the message is dropped and
+//! counted instead of blocking the callback.
+
+use super::display::Display;
+use super::recording::RecordFormat;
+
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Number of messages a queue holds.
+pub const QUEUE_SIZE: usize = 4096;
+
+/// Time without messages after which collapsed controllers are shown.
+const IDLE_TIME: Duration = Duration::from_millis(200);
+
+/// A received message handed to a worker.
+pub struct Received {
+    pub route: usize,
+    pub port: usize,
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+/// Sending end of a bounded queue, which never blocks.
+pub struct QueueSender<T> {
+    tx: SyncSender<T>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender{tx: self.tx.clone(), dropped: self.dropped.clone()}
+    }
+}
+
+impl<T> QueueSender<T> {
+    /// Queue an item, returns false if it was dropped.
+    pub fn send(&self, item: T) -> bool {
+        match self.tx.try_send(item) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Counter of the items dropped because the queue was full.
+    pub fn dropped(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+}
+
+/// Create a bounded queue with QUEUE_SIZE entries.
+pub fn queue<T>() -> (QueueSender<T>, Receiver<T>) {
+    let (tx, rx) = sync_channel(QUEUE_SIZE);
+    (QueueSender{tx, dropped: Arc::new(AtomicU64::new(0))}, rx)
+}
+
+/// A thread returning a result when its queue is closed.
+pub struct Worker<R> {
+    handle: JoinHandle<R>,
+}
+
+impl<R: Send + 'static> Worker<R> {
+    /// Run f in a new thread.
+    pub fn spawn<F>(f: F) -> Worker<R>
+            where F: FnOnce() -> R + Send + 'static {
+        Worker{handle: thread::spawn(f)}
+    }
+
+    /// Wait until the worker has handled all queued items. All senders of its
+    /// queue have to be dropped before.
+    pub fn join(self) -> Option<R> {
+        self.handle.join().ok()
+    }
+}
+
+/// Show the received messages, returns the displays for the session summary.
+///
+/// Displays are indexed by route, routes without one get a display from
+/// new_display(input port), e.g. routes added while running. Dropped messages
+/// are reported as they are noticed. The last values of collapsed controllers
+/// are shown when no messages arrive for a moment and when the queue is
+/// closed.
+pub fn monitor<F>(rx: Receiver<Received>, mut displays: Vec<Option<Display>>, mut new_display: F, dropped: Arc<AtomicU64>)
+        -> Vec<Option<Display>>
+        where F: FnMut(usize) -> Display {
+    let mut reported = 0;
+    loop {
+        let r = match rx.recv_timeout(IDLE_TIME) {
+            Ok(r) => r,
+            Err(RecvTimeoutError::Timeout) => {
+                flush_displays(&mut displays);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if displays.len() <= r.route {
+            displays.resize_with(r.route + 1, || None);
+        }
+        displays[r.route].get_or_insert_with(|| new_display(r.port)).show_message(r.timestamp, r.port, &r.data);
+        let n = dropped.load(Ordering::Relaxed);
+        if n > reported {
+            eprintln!("{} messages not shown, the output is too slow", n - reported);
+            reported = n;
+        }
+    }
+    flush_displays(&mut displays);
+    displays
+}
+
+fn flush_displays(displays: &mut [Option<Display>]) {
+    for display in displays.iter_mut().flatten() {
+        if let Some(line) = display.flush() {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Write the received messages in the given format to the files of their
+/// routes. Routes beyond files, e.g. added while running, get the file
+/// returned by new_file(input port). Files are flushed whenever the queue runs
+/// empty. Returns the files still recording at the end.
+pub fn record<W: Write, F>(rx: Receiver<Received>, files: Vec<Option<(String, W)>>, mut new_file: F, format: RecordFormat,
+                           dropped: Arc<AtomicU64>) -> Vec<Option<(String, W)>>
+        where F: FnMut(usize) -> Option<(String, W)> {
+    let mut opened: HashSet<usize> = (0..files.len()).collect(); // Routes whose file was created or not wanted
+    let mut files: Vec<Option<(String, BufWriter<W>)>> = files.into_iter()
+                                                              .map(|f| f.map(|(name, f)| (name, BufWriter::new(f))))
+                                                              .collect();
+    let mut open = |files: &mut Vec<Option<(String, BufWriter<W>)>>, r: &Received| {
+        if opened.insert(r.route) {
+            if files.len() <= r.route {
+                files.resize_with(r.route + 1, || None);
+            }
+            files[r.route] = new_file(r.port).map(|(name, f)| (name, BufWriter::new(f)));
+        }
+    };
+    while let Ok(r) = rx.recv() {
+        open(&mut files, &r);
+        write(&mut files, format, r);
+        while let Ok(r) = rx.try_recv() {
+            open(&mut files, &r);
+            write(&mut files, format, r);
+        }
+        flush(&mut files);
+    }
+    flush(&mut files);
+    let n = dropped.load(Ordering::Relaxed);
+    if n > 0 {
+        eprintln!("{} messages not recorded, the disk is too slow", n);
+    }
+    files.into_iter()
+         .map(|f| f.and_then(|(name, f)| f.into_inner().ok().map(|f| (name, f))))
+         .collect()
+}
+
+fn write<W: Write>(files: &mut [Option<(String, BufWriter<W>)>], format: RecordFormat, r: Received) {
+    if let Some(entry) = files.get_mut(r.route) {
+        if let Some((name, f)) = entry.as_mut() {
+            if let Err(err) = format.write_line(f, r.timestamp, &r.data) {
+                eprintln!("Error when writing to '{}', stopped recording: {}", name, err);
+                *entry = None;
+            }
+        }
+    }
+}
+
+fn flush<W: Write>(files: &mut [Option<(String, BufWriter<W>)>]) {
+    for entry in files.iter_mut() {
+        if let Some((name, f)) = entry.as_mut() {
+            if let Err(err) = f.flush() {
+                eprintln!("Error when writing to '{}', stopped recording: {}", name, err);
+                *entry = None;
+            }
+        }
+    }
+}
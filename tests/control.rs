@@ -0,0 +1,89 @@
+use miditool::backend::mock::MockBackend;
+use miditool::control::{ControlClient, ControlServer};
+use miditool::display::{MonitorOptions, COLORS_BW};
+use miditool::recording::RecordFormat;
+use miditool::router::{Config, Router, Sinks};
+use miditool::session::{Services, Session, SessionOptions};
+use miditool::traffic::Traffic;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+
+fn socket_path(name: &str) -> String {
+    env::temp_dir().join(format!("miditool-{}-{}.sock", name, process::id())).to_string_lossy().into_owned()
+}
+
+#[test]
+fn changes_routes_over_the_socket() {
+    let path = socket_path("routes");
+    let mock = MockBackend::new(&["keys", "pads"], &["synth", "drums"]);
+    let router = Router::start(&mock, &[Config{in_port: 0, out_port: 0, ..Config::default()}],
+                               &Sinks::default(), &Traffic::new()).unwrap();
+    let router = Mutex::new(router);
+    let server = ControlServer::start(&path).unwrap();
+    assert!(ControlServer::start(&path).is_err()); // In use
+
+    thread::scope(|s| {
+        s.spawn(|| server.serve(&router, &mock));
+
+        let mut client = ControlClient::connect(&path).unwrap();
+        assert_eq!(client.command("add 1 10 1 0").unwrap(), vec!("1"));
+        assert_eq!(client.command("transpose 0 12").unwrap(), Vec::<String>::new());
+        assert_eq!(client.command("mute 1").unwrap(), Vec::<String>::new());
+        assert_eq!(client.command("routes").unwrap(), vec!("0: 0 -> 0, transpose +12", "1: 1 -> 1 (channel 10 -> all), muted"));
+        mock.receive(0, 0, &[0x90, 60, 100]);
+        mock.receive(1, 0, &[0x99, 36, 100]);
+        assert_eq!(client.command("unmute 1").unwrap(), Vec::<String>::new());
+        mock.receive(1, 0, &[0x99, 38, 100]);
+        assert_eq!(mock.sent(0), vec!(vec!(0x90, 72, 100)));
+        assert_eq!(mock.sent(1), vec!(vec!(0x99, 38, 100)));
+
+        // A second client at the same time
+        let mut other = ControlClient::connect(&path).unwrap();
+        assert_eq!(other.command("remove 0").unwrap(), Vec::<String>::new());
+        assert_eq!(client.command("routes").unwrap(), vec!("1: 1 -> 1 (channel 10 -> all)"));
+
+        assert_eq!(client.command("panic").unwrap(), vec!("Reset 1 outputs"));
+        assert_eq!(client.command("mute 0").unwrap_err().to_string(), "No route 0");
+        assert_eq!(client.command("add 5 0 0 0").unwrap_err().to_string(), "Invalid port number");
+        assert_eq!(client.command("louder").unwrap_err().to_string(), "Unknown command 'louder'");
+        server.stop();
+    });
+    router.into_inner().unwrap().close();
+    drop(server);
+    assert!(ControlClient::connect(&path).is_err());
+}
+
+#[test]
+fn monitors_and_records_added_routes() {
+    let path = socket_path("session");
+    let prefix = env::temp_dir().join(format!("miditool-control-{}", process::id())).to_string_lossy().into_owned();
+    let mock = MockBackend::new(&["keys", "pads"], &["synth"]);
+    let display = MonitorOptions::default();
+    let options = SessionOptions{monitor: true, recording: Some((&prefix, RecordFormat::Hex)), colors: &COLORS_BW, display: &display};
+    let session = Session::start(&mock, &[Config{in_port: 0, out_port: 0, ..Config::default()}], &options, &Services::default()).unwrap();
+    let server = ControlServer::start(&path).unwrap();
+
+    let router = session.router();
+    thread::scope(|s| {
+        s.spawn(|| server.serve(router, &mock));
+        let mut client = ControlClient::connect(&path).unwrap();
+        assert_eq!(client.command("add 1 0 0 3").unwrap(), vec!("1"));
+        mock.receive(1, 0, &[0xF8]);
+        mock.receive(1, 0, &[0x90, 36, 100]);
+        server.stop();
+    });
+    assert_eq!(mock.sent(0), vec!(vec!(0xF8), vec!(0x92, 36, 100)));
+
+    // The new port got a display and a file
+    let summary = session.close();
+    assert_eq!(summary.first().map(|l| l.as_str()), Some("Tempo on port 1 (pads):"));
+    let (keys, pads) = (format!("{}_p0", prefix), format!("{}_p1", prefix));
+    assert_eq!(fs::read_to_string(&keys).unwrap(), "");
+    assert_eq!(fs::read_to_string(&pads).unwrap(), "f8\n90 24 64\n");
+    fs::remove_file(&keys).unwrap();
+    fs::remove_file(&pads).unwrap();
+}
@@ -0,0 +1,134 @@
+use miditool::backend::mock::MockBackend;
+use miditool::backend::Backend;
+use miditool::endpoint::Endpoints;
+use miditool::router::{Config, Router, Sinks};
+use miditool::traffic::Traffic;
+
+use std::net::{TcpListener, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn free_udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn free_tcp_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Receiving side: route the endpoint to mock output 0.
+fn receiver(spec: &str, routes: usize) -> (MockBackend, Router) {
+    let mock = MockBackend::new(&[], &["out"]);
+    let mut backend = Endpoints::new(mock.clone());
+    let in_port = backend.input_port(spec).unwrap();
+    assert_eq!(backend.input_port(spec).unwrap(), in_port);
+    let configs: Vec<Config> = (0..routes).map(|_| Config{in_port, out_port: 0, ..Config::default()}).collect();
+    let router = Router::start(&backend, &configs, &Sinks::default(), &Traffic::new()).unwrap();
+    (mock, router)
+}
+
+#[test]
+fn forwards_over_udp() {
+    let spec = format!("udp:127.0.0.1:{}", free_udp_port());
+    let (received, router) = receiver(&spec, 1);
+
+    let mut backend = Endpoints::new(MockBackend::new(&[], &[]));
+    let out_port = backend.output_port(&spec).unwrap();
+    assert_eq!(backend.output_ports().unwrap(), vec!(spec.clone()));
+    let mut out = backend.connect_output(out_port).unwrap();
+    let sysex: Vec<u8> = [0xF0].iter().chain([0x11; 1000].iter()).chain([0xF7].iter()).copied().collect();
+    out.send(&[0x90, 60, 100]).unwrap();
+    out.send(&sysex).unwrap();
+    out.send(&[0x80, 60, 0]).unwrap();
+
+    assert_eq!(received.wait_for_sent(0, 3), vec!(vec!(0x90, 60, 100), sysex, vec!(0x80, 60, 0)));
+    router.close();
+}
+
+#[test]
+fn shares_an_input_between_routes() {
+    let spec = format!("udp:127.0.0.1:{}", free_udp_port());
+    let (received, router) = receiver(&spec, 2);
+
+    let mut backend = Endpoints::new(MockBackend::new(&[], &[]));
+    let out_port = backend.output_port(&spec).unwrap();
+    backend.connect_output(out_port).unwrap().send(&[0xB0, 7, 90]).unwrap();
+
+    assert_eq!(received.wait_for_sent(0, 2), vec!(vec!(0xB0, 7, 90), vec!(0xB0, 7, 90)));
+    router.close();
+}
+
+/// Send message until it arrives, messages sent while disconnected get lost.
+fn send_until_received(out: &mut miditool::backend::Output, received: &MockBackend, message: &[u8]) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while received.sent(0).is_empty() && Instant::now() < deadline {
+        out.send(message).ok();
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Wait until message is the last one received.
+fn wait_for_last(received: &MockBackend, message: &[u8]) -> Option<Vec<u8>> {
+    received.wait_until(0, |sent| sent.last().map(|m| &m[..]) == Some(message)).last().cloned()
+}
+
+#[test]
+fn reconnects_over_tcp() {
+    let spec = format!("tcp:127.0.0.1:{}", free_tcp_port());
+
+    // The output is opened before the receiver listens
+    let mut backend = Endpoints::new(MockBackend::new(&[], &[]));
+    let out_port = backend.output_port(&spec).unwrap();
+    let mut out = backend.connect_output(out_port).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(out.send(&[0x90, 60, 100]).is_err());
+
+    let (received, router) = receiver(&spec, 1);
+    send_until_received(&mut out, &received, &[0xFE]);
+    out.send(&[0x90, 61, 100]).unwrap();
+    assert_eq!(wait_for_last(&received, &[0x90, 61, 100]), Some(vec!(0x90, 61, 100)));
+    router.close();
+
+    // The receiver restarts
+    thread::sleep(Duration::from_millis(300));
+    let (received, router) = receiver(&spec, 1);
+    send_until_received(&mut out, &received, &[0xFE]);
+    out.send(&[0x80, 61, 0]).unwrap();
+    assert_eq!(wait_for_last(&received, &[0x80, 61, 0]), Some(vec!(0x80, 61, 0)));
+    router.close();
+}
+
+#[test]
+fn drops_tcp_peers_that_stop_reading() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let spec = format!("tcp:{}", listener.local_addr().unwrap());
+    let peer = thread::spawn(move || listener.accept().unwrap().0); // Never reads
+
+    let mut backend = Endpoints::new(MockBackend::new(&[], &[]));
+    let out_port = backend.output_port(&spec).unwrap();
+    let mut out = backend.connect_output(out_port).unwrap();
+    let _stream = peer.join().unwrap();
+    let sysex: Vec<u8> = [0xF0].iter().chain([0x11; 60000].iter()).chain([0xF7].iter()).copied().collect();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while out.send(&[0xFE]).is_err() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // Sends don't block once the socket buffers are full, the connection is
+    // given up instead
+    let mut failed = false;
+    while !failed && Instant::now() < deadline {
+        let start = Instant::now();
+        failed = out.send(&sysex).is_err();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+    assert!(failed);
+}
+
+#[test]
+fn rejects_unknown_endpoints() {
+    let mut backend = Endpoints::new(MockBackend::new(&["in"], &["out"]));
+    assert_eq!(backend.input_port("0").unwrap(), 0);
+    assert!(backend.input_port("foo:1").is_err());
+    assert!(backend.output_port("udp:nohost").is_err());
+}
@@ -0,0 +1,68 @@
+use miditool::backend::mock::MockBackend;
+use miditool::endpoint::Endpoints;
+use miditool::osc::{Mapping, OscArg, OscEndpoint, OscMessage};
+use miditool::router::{Config, Router, Sinks};
+use miditool::traffic::Traffic;
+
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn osc(address: &str, value: f32) -> OscMessage {
+    OscMessage{address: address.to_string(), args: vec!(OscArg::Float(value))}
+}
+
+#[test]
+fn sends_midi_as_osc() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let spec = format!("osc:{}", receiver.local_addr().unwrap());
+
+    let mock = MockBackend::new(&["keys"], &[]);
+    let mut backend = Endpoints::new(mock.clone());
+    let out_port = backend.output_port(&spec).unwrap();
+    let router = Router::start(&backend, &[Config{in_port: 0, out_port, ..Config::default()}],
+                               &Sinks::default(), &Traffic::new()).unwrap();
+    mock.receive(0, 0, &[0xB0, 7, 127]);
+    mock.receive(0, 0, &[0xF8]); // Not mapped
+    mock.receive(0, 0, &[0xE3, 0, 0]);
+
+    let mut buf = [0u8; 1024];
+    let mut received = vec!();
+    for _ in 0..2 {
+        let len = receiver.recv(&mut buf).unwrap();
+        received.extend(OscMessage::decode_packet(&buf[..len]).unwrap());
+    }
+    assert_eq!(received, vec!(osc("/midi/ch1/cc/7", 1.0), osc("/midi/ch4/pitchbend", -1.0)));
+    router.close();
+}
+
+#[test]
+fn receives_osc_as_midi() {
+    let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let spec = format!("osc:127.0.0.1:{}", port);
+
+    let mock = MockBackend::new(&[], &["lights"]);
+    let mut backend = Endpoints::new(mock.clone());
+    let mut endpoint = OscEndpoint::parse(&spec).unwrap().unwrap();
+    endpoint.set_mappings(vec!(Mapping::parse("cc:2:7", "/mixer/volume", Some("0..100")).unwrap(),
+                               Mapping::parse("note:10", "/drum/{number}", None).unwrap()));
+    backend.add(Arc::new(endpoint));
+    let in_port = backend.input_port(&spec).unwrap();
+    let router = Router::start(&backend, &[Config{in_port, out_port: 0, ..Config::default()}],
+                               &Sinks::default(), &Traffic::new()).unwrap();
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.send_to(&osc("/mixer/volume", 50.0).encode(), ("127.0.0.1", port)).unwrap();
+    sender.send_to(&osc("/midi/ch1/cc/7", 0.5).encode(), ("127.0.0.1", port)).unwrap(); // Not mapped
+    let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+    for message in [osc("/drum/36", 100.0), osc("/drum/36", 0.0)] {
+        let bytes = message.encode();
+        bundle.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        bundle.extend(bytes);
+    }
+    sender.send_to(&bundle, ("127.0.0.1", port)).unwrap();
+
+    assert_eq!(mock.wait_for_sent(0, 3), vec!(vec!(0xB1, 7, 64), vec!(0x99, 36, 100), vec!(0x89, 36, 0)));
+    router.close();
+}
@@ -0,0 +1,75 @@
+use miditool::backend::mock::MockBackend;
+use miditool::endpoint::Endpoints;
+use miditool::router::{Config, Router, Sinks};
+use miditool::traffic::Traffic;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn fifo_path(name: &str) -> String {
+    env::temp_dir().join(format!("miditool-{}-{}.fifo", name, process::id())).to_string_lossy().into_owned()
+}
+
+/// Routes the input spec to mock output 0 and mock input 0 to the output
+/// spec.
+fn start(in_spec: &str, out_spec: &str) -> (MockBackend, Router) {
+    let mock = MockBackend::new(&["keys"], &["synth"]);
+    let mut backend = Endpoints::new(mock.clone());
+    let pipe_in = backend.input_port(in_spec).unwrap();
+    let pipe_out = backend.output_port(out_spec).unwrap();
+    let configs = vec!(Config{in_port: pipe_in, out_port: 0, ..Config::default()},
+                       Config{in_port: 0, out_port: pipe_out, ..Config::default()});
+    let router = Router::start(&backend, &configs, &Sinks::default(), &Traffic::new()).unwrap();
+    (mock, router)
+}
+
+#[test]
+fn reads_from_fifo() {
+    let (raw, hex) = (fifo_path("raw"), fifo_path("hex"));
+    let (mock, router) = start(&format!("fifo:{}:hex", hex), &format!("fifo:{}", raw));
+
+    // The FIFO is created, writers can come and go
+    for lines in &["90 3c 64\nnot hex\n", "# Comment\n80 3c 00\n"] {
+        let mut writer = OpenOptions::new().write(true).open(&hex).unwrap();
+        writer.write_all(lines.as_bytes()).unwrap();
+    }
+    assert_eq!(mock.wait_for_sent(0, 2), vec!(vec!(0x90, 60, 100), vec!(0x80, 60, 0)));
+    router.close();
+    fs::remove_file(&raw).unwrap();
+    fs::remove_file(&hex).unwrap();
+}
+
+#[test]
+fn writes_to_fifo() {
+    let (json, raw) = (fifo_path("json"), fifo_path("raw-in"));
+    let (mock, router) = start(&format!("fifo:{}", raw), &format!("fifo:{}:json", json));
+
+    // Dropped without a reader
+    mock.receive(0, 0, &[0xB0, 7, 100]);
+    let mut reader = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&json).unwrap();
+    mock.receive(0, 0, &[0x90, 60, 100]);
+    mock.receive(0, 0, &[0xF8]);
+
+    let mut received = String::new();
+    let mut buf = [0u8; 1024];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received.lines().count() < 2 && Instant::now() < deadline {
+        match reader.read(&mut buf) {
+            Ok(len) => received += &String::from_utf8_lossy(&buf[..len]),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(5)),
+            Err(err) => panic!("{}", err),
+        }
+    }
+    let lines: Vec<&str> = received.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("\"bytes\":[144,60,100]}"), "{}", lines[0]);
+    assert!(lines[1].ends_with("\"bytes\":[248]}"), "{}", lines[1]);
+    router.close();
+    fs::remove_file(&json).unwrap();
+    fs::remove_file(&raw).unwrap();
+}
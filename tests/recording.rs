@@ -0,0 +1,81 @@
+use miditool::backend::mock::MockBackend;
+use miditool::backend::Backend;
+use miditool::recording::{self, RecordFormat, Recorded};
+use miditool::router::{Config, Router, Sinks};
+use miditool::traffic::Traffic;
+use miditool::worker;
+
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// Route port 0 to the recorder and return the recorded file.
+fn record(format: RecordFormat, messages: &[(u64, &[u8])]) -> String {
+    let backend = MockBackend::new(&["in"], &[]);
+    let (record_tx, record_rx) = worker::queue();
+    let sinks = Sinks{record: Some(record_tx), ..Sinks::default()};
+    let router = Router::start(&backend, &[Config{in_port: 0, ..Config::default()}], &sinks, &Traffic::new()).unwrap();
+    drop(sinks);
+    for (timestamp, data) in messages {
+        backend.receive(0, *timestamp, data);
+    }
+    router.close();
+    let files = worker::record(record_rx, vec!(Some(("in".to_string(), vec!()))), |_| None, format, Default::default());
+    let (_, data) = files.into_iter().next().unwrap().unwrap();
+    String::from_utf8(data).unwrap()
+}
+
+#[test]
+fn records_hex_lines() {
+    let file = record(RecordFormat::Hex, &[(10, &[0x90, 60, 100]), (20, &[0xF0, 0x7D, 0x01, 0xF7])]);
+    assert_eq!(file, "90 3c 64\nf0 7d 01 f7\n");
+}
+
+#[test]
+fn records_timestamps() {
+    let file = record(RecordFormat::Timed, &[(1000, &[0x90, 60, 100]), (2500, &[0x80, 60, 0])]);
+    assert_eq!(file, "1000: 90 3c 64\n2500: 80 3c 00\n");
+}
+
+#[test]
+fn reads_both_formats() {
+    let messages = recording::read(Cursor::new("# Comment\n90 3c 64\n\n 1500: b0 07 5a \n")).unwrap();
+    assert_eq!(messages, vec!(Recorded{timestamp: None, data: vec!(0x90, 60, 100)},
+                              Recorded{timestamp: Some(1500), data: vec!(0xB0, 7, 90)}));
+}
+
+#[test]
+fn reports_invalid_lines() {
+    let err = recording::read(Cursor::new("90 3c 64\n90 xx 64\n")).unwrap_err();
+    assert_eq!(err.to_string(), "Line 2: Invalid byte 'xx'");
+    let err = recording::read(Cursor::new("abc: 90 3c 64\n")).unwrap_err();
+    assert_eq!(err.to_string(), "Line 1: Invalid timestamp 'abc'");
+    let err = recording::read(Cursor::new("3c 64\n")).unwrap_err();
+    assert_eq!(err.to_string(), "Line 1: Message starts with data byte 3c");
+}
+
+#[test]
+fn plays_back_a_recording() {
+    let file = record(RecordFormat::Timed, &[(5000, &[0x90, 60, 100]), (15000, &[0x80, 60, 0]), (25000, &[0xFC])]);
+    let messages = recording::read(Cursor::new(file)).unwrap();
+    let backend = MockBackend::new(&[], &["out"]);
+    let mut out = backend.connect_output(0).unwrap();
+    let start = Instant::now();
+    assert_eq!(recording::play(&messages, &mut *out, 2.0).unwrap(), 3);
+    // 20 msec of recording at double speed
+    assert!(start.elapsed() >= Duration::from_millis(10));
+    assert_eq!(backend.sent(0), vec!(vec!(0x90, 60, 100), vec!(0x80, 60, 0), vec!(0xFC)));
+}
+
+#[test]
+fn rejects_invalid_speed() {
+    let backend = MockBackend::new(&[], &["out"]);
+    let mut out = backend.connect_output(0).unwrap();
+    assert!(recording::play(&[], &mut *out, 0.0).is_err());
+    assert!(recording::play(&[], &mut *out, f64::NAN).is_err());
+    assert!(recording::play(&[], &mut *out, f64::INFINITY).is_err());
+
+    // The time of the second message doesn't fit in a Duration
+    let messages = [Recorded{timestamp: Some(0), data: vec!(0xF8)}, Recorded{timestamp: Some(1000), data: vec!(0xF8)}];
+    assert!(recording::play(&messages, &mut *out, 1e-300).is_err());
+    assert_eq!(backend.sent(0), vec!(vec!(0xF8)));
+}
@@ -0,0 +1,147 @@
+use miditool::backend::mock::MockBackend;
+use miditool::endpoint::Endpoints;
+use miditool::router::{Config, Router, Sinks};
+use miditool::rtpjournal::Journal;
+use miditool::rtpmidi::{Exchange, Packet};
+use miditool::traffic::Traffic;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PEER_SSRC: u32 = 0x1234_5678;
+
+/// Control and data socket on consecutive ports.
+fn bind_session() -> (UdpSocket, UdpSocket) {
+    loop {
+        let control = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = control.local_addr().unwrap().port();
+        if port == u16::MAX {
+            continue;
+        }
+        if let Ok(data) = UdpSocket::bind(("127.0.0.1", port + 1)) {
+            control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            return (control, data);
+        }
+    }
+}
+
+/// Receive until a session packet matches.
+fn expect(socket: &UdpSocket, matches: impl Fn(&Exchange) -> bool) -> (Exchange, SocketAddr) {
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).expect("No session packet");
+        if let Some(exchange) = Exchange::decode(&buf[..len]) {
+            if matches(&exchange) {
+                return (exchange, from);
+            }
+        }
+    }
+}
+
+fn expect_packet(socket: &UdpSocket) -> Packet {
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf).expect("No RTP packet");
+        if Exchange::decode(&buf[..len]).is_none() {
+            return Packet::decode(&buf[..len]).unwrap();
+        }
+    }
+}
+
+/// Routes the session to mock output 0 and mock input 0 to the session.
+fn start(spec: &str) -> (MockBackend, Router) {
+    let mock = MockBackend::new(&["keys"], &["synth"]);
+    let mut backend = Endpoints::new(mock.clone());
+    let session_in = backend.input_port(spec).unwrap();
+    let session_out = backend.output_port(spec).unwrap();
+    let configs = vec!(Config{in_port: session_in, out_port: 0, ..Config::default()},
+                       Config{in_port: 0, out_port: session_out, ..Config::default()});
+    let router = Router::start(&backend, &configs, &Sinks::default(), &Traffic::new()).unwrap();
+    (mock, router)
+}
+
+#[test]
+fn joins_a_session() {
+    let (control, data) = bind_session();
+    let spec = format!("rtpmidi:127.0.0.1:{}", control.local_addr().unwrap().port());
+    let (mock, router) = start(&spec);
+
+    // Handshake on both ports
+    let (invitation, remote_control) = expect(&control, |e| matches!(e, Exchange::Invitation{..}));
+    let (token, ssrc) = match invitation {
+        Exchange::Invitation{token, ssrc, ..} => (token, ssrc),
+        _ => unreachable!(),
+    };
+    let accepted = Exchange::Accepted{token, ssrc: PEER_SSRC, name: "Mock".to_string()};
+    control.send_to(&accepted.encode(), remote_control).unwrap();
+    let (_, remote_data) = expect(&data, |e| *e == Exchange::Invitation{token, ssrc, name: "MIDI Toolbox".to_string()});
+    data.send_to(&accepted.encode(), remote_data).unwrap();
+
+    // Clock synchronization
+    let (sync, _) = expect(&data, |e| matches!(e, Exchange::Sync{count: 0, ..}));
+    let t1 = match sync {
+        Exchange::Sync{timestamps, ..} => timestamps[0],
+        _ => unreachable!(),
+    };
+    let reply = Exchange::Sync{ssrc: PEER_SSRC, count: 1, timestamps: [t1, 50_000, 0]};
+    data.send_to(&reply.encode(), remote_data).unwrap();
+    expect(&data, |e| matches!(e, Exchange::Sync{count: 2, timestamps: [t, 50_000, _], ..} if *t == t1));
+
+    // Receiving, with the journal of the mock peer
+    let mut journal = Journal::new();
+    let mut send = |seq: u16, message: &[u8], lost: bool| {
+        let packet = Packet{seq, timestamp: 50_000, ssrc: PEER_SSRC, commands: vec!((0, message.to_vec())),
+                            journal: Some(journal.encode())};
+        journal.update(message, seq as u64);
+        if !lost {
+            data.send_to(&packet.encode(), remote_data).unwrap();
+        }
+    };
+    send(1, &[0x90, 60, 100], false);
+    send(2, &[0x80, 60, 0], true);
+    send(3, &[0xB0, 7, 50], true);
+    send(4, &[0x90, 62, 100], false);
+    assert_eq!(mock.wait_for_sent(0, 4), vec!(vec!(0x90, 60, 100), vec!(0xB0, 7, 50), vec!(0x80, 60, 64), vec!(0x90, 62, 100)));
+    expect(&control, |e| *e == Exchange::Feedback{ssrc, seq: 4});
+
+    // Sending
+    mock.receive(0, 0, &[0xB0, 7, 100]);
+    mock.receive(0, 0, &[0x90, 64, 90]);
+    let first = expect_packet(&data);
+    assert_eq!((first.ssrc, first.commands.clone(), first.journal.clone()), (ssrc, vec!((0, vec!(0xB0, 7, 100))), Some(vec!(0, 0, 0))));
+    let second = expect_packet(&data);
+    assert_eq!(second.seq, first.seq.wrapping_add(1));
+    assert_eq!(second.commands, vec!((0, vec!(0x90, 64, 90))));
+    assert_eq!(Journal::new().recover(&second.journal.unwrap()).unwrap(), vec!(vec!(0xB0, 7, 100)));
+
+    router.close();
+    expect(&control, |e| *e == Exchange::End{token, ssrc});
+}
+
+#[test]
+fn connects_two_sessions() {
+    let port = bind_session().0.local_addr().unwrap().port();
+    let (listening, listening_router) = start(&format!("rtpmidi:{}", port));
+    let (inviting, inviting_router) = start(&format!("rtpmidi:127.0.0.1:{}", port));
+
+    // Messages sent before the session is joined get lost
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while listening.sent(0).is_empty() && Instant::now() < deadline {
+        inviting.receive(0, 0, &[0xFE]);
+        thread::sleep(Duration::from_millis(20));
+    }
+    let sysex = [&[0xF0], &[0x33; 2500][..], &[0xF7]].concat();
+    inviting.receive(0, 0, &sysex);
+    inviting.receive(0, 0, &[0x90, 60, 100]);
+    let received = listening.wait_until(0, |sent| sent.last() == Some(&vec!(0x90, 60, 100)));
+    assert_eq!(&received[received.len() - 2..], &[sysex, vec!(0x90, 60, 100)]);
+
+    // And the other direction
+    listening.receive(0, 0, &[0xC3, 10]);
+    assert_eq!(inviting.wait_for_sent(0, 1), vec!(vec!(0xC3, 10)));
+
+    inviting_router.close();
+    listening_router.close();
+}
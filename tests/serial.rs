@@ -0,0 +1,74 @@
+use miditool::backend::mock::MockBackend;
+use miditool::endpoint::Endpoints;
+use miditool::router::{Config, Router, Sinks};
+use miditool::traffic::Traffic;
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::thread;
+use std::time::Duration;
+
+/// Opens a pseudo-terminal, returns the master and the path of the slave,
+/// which stands in for the serial port.
+fn open_pty() -> (File, String) {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(fd >= 0);
+        assert_eq!(libc::grantpt(fd), 0);
+        assert_eq!(libc::unlockpt(fd), 0);
+        let mut name = [0 as libc::c_char; 128];
+        assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+        let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+        (File::from_raw_fd(fd), path)
+    }
+}
+
+/// Routes the serial port to mock output 0 and mock input 0 to the port.
+fn start(spec: &str) -> (MockBackend, Router) {
+    let mock = MockBackend::new(&["keys"], &["synth"]);
+    let mut backend = Endpoints::new(mock.clone());
+    let serial_in = backend.input_port(spec).unwrap();
+    let serial_out = backend.output_port(spec).unwrap();
+    let configs = vec!(Config{in_port: serial_in, out_port: 0, ..Config::default()},
+                       Config{in_port: 0, out_port: serial_out, ..Config::default()});
+    let router = Router::start(&backend, &configs, &Sinks::default(), &Traffic::new()).unwrap();
+    (mock, router)
+}
+
+#[test]
+fn receives_from_serial_port() {
+    let (mut master, path) = open_pty();
+    let (mock, router) = start(&format!("serial:{}", path));
+
+    // Running status, clock within a message and SysEx split over writes
+    master.write_all(&[0x90, 60, 100, 64]).unwrap();
+    master.flush().unwrap();
+    thread::sleep(Duration::from_millis(20));
+    master.write_all(&[0xF8, 100, 0xF0, 0x7D]).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    master.write_all(&[1, 0xF7]).unwrap();
+
+    assert_eq!(mock.wait_for_sent(0, 4), vec!(vec!(0x90, 60, 100), vec!(0xF8), vec!(0x90, 64, 100), vec!(0xF0, 0x7D, 1, 0xF7)));
+    router.close();
+}
+
+#[test]
+fn sends_to_serial_port() {
+    let (mut master, path) = open_pty();
+    let (mock, router) = start(&format!("serial:{}:115200", path));
+
+    mock.receive(0, 0, &[0xB0, 7, 100]);
+    mock.receive(0, 0, &[0xB0, 10, 64]);
+    mock.receive(0, 0, &[0xC0, 5]);
+    let expected = [0xB0, 7, 100, 10, 64, 0xC0, 5];
+    let mut received = vec!();
+    let mut buf = [0u8; 64];
+    while received.len() < expected.len() {
+        let len = master.read(&mut buf).unwrap();
+        received.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(received, expected);
+    router.close();
+}
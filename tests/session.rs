@@ -0,0 +1,39 @@
+use miditool::backend::mock::MockBackend;
+use miditool::display::{MonitorOptions, COLORS_BW};
+use miditool::recording::RecordFormat;
+use miditool::router::Config;
+use miditool::session::{Services, Session, SessionOptions};
+
+use std::env;
+use std::fs;
+use std::process;
+
+#[test]
+fn records_every_port_once() {
+    let prefix = env::temp_dir().join(format!("miditool-session-{}", process::id())).to_string_lossy().into_owned();
+    let backend = MockBackend::new(&["keys", "pads"], &["synth"]);
+    let configs = vec!(Config{in_port: 0, out_port: 0, ..Config::default()},
+                       Config{in_port: 0, out_port: 0, out_channel: 2, ..Config::default()},
+                       Config{in_port: 1, ..Config::default()});
+    let display = MonitorOptions::default();
+    let options = SessionOptions{monitor: false, recording: Some((&prefix, RecordFormat::Hex)), colors: &COLORS_BW, display: &display};
+    let session = Session::start(&backend, &configs, &options, &Services::default()).unwrap();
+    backend.receive(0, 0, &[0x90, 60, 100]);
+    backend.receive(1, 0, &[0x99, 36, 90]);
+    assert_eq!(backend.sent(0), vec!(vec!(0x90, 60, 100), vec!(0x91, 60, 100)));
+    assert!(session.close().is_empty());
+
+    let (keys, pads) = (format!("{}_p0", prefix), format!("{}_p1", prefix));
+    assert_eq!(fs::read_to_string(&keys).unwrap(), "90 3c 64\n");
+    assert_eq!(fs::read_to_string(&pads).unwrap(), "99 24 5a\n");
+    fs::remove_file(&keys).unwrap();
+    fs::remove_file(&pads).unwrap();
+}
+
+#[test]
+fn rejects_the_ui_while_reading_stdin() {
+    let backend = MockBackend::new(&["-"], &[]);
+    let display = MonitorOptions{use_tui: true, ..MonitorOptions::default()};
+    let options = SessionOptions{monitor: false, recording: None, colors: &COLORS_BW, display: &display};
+    assert!(Session::start(&backend, &[Config{in_port: 0, ..Config::default()}], &options, &Services::default()).is_err());
+}
@@ -0,0 +1,112 @@
+use miditool::backend::mock::MockBackend;
+use miditool::backend::Backend;
+use miditool::router::{Config, Router, Sinks};
+use miditool::traffic::Traffic;
+use miditool::web::{self, WebServer};
+use miditool::worker;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Routes mock input 0 to output 0 and streams it to the web server.
+fn start() -> (MockBackend, Arc<Mutex<Router>>, WebServer, thread::JoinHandle<()>) {
+    let mock = MockBackend::new(&["keys"], &["synth"]);
+    let server = WebServer::start("0").unwrap();
+    assert!(server.local_addr().ip().is_loopback());
+    let traffic = Traffic::new();
+    let configs = [Config{in_port: 0, out_port: 0, out_channel: 2, ..Config::default()}];
+    let (web_tx, web_rx) = worker::queue();
+    let sinks = Sinks{web: Some(web_tx), ..Sinks::default()};
+    let router = Arc::new(Mutex::new(Router::start(&mock, &configs, &sinks, &traffic).unwrap()));
+    server.set_status(&mock.input_ports().unwrap(), &mock.output_ports().unwrap(), &router, &traffic);
+    let (clients, port_names) = (server.clients(), mock.input_ports().unwrap());
+    let streamer = thread::spawn(move || web::stream(web_rx, clients, port_names));
+    (mock, router, server, streamer)
+}
+
+fn connect(server: &WebServer) -> TcpStream {
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+/// Send a GET request, returns the status line and the body.
+fn get(server: &WebServer, path: &str) -> (String, String) {
+    let mut stream = connect(server);
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    (head.lines().next().unwrap().to_string(), body[4..].to_string())
+}
+
+#[test]
+fn serves_status() {
+    let (mock, router, server, streamer) = start();
+    mock.receive(0, 0, &[0x90, 60, 100]);
+
+    assert_eq!(get(&server, "/ports"), ("HTTP/1.1 200 OK".to_string(),
+               r#"{"inputs":[{"port":0,"name":"keys"}],"outputs":[{"port":0,"name":"synth"}]}"#.to_string()));
+    assert_eq!(get(&server, "/routes").1,
+               r#"[{"route":0,"name":"0 -> 0 (channel all -> 2)","in":{"port":0,"name":"keys","channel":0},"out":{"port":0,"name":"synth","channel":2},"muted":false}]"#);
+
+    // Changes while running are served right away
+    router.lock().unwrap().set_muted(0, true).unwrap();
+    router.lock().unwrap().add(&mock, Config{in_port: 0, out_port: 0, in_channel: 10, ..Config::default()}).unwrap();
+    assert_eq!(get(&server, "/routes").1,
+               r#"[{"route":0,"name":"0 -> 0 (channel all -> 2)","in":{"port":0,"name":"keys","channel":0},"out":{"port":0,"name":"synth","channel":2},"muted":true},"#.to_string() +
+               r#"{"route":1,"name":"0 -> 0 (channel 10 -> all)","in":{"port":0,"name":"keys","channel":10},"out":{"port":0,"name":"synth","channel":0},"muted":false}]"#);
+    let stats = get(&server, "/stats").1;
+    assert!(stats.starts_with(r#"{"ports":[{"port":0,"name":"keys","messages":1,"#), "{}", stats);
+    assert!(get(&server, "/").1.contains("new WebSocket"));
+    assert_eq!(get(&server, "/missing").0, "HTTP/1.1 404 Not Found");
+
+    let mut stream = connect(&server);
+    stream.write_all(b"POST /ports HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 405"));
+
+    drop(router); // Closes the routes, the server only holds a weak reference
+    streamer.join().unwrap();
+}
+
+#[test]
+fn streams_messages() {
+    let (mock, router, server, streamer) = start();
+    let mut stream = connect(&server);
+    stream.write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let mut head = vec!();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.clients().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    mock.receive(0, 1500, &[0xB0, 7, 90]);
+    let (opcode, payload) = web::read_frame(&mut stream).unwrap();
+    assert_eq!(opcode, 1);
+    assert_eq!(String::from_utf8(payload).unwrap(),
+               r#"{"timestamp":1500,"port":0,"port_name":"keys","type":"ControlChg","channel":1,"controller":7,"value":90,"bytes":[176,7,90]}"#);
+
+    // Masked ping from the client
+    stream.write_all(&[0x89, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2]).unwrap();
+    assert_eq!(web::read_frame(&mut stream).unwrap(), (0xA, b"hi".to_vec()));
+
+    // Closing the server disconnects the client
+    drop(server);
+    assert!(web::read_frame(&mut stream).is_err());
+    drop(router); // Closes the routes, the server only holds a weak reference
+    streamer.join().unwrap();
+}